@@ -56,6 +56,11 @@ void main() {
   float uvx = new_uv_coords.x + x_offset;
   float uvy = new_uv_coords.y + y_offset;
 
+  // Block compressed textures aren't mirrored on upload like other images
+  if (push_constants.flip_xy.z > 0.5) {
+    uvx = 1.0 - uvx;
+  }
+
   //float column = 1.0 - mod(idx, rows);
 
   //float x_offset = idx / coloum;
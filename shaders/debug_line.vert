@@ -0,0 +1,15 @@
+#version 450
+
+layout(location = 0) in vec3 pos;
+layout(location = 1) in vec4 colour;
+
+layout(location = 0) out vec4 o_colour;
+
+layout(push_constant) uniform PushConstants {
+  mat4 view_projection;
+} push_constants;
+
+void main() {
+  o_colour = colour;
+  gl_Position = push_constants.view_projection * vec4(pos, 1.0);
+}
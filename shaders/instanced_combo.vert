@@ -25,6 +25,7 @@ layout (location = 3) out vec4 time_intensity;
 
 layout(push_constant) uniform PushConstants {
   vec2 window_size;
+  float mirror_uv;
 } ubo;
 
 mat4 ortho_projection(float bottom, float top, float left, float right, float near, float far) {
@@ -57,6 +58,11 @@ void main() {
   float uvx = new_uv_coords.x + x_offset;
   float uvy = new_uv_coords.y + y_offset;
 
+  // Block compressed textures aren't mirrored on upload like other images
+  if (ubo.mirror_uv > 0.5) {
+    uvx = 1.0 - uvx;
+  }
+
   o_uv = vec4(uvx, uvy, 0.0, 0.0);
   o_colour = other_colour;
   o_overaly_colour = vec4(overlay_colour.rgb, 0.0);
@@ -10,16 +10,20 @@ layout (location = 3) in vec3 colour;
 layout (location = 4) in vec4 joint_indices;
 layout (location = 5) in vec4 joint_weights;
 
-// Instanced Data input
-layout (location = 6) in mat4 model;
-layout (location = 9) in vec3 offset;
-layout (location = 10) in vec3 scale;
+// Instanced Data input, the columns of the node's transform for this instance
+layout (location = 6) in vec4 model_x;
+layout (location = 7) in vec4 model_y;
+layout (location = 8) in vec4 model_z;
+layout (location = 9) in vec4 model_w;
+layout (location = 10) in vec4 instance_colour;
 
 layout (location = 0) out vec3 o_normal;
-layout (location = 1) out vec3 o_colour;
+layout (location = 1) out vec4 o_colour;
 layout (location = 2) out vec2 o_uv;
 layout (location = 3) out vec3 o_view_vec;
 layout (location = 4) out vec3 o_light_vec;
+layout (location = 5) out vec3 o_world_normal;
+layout (location = 6) out vec3 o_world_view_vec;
 
 layout (set = 0, binding = 0) uniform UBO {
   mat4 projection;
@@ -28,48 +32,30 @@ layout (set = 0, binding = 0) uniform UBO {
   vec2 window_size;
 } ubo;
 
-layout(push_constant) uniform PushConstants {
-  mat4 attrib0;
-  vec4 attrib1;
-  vec4 attrib2;
-  vec4 attrib3;
-  vec4 attrib4;
-} push_constants;
-
 layout(set = 1, binding = 0) readonly buffer JointMatrices {
   mat4 joint_matrices[];
 };
 
-mat4 scale_matrix(vec3 scale) {
-  mat4 s_m = mat4(1.0);
-  
-  s_m[0][0] = scale[0];
-  s_m[1][1] = scale[1];
-  s_m[2][2] = scale[2];
-  
-  return s_m;
-}
-
 void main() {
-  o_normal = normal;
-  o_colour = colour;
+  o_colour = vec4(colour, 1.0) * instance_colour;
   o_uv = uv;
-  
-  vec3 model_scale = scale.xyz;
-  
-  vec3 obj_pos = vec3(pos.x*model_scale.x, pos.y*model_scale.y, pos.z*model_scale.z) + offset.xyz;
-  mat4 scale_matrix = scale_matrix(model_scale);
-  
+
   mat4 skin_mat = joint_weights.x * joint_matrices[int(joint_indices.x)] +
                   joint_weights.y * joint_matrices[int(joint_indices.y)] +
                   joint_weights.z * joint_matrices[int(joint_indices.z)] +
                   joint_weights.w * joint_matrices[int(joint_indices.w)];
-  
-  gl_Position = ubo.projection * ubo.view * model * skin_mat * vec4(obj_pos.xyz, 1.0);
-  
-  vec4 pos = ubo.view * vec4(obj_pos, 1.0);
-  o_normal = mat3(ubo.view) * normal;
+
+  mat4 world = mat4(model_x, model_y, model_z, model_w) * skin_mat;
+  vec4 world_pos = world * vec4(pos.xyz, 1.0);
+  gl_Position = ubo.projection * ubo.view * world_pos;
+
+  vec3 camera_pos = -transpose(mat3(ubo.view)) * ubo.view[3].xyz;
+  o_world_normal = mat3(world) * normal;
+  o_world_view_vec = camera_pos - world_pos.xyz;
+
+  vec4 view_pos = ubo.view * world_pos;
+  o_normal = mat3(ubo.view * world) * normal;
   vec3 l_pos = mat3(ubo.view) * ubo.light_pos.xyz;
-  o_light_vec = l_pos - pos.xyz;
-  o_view_vec = -pos.xyz;
+  o_light_vec = l_pos - view_pos.xyz;
+  o_view_vec = -view_pos.xyz;
 }
@@ -10,10 +10,13 @@ layout (location = 4) in vec4 joint_indices;
 layout (location = 5) in vec4 joint_weights;
 
 layout (location = 0) out vec3 o_normal;
-layout (location = 1) out vec3 o_colour;
+layout (location = 1) out vec4 o_colour;
 layout (location = 2) out vec2 o_uv;
 layout (location = 3) out vec3 o_view_vec;
 layout (location = 4) out vec3 o_light_vec;
+// World space, environment lighting is looked up by world direction
+layout (location = 5) out vec3 o_world_normal;
+layout (location = 6) out vec3 o_world_view_vec;
 
 layout (set = 0, binding = 0) uniform UBO {
   mat4 projection;
@@ -46,7 +49,7 @@ mat4 scale_matrix(vec3 scale) {
 
 void main() {
   o_normal = normal;
-  o_colour = colour;
+  o_colour = vec4(colour, 1.0);
   o_uv = uv;
   
   vec3 model_scale = push_constants.scale.xyz;
@@ -60,6 +63,11 @@ void main() {
                   joint_weights.w * joint_matrices[int(joint_indices.w)];
   
   gl_Position = ubo.projection * ubo.view * push_constants.model * skin_mat * vec4(pos.xyz, 1.0);
+
+  mat4 world = push_constants.model * skin_mat;
+  vec3 camera_pos = -transpose(mat3(ubo.view)) * ubo.view[3].xyz;
+  o_world_normal = mat3(world) * normal;
+  o_world_view_vec = camera_pos - (world * vec4(pos.xyz, 1.0)).xyz;
   
   vec4 pos = ubo.view * vec4(obj_pos, 1.0);
   o_normal = mat3(ubo.view * push_constants.model) * normal;
@@ -4,10 +4,12 @@
 
 
 layout (location = 0) in vec3 o_normal;
-layout (location = 1) in vec3 o_colour;
+layout (location = 1) in vec4 o_colour;
 layout (location = 2) in vec2 o_uv;
 layout (location = 3) in vec3 o_view_vec;
 layout (location = 4) in vec3 o_light_vec;
+layout (location = 5) in vec3 o_world_normal;
+layout (location = 6) in vec3 o_world_view_vec;
 
 layout (location = 0) out vec4 uFragColor;
 
@@ -26,6 +28,17 @@ layout (set = 2, binding = 3) uniform sampler2D metallic_roughness;
 layout (set = 2, binding = 4) uniform sampler2D occlusion;
 layout (set = 2, binding = 5) uniform sampler2D emissive;
 
+// Image based lighting baked from the loaded environment
+layout (set = 3, binding = 0) uniform sampler2D brdf_lut;
+layout (set = 3, binding = 1) uniform samplerCube irradiance_map;
+layout (set = 3, binding = 2) uniform samplerCube specular_map;
+layout (set = 3, binding = 3) uniform Environment {
+  float enabled;
+  float intensity;
+  float specular_mips;
+  float pad;
+} environment;
+
 const float M_PI = 3.141592653589793;
 const float c_MinRoughness = 0.04;
 
@@ -63,12 +76,14 @@ void main() {
   float perceptualRoughness;
   float metallic;
   vec3 diffuseColor;
-  vec4 baseColor = texture(base_colour, o_uv) * vec4(o_colour, 1.0) * pbr_ubo.base_colour_factor;
+  vec4 baseColor = texture(base_colour, o_uv) * o_colour * pbr_ubo.base_colour_factor;
 
   vec3 f0 = vec3(0.04);
 
-  perceptualRoughness = 0.6; //material.roughnessFactor;
-  metallic = 0.4; //material.metallicFactor;
+  // Roughness is in green and metalness in blue
+  vec4 metallic_roughness_sample = texture(metallic_roughness, o_uv);
+  perceptualRoughness = pbr_ubo.roughness * metallic_roughness_sample.g;
+  metallic = pbr_ubo.metallic * metallic_roughness_sample.b;
   
   perceptualRoughness = clamp(perceptualRoughness, c_MinRoughness, 1.0);
   metallic = clamp(metallic, 0.0, 1.0);
@@ -116,8 +131,27 @@ void main() {
 	vec3 color = NdotL * u_LightColor * (diffuseContrib + specContrib);
 
 	// Calculate lighting contribution from image based lighting source (IBL)
-	// color += getIBLContribution(pbrInputs, n, reflection);
-  color += diffuseColor;//baseColor.rgb;
+  if (environment.enabled > 0.5) {
+    vec3 world_n = normalize(o_world_normal);
+    vec3 world_v = normalize(o_world_view_vec);
+    float n_dot_v = clamp(dot(world_n, world_v), 0.001, 1.0);
+
+    vec3 fresnel = specularColor + (max(vec3(1.0 - perceptualRoughness), specularColor) - specularColor) *
+                   pow(1.0 - n_dot_v, 5.0);
+    vec2 brdf = texture(brdf_lut, vec2(n_dot_v, perceptualRoughness)).rg;
+
+    vec3 diffuse_light = texture(irradiance_map, world_n).rgb * diffuseColor * (vec3(1.0) - fresnel);
+    float lod = perceptualRoughness * (environment.specular_mips - 1.0);
+    vec3 specular_light = textureLod(specular_map, reflect(-world_v, world_n), lod).rgb *
+                          (specularColor * brdf.x + brdf.y);
+
+    float occlusion_strength = texture(occlusion, o_uv).r;
+    color += (diffuse_light + specular_light) * environment.intensity * occlusion_strength;
+  } else {
+    color += diffuseColor;//baseColor.rgb;
+  }
+
+  color += texture(emissive, o_uv).rgb * pbr_ubo.emissive.rgb;
   
   
 
@@ -0,0 +1,57 @@
+#version 450
+
+layout (set = 0, binding = 0) uniform sampler2D hdr;
+layout (set = 0, binding = 1) uniform sampler2D bloom;
+// size * size wide and size tall, one size x size slice per blue step
+layout (set = 0, binding = 2) uniform sampler2D lut;
+
+layout (location = 0) out vec4 outColour;
+
+layout (push_constant) uniform Post {
+  vec4 screen; // 1 / width, 1 / height, bloom intensity, exposure
+  vec4 grading; // tone mapping, lut strength, lut size, empty
+} post;
+
+// Narkowicz's fit of the ACES filmic curve
+vec3 aces(vec3 x) {
+  return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+vec3 linear_to_srgb(vec3 colour) {
+  vec3 low = colour * 12.92;
+  vec3 high = 1.055 * pow(colour, vec3(1.0 / 2.4)) - 0.055;
+  return mix(high, low, lessThanEqual(colour, vec3(0.0031308)));
+}
+
+// The lut is authored in display space, sampling the sRGB texture brings it back to linear
+vec3 grade(vec3 colour) {
+  float size = post.grading.z;
+  vec3 cell = linear_to_srgb(colour) * (size - 1.0);
+  float slice = floor(cell.b);
+  float next_slice = min(slice + 1.0, size - 1.0);
+
+  vec2 uv = vec2((cell.r + 0.5) / (size * size), (cell.g + 0.5) / size);
+  vec3 low = textureLod(lut, uv + vec2(slice / size, 0.0), 0.0).rgb;
+  vec3 high = textureLod(lut, uv + vec2(next_slice / size, 0.0), 0.0).rgb;
+
+  return mix(low, high, cell.b - slice);
+}
+
+void main() {
+  vec2 uv = gl_FragCoord.xy * post.screen.xy;
+
+  vec3 colour = texture(hdr, uv).rgb + texture(bloom, uv).rgb * post.screen.z;
+  colour = max(colour * post.screen.w, vec3(0.0));
+
+  if (post.grading.x > 1.5) {
+    colour = aces(colour);
+  } else if (post.grading.x > 0.5) {
+    colour = colour / (1.0 + colour); // Reinhard
+  } else {
+    colour = clamp(colour, 0.0, 1.0);
+  }
+
+  colour = mix(colour, grade(colour), post.grading.y);
+
+  outColour = vec4(colour, 1.0);
+}
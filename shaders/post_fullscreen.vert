@@ -0,0 +1,7 @@
+#version 450
+
+// A single triangle covering the screen, no vertex buffer is bound
+void main() {
+  vec2 pos = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+  gl_Position = vec4(pos * 2.0 - 1.0, 1.0, 1.0);
+}
@@ -0,0 +1,19 @@
+#version 450
+
+layout(location = 0) in vec2 pos;
+layout(location = 1) in vec4 colour;
+
+layout(location = 0) out vec4 o_colour;
+
+layout(push_constant) uniform PushConstants {
+  vec4 window_size_camera; // window width height, 2d camera x y
+} push_constants;
+
+void main() {
+  o_colour = colour;
+
+  // Pixels from the bottom left like the sprites, at the same depth as them
+  vec2 window_size = push_constants.window_size_camera.xy;
+  vec2 screen_pos = pos + push_constants.window_size_camera.zw;
+  gl_Position = vec4(screen_pos / window_size * 2.0 - 1.0, 1.0, 1.0);
+}
@@ -0,0 +1,23 @@
+#version 450
+
+layout (set = 0, binding = 0) uniform samplerCube environment;
+
+layout (location = 0) out vec4 outColour;
+
+layout (push_constant) uniform Sky {
+  mat4 view;
+  vec4 screen; // 1 / width, 1 / height, empty, empty
+  vec4 projection; // x scale, y scale, intensity, empty
+} sky;
+
+// Drawn at the far plane after the models, so it only lands where nothing else was drawn
+void main() {
+  vec2 uv = gl_FragCoord.xy * sky.screen.xy;
+  // The viewport is flipped, the top of the screen is +1
+  vec2 ndc = vec2(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0);
+
+  vec3 view_direction = vec3(ndc.x / sky.projection.x, ndc.y / sky.projection.y, -1.0);
+  vec3 direction = normalize(transpose(mat3(sky.view)) * view_direction);
+
+  outColour = vec4(texture(environment, direction).rgb * sky.projection.z, 1.0);
+}
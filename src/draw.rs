@@ -18,6 +18,8 @@
 use glam::{Vec2, Vec3, Vec4};
 use std::collections::HashMap;
 
+use crate::extra::shapes::{Shape, ShapeVertex};
+
 const EMPTY: f32 = 0.0;
 
 #[derive(Clone)]
@@ -41,6 +43,10 @@ pub struct Draw {
   intensity: f32,
   adding_buffer_data: bool,
   buffer_name: Option<String>,
+  pipeline: Option<String>,
+  particles: Option<String>,
+  shape: Option<Shape>,
+  outline: Option<(f32, Vec4)>,
 }
 
 impl Draw {
@@ -63,6 +69,10 @@ impl Draw {
       intensity: -1.0,
       adding_buffer_data: false,
       buffer_name: None,
+      pipeline: None,
+      particles: None,
+      shape: None,
+      outline: None,
     }
   }
 
@@ -94,6 +104,63 @@ impl Draw {
     }
   }
 
+  /// Draws the particles of an emitter created with `MaatGraphics::create_particle_emitter`.
+  pub fn particles(emitter: &str) -> Draw {
+    Draw {
+      particles: Some(emitter.to_string()),
+      ..Draw::new()
+    }
+  }
+
+  /// A plain coloured rectangle from its bottom left corner, without a texture.
+  pub fn rect(position: Vec2, size: Vec2) -> Draw {
+    Draw::rounded_rect(position, size, 0.0)
+  }
+
+  pub fn rounded_rect(position: Vec2, size: Vec2, radius: f32) -> Draw {
+    Draw {
+      shape: Some(Shape::Rect {
+        position,
+        size,
+        radius,
+      }),
+      ..Draw::new()
+    }
+  }
+
+  pub fn circle(centre: Vec2, radius: f32) -> Draw {
+    Draw {
+      shape: Some(Shape::Circle { centre, radius }),
+      ..Draw::new()
+    }
+  }
+
+  pub fn line(from: Vec2, to: Vec2, thickness: f32) -> Draw {
+    Draw {
+      shape: Some(Shape::Line {
+        from,
+        to,
+        thickness,
+      }),
+      ..Draw::new()
+    }
+  }
+
+  /// A simple polygon, its edges may not cross each other.
+  pub fn polygon(points: Vec<Vec2>) -> Draw {
+    Draw {
+      shape: Some(Shape::Polygon(points)),
+      ..Draw::new()
+    }
+  }
+
+  /// Outlines a shape, centred on its edge. Give the shape a transparent colour to only draw
+  /// the outline.
+  pub fn outline(mut self, thickness: f32, colour: Vec4) -> Draw {
+    self.outline = Some((thickness, colour));
+    self
+  }
+
   pub fn instance_render(mut self, buffer_name: &str) -> Draw {
     self.buffer_name = Some(buffer_name.to_owned());
     self.adding_buffer_data = true;
@@ -163,6 +230,13 @@ impl Draw {
     self
   }
 
+  /// Draws the texture with a pipeline registered through `MaatGraphics::create_sprite_pipeline`
+  /// instead of the default one.
+  pub fn pipeline(mut self, pipeline: &str) -> Draw {
+    self.pipeline = Some(pipeline.to_string());
+    self
+  }
+
   pub fn set_2d_camera_location(pos: Vec2) -> Draw {
     Draw {
       camera_2d_pos: Some(pos),
@@ -174,6 +248,27 @@ impl Draw {
     self.texture.clone()
   }
 
+  pub fn get_pipeline(&self) -> Option<String> {
+    self.pipeline.clone()
+  }
+
+  pub fn get_particles(&self) -> Option<String> {
+    self.particles.clone()
+  }
+
+  pub fn get_shape(&self) -> Option<&Shape> {
+    self.shape.as_ref()
+  }
+
+  /// Triangles of the shape in screen pixels, empty for draws that aren't shapes.
+  pub fn shape_vertices(&self) -> Vec<ShapeVertex> {
+    self
+      .shape
+      .as_ref()
+      .map(|shape| shape.vertices(self.colour, self.outline, self.rotation))
+      .unwrap_or_default()
+  }
+
   pub fn get_text(&self) -> Option<String> {
     self.text.clone()
   }
@@ -0,0 +1,103 @@
+use std::sync::mpsc;
+use std::thread;
+
+use crate::extra::gltf_loader::{self, ParsedGltf};
+use crate::extra::CompressedTexture;
+
+pub enum LoadedAsset {
+  Texture(String, image::RgbaImage),
+  CompressedTexture(String, CompressedTexture),
+  Model(Box<ParsedGltf>),
+  Failed(String, String), // reference, reason
+}
+
+impl LoadedAsset {
+  pub fn reference(&self) -> &str {
+    match self {
+      LoadedAsset::Texture(reference, _) => reference,
+      LoadedAsset::CompressedTexture(reference, _) => reference,
+      LoadedAsset::Model(parsed) => parsed.reference(),
+      LoadedAsset::Failed(reference, _) => reference,
+    }
+  }
+}
+
+/// Decodes textures and parses glTF files on worker threads. The results are collected on the
+/// main thread with `finished_assets` so the device is only ever touched from there.
+pub struct AssetLoader {
+  sender: mpsc::Sender<LoadedAsset>,
+  receiver: mpsc::Receiver<LoadedAsset>,
+  loading: Vec<String>,
+}
+
+impl AssetLoader {
+  pub fn new() -> AssetLoader {
+    let (sender, receiver) = mpsc::channel();
+
+    AssetLoader {
+      sender,
+      receiver,
+      loading: Vec::new(),
+    }
+  }
+
+  pub fn is_loading(&self, reference: &str) -> bool {
+    self.loading.iter().any(|r| r == reference)
+  }
+
+  pub fn load_texture<T: Into<String>>(&mut self, texture_ref: T, texture: T) {
+    let texture_ref = texture_ref.into();
+    let texture = texture.into();
+    let sender = self.sender.clone();
+
+    self.loading.push(texture_ref.to_string());
+
+    thread::spawn(move || {
+      if CompressedTexture::is_container(&texture) {
+        let loaded = match CompressedTexture::load(&texture) {
+          Ok(compressed) => LoadedAsset::CompressedTexture(texture_ref, compressed),
+          Err(e) => LoadedAsset::Failed(texture_ref, e),
+        };
+
+        sender.send(loaded).ok();
+        return;
+      }
+
+      let loaded = match image::open(&texture) {
+        Ok(image) => LoadedAsset::Texture(texture_ref, image.fliph().to_rgba8()),
+        Err(e) => LoadedAsset::Failed(texture_ref, format!("{}: {}", texture, e)),
+      };
+
+      sender.send(loaded).ok();
+    });
+  }
+
+  pub fn load_model<T: Into<String>>(&mut self, model_ref: T, model: &[u8]) {
+    let model_ref = model_ref.into();
+    let model = model.to_vec();
+    let sender = self.sender.clone();
+
+    self.loading.push(model_ref.to_string());
+
+    thread::spawn(move || {
+      let loaded = match gltf_loader::parse_gltf(model_ref.to_string(), &model) {
+        Ok(parsed) => LoadedAsset::Model(Box::new(parsed)),
+        Err(e) => LoadedAsset::Failed(model_ref, e.to_string()),
+      };
+
+      sender.send(loaded).ok();
+    });
+  }
+
+  pub fn finished_assets(&mut self) -> Vec<LoadedAsset> {
+    let finished = self.receiver.try_iter().collect::<Vec<LoadedAsset>>();
+
+    for asset in &finished {
+      if let Some(idx) = self.loading.iter().position(|r| r == asset.reference()) {
+        self.loading.remove(idx);
+      }
+    }
+
+    finished
+  }
+}
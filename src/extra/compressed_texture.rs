@@ -0,0 +1,596 @@
+use std::fs;
+use std::path::Path;
+
+use ash::vk;
+
+const KTX2_IDENTIFIER: [u8; 12] = [
+  0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+];
+const KTX2_HEADER_SIZE: usize = 80;
+const KTX2_LEVEL_INDEX_SIZE: usize = 24;
+
+const DDS_MAGIC: &[u8; 4] = b"DDS ";
+const DDS_HEADER_SIZE: usize = 128;
+const DDS_DX10_HEADER_SIZE: usize = 20;
+const DDSD_MIPMAPCOUNT: u32 = 0x20000;
+const DDSCAPS2_CUBEMAP: u32 = 0x200;
+const DDPF_FOURCC: u32 = 0x4;
+const DDPF_RGB: u32 = 0x40;
+const DDS_DIMENSION_TEXTURE2D: u32 = 3;
+
+/// A texture read straight from a KTX2 or DDS container, kept in the format it was stored in
+/// along with any mip levels baked into the file. Only single layer 2D textures are supported.
+#[derive(Clone, Debug)]
+pub struct CompressedTexture {
+  format: vk::Format,
+  width: u32,
+  height: u32,
+  levels: Vec<Vec<u8>>,
+}
+
+impl CompressedTexture {
+  /// Whether the file is a container that should be loaded with `CompressedTexture`.
+  pub fn is_container(path: &str) -> bool {
+    match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+      Some(ext) => ext.eq_ignore_ascii_case("ktx2") || ext.eq_ignore_ascii_case("dds"),
+      None => false,
+    }
+  }
+
+  pub fn load(path: &str) -> Result<CompressedTexture, String> {
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    CompressedTexture::from_bytes(&bytes).map_err(|e| format!("{}: {}", path, e))
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Result<CompressedTexture, String> {
+    if bytes.starts_with(&KTX2_IDENTIFIER) {
+      CompressedTexture::from_ktx2(bytes)
+    } else if bytes.starts_with(DDS_MAGIC) {
+      CompressedTexture::from_dds(bytes)
+    } else {
+      Err("Not a KTX2 or DDS file".to_string())
+    }
+  }
+
+  pub fn from_ktx2(bytes: &[u8]) -> Result<CompressedTexture, String> {
+    if bytes.len() < KTX2_HEADER_SIZE || !bytes.starts_with(&KTX2_IDENTIFIER) {
+      return Err("Invalid KTX2 header".to_string());
+    }
+
+    let format = vk::Format::from_raw(read_u32(bytes, 12)? as i32);
+    let width = read_u32(bytes, 20)?;
+    let height = read_u32(bytes, 24)?.max(1);
+    let depth = read_u32(bytes, 28)?;
+    let layers = read_u32(bytes, 32)?;
+    let faces = read_u32(bytes, 36)?;
+    let level_count = read_u32(bytes, 40)?.max(1);
+    let supercompression = read_u32(bytes, 44)?;
+
+    if format == vk::Format::UNDEFINED {
+      return Err("Basis Universal KTX2 textures aren't supported".to_string());
+    }
+    if supercompression != 0 {
+      return Err(format!(
+        "KTX2 supercompression scheme {} isn't supported",
+        supercompression
+      ));
+    }
+    if depth > 1 || layers > 1 || faces != 1 {
+      return Err("Only single layer 2D KTX2 textures are supported".to_string());
+    }
+    check_level_count(width, height, level_count)?;
+
+    let mut levels = Vec::new();
+    for level in 0..level_count {
+      let index = KTX2_HEADER_SIZE + level as usize * KTX2_LEVEL_INDEX_SIZE;
+      let offset = read_u64(bytes, index)?;
+      let end = offset
+        .checked_add(read_u64(bytes, index + 8)?)
+        .filter(|end| *end <= bytes.len() as u64)
+        .ok_or(format!("KTX2 level {} is outside of the file", level))?;
+
+      let data = &bytes[offset as usize..end as usize];
+      levels.push(data.to_vec());
+    }
+
+    CompressedTexture::new(format, width, height, levels)
+  }
+
+  pub fn from_dds(bytes: &[u8]) -> Result<CompressedTexture, String> {
+    if bytes.len() < DDS_HEADER_SIZE || !bytes.starts_with(DDS_MAGIC) {
+      return Err("Invalid DDS header".to_string());
+    }
+
+    let flags = read_u32(bytes, 8)?;
+    let height = read_u32(bytes, 12)?;
+    let width = read_u32(bytes, 16)?;
+    let mip_count = if flags & DDSD_MIPMAPCOUNT != 0 {
+      read_u32(bytes, 28)?.max(1)
+    } else {
+      1
+    };
+    let pixel_flags = read_u32(bytes, 80)?;
+    let four_cc = bytes.get(84..88).unwrap_or_default();
+    let caps2 = read_u32(bytes, 112)?;
+
+    if caps2 & DDSCAPS2_CUBEMAP != 0 {
+      return Err("DDS cube maps aren't supported".to_string());
+    }
+    check_level_count(width, height, mip_count)?;
+
+    let mut data_offset = DDS_HEADER_SIZE;
+    let format = if pixel_flags & DDPF_FOURCC != 0 {
+      match four_cc {
+        b"DX10" => {
+          let dxgi_format = read_u32(bytes, DDS_HEADER_SIZE)?;
+          let dimension = read_u32(bytes, DDS_HEADER_SIZE + 4)?;
+          let array_size = read_u32(bytes, DDS_HEADER_SIZE + 12)?;
+
+          if dimension != DDS_DIMENSION_TEXTURE2D || array_size > 1 {
+            return Err("Only single layer 2D DDS textures are supported".to_string());
+          }
+
+          data_offset += DDS_DX10_HEADER_SIZE;
+          dxgi_to_vk_format(dxgi_format)
+            .ok_or(format!("Unsupported DXGI format {}", dxgi_format))?
+        }
+        b"DXT1" => vk::Format::BC1_RGBA_UNORM_BLOCK,
+        b"DXT2" | b"DXT3" => vk::Format::BC2_UNORM_BLOCK,
+        b"DXT4" | b"DXT5" => vk::Format::BC3_UNORM_BLOCK,
+        b"ATI1" | b"BC4U" => vk::Format::BC4_UNORM_BLOCK,
+        b"BC4S" => vk::Format::BC4_SNORM_BLOCK,
+        b"ATI2" | b"BC5U" => vk::Format::BC5_UNORM_BLOCK,
+        b"BC5S" => vk::Format::BC5_SNORM_BLOCK,
+        _ => {
+          return Err(format!(
+            "Unsupported DDS FourCC {}",
+            String::from_utf8_lossy(four_cc)
+          ))
+        }
+      }
+    } else if pixel_flags & DDPF_RGB != 0 && read_u32(bytes, 88)? == 32 {
+      match (read_u32(bytes, 92)?, read_u32(bytes, 100)?) {
+        (0xff, 0xff0000) => vk::Format::R8G8B8A8_UNORM,
+        (0xff0000, 0xff) => vk::Format::B8G8R8A8_UNORM,
+        _ => return Err("Unsupported DDS RGB channel layout".to_string()),
+      }
+    } else {
+      return Err("Unsupported DDS pixel format".to_string());
+    };
+
+    let mut levels = Vec::new();
+    let mut offset = data_offset;
+    for level in 0..mip_count {
+      let length = level_size(
+        format,
+        level_dimension(width, level),
+        level_dimension(height, level),
+      )?;
+
+      let data = offset
+        .checked_add(length)
+        .and_then(|end| bytes.get(offset..end))
+        .ok_or(format!("DDS level {} is outside of the file", level))?;
+      levels.push(data.to_vec());
+      offset += length;
+    }
+
+    CompressedTexture::new(format, width, height, levels)
+  }
+
+  fn new(
+    format: vk::Format,
+    width: u32,
+    height: u32,
+    levels: Vec<Vec<u8>>,
+  ) -> Result<CompressedTexture, String> {
+    if width == 0 || height == 0 {
+      return Err("Texture has no size".to_string());
+    }
+    check_level_count(width, height, levels.len() as u32)?;
+
+    for (level, data) in levels.iter().enumerate() {
+      let level = level as u32;
+      let expected = level_size(
+        format,
+        level_dimension(width, level),
+        level_dimension(height, level),
+      )?;
+
+      if data.len() < expected {
+        return Err(format!(
+          "Mip level {} is {} bytes, expected {}",
+          level,
+          data.len(),
+          expected
+        ));
+      }
+    }
+
+    Ok(CompressedTexture {
+      format,
+      width,
+      height,
+      levels,
+    })
+  }
+
+  pub fn format(&self) -> vk::Format {
+    self.format
+  }
+
+  pub fn width(&self) -> u32 {
+    self.width
+  }
+
+  pub fn height(&self) -> u32 {
+    self.height
+  }
+
+  pub fn levels(&self) -> &Vec<Vec<u8>> {
+    &self.levels
+  }
+
+  /// All levels packed together along with the offset of each one, ready for a staging buffer.
+  pub fn packed_levels(&self) -> (Vec<u8>, Vec<u64>) {
+    let mut data = Vec::new();
+    let mut offsets = Vec::new();
+
+    for level in &self.levels {
+      offsets.push(data.len() as u64);
+      data.extend_from_slice(level);
+    }
+
+    (data, offsets)
+  }
+
+  /// CPU fallback for devices that can't sample the stored format. Gives an RGBA8 texture with
+  /// the same mip levels. BC6H, BC7 and ASTC have no decoder here.
+  pub fn decode_rgba(&self) -> Result<CompressedTexture, String> {
+    let format = if is_srgb(self.format) {
+      vk::Format::R8G8B8A8_SRGB
+    } else {
+      vk::Format::R8G8B8A8_UNORM
+    };
+
+    let levels = self
+      .levels
+      .iter()
+      .enumerate()
+      .map(|(level, data)| {
+        let width = level_dimension(self.width, level as u32);
+        let height = level_dimension(self.height, level as u32);
+        decode_level(self.format, data, width, height)
+      })
+      .collect::<Result<Vec<Vec<u8>>, String>>()?;
+
+    Ok(CompressedTexture {
+      format,
+      width: self.width,
+      height: self.height,
+      levels,
+    })
+  }
+
+  /// Decodes the texture when the device can't sample its format. Uncompressed data is mirrored
+  /// like images decoded with `image` to suit the sprite quad, block compressed data can't be so
+  /// the returned flag is set and the uvs have to be mirrored when it's drawn instead.
+  pub fn for_upload(self, sampled: bool) -> Result<(CompressedTexture, bool), String> {
+    let texture = if sampled { self } else { self.decode_rgba()? };
+
+    match format_block_size(texture.format) {
+      Some((1, 1, texel_size)) => Ok((texture.flip_horizontally(texel_size as usize), false)),
+      _ => Ok((texture, true)),
+    }
+  }
+
+  fn flip_horizontally(mut self, texel_size: usize) -> CompressedTexture {
+    for (level, data) in self.levels.iter_mut().enumerate() {
+      let width = level_dimension(self.width, level as u32) as usize;
+      for row in data.chunks_exact_mut(width * texel_size) {
+        for x in 0..width / 2 {
+          let mirrored_x = width - 1 - x;
+          for i in 0..texel_size {
+            row.swap(x * texel_size + i, mirrored_x * texel_size + i);
+          }
+        }
+      }
+    }
+
+    self
+  }
+}
+
+/// Block width, block height and bytes per block.
+pub fn format_block_size(format: vk::Format) -> Option<(u32, u32, u32)> {
+  let size = match format {
+    vk::Format::R8G8B8A8_UNORM
+    | vk::Format::R8G8B8A8_SRGB
+    | vk::Format::B8G8R8A8_UNORM
+    | vk::Format::B8G8R8A8_SRGB => (1, 1, 4),
+    vk::Format::BC1_RGB_UNORM_BLOCK
+    | vk::Format::BC1_RGB_SRGB_BLOCK
+    | vk::Format::BC1_RGBA_UNORM_BLOCK
+    | vk::Format::BC1_RGBA_SRGB_BLOCK
+    | vk::Format::BC4_UNORM_BLOCK
+    | vk::Format::BC4_SNORM_BLOCK => (4, 4, 8),
+    vk::Format::BC2_UNORM_BLOCK
+    | vk::Format::BC2_SRGB_BLOCK
+    | vk::Format::BC3_UNORM_BLOCK
+    | vk::Format::BC3_SRGB_BLOCK
+    | vk::Format::BC5_UNORM_BLOCK
+    | vk::Format::BC5_SNORM_BLOCK
+    | vk::Format::BC6H_UFLOAT_BLOCK
+    | vk::Format::BC6H_SFLOAT_BLOCK
+    | vk::Format::BC7_UNORM_BLOCK
+    | vk::Format::BC7_SRGB_BLOCK => (4, 4, 16),
+    vk::Format::ASTC_4X4_UNORM_BLOCK | vk::Format::ASTC_4X4_SRGB_BLOCK => (4, 4, 16),
+    vk::Format::ASTC_5X4_UNORM_BLOCK | vk::Format::ASTC_5X4_SRGB_BLOCK => (5, 4, 16),
+    vk::Format::ASTC_5X5_UNORM_BLOCK | vk::Format::ASTC_5X5_SRGB_BLOCK => (5, 5, 16),
+    vk::Format::ASTC_6X5_UNORM_BLOCK | vk::Format::ASTC_6X5_SRGB_BLOCK => (6, 5, 16),
+    vk::Format::ASTC_6X6_UNORM_BLOCK | vk::Format::ASTC_6X6_SRGB_BLOCK => (6, 6, 16),
+    vk::Format::ASTC_8X5_UNORM_BLOCK | vk::Format::ASTC_8X5_SRGB_BLOCK => (8, 5, 16),
+    vk::Format::ASTC_8X6_UNORM_BLOCK | vk::Format::ASTC_8X6_SRGB_BLOCK => (8, 6, 16),
+    vk::Format::ASTC_8X8_UNORM_BLOCK | vk::Format::ASTC_8X8_SRGB_BLOCK => (8, 8, 16),
+    vk::Format::ASTC_10X5_UNORM_BLOCK | vk::Format::ASTC_10X5_SRGB_BLOCK => (10, 5, 16),
+    vk::Format::ASTC_10X6_UNORM_BLOCK | vk::Format::ASTC_10X6_SRGB_BLOCK => (10, 6, 16),
+    vk::Format::ASTC_10X8_UNORM_BLOCK | vk::Format::ASTC_10X8_SRGB_BLOCK => (10, 8, 16),
+    vk::Format::ASTC_10X10_UNORM_BLOCK | vk::Format::ASTC_10X10_SRGB_BLOCK => (10, 10, 16),
+    vk::Format::ASTC_12X10_UNORM_BLOCK | vk::Format::ASTC_12X10_SRGB_BLOCK => (12, 10, 16),
+    vk::Format::ASTC_12X12_UNORM_BLOCK | vk::Format::ASTC_12X12_SRGB_BLOCK => (12, 12, 16),
+    _ => return None,
+  };
+
+  Some(size)
+}
+
+fn level_dimension(size: u32, level: u32) -> u32 {
+  size.checked_shr(level).unwrap_or(0).max(1)
+}
+
+// A full mip chain halves the largest side down to 1, floor(log2(max(width, height))) + 1 levels
+fn check_level_count(width: u32, height: u32, level_count: u32) -> Result<(), String> {
+  let max_levels = 32 - width.max(height).max(1).leading_zeros();
+  if level_count > max_levels {
+    return Err(format!(
+      "{} mip levels, a {}x{} texture has at most {}",
+      level_count, width, height, max_levels
+    ));
+  }
+
+  Ok(())
+}
+
+fn level_size(format: vk::Format, width: u32, height: u32) -> Result<usize, String> {
+  let (block_width, block_height, bytes) =
+    format_block_size(format).ok_or(format!("Unsupported texture format {:?}", format))?;
+
+  (width.div_ceil(block_width) as usize)
+    .checked_mul(height.div_ceil(block_height) as usize)
+    .and_then(|blocks| blocks.checked_mul(bytes as usize))
+    .ok_or(format!(
+      "A {}x{} {:?} level is too large",
+      width, height, format
+    ))
+}
+
+fn is_srgb(format: vk::Format) -> bool {
+  matches!(
+    format,
+    vk::Format::R8G8B8A8_SRGB
+      | vk::Format::B8G8R8A8_SRGB
+      | vk::Format::BC1_RGB_SRGB_BLOCK
+      | vk::Format::BC1_RGBA_SRGB_BLOCK
+      | vk::Format::BC2_SRGB_BLOCK
+      | vk::Format::BC3_SRGB_BLOCK
+      | vk::Format::BC7_SRGB_BLOCK
+  )
+}
+
+fn dxgi_to_vk_format(dxgi_format: u32) -> Option<vk::Format> {
+  let format = match dxgi_format {
+    28 => vk::Format::R8G8B8A8_UNORM,
+    29 => vk::Format::R8G8B8A8_SRGB,
+    71 => vk::Format::BC1_RGBA_UNORM_BLOCK,
+    72 => vk::Format::BC1_RGBA_SRGB_BLOCK,
+    74 => vk::Format::BC2_UNORM_BLOCK,
+    75 => vk::Format::BC2_SRGB_BLOCK,
+    77 => vk::Format::BC3_UNORM_BLOCK,
+    78 => vk::Format::BC3_SRGB_BLOCK,
+    80 => vk::Format::BC4_UNORM_BLOCK,
+    81 => vk::Format::BC4_SNORM_BLOCK,
+    83 => vk::Format::BC5_UNORM_BLOCK,
+    84 => vk::Format::BC5_SNORM_BLOCK,
+    87 => vk::Format::B8G8R8A8_UNORM,
+    91 => vk::Format::B8G8R8A8_SRGB,
+    95 => vk::Format::BC6H_UFLOAT_BLOCK,
+    96 => vk::Format::BC6H_SFLOAT_BLOCK,
+    98 => vk::Format::BC7_UNORM_BLOCK,
+    99 => vk::Format::BC7_SRGB_BLOCK,
+    _ => return None,
+  };
+
+  Some(format)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, String> {
+  bytes
+    .get(offset..offset + 4)
+    .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    .ok_or_else(|| "Unexpected end of file".to_string())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, String> {
+  Ok(read_u32(bytes, offset)? as u64 | (read_u32(bytes, offset + 4)? as u64) << 32)
+}
+
+fn decode_level(
+  format: vk::Format,
+  data: &[u8],
+  width: u32,
+  height: u32,
+) -> Result<Vec<u8>, String> {
+  let mut rgba = vec![0u8; (width * height * 4) as usize];
+
+  match format {
+    vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => {
+      let length = rgba.len();
+      rgba.copy_from_slice(&data[..length]);
+      return Ok(rgba);
+    }
+    vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => {
+      for (dst, src) in rgba.chunks_mut(4).zip(data.chunks(4)) {
+        dst.copy_from_slice(&[src[2], src[1], src[0], src[3]]);
+      }
+      return Ok(rgba);
+    }
+    _ => {}
+  }
+
+  let (_, _, block_bytes) = format_block_size(format).unwrap_or((4, 4, 16));
+  let blocks_wide = width.div_ceil(4);
+
+  for (i, block) in data.chunks(block_bytes as usize).enumerate() {
+    let block_x = (i as u32 % blocks_wide) * 4;
+    let block_y = (i as u32 / blocks_wide) * 4;
+    if block_y >= height {
+      break;
+    }
+
+    let texels = decode_block(format, block)?;
+
+    for (t, texel) in texels.iter().enumerate() {
+      let x = block_x + t as u32 % 4;
+      let y = block_y + t as u32 / 4;
+      if x < width && y < height {
+        let idx = ((y * width + x) * 4) as usize;
+        rgba[idx..idx + 4].copy_from_slice(texel);
+      }
+    }
+  }
+
+  Ok(rgba)
+}
+
+fn decode_block(format: vk::Format, block: &[u8]) -> Result<[[u8; 4]; 16], String> {
+  let texels = match format {
+    vk::Format::BC1_RGB_UNORM_BLOCK
+    | vk::Format::BC1_RGB_SRGB_BLOCK
+    | vk::Format::BC1_RGBA_UNORM_BLOCK
+    | vk::Format::BC1_RGBA_SRGB_BLOCK => decode_bc1(block, true),
+    vk::Format::BC2_UNORM_BLOCK | vk::Format::BC2_SRGB_BLOCK => {
+      let mut texels = decode_bc1(&block[8..], false);
+      let mut alpha = 0u64;
+      for (i, byte) in block[..8].iter().enumerate() {
+        alpha |= (*byte as u64) << (8 * i);
+      }
+      for (i, texel) in texels.iter_mut().enumerate() {
+        texel[3] = ((alpha >> (4 * i)) & 0xF) as u8 * 17;
+      }
+      texels
+    }
+    vk::Format::BC3_UNORM_BLOCK | vk::Format::BC3_SRGB_BLOCK => {
+      let mut texels = decode_bc1(&block[8..], false);
+      for (texel, alpha) in texels.iter_mut().zip(decode_bc4(&block[..8]).iter()) {
+        texel[3] = *alpha;
+      }
+      texels
+    }
+    vk::Format::BC4_UNORM_BLOCK => {
+      let mut texels = [[0, 0, 0, 255]; 16];
+      for (texel, red) in texels.iter_mut().zip(decode_bc4(block).iter()) {
+        texel[0] = *red;
+      }
+      texels
+    }
+    vk::Format::BC5_UNORM_BLOCK => {
+      let mut texels = [[0, 0, 0, 255]; 16];
+      let red = decode_bc4(&block[..8]);
+      let green = decode_bc4(&block[8..]);
+      for (i, texel) in texels.iter_mut().enumerate() {
+        texel[0] = red[i];
+        texel[1] = green[i];
+      }
+      texels
+    }
+    _ => return Err(format!("No CPU decoder for {:?}", format)),
+  };
+
+  Ok(texels)
+}
+
+fn decode_bc1(block: &[u8], allow_transparent: bool) -> [[u8; 4]; 16] {
+  let c0 = u16::from_le_bytes([block[0], block[1]]);
+  let c1 = u16::from_le_bytes([block[2], block[3]]);
+  let indices = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+
+  let a = rgb565(c0);
+  let b = rgb565(c1);
+  let mix = |wa: u32, wb: u32| {
+    let total = wa + wb;
+    [
+      ((a[0] as u32 * wa + b[0] as u32 * wb) / total) as u8,
+      ((a[1] as u32 * wa + b[1] as u32 * wb) / total) as u8,
+      ((a[2] as u32 * wa + b[2] as u32 * wb) / total) as u8,
+      255,
+    ]
+  };
+
+  let palette = if c0 > c1 || !allow_transparent {
+    [a, b, mix(2, 1), mix(1, 2)]
+  } else {
+    [a, b, mix(1, 1), [0, 0, 0, 0]]
+  };
+
+  let mut texels = [[0; 4]; 16];
+  for (i, texel) in texels.iter_mut().enumerate() {
+    *texel = palette[((indices >> (2 * i)) & 0x3) as usize];
+  }
+
+  texels
+}
+
+fn rgb565(colour: u16) -> [u8; 4] {
+  let r = ((colour >> 11) & 0x1F) as u8;
+  let g = ((colour >> 5) & 0x3F) as u8;
+  let b = (colour & 0x1F) as u8;
+
+  [
+    (r << 3) | (r >> 2),
+    (g << 2) | (g >> 4),
+    (b << 3) | (b >> 2),
+    255,
+  ]
+}
+
+fn decode_bc4(block: &[u8]) -> [u8; 16] {
+  let a0 = block[0] as u32;
+  let a1 = block[1] as u32;
+
+  let mut palette = [0u8; 8];
+  palette[0] = a0 as u8;
+  palette[1] = a1 as u8;
+  if a0 > a1 {
+    for k in 1..7 {
+      palette[k + 1] = (((7 - k as u32) * a0 + k as u32 * a1) / 7) as u8;
+    }
+  } else {
+    for k in 1..5 {
+      palette[k + 1] = (((5 - k as u32) * a0 + k as u32 * a1) / 5) as u8;
+    }
+    palette[6] = 0;
+    palette[7] = 255;
+  }
+
+  let mut bits = 0u64;
+  for (i, byte) in block[2..8].iter().enumerate() {
+    bits |= (*byte as u64) << (8 * i);
+  }
+
+  let mut values = [0u8; 16];
+  for (i, value) in values.iter_mut().enumerate() {
+    *value = palette[((bits >> (3 * i)) & 0x7) as usize];
+  }
+
+  values
+}
@@ -0,0 +1,202 @@
+//! Immediate mode debug lines, shapes are broken into line list vertices each frame.
+
+use std::f32::consts::PI;
+
+use glam::{Mat4, Vec3, Vec4};
+
+use crate::extra::frustum::Aabb;
+
+// Line segments around each circle of a sphere
+pub const CIRCLE_SEGMENTS: usize = 24;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DebugVertex {
+  pub pos: [f32; 3],
+  pub colour: [f32; 4],
+}
+
+/// Lines drawn with the depth test against models, and lines drawn over everything.
+#[derive(Default)]
+pub struct DebugLines {
+  depth_tested: Vec<DebugVertex>,
+  overlay: Vec<DebugVertex>,
+}
+
+impl DebugLines {
+  pub fn new() -> DebugLines {
+    DebugLines::default()
+  }
+
+  /// Two vertices for each line.
+  pub fn vertices(&self, depth_test: bool) -> &Vec<DebugVertex> {
+    if depth_test {
+      &self.depth_tested
+    } else {
+      &self.overlay
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.depth_tested.is_empty() && self.overlay.is_empty()
+  }
+
+  pub fn clear(&mut self) {
+    self.depth_tested.clear();
+    self.overlay.clear();
+  }
+
+  pub fn line(&mut self, start: Vec3, end: Vec3, colour: [f32; 4], depth_test: bool) {
+    let vertices = if depth_test {
+      &mut self.depth_tested
+    } else {
+      &mut self.overlay
+    };
+
+    vertices.push(DebugVertex {
+      pos: start.to_array(),
+      colour,
+    });
+    vertices.push(DebugVertex {
+      pos: end.to_array(),
+      colour,
+    });
+  }
+
+  /// The 12 edges between 8 corners, ordered by bits of their index with x the lowest.
+  pub fn box_corners(&mut self, corners: [Vec3; 8], colour: [f32; 4], depth_test: bool) {
+    for i in 0..8 {
+      for axis in [1, 2, 4] {
+        if i & axis == 0 {
+          self.line(corners[i], corners[i | axis], colour, depth_test);
+        }
+      }
+    }
+  }
+
+  /// `aabb` moved by `matrix`, rotated boxes are drawn rotated rather than grown to fit.
+  pub fn aabb(&mut self, aabb: &Aabb, matrix: &Mat4, colour: [f32; 4], depth_test: bool) {
+    let corners = [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+      matrix.transform_point3(Vec3::new(
+        if i & 1 == 0 { aabb.min.x } else { aabb.max.x },
+        if i & 2 == 0 { aabb.min.y } else { aabb.max.y },
+        if i & 4 == 0 { aabb.min.z } else { aabb.max.z },
+      ))
+    });
+
+    self.box_corners(corners, colour, depth_test);
+  }
+
+  /// A circle facing along `normal`.
+  pub fn circle(
+    &mut self,
+    centre: Vec3,
+    normal: Vec3,
+    radius: f32,
+    colour: [f32; 4],
+    depth_test: bool,
+  ) {
+    let normal = normal.normalize_or_zero();
+    if normal == Vec3::ZERO {
+      return;
+    }
+    let tangent = normal.any_orthonormal_vector();
+    let bitangent = normal.cross(tangent);
+
+    let point = |i: usize| {
+      let angle = i as f32 / CIRCLE_SEGMENTS as f32 * 2.0 * PI;
+      centre + (tangent * angle.cos() + bitangent * angle.sin()) * radius
+    };
+
+    for i in 0..CIRCLE_SEGMENTS {
+      self.line(point(i), point(i + 1), colour, depth_test);
+    }
+  }
+
+  /// A circle around each axis.
+  pub fn sphere(&mut self, centre: Vec3, radius: f32, colour: [f32; 4], depth_test: bool) {
+    for axis in [Vec3::X, Vec3::Y, Vec3::Z] {
+      self.circle(centre, axis, radius, colour, depth_test);
+    }
+  }
+
+  /// A line with four lines making a head at `end`, a fifth of its length.
+  pub fn arrow(&mut self, start: Vec3, end: Vec3, colour: [f32; 4], depth_test: bool) {
+    self.line(start, end, colour, depth_test);
+
+    let direction = end - start;
+    let length = direction.length();
+    if length <= f32::EPSILON {
+      return;
+    }
+
+    let direction = direction / length;
+    let back = direction * length * 0.2;
+    let side = direction.any_orthonormal_vector() * length * 0.1;
+    let up = direction.cross(side);
+    for offset in [side, -side, up, -up] {
+      self.line(end, end - back + offset, colour, depth_test);
+    }
+  }
+
+  /// A flat grid on the xz plane, `size` wide with `divisions` squares along each side.
+  pub fn grid(
+    &mut self,
+    centre: Vec3,
+    size: f32,
+    divisions: u32,
+    colour: [f32; 4],
+    depth_test: bool,
+  ) {
+    let divisions = divisions.max(1);
+    let half = size * 0.5;
+
+    for i in 0..=divisions {
+      let offset = i as f32 / divisions as f32 * size - half;
+      self.line(
+        centre + Vec3::new(offset, 0.0, -half),
+        centre + Vec3::new(offset, 0.0, half),
+        colour,
+        depth_test,
+      );
+      self.line(
+        centre + Vec3::new(-half, 0.0, offset),
+        centre + Vec3::new(half, 0.0, offset),
+        colour,
+        depth_test,
+      );
+    }
+  }
+
+  /// The corners of what a camera sees out to `far` along its view direction, near corners
+  /// first with the same bit order as `box_corners`. `far` stands in for the projection's far
+  /// plane, which may be infinitely far away.
+  pub fn frustum_corners(projection: [f32; 16], view: [f32; 16], far: f32) -> [Vec3; 8] {
+    let inverse_projection = Mat4::from_cols_array(&projection).inverse();
+    let inverse_view = Mat4::from_cols_array(&view).inverse();
+
+    [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+      let x = if i & 1 == 0 { -1.0 } else { 1.0 };
+      let y = if i & 2 == 0 { -1.0 } else { 1.0 };
+
+      let near = inverse_projection * Vec4::new(x, y, 0.0, 1.0);
+      let mut near = near.truncate() / near.w;
+      if i & 4 != 0 {
+        near *= far / -near.z;
+      }
+
+      inverse_view.transform_point3(near)
+    })
+  }
+
+  pub fn frustum(
+    &mut self,
+    projection: [f32; 16],
+    view: [f32; 16],
+    far: f32,
+    colour: [f32; 4],
+    depth_test: bool,
+  ) {
+    let corners = DebugLines::frustum_corners(projection, view, far);
+    self.box_corners(corners, colour, depth_test);
+  }
+}
@@ -0,0 +1,536 @@
+//! Image based lighting baked on the CPU when an environment is loaded: the skybox cube map, an
+//! irradiance cube map for diffuse light, a specular cube map prefiltered for each roughness mip
+//! and the split sum BRDF lookup table.
+
+use std::f32::consts::PI;
+use std::path::Path;
+
+use glam::{Vec2, Vec3};
+
+pub const IRRADIANCE_SIZE: u32 = 16;
+pub const SPECULAR_SIZE: u32 = 128;
+// Roughness goes from 0 at the first mip to 1 at the last
+pub const SPECULAR_MIPS: u32 = 6;
+pub const BRDF_LUT_SIZE: u32 = 64;
+const MAX_SKYBOX_SIZE: u32 = 512;
+const SPECULAR_SAMPLES: u32 = 64;
+const BRDF_SAMPLES: u32 = 128;
+
+/// Square faces of linear colour in the +x, -x, +y, -y, +z, -z order Vulkan cube maps use.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cubemap {
+  size: u32,
+  faces: Vec<Vec<Vec3>>,
+}
+
+impl Cubemap {
+  /// Fills each texel with `f` of the direction through its centre.
+  pub fn from_fn<F: Fn(Vec3) -> Vec3>(size: u32, f: F) -> Cubemap {
+    let faces = (0..6)
+      .map(|face| {
+        (0..size * size)
+          .map(|i| {
+            let uv = Vec2::new(
+              ((i % size) as f32 + 0.5) / size as f32,
+              ((i / size) as f32 + 0.5) / size as f32,
+            );
+            f(Cubemap::direction(face, uv))
+          })
+          .collect()
+      })
+      .collect();
+
+    Cubemap { size, faces }
+  }
+
+  /// Resamples a longitude, latitude image with +y at the top row.
+  pub fn from_equirectangular(width: u32, height: u32, pixels: &[Vec3]) -> Cubemap {
+    let size = (width / 4).clamp(1, MAX_SKYBOX_SIZE);
+    Cubemap::from_fn(size, |direction| {
+      let uv = equirectangular_uv(direction);
+      sample_bilinear(
+        width,
+        height,
+        pixels,
+        uv * Vec2::new(width as f32, height as f32),
+        true,
+      )
+    })
+  }
+
+  pub fn from_faces(faces: Vec<(u32, u32, Vec<Vec3>)>) -> Result<Cubemap, String> {
+    if faces.len() != 6 {
+      return Err(format!("A cube map needs 6 faces, got {}", faces.len()));
+    }
+
+    let size = faces[0].0;
+    if faces
+      .iter()
+      .any(|(width, height, _)| *width != size || *height != size)
+    {
+      return Err("Cube map faces must all be square and the same size".to_string());
+    }
+
+    Ok(Cubemap {
+      size,
+      faces: faces.into_iter().map(|(_, _, pixels)| pixels).collect(),
+    })
+  }
+
+  pub fn size(&self) -> u32 {
+    self.size
+  }
+
+  pub fn texel(&self, face: usize, x: u32, y: u32) -> Vec3 {
+    self.faces[face][(y * self.size + x) as usize]
+  }
+
+  /// Direction through a point on a face, `uv` from 0 to 1 across the face.
+  pub fn direction(face: usize, uv: Vec2) -> Vec3 {
+    let s = uv.x * 2.0 - 1.0;
+    let t = uv.y * 2.0 - 1.0;
+    let direction = match face {
+      0 => Vec3::new(1.0, -t, -s),
+      1 => Vec3::new(-1.0, -t, s),
+      2 => Vec3::new(s, 1.0, t),
+      3 => Vec3::new(s, -1.0, -t),
+      4 => Vec3::new(s, -t, 1.0),
+      _ => Vec3::new(-s, -t, -1.0),
+    };
+
+    direction.normalize()
+  }
+
+  /// The face a direction hits and where on it, the inverse of `direction`.
+  pub fn face_uv(direction: Vec3) -> (usize, Vec2) {
+    let abs = direction.abs();
+    let (face, major, s, t) = if abs.x >= abs.y && abs.x >= abs.z {
+      if direction.x > 0.0 {
+        (0, abs.x, -direction.z, -direction.y)
+      } else {
+        (1, abs.x, direction.z, -direction.y)
+      }
+    } else if abs.y >= abs.z {
+      if direction.y > 0.0 {
+        (2, abs.y, direction.x, direction.z)
+      } else {
+        (3, abs.y, direction.x, -direction.z)
+      }
+    } else if direction.z > 0.0 {
+      (4, abs.z, direction.x, -direction.y)
+    } else {
+      (5, abs.z, -direction.x, -direction.y)
+    };
+
+    (face, Vec2::new(s / major + 1.0, t / major + 1.0) * 0.5)
+  }
+
+  /// Bilinear within the face the direction hits, edges clamp rather than wrap to the next face.
+  pub fn sample(&self, direction: Vec3) -> Vec3 {
+    let (face, uv) = Cubemap::face_uv(direction);
+    sample_bilinear(
+      self.size,
+      self.size,
+      &self.faces[face],
+      uv * self.size as f32,
+      false,
+    )
+  }
+
+  /// Half the size, each texel the average of the four it covers.
+  pub fn downsample(&self) -> Cubemap {
+    let size = (self.size / 2).max(1);
+    let step = if self.size > 1 { 2 } else { 1 };
+    let faces = (0..6)
+      .map(|face| {
+        (0..size * size)
+          .map(|i| {
+            let (x, y) = ((i % size) * step, (i / size) * step);
+            let last = self.size - 1;
+            (self.texel(face, x, y)
+              + self.texel(face, (x + 1).min(last), y)
+              + self.texel(face, x, (y + 1).min(last))
+              + self.texel(face, (x + 1).min(last), (y + 1).min(last)))
+              * 0.25
+          })
+          .collect()
+      })
+      .collect();
+
+    Cubemap { size, faces }
+  }
+
+  /// This map followed by each downsample of it to 1x1.
+  pub fn mip_chain(&self) -> Vec<Cubemap> {
+    let mut mips = vec![self.clone()];
+    while mips.last().unwrap().size > 1 {
+      let next = mips.last().unwrap().downsample();
+      mips.push(next);
+    }
+
+    mips
+  }
+
+  /// Blends between the two nearest mips of a chain from `mip_chain`.
+  pub fn sample_lod(mips: &[Cubemap], direction: Vec3, lod: f32) -> Vec3 {
+    let lod = lod.clamp(0.0, (mips.len() - 1) as f32);
+    let lower = lod.floor() as usize;
+    let upper = (lower + 1).min(mips.len() - 1);
+
+    mips[lower]
+      .sample(direction)
+      .lerp(mips[upper].sample(direction), lod - lower as f32)
+  }
+}
+
+/// Where a direction lands on a longitude, latitude image, u around from -z and v down from +y.
+pub fn equirectangular_uv(direction: Vec3) -> Vec2 {
+  let direction = direction.normalize();
+  Vec2::new(
+    0.5 + direction.x.atan2(-direction.z) / (2.0 * PI),
+    direction.y.clamp(-1.0, 1.0).acos() / PI,
+  )
+}
+
+// Texel centres are at half coordinates, wrapping across the sides of equirectangular images
+fn sample_bilinear(width: u32, height: u32, pixels: &[Vec3], position: Vec2, wrap_x: bool) -> Vec3 {
+  let position = position - Vec2::splat(0.5);
+  let x0 = position.x.floor();
+  let y0 = position.y.floor();
+  let fraction = position - Vec2::new(x0, y0);
+
+  let texel = |x: i32, y: i32| {
+    let x = if wrap_x {
+      x.rem_euclid(width as i32)
+    } else {
+      x.clamp(0, width as i32 - 1)
+    };
+    let y = y.clamp(0, height as i32 - 1);
+    pixels[(y as u32 * width + x as u32) as usize]
+  };
+
+  let (x0, y0) = (x0 as i32, y0 as i32);
+  let top = texel(x0, y0).lerp(texel(x0 + 1, y0), fraction.x);
+  let bottom = texel(x0, y0 + 1).lerp(texel(x0 + 1, y0 + 1), fraction.x);
+  top.lerp(bottom, fraction.y)
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+  let c = value as f32 / 255.0;
+  if c <= 0.04045 {
+    c / 12.92
+  } else {
+    ((c + 0.055) / 1.055).powf(2.4)
+  }
+}
+
+/// Loads an image as linear colour, Radiance .hdr files as they are and anything else as sRGB.
+pub fn load_linear_image(path: &str) -> Result<(u32, u32, Vec<Vec3>), String> {
+  let is_hdr = Path::new(path)
+    .extension()
+    .and_then(|ext| ext.to_str())
+    .is_some_and(|ext| ext.eq_ignore_ascii_case("hdr"));
+
+  if is_hdr {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let decoder = image::codecs::hdr::HdrDecoder::new(std::io::BufReader::new(file))
+      .map_err(|e| format!("{}: {}", path, e))?;
+    let metadata = decoder.metadata();
+    let pixels = decoder
+      .read_image_hdr()
+      .map_err(|e| format!("{}: {}", path, e))?
+      .into_iter()
+      .map(|pixel| Vec3::new(pixel[0], pixel[1], pixel[2]))
+      .collect();
+
+    Ok((metadata.width, metadata.height, pixels))
+  } else {
+    let image = image::open(path)
+      .map_err(|e| format!("{}: {}", path, e))?
+      .to_rgb8();
+    let (width, height) = image.dimensions();
+    let pixels = image
+      .pixels()
+      .map(|pixel| {
+        Vec3::new(
+          srgb_to_linear(pixel[0]),
+          srgb_to_linear(pixel[1]),
+          srgb_to_linear(pixel[2]),
+        )
+      })
+      .collect();
+
+    Ok((width, height, pixels))
+  }
+}
+
+/// Radiance projected onto the first nine real spherical harmonics.
+pub fn project_sh(source: &Cubemap) -> [Vec3; 9] {
+  let mut sh = [Vec3::ZERO; 9];
+  let mut total_weight = 0.0;
+  let size = source.size;
+
+  for face in 0..6 {
+    for y in 0..size {
+      for x in 0..size {
+        let s = ((x as f32 + 0.5) / size as f32) * 2.0 - 1.0;
+        let t = ((y as f32 + 0.5) / size as f32) * 2.0 - 1.0;
+        // Solid angle of the texel, relative to the others
+        let weight = 1.0 / (1.0 + s * s + t * t).powf(1.5);
+        let direction = Cubemap::direction(face, Vec2::new(s + 1.0, t + 1.0) * 0.5);
+        let colour = source.texel(face, x, y);
+
+        for (coefficient, basis) in sh.iter_mut().zip(sh_basis(direction).iter()) {
+          *coefficient += colour * *basis * weight;
+        }
+        total_weight += weight;
+      }
+    }
+  }
+
+  let normalise = 4.0 * PI / total_weight;
+  for coefficient in sh.iter_mut() {
+    *coefficient *= normalise;
+  }
+
+  sh
+}
+
+fn sh_basis(d: Vec3) -> [f32; 9] {
+  [
+    0.282095,
+    0.488603 * d.y,
+    0.488603 * d.z,
+    0.488603 * d.x,
+    1.092548 * d.x * d.y,
+    1.092548 * d.y * d.z,
+    0.315392 * (3.0 * d.z * d.z - 1.0),
+    1.092548 * d.x * d.z,
+    0.546274 * (d.x * d.x - d.y * d.y),
+  ]
+}
+
+/// Cosine weighted irradiance around a normal divided by pi, the light a white lambertian
+/// surface reflects.
+pub fn sh_irradiance(sh: &[Vec3; 9], normal: Vec3) -> Vec3 {
+  // Convolution with the clamped cosine lobe for each band
+  const BAND: [f32; 9] = [
+    PI,
+    2.0 * PI / 3.0,
+    2.0 * PI / 3.0,
+    2.0 * PI / 3.0,
+    PI / 4.0,
+    PI / 4.0,
+    PI / 4.0,
+    PI / 4.0,
+    PI / 4.0,
+  ];
+
+  let basis = sh_basis(normal.normalize());
+  let mut irradiance = Vec3::ZERO;
+  for i in 0..9 {
+    irradiance += sh[i] * basis[i] * BAND[i];
+  }
+
+  (irradiance / PI).max(Vec3::ZERO)
+}
+
+pub fn hammersley(i: u32, count: u32) -> Vec2 {
+  Vec2::new(
+    i as f32 / count as f32,
+    i.reverse_bits() as f32 * 2.328_306_4e-10,
+  )
+}
+
+/// Half vector around `normal` distributed like GGX with `alpha` as roughness squared.
+pub fn importance_sample_ggx(xi: Vec2, normal: Vec3, alpha: f32) -> Vec3 {
+  let phi = 2.0 * PI * xi.x;
+  let cos_theta = ((1.0 - xi.y) / (1.0 + (alpha * alpha - 1.0) * xi.y)).sqrt();
+  let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+  let h = Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta);
+
+  let up = if normal.z.abs() < 0.999 {
+    Vec3::Z
+  } else {
+    Vec3::X
+  };
+  let tangent = up.cross(normal).normalize();
+  let bitangent = normal.cross(tangent);
+
+  (tangent * h.x + bitangent * h.y + normal * h.z).normalize()
+}
+
+fn ggx_distribution(n_dot_h: f32, alpha: f32) -> f32 {
+  let alpha_squared = alpha * alpha;
+  let f = n_dot_h * n_dot_h * (alpha_squared - 1.0) + 1.0;
+  alpha_squared / (PI * f * f)
+}
+
+/// Radiance reflected towards `direction` off a surface facing it, picking the source mip from
+/// each sample's footprint so few samples don't alias.
+pub fn prefilter(source_mips: &[Cubemap], direction: Vec3, roughness: f32) -> Vec3 {
+  let normal = direction.normalize();
+  if roughness <= 0.0 {
+    return Cubemap::sample_lod(source_mips, normal, 0.0);
+  }
+
+  let alpha = roughness * roughness;
+  let source_size = source_mips[0].size as f32;
+  let texel_solid_angle = 4.0 * PI / (6.0 * source_size * source_size);
+
+  let mut colour = Vec3::ZERO;
+  let mut total_weight = 0.0;
+  for i in 0..SPECULAR_SAMPLES {
+    let h = importance_sample_ggx(hammersley(i, SPECULAR_SAMPLES), normal, alpha);
+    let l = (2.0 * normal.dot(h) * h - normal).normalize();
+    let n_dot_l = normal.dot(l);
+    if n_dot_l <= 0.0 {
+      continue;
+    }
+
+    // With the view along the normal, n.h and v.h are the same
+    let n_dot_h = normal.dot(h).max(0.0);
+    let pdf = ggx_distribution(n_dot_h, alpha) * 0.25 + 0.0001;
+    let sample_solid_angle = 1.0 / (SPECULAR_SAMPLES as f32 * pdf);
+    let lod = 0.5 * (sample_solid_angle / texel_solid_angle).log2() + 1.0;
+
+    colour += Cubemap::sample_lod(source_mips, l, lod) * n_dot_l;
+    total_weight += n_dot_l;
+  }
+
+  colour / total_weight.max(0.0001)
+}
+
+/// Scale and bias applied to F0 for the split sum approximation, indexed by n.v and roughness.
+pub fn integrate_brdf(n_dot_v: f32, roughness: f32) -> Vec2 {
+  let n_dot_v = n_dot_v.max(0.0001);
+  let v = Vec3::new((1.0 - n_dot_v * n_dot_v).sqrt(), 0.0, n_dot_v);
+  let alpha = roughness * roughness;
+  // Schlick-GGX with the image based lighting k
+  let k = alpha / 2.0;
+  let geometry = |n_dot: f32| n_dot / (n_dot * (1.0 - k) + k);
+
+  let mut result = Vec2::ZERO;
+  for i in 0..BRDF_SAMPLES {
+    let h = importance_sample_ggx(hammersley(i, BRDF_SAMPLES), Vec3::Z, alpha);
+    let l = 2.0 * v.dot(h) * h - v;
+
+    let n_dot_l = l.z.max(0.0);
+    if n_dot_l > 0.0 {
+      let n_dot_h = h.z.max(0.0);
+      let v_dot_h = v.dot(h).max(0.0);
+      let g = geometry(n_dot_l) * geometry(n_dot_v);
+      let g_visible = g * v_dot_h / (n_dot_h * n_dot_v).max(0.0001);
+      let fresnel = (1.0 - v_dot_h).powi(5);
+
+      result += Vec2::new((1.0 - fresnel) * g_visible, fresnel * g_visible);
+    }
+  }
+
+  result / BRDF_SAMPLES as f32
+}
+
+/// Rows of roughness from 0, columns of n.v from 0, sampled at texel centres.
+pub fn brdf_lut(size: u32) -> Vec<Vec2> {
+  (0..size * size)
+    .map(|i| {
+      let n_dot_v = ((i % size) as f32 + 0.5) / size as f32;
+      let roughness = ((i / size) as f32 + 0.5) / size as f32;
+      integrate_brdf(n_dot_v, roughness)
+    })
+    .collect()
+}
+
+pub struct EnvironmentMaps {
+  pub skybox: Cubemap,
+  pub irradiance: Cubemap,
+  // One per roughness step, each half the size of the one before
+  pub specular: Vec<Cubemap>,
+}
+
+impl EnvironmentMaps {
+  pub fn bake(skybox: Cubemap) -> EnvironmentMaps {
+    let source_mips = skybox.mip_chain();
+
+    let sh = project_sh(source_mips.last().map_or(&skybox, |mip| {
+      // A 32x32 face is plenty for the low frequencies spherical harmonics keep
+      source_mips.iter().find(|mip| mip.size <= 32).unwrap_or(mip)
+    }));
+    let irradiance = Cubemap::from_fn(IRRADIANCE_SIZE, |normal| sh_irradiance(&sh, normal));
+
+    let specular = (0..SPECULAR_MIPS)
+      .map(|mip| {
+        let roughness = mip as f32 / (SPECULAR_MIPS - 1) as f32;
+        Cubemap::from_fn((SPECULAR_SIZE >> mip).max(1), |direction| {
+          prefilter(&source_mips, direction, roughness)
+        })
+      })
+      .collect();
+
+    EnvironmentMaps {
+      skybox,
+      irradiance,
+      specular,
+    }
+  }
+}
+
+/// IEEE half precision, rounding to nearest and flushing values too small for it to zero.
+pub fn f32_to_f16(value: f32) -> u16 {
+  let bits = value.to_bits();
+  let sign = ((bits >> 16) & 0x8000) as u16;
+  let exponent = ((bits >> 23) & 0xff) as i32;
+  let mantissa = bits & 0x007f_ffff;
+
+  if exponent == 0xff {
+    // Infinity stays infinity, NaN stays NaN
+    return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+  }
+
+  let exponent = exponent - 127 + 15;
+  if exponent >= 0x1f {
+    return sign | 0x7c00;
+  }
+  if exponent <= 0 {
+    if exponent < -10 {
+      return sign;
+    }
+    // Subnormal, shift in the implicit leading one
+    let mantissa = mantissa | 0x0080_0000;
+    let shift = (14 - exponent) as u32;
+    let half = mantissa >> shift;
+    let round = (mantissa >> (shift - 1)) & 1;
+    return sign | (half + round) as u16;
+  }
+
+  let half = ((exponent as u32) << 10) | (mantissa >> 13);
+  let round = (mantissa >> 12) & 1;
+  // Rounding can carry into the exponent, which is still the right answer
+  sign | (half + round) as u16
+}
+
+/// RGBA16F bytes for each level with the faces of a level one after the other, along with the
+/// offset of each level.
+pub fn pack_rgba16f(levels: &[Vec<Vec<Vec3>>]) -> (Vec<u8>, Vec<u64>) {
+  let mut data = Vec::new();
+  let mut offsets = Vec::new();
+
+  for level in levels {
+    offsets.push(data.len() as u64);
+    for layer in level {
+      for colour in layer {
+        for channel in [colour.x, colour.y, colour.z, 1.0] {
+          data.extend_from_slice(&f32_to_f16(channel).to_le_bytes());
+        }
+      }
+    }
+  }
+
+  (data, offsets)
+}
+
+impl Cubemap {
+  pub fn faces(&self) -> &Vec<Vec<Vec3>> {
+    &self.faces
+  }
+}
@@ -0,0 +1,130 @@
+//! View frustums and bounding volumes for skipping models the camera can't see.
+
+use glam::{Mat4, Vec3, Vec4};
+
+/// Axis aligned bounding box.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+  pub min: Vec3,
+  pub max: Vec3,
+}
+
+impl Aabb {
+  pub fn new(min: Vec3, max: Vec3) -> Aabb {
+    Aabb { min, max }
+  }
+
+  pub fn centre(&self) -> Vec3 {
+    (self.min + self.max) * 0.5
+  }
+
+  pub fn half_extents(&self) -> Vec3 {
+    (self.max - self.min) * 0.5
+  }
+
+  /// The sphere around the box, as centre and radius.
+  pub fn bounding_sphere(&self) -> (Vec3, f32) {
+    (self.centre(), self.half_extents().length())
+  }
+
+  /// The smallest box holding this one after `matrix` moves it, rotated boxes grow to fit.
+  pub fn transform(&self, matrix: &Mat4) -> Aabb {
+    let centre = matrix.transform_point3(self.centre());
+    let half_extents = self.half_extents();
+    let extents = matrix.x_axis.truncate().abs() * half_extents.x
+      + matrix.y_axis.truncate().abs() * half_extents.y
+      + matrix.z_axis.truncate().abs() * half_extents.z;
+
+    Aabb::new(centre - extents, centre + extents)
+  }
+
+  pub fn union(&self, other: &Aabb) -> Aabb {
+    Aabb::new(self.min.min(other.min), self.max.max(other.max))
+  }
+}
+
+/// The six planes bounding what a camera sees, left, right, bottom, top, near then far. Each
+/// plane is `xyz` normal pointing inside and `w` distance, a point is inside when
+/// `normal.dot(point) + w >= 0`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frustum {
+  planes: [Vec4; 6],
+}
+
+impl Frustum {
+  /// Pulls the planes out of a projection * view matrix, with Vulkan's 0 to 1 depth. Positions
+  /// are tested in whatever space the matrix takes them from.
+  pub fn from_matrix(matrix: Mat4) -> Frustum {
+    let row = |i| matrix.row(i);
+
+    let mut planes = [
+      row(3) + row(0),
+      row(3) - row(0),
+      row(3) + row(1),
+      row(3) - row(1),
+      row(2),
+      row(3) - row(2),
+    ];
+
+    // A projection without a far plane leaves a plane with no normal that everything is inside
+    for plane in &mut planes {
+      let length = plane.truncate().length();
+      if length > f32::EPSILON {
+        *plane /= length;
+      }
+    }
+
+    Frustum { planes }
+  }
+
+  /// The world space frustum of a camera's projection and view matrices.
+  pub fn from_camera(projection: [f32; 16], view: [f32; 16]) -> Frustum {
+    Frustum::from_matrix(Mat4::from_cols_array(&projection) * Mat4::from_cols_array(&view))
+  }
+
+  pub fn planes(&self) -> &[Vec4; 6] {
+    &self.planes
+  }
+
+  fn distance(plane: Vec4, point: Vec3) -> f32 {
+    plane.truncate().dot(point) + plane.w
+  }
+
+  pub fn contains_point(&self, point: Vec3) -> bool {
+    self
+      .planes
+      .iter()
+      .all(|plane| Frustum::distance(*plane, point) >= 0.0)
+  }
+
+  pub fn intersects_sphere(&self, centre: Vec3, radius: f32) -> bool {
+    self
+      .planes
+      .iter()
+      .all(|plane| Frustum::distance(*plane, centre) >= -radius)
+  }
+
+  /// Conservative, boxes near the frustum's corners can pass while being just outside.
+  pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+    self.planes.iter().all(|plane| {
+      // The corner furthest along the plane normal
+      let normal = plane.truncate();
+      let corner = Vec3::select(normal.cmpge(Vec3::ZERO), aabb.max, aabb.min);
+      Frustum::distance(*plane, corner) >= 0.0
+    })
+  }
+}
+
+/// Primitives drawn and skipped for being outside the camera's view since the frame started.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CullingStats {
+  pub drawn: usize,
+  pub culled: usize,
+}
+
+impl CullingStats {
+  pub fn add(&mut self, other: CullingStats) {
+    self.drawn += other.drawn;
+    self.culled += other.culled;
+  }
+}
@@ -4,11 +4,12 @@ use ash::vk;
 use gltf;
 use gltf::animation::Property;
 
-use crate::extra::Math;
-use crate::glam::{Mat4, Quat, Vec3};
+use crate::extra::{frustum::Aabb, Math};
+use crate::glam::{EulerRot, Mat4, Quat, Vec3};
 use crate::shader_handlers::TextureHandler;
 use crate::vkwrapper::{
-  Buffer, DescriptorPoolBuilder, DescriptorSet, DescriptorWriter, Sampler, VkDevice, Vulkan,
+  Buffer, DescriptorAllocator, DescriptorPoolBuilder, DescriptorSet, DescriptorWriter, Sampler,
+  VkDevice, Vulkan,
 };
 use crate::Image as vkimage;
 
@@ -89,6 +90,16 @@ pub struct Primitive {
   pub bounding_box_max: [f32; 3],
 }
 
+impl Primitive {
+  /// Bounds of the vertices before the node transform.
+  pub fn bounds(&self) -> Aabb {
+    Aabb::new(
+      Vec3::from(self.bounding_box_min),
+      Vec3::from(self.bounding_box_max),
+    )
+  }
+}
+
 pub struct Mesh {
   pub primitives: Vec<Primitive>,
 }
@@ -124,6 +135,7 @@ pub struct MaterialUbo {
 
 pub struct Material {
   descriptor_set: DescriptorSet,
+  material_buffer: Buffer<MaterialUbo>,
   material_ubo: MaterialUbo,
   base_colour_texture: Option<usize>,
   metallic_roughness_texture: Option<usize>,
@@ -152,10 +164,11 @@ pub struct GltfModel {
 
   animations: Vec<Animation>,
 
+  images: Vec<vkimage>,
   textures: Vec<Texture>,
   materials: Vec<Material>,
 
-  descriptor_pool: vk::DescriptorPool,
+  descriptor_pool: DescriptorAllocator,
   active_animation: i32,
 }
 
@@ -166,7 +179,7 @@ impl MaterialUbo {
       roughness: 0.6,
       metallic: 0.4,
       double_sided: -1.0,
-      emissive: [1.0; 4],
+      emissive: [0.0, 0.0, 0.0, 1.0],
       pad: 0.0,
     }
   }
@@ -360,6 +373,21 @@ impl CollisionInformation {
 }
 
 impl Node {
+  /// Translation, rotation and scale from the start of a model's draw data, the rotation is
+  /// euler angles in degrees.
+  pub fn transform_from_data(data: &[f32]) -> (Vec3, Quat, Vec3) {
+    let translation = Vec3::new(data[0], data[1], data[2]);
+    let rotation = Quat::from_euler(
+      EulerRot::YXZ,
+      data[9].to_radians(),
+      data[8].to_radians(),
+      data[10].to_radians(),
+    );
+    let scale = Vec3::new(data[4], data[5], data[6]);
+
+    (translation, rotation, scale)
+  }
+
   pub fn calculate_global_matrix(
     nodes: &[Node],
     idx: usize,
@@ -449,7 +477,10 @@ impl Node {
 }
 
 impl GltfModel {
-  pub fn mesh_descriptor(device: &VkDevice, descriptor_pool: &vk::DescriptorPool) -> DescriptorSet {
+  pub fn mesh_descriptor(
+    device: &VkDevice,
+    descriptor_pool: &DescriptorAllocator,
+  ) -> DescriptorSet {
     DescriptorSet::builder()
       .uniform_buffer_fragment()
       .combined_image_sampler_fragment()
@@ -492,6 +523,31 @@ impl GltfModel {
     &self.collision_info
   }
 
+  pub fn destroy(&mut self, device: &VkDevice) {
+    self.mesh_index_buffer.destroy(device);
+    self.mesh_vertex_buffer.destroy(device);
+
+    for skin in &self.mesh_skins {
+      skin.inverse_bind_matrix_buffer.destroy(device);
+      skin.descriptor_set.destroy(device);
+    }
+
+    for material in &self.materials {
+      material.material_buffer.destroy(device);
+      material.descriptor_set.destroy(device);
+    }
+
+    for texture in &mut self.textures {
+      texture.sampler.destroy(device);
+    }
+
+    for image in &self.images {
+      image.destroy(device);
+    }
+
+    self.descriptor_pool.destroy(device);
+  }
+
   pub fn update_animation(&mut self, _vulkan: &mut Vulkan, delta_time: f32) {
     if self.active_animation != -1 && self.active_animation < self.animations.len() as i32 {
       let anim_idx = self.active_animation as usize;
@@ -698,7 +754,7 @@ fn load_skins(
   vulkan: &mut Vulkan,
   gltf: &gltf::Document,
   buffers: &[gltf::buffer::Data],
-  descriptor_pool: &vk::DescriptorPool,
+  descriptor_pool: &DescriptorAllocator,
   nodes: &mut Vec<Node>,
   skins: &mut Vec<Skin>,
 ) {
@@ -777,15 +833,13 @@ fn load_skins(
   }
 }
 
-fn load_images(
-  vulkan: &mut Vulkan,
+fn decode_images(
   gltf: &gltf::Document,
   buffers: &[gltf::buffer::Data],
-  images: &mut Vec<vkimage>,
-) {
-  let gltf_images = gltf.images();
+) -> Vec<image::ImageBuffer<image::Rgba<u8>, Vec<u8>>> {
+  let mut images = Vec::new();
 
-  for image in gltf_images {
+  for image in gltf.images() {
     let image_data = image.source();
     let some_image = {
       match image_data {
@@ -811,10 +865,50 @@ fn load_images(
     };
 
     if let Some(image) = some_image {
-      let loaded_image = TextureHandler::create_device_local_texture_from_image(vulkan, image);
-      images.push(loaded_image);
+      images.push(image);
     }
   }
+
+  images
+}
+
+/// Base colour and emissive images hold colours and are sampled as sRGB, normal, metallic
+/// roughness and occlusion maps hold linear data.
+fn image_formats(gltf: &gltf::Document) -> Vec<vk::Format> {
+  let mut formats = vec![vk::Format::A8B8G8R8_SRGB_PACK32; gltf.images().len()];
+
+  for material in gltf.materials() {
+    let pbr = material.pbr_metallic_roughness();
+    let linear_textures = [
+      material.normal_texture().map(|info| info.texture()),
+      pbr.metallic_roughness_texture().map(|info| info.texture()),
+      material.occlusion_texture().map(|info| info.texture()),
+    ];
+
+    for texture in linear_textures.iter().flatten() {
+      formats[texture.source().index()] = vk::Format::A8B8G8R8_UNORM_PACK32;
+    }
+  }
+
+  formats
+}
+
+fn load_images(
+  vulkan: &mut Vulkan,
+  decoded_images: Vec<image::ImageBuffer<image::Rgba<u8>, Vec<u8>>>,
+  formats: Vec<vk::Format>,
+  images: &mut Vec<vkimage>,
+) {
+  let mut transfers = Vec::new();
+
+  for (image, format) in decoded_images.into_iter().zip(formats) {
+    let (staging_buffer, loaded_image) =
+      TextureHandler::create_staged_texture_from_image(vulkan, image, format);
+    images.push(loaded_image.clone());
+    transfers.push((staging_buffer, loaded_image));
+  }
+
+  vulkan.transfer_buffers_to_device_local_images(transfers);
 }
 
 fn load_textures(vulkan: &mut Vulkan, gltf: &gltf::Document, textures: &mut Vec<Texture>) {
@@ -824,6 +918,7 @@ fn load_textures(vulkan: &mut Vulkan, gltf: &gltf::Document, textures: &mut Vec<
 
     let mut sampler = Sampler::builder()
       .mipmap_mode_linear()
+      .anisotropy(16.0)
       .border_colour_float_opaque_white()
       .compare_op_never();
 
@@ -832,7 +927,14 @@ fn load_textures(vulkan: &mut Vulkan, gltf: &gltf::Document, textures: &mut Vec<
         match min {
           gltf::texture::MinFilter::Nearest => sampler.min_filter_nearest(),
           gltf::texture::MinFilter::Linear => sampler.min_filter_linear(),
-          _ => sampler.min_filter_linear(),
+          gltf::texture::MinFilter::NearestMipmapNearest => {
+            sampler.min_filter_nearest().mipmap_mode_nearest()
+          }
+          gltf::texture::MinFilter::LinearMipmapNearest => {
+            sampler.min_filter_linear().mipmap_mode_nearest()
+          }
+          gltf::texture::MinFilter::NearestMipmapLinear => sampler.min_filter_nearest(),
+          gltf::texture::MinFilter::LinearMipmapLinear => sampler.min_filter_linear(),
         }
       };
     } else {
@@ -873,7 +975,7 @@ fn load_textures(vulkan: &mut Vulkan, gltf: &gltf::Document, textures: &mut Vec<
 
 fn load_material(
   vulkan: &mut Vulkan,
-  descriptor_pool: &vk::DescriptorPool,
+  descriptor_pool: &DescriptorAllocator,
   gltf: &gltf::Document,
   materials: &mut Vec<Material>,
   textures: &[Texture],
@@ -924,7 +1026,7 @@ fn load_material(
     let normal_map = if let Some(normal_texture) = material.normal_texture() {
       let label = normal_texture.texture().index() as usize;
       let sampler = &textures[label].sampler;
-      images.push(mesh_images[label].clone());
+      images.push(mesh_images[textures[label].image_index as usize].clone());
       samplers.push(sampler.clone());
       Some(label)
     } else {
@@ -936,7 +1038,7 @@ fn load_material(
     let metallic_roughness_texture = if let Some(info) = pbr.metallic_roughness_texture() {
       let label = info.texture().index() as usize;
       let sampler = &textures[label].sampler;
-      images.push(mesh_images[label].clone());
+      images.push(mesh_images[textures[label].image_index as usize].clone());
       samplers.push(sampler.clone());
       Some(label)
     } else {
@@ -948,7 +1050,7 @@ fn load_material(
     let occlusion_texture = if let Some(occlusion_texture) = material.occlusion_texture() {
       let label = occlusion_texture.texture().index() as usize;
       let sampler = &textures[label].sampler;
-      images.push(mesh_images[label].clone());
+      images.push(mesh_images[textures[label].image_index as usize].clone());
       samplers.push(sampler.clone());
       Some(label)
     } else {
@@ -960,7 +1062,7 @@ fn load_material(
     let emissive_texture = if let Some(info) = material.emissive_texture() {
       let label = info.texture().index() as usize;
       let sampler = &textures[label].sampler;
-      images.push(mesh_images[label].clone());
+      images.push(mesh_images[textures[label].image_index as usize].clone());
       samplers.push(sampler.clone());
       Some(label)
     } else {
@@ -975,6 +1077,7 @@ fn load_material(
 
     materials.push(Material {
       descriptor_set,
+      material_buffer,
       material_ubo,
       base_colour_texture,
       metallic_roughness_texture,
@@ -1197,6 +1300,9 @@ fn load_node(
       let mut b_box_min: [f32; 3] = [0.0; 3];
       let mut b_box_max: [f32; 3] = [0.0; 3];
 
+      // The vertices are untouched so the primitive keeps the bounds they were built with
+      let mesh_bounds = primitive.bounding_box();
+
       match primitive.bounding_box() {
         gltf::mesh::BoundingBox { min, max } => {
           b_box_min[0] = min[0] * nodes[node_idx].scale[0];
@@ -1228,8 +1334,8 @@ fn load_node(
         index_count: index_count as u32,
         material_index: mat_idx as i32,
         displacement,
-        bounding_box_min: b_box_min,
-        bounding_box_max: b_box_max,
+        bounding_box_min: mesh_bounds.min,
+        bounding_box_max: mesh_bounds.max,
       });
       first_index += index_count;
 
@@ -1277,41 +1383,42 @@ pub fn update_joints(
   }
 }
 
-pub fn load_gltf<T: Into<String>>(
-  vulkan: &mut Vulkan,
-  sampler: &Sampler,
-  dummy_texture: &vkimage,
-  reference: T,
-  location: &[u8], //T,
-) -> GltfModel {
-  //let location = location.into();
+/// Everything in a glTF file that can be worked out without touching the device, so it can be
+/// built on a worker thread and handed to `upload_gltf` on the main thread.
+pub struct ParsedGltf {
+  reference: String,
+  gltf: gltf::Document,
+  buffers: Vec<gltf::buffer::Data>,
+  decoded_images: Vec<image::ImageBuffer<image::Rgba<u8>, Vec<u8>>>,
+
+  nodes: Vec<Node>,
+  animations: Vec<Animation>,
+  index_buffer: Vec<u32>,
+  vertex_buffer: Vec<MeshVertex>,
+  collision_objects: Vec<CollisionObject>,
+}
 
+impl ParsedGltf {
+  pub fn reference(&self) -> &str {
+    &self.reference
+  }
+}
+
+pub fn parse_gltf<T: Into<String>>(
+  reference: T,
+  location: &[u8],
+) -> Result<ParsedGltf, gltf::Error> {
   let reference = reference.into();
-  println!("Loading model: {}", reference.to_string());
 
-  let mut images: Vec<vkimage> = Vec::new();
-  let mut textures: Vec<Texture> = Vec::new();
-  let mut materials: Vec<Material> = Vec::new();
   let mut nodes: Vec<Node> = Vec::new();
-  let mut mesh_animations: Vec<Animation> = Vec::new();
-  let mut mesh_skins: Vec<Skin> = Vec::new();
+  let mut animations: Vec<Animation> = Vec::new();
 
   let mut index_buffer = Vec::new();
   let mut vertex_buffer = Vec::new();
 
   let mut collision_objects = Vec::new();
 
-  //let dummy_image = TextureHandler::create_blank_image();
-  //let dummy_texture = TextureHandler::create_device_local_texture_from_image(vulkan, dummy_image);
-  //let image_view_info = dummy_texture.build_imageview(&dummy_texture.internal());
-
-  let (gltf, buffers, _images) = gltf::import_slice(location).unwrap();
-
-  let mut descriptor_pool = DescriptorPoolBuilder::new()
-    .num_uniform_buffers((images.len() as u32).max(1))
-    .num_storage((gltf.skins().len() as u32).max(1))
-    .num_combined_image_samplers((images.len() as u32).max(1) * 100)
-    .build(vulkan.device());
+  let (gltf, buffers, _images) = gltf::import_slice(location)?;
 
   for scene in gltf.scenes() {
     for node in scene.nodes() {
@@ -1327,11 +1434,58 @@ pub fn load_gltf<T: Into<String>>(
     }
   }
 
+  let decoded_images = decode_images(&gltf, &buffers);
+  load_animation(&gltf, &buffers, &nodes, &mut animations);
+
+  Ok(ParsedGltf {
+    reference,
+    gltf,
+    buffers,
+    decoded_images,
+
+    nodes,
+    animations,
+    index_buffer,
+    vertex_buffer,
+    collision_objects,
+  })
+}
+
+pub fn upload_gltf(
+  vulkan: &mut Vulkan,
+  sampler: &Sampler,
+  dummy_texture: &vkimage,
+  parsed: ParsedGltf,
+) -> GltfModel {
+  let ParsedGltf {
+    reference,
+    gltf,
+    buffers,
+    decoded_images,
+
+    mut nodes,
+    animations: mesh_animations,
+    index_buffer,
+    vertex_buffer,
+    collision_objects,
+  } = parsed;
+
+  let mut images: Vec<vkimage> = Vec::new();
+  let mut textures: Vec<Texture> = Vec::new();
+  let mut materials: Vec<Material> = Vec::new();
+  let mut mesh_skins: Vec<Skin> = Vec::new();
+
+  let descriptor_pool = DescriptorPoolBuilder::new()
+    .num_uniform_buffers((gltf.meshes().len() as u32).max(1))
+    .num_storage((gltf.skins().len() as u32).max(1))
+    .num_combined_image_samplers((gltf.materials().len() as u32).max(1))
+    .build(vulkan.device());
+
   load_textures(vulkan, &gltf, &mut textures);
-  load_images(vulkan, &gltf, &buffers, &mut images);
+  load_images(vulkan, decoded_images, image_formats(&gltf), &mut images);
   load_material(
     vulkan,
-    &mut descriptor_pool,
+    &descriptor_pool,
     &gltf,
     &mut materials,
     &mut textures,
@@ -1347,7 +1501,6 @@ pub fn load_gltf<T: Into<String>>(
     &mut nodes,
     &mut mesh_skins,
   );
-  load_animation(&gltf, &buffers, &nodes, &mut mesh_animations);
 
   let mesh_index_buffer = Buffer::<u32>::new_index(&vulkan.device(), index_buffer);
   let mesh_vertex_buffer = Buffer::<MeshVertex>::new_vertex(vulkan.device(), vertex_buffer);
@@ -1374,6 +1527,7 @@ pub fn load_gltf<T: Into<String>>(
 
     animations: mesh_animations,
 
+    images,
     textures,
     materials,
 
@@ -1381,3 +1535,24 @@ pub fn load_gltf<T: Into<String>>(
     active_animation: 0, //8, //2, //5,
   }
 }
+
+pub fn load_gltf<T: Into<String>>(
+  vulkan: &mut Vulkan,
+  sampler: &Sampler,
+  dummy_texture: &vkimage,
+  reference: T,
+  location: &[u8], //T,
+) -> GltfModel {
+  //let location = location.into();
+
+  let reference = reference.into();
+  println!("Loading model: {}", reference.to_string());
+
+  //let dummy_image = TextureHandler::create_blank_image();
+  //let dummy_texture = TextureHandler::create_device_local_texture_from_image(vulkan, dummy_image);
+  //let image_view_info = dummy_texture.build_imageview(&dummy_texture.internal());
+
+  let parsed = parse_gltf(reference, location).unwrap();
+
+  upload_gltf(vulkan, sampler, dummy_texture, parsed)
+}
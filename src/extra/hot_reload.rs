@@ -0,0 +1,135 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use std::time::SystemTime;
+
+const POLL_INTERVAL: f32 = 0.5;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum WatchedAsset {
+  Texture(String),
+  Model(String),
+  Font,
+  Shaders,
+}
+
+struct WatchedFile {
+  asset: WatchedAsset,
+  path: String,
+  modified: Option<SystemTime>,
+}
+
+/// Polls the modification times of loaded files so they can be reloaded while the game runs.
+/// Paths are always recorded but nothing is polled until `enable` is called.
+pub struct HotReloader {
+  enabled: bool,
+  shader_directory: Option<String>,
+  files: Vec<WatchedFile>,
+  time_since_poll: f32,
+}
+
+impl HotReloader {
+  pub fn new() -> HotReloader {
+    HotReloader {
+      enabled: false,
+      shader_directory: None,
+      files: Vec::new(),
+      time_since_poll: 0.0,
+    }
+  }
+
+  pub fn enable(&mut self, shader_directory: Option<String>) {
+    self.enabled = true;
+
+    if let Some(directory) = shader_directory {
+      let shaders = match fs::read_dir(&directory) {
+        Ok(entries) => entries
+          .filter_map(|entry| entry.ok())
+          .map(|entry| entry.path())
+          .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("spv"))
+          .map(|path| path.to_string_lossy().to_string())
+          .collect::<Vec<String>>(),
+        Err(e) => {
+          println!("Unable to watch shader directory {}: {}", directory, e);
+          Vec::new()
+        }
+      };
+
+      self.watch(WatchedAsset::Shaders, shaders);
+      self.shader_directory = Some(directory);
+    }
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.enabled
+  }
+
+  pub fn shader_directory(&self) -> Option<&str> {
+    self.shader_directory.as_deref()
+  }
+
+  /// Replaces any files previously watched for the asset.
+  pub fn watch(&mut self, asset: WatchedAsset, paths: Vec<String>) {
+    self.files.retain(|file| file.asset != asset);
+
+    for path in paths {
+      self.files.push(WatchedFile {
+        asset: asset.clone(),
+        modified: modified_time(&path),
+        path,
+      });
+    }
+  }
+
+  pub fn unwatch(&mut self, asset: &WatchedAsset) {
+    self.files.retain(|file| file.asset != *asset);
+  }
+
+  /// Returns each asset with a file that changed since the last poll, along with that file.
+  pub fn poll(&mut self, delta_time: f32) -> Vec<(WatchedAsset, String)> {
+    let mut changed: Vec<(WatchedAsset, String)> = Vec::new();
+
+    if !self.enabled {
+      return changed;
+    }
+
+    self.time_since_poll += delta_time;
+    if self.time_since_poll < POLL_INTERVAL {
+      return changed;
+    }
+    self.time_since_poll = 0.0;
+
+    for file in &mut self.files {
+      let modified = modified_time(&file.path);
+      if modified.is_some() && modified != file.modified {
+        file.modified = modified;
+
+        if !changed.iter().any(|(asset, _)| *asset == file.asset) {
+          changed.push((file.asset.clone(), file.path.to_string()));
+        }
+      }
+    }
+
+    changed
+  }
+}
+
+/// Reads a compiled shader from the shader directory, falling back to the copy built into the
+/// library when there isn't one.
+pub fn shader_source(
+  shader_directory: Option<&str>,
+  name: &str,
+  builtin: &[u8],
+) -> Cursor<Vec<u8>> {
+  if let Some(directory) = shader_directory {
+    if let Ok(bytes) = fs::read(Path::new(directory).join(name)) {
+      return Cursor::new(bytes);
+    }
+  }
+
+  Cursor::new(builtin.to_vec())
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+  fs::metadata(path).and_then(|m| m.modified()).ok()
+}
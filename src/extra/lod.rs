@@ -0,0 +1,127 @@
+//! Level of detail groups, several models of one object picked between by how far away it is.
+
+use std::mem;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LodMetric {
+  // Each level is used until the camera is this far from the model
+  Distance,
+  // Each level is used while the model's bounding sphere is at least this fraction of the
+  // screen's height
+  ScreenSize,
+}
+
+pub struct LodGroup {
+  // Model reference and threshold for each level, most detailed first
+  levels: Vec<(String, f32)>,
+  metric: LodMetric,
+  hysteresis: f32,
+  // Levels picked for each draw of the group last frame and so far this frame, in draw order.
+  // levels.len() is drawing nothing.
+  selected: Vec<usize>,
+  current: Vec<usize>,
+}
+
+impl LodGroup {
+  /// Past the last threshold the group isn't drawn, make it infinite for distance or 0.0 for
+  /// screen size to keep the last level. Levels only change once they are `hysteresis`, as a
+  /// fraction, past a threshold so models near one don't flicker between levels.
+  pub fn new(levels: Vec<(String, f32)>, metric: LodMetric, hysteresis: f32) -> LodGroup {
+    LodGroup {
+      levels,
+      metric,
+      hysteresis: hysteresis.clamp(0.0, 0.99),
+      selected: Vec::new(),
+      current: Vec::new(),
+    }
+  }
+
+  pub fn levels(&self) -> &Vec<(String, f32)> {
+    &self.levels
+  }
+
+  pub fn metric(&self) -> LodMetric {
+    self.metric
+  }
+
+  /// The distance each level is used to, `radius` and `projection_scale`, the projection's
+  /// vertical scale, turn screen sizes into distances.
+  pub fn threshold_distances(&self, radius: f32, projection_scale: f32) -> Vec<f32> {
+    self
+      .levels
+      .iter()
+      .map(|(_, threshold)| match self.metric {
+        LodMetric::Distance => *threshold,
+        LodMetric::ScreenSize => {
+          if *threshold > 0.0 {
+            radius * projection_scale / threshold
+          } else {
+            f32::INFINITY
+          }
+        }
+      })
+      .collect()
+  }
+
+  /// The level to draw at `distance`, given the level drawn last time if there was one, or
+  /// `thresholds.len()` to draw nothing. The thresholds before the previous level are pulled
+  /// closer and the rest pushed further away.
+  pub fn select_level(
+    distance: f32,
+    thresholds: &[f32],
+    hysteresis: f32,
+    previous: Option<usize>,
+  ) -> usize {
+    thresholds
+      .iter()
+      .enumerate()
+      .position(|(i, threshold)| {
+        let band = match previous {
+          Some(previous) if i < previous => 1.0 - hysteresis,
+          Some(_) => 1.0 + hysteresis,
+          None => 1.0,
+        };
+        distance < threshold * band
+      })
+      .unwrap_or(thresholds.len())
+  }
+
+  /// The model to draw without hysteresis, for draws that aren't tracked between frames.
+  pub fn model_at(&self, distance: f32, radius: f32, projection_scale: f32) -> Option<&str> {
+    let thresholds = self.threshold_distances(radius, projection_scale);
+    let level = LodGroup::select_level(distance, &thresholds, self.hysteresis, None);
+
+    self
+      .levels
+      .get(level)
+      .map(|(model_ref, _)| model_ref.as_str())
+  }
+
+  /// Picks the level for the next draw of the group this frame, `None` when it is too far to draw.
+  pub fn select(&mut self, distance: f32, radius: f32, projection_scale: f32) -> Option<&str> {
+    let thresholds = self.threshold_distances(radius, projection_scale);
+    let previous = self.selected.get(self.current.len()).copied();
+    let level = LodGroup::select_level(distance, &thresholds, self.hysteresis, previous);
+    self.current.push(level);
+
+    self
+      .levels
+      .get(level)
+      .map(|(model_ref, _)| model_ref.as_str())
+  }
+
+  /// Keeps this frame's levels for the next frame's hysteresis, draws are matched up by the
+  /// order they come in.
+  pub fn start_frame(&mut self) {
+    self.selected = mem::take(&mut self.current);
+  }
+
+  /// Levels picked for each draw since the frame started, `None` where nothing was drawn.
+  pub fn selected_levels(&self) -> Vec<Option<usize>> {
+    self
+      .current
+      .iter()
+      .map(|level| Some(*level).filter(|level| *level < self.levels.len()))
+      .collect()
+  }
+}
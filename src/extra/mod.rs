@@ -1,4 +1,15 @@
+pub use self::asset_loader::{AssetLoader, LoadedAsset};
+pub use self::compressed_texture::CompressedTexture;
+pub use self::hot_reload::{shader_source, HotReloader, WatchedAsset};
 pub use self::math::{Math, Swizzle2, Swizzle3, Swizzle4, Vector2, Vector3, Vector4, VectorMath};
 
+mod asset_loader;
+pub mod compressed_texture;
+pub mod debug_lines;
+pub mod environment;
+pub mod frustum;
 pub mod gltf_loader;
+mod hot_reload;
+pub mod lod;
 mod math;
+pub mod shapes;
@@ -0,0 +1,347 @@
+//! Plain coloured 2D shapes, broken into triangles on the CPU. Edges fade out over a pixel so
+//! they stay smooth without multisampling.
+
+use std::f32::consts::PI;
+
+use glam::{Vec2, Vec4};
+
+// Width of the fade at the edge of shapes in pixels
+pub const FRINGE: f32 = 1.0;
+// Longest edge of the curves of circles and rounded corners in pixels
+const CURVE_SEGMENT_LENGTH: f32 = 4.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShapeVertex {
+  pub pos: [f32; 2],
+  pub colour: [f32; 4],
+}
+
+/// In pixels from the bottom left of the screen with y pointing up, like sprites.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Shape {
+  // Bottom left corner and size, with the corners rounded by radius
+  Rect {
+    position: Vec2,
+    size: Vec2,
+    radius: f32,
+  },
+  Circle {
+    centre: Vec2,
+    radius: f32,
+  },
+  Line {
+    from: Vec2,
+    to: Vec2,
+    thickness: f32,
+  },
+  // A simple polygon, its points in either winding order
+  Polygon(Vec<Vec2>),
+}
+
+impl Shape {
+  /// The closed outline of the shape.
+  pub fn path(&self) -> Vec<Vec2> {
+    match self {
+      Shape::Rect {
+        position,
+        size,
+        radius,
+      } => {
+        let radius = radius.min(size.x * 0.5).min(size.y * 0.5);
+        if radius <= 0.0 {
+          return vec![
+            *position,
+            *position + Vec2::new(size.x, 0.0),
+            *position + *size,
+            *position + Vec2::new(0.0, size.y),
+          ];
+        }
+
+        let segments = (curve_segments(radius) / 4).max(2);
+        let corners = [
+          (*position + Vec2::splat(radius), PI),
+          (*position + Vec2::new(size.x - radius, radius), PI * 1.5),
+          (*position + *size - Vec2::splat(radius), 0.0),
+          (*position + Vec2::new(radius, size.y - radius), PI * 0.5),
+        ];
+
+        corners
+          .iter()
+          .flat_map(|(centre, start)| arc(*centre, radius, *start, start + PI * 0.5, segments))
+          .collect()
+      }
+      Shape::Circle { centre, radius } => {
+        let segments = curve_segments(*radius);
+        let mut points = arc(*centre, *radius, 0.0, PI * 2.0, segments);
+        points.pop();
+        points
+      }
+      Shape::Line {
+        from,
+        to,
+        thickness,
+      } => {
+        let direction = (*to - *from).normalize_or_zero();
+        let side = direction.perp() * *thickness * 0.5;
+        vec![*from + side, *to + side, *to - side, *from - side]
+      }
+      Shape::Polygon(points) => points.clone(),
+    }
+  }
+
+  /// Triangles of the shape filled with `colour` when it isn't transparent, and an `outline`
+  /// of thickness and colour centred on its edge. Rotated in degrees about the centre of its
+  /// bounds, the same way as sprites.
+  pub fn vertices(
+    &self,
+    colour: Vec4,
+    outline: Option<(f32, Vec4)>,
+    rotation: f32,
+  ) -> Vec<ShapeVertex> {
+    let mut path = self.path();
+    path.dedup_by(|a, b| a.distance_squared(*b) < 1e-6);
+    while path.len() > 1 && path[0].distance_squared(path[path.len() - 1]) < 1e-6 {
+      path.pop();
+    }
+
+    if path.len() < 3 {
+      return Vec::new();
+    }
+
+    if rotation != 0.0 {
+      let (min, max) = path.iter().fold((path[0], path[0]), |(min, max), p| {
+        (min.min(*p), max.max(*p))
+      });
+      let centre = (min + max) * 0.5;
+      let (sin, cos) = rotation.to_radians().sin_cos();
+      for point in &mut path {
+        let offset = *point - centre;
+        *point = centre
+          + Vec2::new(
+            cos * offset.x + sin * offset.y,
+            -sin * offset.x + cos * offset.y,
+          );
+      }
+    }
+
+    let mut vertices = Vec::new();
+    if colour.w > 0.0 {
+      vertices.append(&mut fill(&path, colour));
+    }
+    if let Some((thickness, outline_colour)) = outline {
+      if thickness > 0.0 && outline_colour.w > 0.0 {
+        vertices.append(&mut stroke(&path, thickness, outline_colour));
+      }
+    }
+
+    vertices
+  }
+}
+
+fn curve_segments(radius: f32) -> usize {
+  ((2.0 * PI * radius / CURVE_SEGMENT_LENGTH).ceil() as usize).clamp(12, 128)
+}
+
+/// Points from `start` to `end` angle inclusive, anticlockwise with y pointing up the screen.
+pub fn arc(centre: Vec2, radius: f32, start: f32, end: f32, segments: usize) -> Vec<Vec2> {
+  (0..=segments)
+    .map(|i| {
+      let angle = start + (end - start) * i as f32 / segments as f32;
+      centre + Vec2::new(angle.cos(), angle.sin()) * radius
+    })
+    .collect()
+}
+
+/// Twice the signed area, positive when the points go anticlockwise with y up.
+pub fn signed_area(points: &[Vec2]) -> f32 {
+  (0..points.len())
+    .map(|i| points[i].perp_dot(points[(i + 1) % points.len()]))
+    .sum()
+}
+
+// Normals pointing out of the shape at each point, lengthened at corners so edges offset by them
+// stay parallel. Very sharp corners are limited so they don't spike out.
+fn point_normals(points: &[Vec2]) -> Vec<Vec2> {
+  let winding = if signed_area(points) >= 0.0 {
+    1.0
+  } else {
+    -1.0
+  };
+  let count = points.len();
+
+  let edge_normals = (0..count)
+    .map(|i| {
+      let direction = (points[(i + 1) % count] - points[i]).normalize_or_zero();
+      Vec2::new(direction.y, -direction.x) * winding
+    })
+    .collect::<Vec<_>>();
+
+  (0..count)
+    .map(|i| {
+      let normal = (edge_normals[(i + count - 1) % count] + edge_normals[i]) * 0.5;
+      let length_squared = normal.length_squared();
+      if length_squared > 1e-6 {
+        normal * (1.0 / length_squared).min(100.0)
+      } else {
+        normal
+      }
+    })
+    .collect()
+}
+
+/// Splits a simple polygon into triangles of its point indices by clipping ears.
+pub fn triangulate(points: &[Vec2]) -> Vec<[usize; 3]> {
+  let winding = if signed_area(points) >= 0.0 {
+    1.0
+  } else {
+    -1.0
+  };
+  let mut remaining = (0..points.len()).collect::<Vec<_>>();
+  let mut triangles = Vec::new();
+
+  let inside = |p: Vec2, a: Vec2, b: Vec2, c: Vec2| {
+    (b - a).perp_dot(p - a) * winding >= 0.0
+      && (c - b).perp_dot(p - b) * winding >= 0.0
+      && (a - c).perp_dot(p - c) * winding >= 0.0
+  };
+
+  while remaining.len() > 3 {
+    let count = remaining.len();
+    let ear = (0..count).find(|&i| {
+      let (a, b, c) = (
+        remaining[(i + count - 1) % count],
+        remaining[i],
+        remaining[(i + 1) % count],
+      );
+      let convex = (points[b] - points[a]).perp_dot(points[c] - points[b]) * winding > 0.0;
+
+      convex
+        && remaining
+          .iter()
+          .filter(|&&p| p != a && p != b && p != c)
+          .all(|&p| !inside(points[p], points[a], points[b], points[c]))
+    });
+
+    // Self intersecting or degenerate polygons have no ears left, fan what remains
+    let i = match ear {
+      Some(i) => i,
+      None => {
+        for i in 1..count - 1 {
+          triangles.push([remaining[0], remaining[i], remaining[i + 1]]);
+        }
+        return triangles;
+      }
+    };
+
+    triangles.push([
+      remaining[(i + count - 1) % count],
+      remaining[i],
+      remaining[(i + 1) % count],
+    ]);
+    remaining.remove(i);
+  }
+
+  if remaining.len() == 3 {
+    triangles.push([remaining[0], remaining[1], remaining[2]]);
+  }
+
+  triangles
+}
+
+fn vertex(pos: Vec2, colour: Vec4) -> ShapeVertex {
+  ShapeVertex {
+    pos: pos.to_array(),
+    colour: colour.to_array(),
+  }
+}
+
+// Two triangles joining the edge from a to b on one ring to the same edge on the next ring out
+fn quad(
+  vertices: &mut Vec<ShapeVertex>,
+  inner: (Vec2, Vec2),
+  outer: (Vec2, Vec2),
+  inner_colour: Vec4,
+  outer_colour: Vec4,
+) {
+  vertices.push(vertex(inner.0, inner_colour));
+  vertices.push(vertex(inner.1, inner_colour));
+  vertices.push(vertex(outer.1, outer_colour));
+  vertices.push(vertex(inner.0, inner_colour));
+  vertices.push(vertex(outer.1, outer_colour));
+  vertices.push(vertex(outer.0, outer_colour));
+}
+
+/// The inside of a closed path with its edge faded over `FRINGE` pixels.
+pub fn fill(points: &[Vec2], colour: Vec4) -> Vec<ShapeVertex> {
+  let normals = point_normals(points);
+  let transparent = colour * Vec4::new(1.0, 1.0, 1.0, 0.0);
+
+  let inner = (0..points.len())
+    .map(|i| points[i] - normals[i] * FRINGE * 0.5)
+    .collect::<Vec<_>>();
+  let outer = (0..points.len())
+    .map(|i| points[i] + normals[i] * FRINGE * 0.5)
+    .collect::<Vec<_>>();
+
+  let mut vertices = triangulate(points)
+    .iter()
+    .flat_map(|triangle| triangle.iter().map(|&i| vertex(inner[i], colour)))
+    .collect::<Vec<_>>();
+
+  for i in 0..points.len() {
+    let next = (i + 1) % points.len();
+    quad(
+      &mut vertices,
+      (inner[i], inner[next]),
+      (outer[i], outer[next]),
+      colour,
+      transparent,
+    );
+  }
+
+  vertices
+}
+
+/// A line `thickness` wide along a closed path, faded over `FRINGE` pixels on both sides. Lines
+/// thinner than the fringe fade out instead of getting thinner.
+pub fn stroke(points: &[Vec2], thickness: f32, colour: Vec4) -> Vec<ShapeVertex> {
+  let normals = point_normals(points);
+  let transparent = colour * Vec4::new(1.0, 1.0, 1.0, 0.0);
+  let colour = colour * Vec4::new(1.0, 1.0, 1.0, (thickness / FRINGE).min(1.0));
+
+  let half_width = ((thickness - FRINGE) * 0.5).max(0.0);
+  let rings = [
+    (-(half_width + FRINGE), transparent),
+    (-half_width, colour),
+    (half_width, colour),
+    (half_width + FRINGE, transparent),
+  ];
+
+  let mut vertices = Vec::new();
+  for ring in rings.windows(2) {
+    let (inner_offset, inner_colour) = ring[0];
+    let (outer_offset, outer_colour) = ring[1];
+    if inner_offset == outer_offset {
+      continue;
+    }
+
+    for i in 0..points.len() {
+      let next = (i + 1) % points.len();
+      quad(
+        &mut vertices,
+        (
+          points[i] + normals[i] * inner_offset,
+          points[next] + normals[next] * inner_offset,
+        ),
+        (
+          points[i] + normals[i] * outer_offset,
+          points[next] + normals[next] * outer_offset,
+        ),
+        inner_colour,
+        outer_colour,
+      );
+    }
+  }
+
+  vertices
+}
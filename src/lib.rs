@@ -10,13 +10,22 @@ use std::sync::mpsc;
 use std::thread;
 
 pub use crate::extra::{
-  gltf_loader::CollisionInformation, Math, Swizzle2, Swizzle3, Swizzle4, Vector2, Vector3, Vector4,
-  VectorMath,
+  frustum::{Aabb, CullingStats, Frustum},
+  gltf_loader::CollisionInformation,
+  lod::LodMetric,
+  Math, Swizzle2, Swizzle3, Swizzle4, Vector2, Vector3, Vector4, VectorMath,
+};
+pub use crate::shader_handlers::{
+  Camera, ComputeFence, ComputeImageFormat, CustomPipeline, Particle, ParticleEmitter,
+  ParticleStep, PostProcessSettings, ToneMapping,
+};
+pub use crate::vkwrapper::{
+  GpuFeatures, GpuInfo, GpuLimits, GpuPreference, GpuType, GraphicsPipelineBuilder, MemoryStats,
+  PresentMode, VkWindow,
 };
-pub use crate::shader_handlers::Camera;
-pub use crate::vkwrapper::VkWindow;
 
 pub use crate::draw::Draw;
+pub use crate::vkwrapper::vulkan::{DEFAULT_FRAMES_IN_FLIGHT, MAX_FRAMES_IN_FLIGHT};
 
 use winit::window::CursorGrabMode;
 
@@ -37,7 +46,11 @@ use winit::{
   window::Fullscreen,
 };
 
-use crate::shader_handlers::{ComputeHandler, ModelHandler, TextureHandler};
+use crate::extra::{gltf_loader, AssetLoader, HotReloader, LoadedAsset, WatchedAsset};
+use crate::shader_handlers::{
+  ComputeHandler, ComputeTaskHandler, DebugHandler, ModelHandler, ParticleHandler,
+  PostProcessHandler, ShapeHandler, TextureHandler,
+};
 use crate::vkwrapper::{/*ComputeShader, DescriptorPoolBuilder, DescriptorSet,*/ Image, Vulkan,};
 
 pub const DELTA_STEP: f32 = 0.01;
@@ -108,6 +121,7 @@ impl ControllerInput {
   }
 }
 
+#[derive(Clone, Copy)]
 pub enum DrawMode {
   Polygon,
   Wireframe,
@@ -122,6 +136,34 @@ pub enum MaatSetting {
   WindowSetSize(f32, f32),
   WindowResizable(bool),
   LimitFps(f32, bool),
+  Msaa(u32),                // samples, clamped to what the device supports, 1 turns it off
+  PresentMode(PresentMode), // falls back to the closest supported mode, Fifo always works
+  SwapchainImageCount(u32), // clamped to the surface limits
+  Bloom(bool, f32, f32),    // enabled, brightness threshold, intensity
+  Exposure(bool, f32),      // enabled, multiplier applied before tone mapping
+  ToneMapping(ToneMapping),
+  ColourGrading(Option<String>), // lut strip location, None turns grading off
+  EnvironmentIntensity(f32),     // scales the skybox and the light it gives models
+  FrustumCulling(bool),          // skip model primitives outside the camera's view, on by default
+}
+
+/// Settings that can only be chosen when the renderer is created.
+pub struct MaatOptions {
+  pub gpu_preference: GpuPreference,
+  // How many frames the CPU can record ahead of the GPU, clamped to 1..=MAX_FRAMES_IN_FLIGHT
+  pub frames_in_flight: usize,
+  // Pipeline cache file, loaded on start up if it matches the device and saved on destroy
+  pub pipeline_cache_path: Option<String>,
+}
+
+impl Default for MaatOptions {
+  fn default() -> MaatOptions {
+    MaatOptions {
+      gpu_preference: GpuPreference::Discrete,
+      frames_in_flight: DEFAULT_FRAMES_IN_FLIGHT,
+      pipeline_cache_path: None,
+    }
+  }
 }
 
 pub enum MaatEvent<'a, S: Into<String>> {
@@ -151,19 +193,28 @@ pub enum MaatEvent<'a, S: Into<String>> {
   GamepadAxis(AxisInput),
   Resized(u32, u32),
   UpdateMaatSettings(&'a Vec<(KeyCode, ModifiersState)>, &'a mut Vec<MaatSetting>),
-  // NewModelLoaded -> HashMap<>
+  AssetLoaded(String),
+  AssetFailed(String, String), // asset ref, reason
   UnhandledWindowEvent(WindowEvent),
   UnhandledDeviceEvent(DeviceEvent),
 }
 
+// Render target, camera and the models to draw into it
+type RenderTargetDraw = (String, Camera, Vec<(Vec<f32>, String)>);
+
 pub struct MaatGraphics {
   vulkan: Vulkan,
   compute_handler: ComputeHandler,
+  compute_task_handler: ComputeTaskHandler,
+  particle_handler: ParticleHandler,
+  debug_handler: DebugHandler,
+  shape_handler: ShapeHandler,
+  post_process_handler: PostProcessHandler,
   texture_handler: TextureHandler,
   model_handler: ModelHandler,
-  //compute_descriptor_pool: vk::DescriptorPool,
-  //compute_shader: ComputeShader,
-  //compute_descriptor_sets: DescriptorSet,
+  render_target_draws: Vec<RenderTargetDraw>,
+  asset_loader: AssetLoader,
+  hot_reloader: HotReloader,
   gamepads: Option<Gilrs>,
   active_controller: Option<GamepadId>,
 }
@@ -174,41 +225,89 @@ impl MaatGraphics {
     event_loop: &EventLoop<()>,
     screen_resolution: [u32; 2],
     font_location: T,
+  ) -> MaatGraphics {
+    MaatGraphics::new_with_gpu(
+      window,
+      event_loop,
+      screen_resolution,
+      font_location,
+      GpuPreference::Discrete,
+    )
+  }
+
+  /// The `MAAT_GPU` environment variable, a device index or part of its name, overrides
+  /// `gpu_preference`.
+  pub fn new_with_gpu<T: Into<String>>(
+    window: &mut VkWindow,
+    event_loop: &EventLoop<()>,
+    screen_resolution: [u32; 2],
+    font_location: T,
+    gpu_preference: GpuPreference,
+  ) -> MaatGraphics {
+    MaatGraphics::new_with_options(
+      window,
+      event_loop,
+      screen_resolution,
+      font_location,
+      MaatOptions {
+        gpu_preference,
+        ..Default::default()
+      },
+    )
+  }
+
+  pub fn new_with_options<T: Into<String>>(
+    window: &mut VkWindow,
+    event_loop: &EventLoop<()>,
+    screen_resolution: [u32; 2],
+    font_location: T,
+    options: MaatOptions,
   ) -> MaatGraphics {
     let screen_resolution = vk::Extent2D {
       width: screen_resolution[0],
       height: screen_resolution[1],
     };
-    let mut vulkan = Vulkan::new(window, event_loop, screen_resolution);
-
-    //let compute_descriptor_pool = DescriptorPoolBuilder::new()
-    //  .num_storage(5)
-    //  .build(vulkan.device());
-    //let compute_descriptor_sets = DescriptorSet::builder()
-    //  .storage_compute()
-    //  .build(vulkan.device(), &compute_descriptor_pool);
-    //let compute_shader = ComputeShader::new(
-    //  vulkan.device(),
-    //  Cursor::new(&include_bytes!("../shaders/collatz_comp.spv")[..]),
-    //  &compute_descriptor_sets,
-    //);
-
-    //let mut compute_data = vec![64, 32, 8, 12, 96];
-    //vulkan.run_compute(&compute_shader, &compute_descriptor_sets, &mut compute_data);
-    //println!("Compute Data: {:?}", compute_data);
+    let mut vulkan = Vulkan::new(
+      window,
+      event_loop,
+      screen_resolution,
+      &options.gpu_preference,
+      options.frames_in_flight,
+      options.pipeline_cache_path.as_deref(),
+    );
+
+    let font_location = font_location.into();
+    let mut hot_reloader = HotReloader::new();
+    hot_reloader.watch(
+      WatchedAsset::Font,
+      vec![
+        font_location.to_string() + ".png",
+        font_location.to_string() + ".fnt",
+      ],
+    );
 
     let texture_handler = TextureHandler::new(&mut vulkan, screen_resolution, font_location);
     let mut model_handler = ModelHandler::new(&mut vulkan, screen_resolution);
     let compute_handler = ComputeHandler::new(&mut vulkan, model_handler.mut_camera());
+    let compute_task_handler = ComputeTaskHandler::new(&mut vulkan);
+    let particle_handler = ParticleHandler::new(&mut vulkan);
+    let post_process_handler = PostProcessHandler::new(&mut vulkan);
+    let debug_handler = DebugHandler::new(&mut vulkan);
+    let shape_handler = ShapeHandler::new(&mut vulkan);
 
     MaatGraphics {
       vulkan,
       texture_handler,
       model_handler,
       compute_handler,
-      //compute_descriptor_pool,
-      //compute_shader,
-      //compute_descriptor_sets,
+      compute_task_handler,
+      particle_handler,
+      debug_handler,
+      shape_handler,
+      post_process_handler,
+      render_target_draws: Vec::new(),
+      asset_loader: AssetLoader::new(),
+      hot_reloader,
       gamepads: None,
       active_controller: None,
     }
@@ -253,28 +352,369 @@ impl MaatGraphics {
     }
   }
 
+  /// Development aid: textures, models loaded from a file and the font are reloaded in place
+  /// when their files change. Pipelines are rebuilt from any `.spv` files in the shader
+  /// directory, falling back to the built in shaders for files that aren't there.
+  pub fn enable_hot_reload<T: Into<String>>(&mut self, shader_directory: Option<T>) {
+    self
+      .hot_reloader
+      .enable(shader_directory.map(|directory| directory.into()));
+
+    if self.hot_reloader.shader_directory().is_some() {
+      self.reload_shaders();
+    }
+  }
+
+  fn reload_shaders(&mut self) {
+    let shader_directory = self.hot_reloader.shader_directory();
+
+    self
+      .texture_handler
+      .reload_shaders(&mut self.vulkan, shader_directory);
+    self
+      .model_handler
+      .reload_shaders(&mut self.vulkan, shader_directory);
+    self
+      .particle_handler
+      .reload_shaders(&mut self.vulkan, shader_directory);
+    self
+      .post_process_handler
+      .reload_shaders(&mut self.vulkan, shader_directory);
+    self
+      .debug_handler
+      .rebuild_pipelines(&mut self.vulkan, shader_directory);
+    self
+      .shape_handler
+      .rebuild_pipelines(&mut self.vulkan, shader_directory);
+  }
+
+  fn rebuild_pipelines(&mut self) {
+    let shader_directory = self.hot_reloader.shader_directory();
+
+    self
+      .texture_handler
+      .rebuild_pipelines(&mut self.vulkan, shader_directory);
+    self
+      .model_handler
+      .rebuild_pipelines(&mut self.vulkan, shader_directory);
+    self
+      .post_process_handler
+      .rebuild_pipelines(&mut self.vulkan, shader_directory);
+    self
+      .debug_handler
+      .rebuild_pipelines(&mut self.vulkan, shader_directory);
+    self
+      .shape_handler
+      .rebuild_pipelines(&mut self.vulkan, shader_directory);
+  }
+
+  fn reload_changed_assets(&mut self, delta_time: f32) {
+    for (asset, path) in self.hot_reloader.poll(delta_time) {
+      match asset {
+        WatchedAsset::Texture(texture_ref) => {
+          self
+            .texture_handler
+            .reload_texture(&mut self.vulkan, &texture_ref, &path);
+        }
+        WatchedAsset::Model(model_ref) => {
+          match std::fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| gltf_loader::parse_gltf(model_ref, &bytes).map_err(|e| e.to_string()))
+          {
+            Ok(parsed) => {
+              self
+                .model_handler
+                .load_parsed_model(&mut self.vulkan, parsed);
+            }
+            Err(e) => {
+              println!("Failed to reload model {}: {}", path, e);
+            }
+          }
+        }
+        WatchedAsset::Font => {
+          let font_location = path.trim_end_matches(".png").trim_end_matches(".fnt");
+          self.texture_handler.reload_font(
+            &mut self.vulkan,
+            font_location,
+            self.hot_reloader.shader_directory(),
+          );
+        }
+        WatchedAsset::Shaders => {
+          self.reload_shaders();
+        }
+      }
+    }
+  }
+
   pub fn create_instance_render_buffer<T: Into<String>>(&mut self, buffer_name: T, texture: T) {
     self
       .texture_handler
       .create_instance_render_buffer(&mut self.vulkan, buffer_name, texture);
   }
 
+  /// KTX2 and DDS files are uploaded in their stored format along with their mips. Block
+  /// compressed data can't be mirrored to suit the sprite quad like other images, so the sprite
+  /// shaders mirror its uvs instead.
   pub fn load_texture<T: Into<String>>(&mut self, texture_ref: T, texture: T) {
+    let texture_ref = texture_ref.into();
+    let texture = texture.into();
+
+    self.hot_reloader.watch(
+      WatchedAsset::Texture(texture_ref.to_string()),
+      vec![texture.to_string()],
+    );
     self
       .texture_handler
       .load_texture(&mut self.vulkan, texture_ref, texture);
   }
 
   pub fn load_model<T: Into<String>>(&mut self, model_ref: T, model: &[u8]) {
+    let model_ref = model_ref.into();
+
+    self
+      .hot_reloader
+      .unwatch(&WatchedAsset::Model(model_ref.to_string()));
     self
       .model_handler
       .load_model(&mut self.vulkan, model_ref, model);
   }
 
-  pub fn instance_render_model<T: Into<String>>(&mut self, _model_ref: T) {
-    //self
-    //  .model_handler
-    //  .create_instance_render_buffer(&mut self.vulkan, model_ref);
+  /// Same as `load_model` but reads the glTF from a file, which lets it be hot reloaded.
+  pub fn load_model_file<T: Into<String>>(&mut self, model_ref: T, model: T) {
+    let model_ref = model_ref.into();
+    let model = model.into();
+
+    let bytes =
+      std::fs::read(&model).unwrap_or_else(|e| panic!("Failed to load model {}: {}", model, e));
+
+    self
+      .hot_reloader
+      .watch(WatchedAsset::Model(model_ref.to_string()), vec![model]);
+    self
+      .model_handler
+      .load_model(&mut self.vulkan, model_ref, &bytes);
+  }
+
+  /// Decodes the texture on a worker thread, `MaatEvent::AssetLoaded` is sent once it can be
+  /// drawn or `MaatEvent::AssetFailed` if it can't be loaded. Until then the texture draws as
+  /// the dummy texture.
+  pub fn load_texture_async<T: Into<String>>(&mut self, texture_ref: T, texture: T) {
+    let texture_ref = texture_ref.into();
+    let texture = texture.into();
+
+    self.hot_reloader.watch(
+      WatchedAsset::Texture(texture_ref.to_string()),
+      vec![texture.to_string()],
+    );
+    self.asset_loader.load_texture(texture_ref, texture);
+  }
+
+  /// Parses the model on a worker thread, `MaatEvent::AssetLoaded` is sent once it can be
+  /// drawn or `MaatEvent::AssetFailed` if it can't be parsed. Until then draws of the model are
+  /// skipped.
+  pub fn load_model_async<T: Into<String>>(&mut self, model_ref: T, model: &[u8]) {
+    let model_ref = model_ref.into();
+
+    self
+      .hot_reloader
+      .unwatch(&WatchedAsset::Model(model_ref.to_string()));
+    self.asset_loader.load_model(model_ref, model);
+  }
+
+  pub fn is_asset_loading(&self, asset_ref: &str) -> bool {
+    self.asset_loader.is_loading(asset_ref)
+  }
+
+  // References of the assets uploaded, and of those that failed with the reason
+  fn upload_loaded_assets(&mut self) -> (Vec<String>, Vec<(String, String)>) {
+    let mut loaded = Vec::new();
+    let mut failed = Vec::new();
+    let mut textures = Vec::new();
+    let mut compressed_textures = Vec::new();
+
+    for asset in self.asset_loader.finished_assets() {
+      match asset {
+        LoadedAsset::Texture(texture_ref, image) => {
+          loaded.push(texture_ref.to_string());
+          textures.push((texture_ref, image));
+        }
+        LoadedAsset::CompressedTexture(texture_ref, texture) => {
+          loaded.push(texture_ref.to_string());
+          compressed_textures.push((texture_ref, texture));
+        }
+        LoadedAsset::Model(parsed) => {
+          loaded.push(parsed.reference().to_string());
+          self
+            .model_handler
+            .load_parsed_model(&mut self.vulkan, *parsed);
+        }
+        LoadedAsset::Failed(asset_ref, reason) => {
+          println!("Failed to load asset {}: {}", asset_ref, reason);
+          failed.push((asset_ref, reason));
+        }
+      }
+    }
+
+    self
+      .texture_handler
+      .load_decoded_textures(&mut self.vulkan, textures);
+    self
+      .texture_handler
+      .load_compressed_textures(&mut self.vulkan, compressed_textures);
+
+    (loaded, failed)
+  }
+
+  /// The texture stops drawing straight away, its memory is released once the frames in flight
+  /// that used it have finished.
+  pub fn unload_texture(&mut self, texture_ref: &str) {
+    self
+      .hot_reloader
+      .unwatch(&WatchedAsset::Texture(texture_ref.to_string()));
+    self
+      .texture_handler
+      .unload_texture(&mut self.vulkan, texture_ref);
+  }
+
+  /// The model stops drawing straight away, its memory is released once the frames in flight
+  /// that used it have finished.
+  pub fn unload_model(&mut self, model_ref: &str) {
+    self
+      .hot_reloader
+      .unwatch(&WatchedAsset::Model(model_ref.to_string()));
+    self.model_handler.unload_model(&mut self.vulkan, model_ref);
+  }
+
+  /// Registers a pipeline for drawing textures, selected per draw with `Draw::pipeline`. Sets 0
+  /// and 1 hold the same uniforms and texture the built in `combo` shaders get, sets 2 and up
+  /// are the pipeline's own. `flip_xy.z` in the push constants is 1 when the texture's uvs need
+  /// mirroring, see `load_texture`.
+  pub fn create_sprite_pipeline<T: Into<String>>(
+    &mut self,
+    pipeline_ref: T,
+    pipeline: CustomPipeline,
+  ) -> Result<(), String> {
+    self
+      .texture_handler
+      .create_pipeline(&mut self.vulkan, pipeline_ref, &pipeline)
+  }
+
+  /// Registers a pipeline for drawing models, assigned to a model with `set_model_pipeline`.
+  /// Sets 0 to 3 hold the same camera, skins, material and lighting the built in mesh shaders
+  /// get, sets 4 and up are the pipeline's own.
+  pub fn create_model_pipeline<T: Into<String>>(
+    &mut self,
+    pipeline_ref: T,
+    pipeline: CustomPipeline,
+  ) -> Result<(), String> {
+    self
+      .model_handler
+      .create_pipeline(&mut self.vulkan, pipeline_ref, &pipeline)
+  }
+
+  /// Samples a texture through a `sampler2D` in one of a custom pipeline's own sets. Unbound
+  /// samplers show the dummy texture.
+  pub fn bind_pipeline_texture(
+    &mut self,
+    pipeline_ref: &str,
+    set: u32,
+    binding: u32,
+    texture_ref: &str,
+  ) -> Result<(), String> {
+    if self.texture_handler.has_pipeline(pipeline_ref) {
+      self
+        .texture_handler
+        .bind_pipeline_texture(pipeline_ref, set, binding, texture_ref)
+    } else {
+      self
+        .model_handler
+        .bind_pipeline_texture(pipeline_ref, set, binding, texture_ref)
+    }
+  }
+
+  /// Fills a uniform or storage buffer in one of a custom pipeline's own sets, and can be
+  /// called every frame. The pipeline draws as the default one until all of its buffers have
+  /// data.
+  pub fn bind_pipeline_data<D: Copy>(
+    &mut self,
+    pipeline_ref: &str,
+    set: u32,
+    binding: u32,
+    data: &[D],
+  ) -> Result<(), String> {
+    let bytes = ComputeTaskHandler::as_bytes(data);
+
+    if self.texture_handler.has_pipeline(pipeline_ref) {
+      self
+        .texture_handler
+        .bind_pipeline_data(pipeline_ref, set, binding, bytes)
+    } else {
+      self
+        .model_handler
+        .bind_pipeline_data(pipeline_ref, set, binding, bytes)
+    }
+  }
+
+  pub fn remove_pipeline(&mut self, pipeline_ref: &str) {
+    self
+      .texture_handler
+      .remove_pipeline(&mut self.vulkan, pipeline_ref);
+    self
+      .model_handler
+      .remove_pipeline(&mut self.vulkan, pipeline_ref);
+  }
+
+  /// `None` goes back to the default mesh pipeline.
+  pub fn set_model_pipeline(&mut self, model_ref: &str, pipeline_ref: Option<&str>) {
+    self
+      .model_handler
+      .set_model_pipeline(model_ref, pipeline_ref);
+  }
+
+  /// Draws of the model in `draw` are batched into one instanced draw per primitive for every
+  /// 4096 instances. Each draw's data may carry an rgba colour after the transform, starting at
+  /// index 12, that tints that instance.
+  pub fn instance_render_model<T: Into<String>>(&mut self, model_ref: T) {
+    self
+      .model_handler
+      .instance_render_model(&self.vulkan, &model_ref.into());
+  }
+
+  pub fn stop_instance_render_model(&mut self, model_ref: &str) {
+    self
+      .model_handler
+      .stop_instance_render_model(&mut self.vulkan, model_ref);
+  }
+
+  /// Drawing `group_ref` draws one of the `levels` models, most detailed first, picked from the
+  /// camera each frame. With `LodMetric::Distance` each level is drawn until the camera is its
+  /// threshold away, with `LodMetric::ScreenSize` while the model covers at least its threshold
+  /// of the screen's height. `hysteresis` is how far past a threshold, as a fraction of it, a
+  /// model has to go before it changes level.
+  pub fn create_lod_group<T: Into<String>>(
+    &mut self,
+    group_ref: T,
+    levels: Vec<(T, f32)>,
+    metric: LodMetric,
+    hysteresis: f32,
+  ) {
+    let levels = levels
+      .into_iter()
+      .map(|(model_ref, threshold)| (model_ref.into(), threshold))
+      .collect();
+    self
+      .model_handler
+      .create_lod_group(&group_ref.into(), levels, metric, hysteresis);
+  }
+
+  pub fn remove_lod_group(&mut self, group_ref: &str) {
+    self.model_handler.remove_lod_group(group_ref);
+  }
+
+  /// The level picked for each draw of a LOD group in the last frame, in the order they were
+  /// drawn, `None` where the group was too far away to draw.
+  pub fn selected_lods(&self, group_ref: &str) -> Vec<Option<usize>> {
+    self.model_handler.selected_lods(group_ref)
   }
 
   pub fn all_collision_models(&self) -> HashMap<String, CollisionInformation> {
@@ -305,10 +745,126 @@ impl MaatGraphics {
     self.model_handler.camera()
   }
 
+  /// Debug lines are drawn over the models in the next frame only, so add them every frame they
+  /// should be seen. Without `depth_test` they show through models.
+  pub fn debug_line(&mut self, start: [f32; 3], end: [f32; 3], colour: [f32; 4], depth_test: bool) {
+    self
+      .debug_handler
+      .lines()
+      .line(start.into(), end.into(), colour, depth_test);
+  }
+
+  pub fn debug_aabb(&mut self, min: [f32; 3], max: [f32; 3], colour: [f32; 4], depth_test: bool) {
+    self.debug_handler.lines().aabb(
+      &Aabb::new(min.into(), max.into()),
+      &glam::Mat4::IDENTITY,
+      colour,
+      depth_test,
+    );
+  }
+
+  pub fn debug_sphere(
+    &mut self,
+    centre: [f32; 3],
+    radius: f32,
+    colour: [f32; 4],
+    depth_test: bool,
+  ) {
+    self
+      .debug_handler
+      .lines()
+      .sphere(centre.into(), radius, colour, depth_test);
+  }
+
+  pub fn debug_arrow(
+    &mut self,
+    start: [f32; 3],
+    end: [f32; 3],
+    colour: [f32; 4],
+    depth_test: bool,
+  ) {
+    self
+      .debug_handler
+      .lines()
+      .arrow(start.into(), end.into(), colour, depth_test);
+  }
+
+  /// On the xz plane, `size` wide with `divisions` squares along each side.
+  pub fn debug_grid(
+    &mut self,
+    centre: [f32; 3],
+    size: f32,
+    divisions: u32,
+    colour: [f32; 4],
+    depth_test: bool,
+  ) {
+    self
+      .debug_handler
+      .lines()
+      .grid(centre.into(), size, divisions, colour, depth_test);
+  }
+
+  /// What `camera` sees, cut off `far` along its view direction.
+  pub fn debug_camera_frustum(
+    &mut self,
+    camera: &Camera,
+    far: f32,
+    colour: [f32; 4],
+    depth_test: bool,
+  ) {
+    self.debug_handler.lines().frustum(
+      camera.perspective_matrix(),
+      camera.view_matrix(),
+      far,
+      colour,
+      depth_test,
+    );
+  }
+
+  /// The bones of a model drawn with `data`, a line from each node to its parent.
+  pub fn debug_skeleton(
+    &mut self,
+    model_ref: &str,
+    data: &[f32],
+    colour: [f32; 4],
+    depth_test: bool,
+  ) {
+    if let Some(nodes) = self.model_handler.model_nodes(model_ref) {
+      for (start, end) in ModelHandler::skeleton_lines(nodes, data) {
+        self
+          .debug_handler
+          .lines()
+          .line(start, end, colour, depth_test);
+      }
+    }
+  }
+
+  /// The bounds of each collision object of a model drawn with `data`.
+  pub fn debug_collision_bounds(
+    &mut self,
+    model_ref: &str,
+    data: &[f32],
+    colour: [f32; 4],
+    depth_test: bool,
+  ) {
+    let (translation, rotation, scale) = gltf_loader::Node::transform_from_data(data);
+    let matrix = glam::Mat4::from_scale_rotation_translation(scale, rotation, translation);
+    for bounds in self.model_handler.collision_bounds(model_ref) {
+      self
+        .debug_handler
+        .lines()
+        .aabb(&bounds, &matrix, colour, depth_test);
+    }
+  }
+
   pub fn mut_camera(&mut self) -> &mut Camera {
     self.model_handler.mut_camera()
   }
 
+  pub fn post_process_settings(&self) -> &PostProcessSettings {
+    self.post_process_handler.settings()
+  }
+
   pub fn update_maat_settings(
     &mut self,
     window: &mut VkWindow,
@@ -320,7 +876,11 @@ impl MaatGraphics {
     for setting in maat_settings {
       match setting {
         MaatSetting::DrawMode(mode) => {
-          self.model_handler.set_draw_mode(&self.vulkan, mode);
+          self.model_handler.set_draw_mode(
+            &mut self.vulkan,
+            mode,
+            self.hot_reloader.shader_directory(),
+          );
         }
         MaatSetting::MouseVisibility(visible) => {
           window.internal().set_cursor_visible(visible);
@@ -373,6 +933,45 @@ impl MaatGraphics {
           *limit_fps = should_limit;
           *fps_limit = (1.0 / limit).min(1.0);
         }
+        MaatSetting::Msaa(samples) => {
+          let previous_samples = self.vulkan.msaa_samples();
+          if self.vulkan.set_msaa_samples(samples) != previous_samples {
+            self.rebuild_pipelines();
+          }
+        }
+        MaatSetting::PresentMode(present_mode) => {
+          self.vulkan.set_present_mode(present_mode);
+        }
+        MaatSetting::SwapchainImageCount(image_count) => {
+          self.vulkan.set_swapchain_image_count(Some(image_count));
+        }
+        MaatSetting::Bloom(enabled, threshold, intensity) => {
+          self
+            .post_process_handler
+            .set_bloom(enabled, threshold, intensity);
+        }
+        MaatSetting::Exposure(enabled, exposure_multiplier) => {
+          self
+            .post_process_handler
+            .set_exposure(enabled, exposure_multiplier);
+        }
+        MaatSetting::ToneMapping(tone_mapping) => {
+          self.post_process_handler.set_tone_mapping(tone_mapping);
+        }
+        MaatSetting::ColourGrading(lut_location) => {
+          self
+            .post_process_handler
+            .set_colour_grading(&mut self.vulkan, lut_location.as_deref());
+        }
+        MaatSetting::EnvironmentIntensity(intensity) => {
+          self
+            .model_handler
+            .mut_environment()
+            .set_intensity(&mut self.vulkan, intensity);
+        }
+        MaatSetting::FrustumCulling(enabled) => {
+          self.model_handler.set_frustum_culling(enabled);
+        }
       }
     }
   }
@@ -386,22 +985,34 @@ impl MaatGraphics {
     L: Into<String>,
     S: Into<String>,
   {
-    if self.model_handler.mut_camera().is_updated() {
+    if let Some(present_index) = self.vulkan.start_render() {
+      // Written after start_render waited for the frame, the GPU may still read the other copies
+      if self.model_handler.mut_camera().is_updated() {
+        self.model_handler.update_uniform_buffer(&self.vulkan);
+      }
+      self.model_handler.start_frame();
       self
         .model_handler
-        .update_uniform_buffer(self.vulkan.device());
-    }
+        .update_pipeline_resources(&mut self.vulkan, &self.texture_handler);
+
+      self.draw_render_targets();
 
-    if let Some(present_index) = self.vulkan.start_render() {
       self.vulkan.begin_renderpass_model(present_index);
       for (data, model) in model_data {
         self
           .model_handler
           .draw(&mut self.vulkan, data, &model.into());
       }
+      self.model_handler.draw_instanced_models(&mut self.vulkan);
+      self.model_handler.draw_skybox(&mut self.vulkan);
+      self
+        .debug_handler
+        .draw(&mut self.vulkan, self.model_handler.camera());
 
       self.vulkan.end_renderpass();
+      self.post_process_handler.run(&mut self.vulkan);
       self.vulkan.begin_renderpass_texture(present_index);
+      self.post_process_handler.composite(&mut self.vulkan);
 
       for (data, texture, some_text) in texture_data {
         if some_text.is_none() {
@@ -416,6 +1027,9 @@ impl MaatGraphics {
       self.vulkan.end_renderpass();
       self.vulkan.end_render(present_index);
     }
+
+    // Lines are only meant for the frame they were added in, even if it wasn't drawn
+    self.debug_handler.clear();
   }
 
   pub fn draw<S: Into<String>>(
@@ -424,89 +1038,422 @@ impl MaatGraphics {
     model_data: Vec<(Vec<f32>, S)>,
     time: f32,
   ) {
-    if self.model_handler.mut_camera().is_updated() {
+    if let Some(present_index) = self.vulkan.start_render() {
+      // Written after start_render waited for the frame, the GPU may still read the other copies
+      if self.model_handler.mut_camera().is_updated() {
+        self.model_handler.update_uniform_buffer(&self.vulkan);
+      }
+      self.model_handler.start_frame();
       self
         .model_handler
-        .update_uniform_buffer(self.vulkan.device());
-    }
+        .update_pipeline_resources(&mut self.vulkan, &self.texture_handler);
+
+      self.draw_render_targets();
+
+      // Dispatches can't be recorded inside a render pass
+      self.particle_handler.simulate(
+        &mut self.vulkan,
+        self.texture_handler.camera_location(),
+        time,
+      );
 
-    if let Some(present_index) = self.vulkan.start_render() {
       self.vulkan.begin_renderpass_model(present_index);
       for (data, model) in model_data {
         self
           .model_handler
           .draw(&mut self.vulkan, data, &model.into());
       }
+      self.model_handler.draw_instanced_models(&mut self.vulkan);
+      self.model_handler.draw_skybox(&mut self.vulkan);
+      self
+        .debug_handler
+        .draw(&mut self.vulkan, self.model_handler.camera());
+
+      self.vulkan.end_renderpass();
+      self.post_process_handler.run(&mut self.vulkan);
+      self.vulkan.begin_renderpass_texture(present_index);
+      self.post_process_handler.composite(&mut self.vulkan);
+
+      //let mut text_count = 0;
+
+      self.shape_handler.prepare(&mut self.vulkan, &texture_data);
+
+      for draw in texture_data {
+        if let Some(buffer_name) = draw.get_buffer() {
+          if draw.adding_buffer_data() {
+            self
+              .texture_handler
+              .add_instanced_texture(draw.texture_data(time), &buffer_name);
+          } else {
+            self
+              .texture_handler
+              .draw_instanced_texture(&mut self.vulkan, &buffer_name);
+          }
+        } else if let Some(texture) = draw.get_texture() {
+          self.texture_handler.draw_with_pipeline(
+            &mut self.vulkan,
+            draw.texture_data(time),
+            &texture,
+            draw.get_pipeline().as_deref(),
+          );
+        } else if let Some(emitter) = draw.get_particles() {
+          let frame = self.vulkan.current_frame();
+          if let Some((texture, instances, instance_count)) =
+            self.particle_handler.draw_data(&emitter, frame)
+          {
+            self.texture_handler.draw_particles(
+              &mut self.vulkan,
+              texture,
+              instances,
+              instance_count,
+            );
+          }
+        } else if draw.get_shape().is_some() {
+          self.shape_handler.draw_next(
+            &mut self.vulkan,
+            self.texture_handler.window_size(),
+            self.texture_handler.camera_location(),
+          );
+        } else if let Some(camera) = draw.get_camera() {
+          self.texture_handler.set_camera_location(camera);
+        } else {
+          self.texture_handler.add_text_data(draw, &mut self.vulkan);
+        }
+
+        //else if let Some(text) = draw.get_text() {
+
+        //self
+        //  .texture_handler
+        //  .add_text_data(&mut text_count, draw.text_data(), &text, "");
+        //}
+      }
+
+      self.texture_handler.draw_new_text(&mut self.vulkan);
+      //self
+      //  .texture_handler
+      //  .draw_instanced_text(&mut self.vulkan, text_count);
+
+      self.vulkan.end_renderpass();
+      self.vulkan.end_render(present_index);
+    }
+
+    // Lines are only meant for the frame they were added in, even if it wasn't drawn
+    self.debug_handler.clear();
+  }
+
+  pub fn update_animations(&mut self, delta_time: f32) {
+    self
+      .model_handler
+      .update_animations(&mut self.vulkan, delta_time);
+  }
+
+  /// Adds a particle emitter, or changes the settings of the one with the same name. Draw it
+  /// with `Draw::particles`.
+  pub fn create_particle_emitter<T: Into<String>>(
+    &mut self,
+    emitter_ref: T,
+    emitter: ParticleEmitter,
+  ) {
+    self
+      .particle_handler
+      .create_emitter(&mut self.vulkan, &emitter_ref.into(), emitter);
+  }
+
+  pub fn remove_particle_emitter(&mut self, emitter_ref: &str) {
+    self
+      .particle_handler
+      .remove_emitter(&mut self.vulkan, emitter_ref);
+  }
+
+  pub fn set_particle_emitter_position(&mut self, emitter_ref: &str, position: glam::Vec2) {
+    self
+      .particle_handler
+      .set_emitter_position(emitter_ref, position);
+  }
+
+  pub fn particle_emitter(&self, emitter_ref: &str) -> Option<&ParticleEmitter> {
+    self.particle_handler.emitter(emitter_ref)
+  }
+
+  /// Advances the particles by `delta_time` on the next draw.
+  pub fn update_particles(&mut self, delta_time: f32) {
+    self.particle_handler.update(delta_time);
+  }
+
+  /// Waits for the GPU and reads an emitter's particles back, along with the step that produced
+  /// them so it can be checked against `ParticleEmitter::simulate`.
+  pub fn read_particles(&self, emitter_ref: &str) -> Option<(Vec<Particle>, Option<ParticleStep>)> {
+    self
+      .particle_handler
+      .read_particles(&self.vulkan, emitter_ref)
+  }
+
+  /// Creates a texture models can be drawn into with `draw_to_render_target`, drawn like any
+  /// other with `Draw::texture(name)`. Replaces a texture or render target with the same name.
+  pub fn create_render_target<T: Into<String>>(&mut self, name: T, width: u32, height: u32) {
+    let name = name.into();
+    let colour_image = self
+      .vulkan
+      .create_render_target(&name, width, height)
+      .colour_image()
+      .clone();
+
+    self
+      .texture_handler
+      .insert_render_target(&mut self.vulkan, name.to_string(), &colour_image);
+    self
+      .model_handler
+      .create_render_target_camera(&mut self.vulkan, &name);
+  }
+
+  pub fn remove_render_target(&mut self, name: &str) {
+    self.texture_handler.unload_texture(&mut self.vulkan, name);
+    self
+      .model_handler
+      .remove_render_target_camera(&mut self.vulkan, name);
+    self.vulkan.destroy_render_target(name);
+  }
+
+  /// Draws models into a render target from `camera` on the next draw, before the screen's
+  /// models. The camera's aspect ratio isn't changed to match the target.
+  pub fn draw_to_render_target<T: Into<String>, S: Into<String>>(
+    &mut self,
+    name: T,
+    camera: &Camera,
+    model_data: Vec<(Vec<f32>, S)>,
+  ) {
+    self.render_target_draws.push((
+      name.into(),
+      camera.clone(),
+      model_data
+        .into_iter()
+        .map(|(data, model)| (data, model.into()))
+        .collect(),
+    ));
+  }
+
+  /// Lights models with a longitude, latitude panorama, a Radiance .hdr or an sRGB image, and
+  /// draws it as the sky behind them. The lighting is baked on load, which can take a moment.
+  pub fn load_environment(&mut self, location: &str) -> Result<(), String> {
+    self
+      .model_handler
+      .mut_environment()
+      .load_equirectangular(&mut self.vulkan, location)
+  }
+
+  /// Like `load_environment` from six square faces in the +x, -x, +y, -y, +z, -z order.
+  pub fn load_environment_cubemap(&mut self, locations: [&str; 6]) -> Result<(), String> {
+    self
+      .model_handler
+      .mut_environment()
+      .load_cubemap(&mut self.vulkan, locations)
+  }
+
+  /// Goes back to no sky and the flat ambient light.
+  pub fn clear_environment(&mut self) {
+    self
+      .model_handler
+      .mut_environment()
+      .clear_environment(&mut self.vulkan);
+  }
+
+  fn draw_render_targets(&mut self) {
+    for (name, camera, model_data) in self.render_target_draws.drain(..) {
+      if self.vulkan.begin_renderpass_render_target(&name) {
+        self
+          .model_handler
+          .draw_to_render_target(&mut self.vulkan, &name, &camera, model_data);
+        self.vulkan.end_renderpass_render_target(&name);
+      }
+    }
+  }
+
+  /// Loads a compute shader from a SPIR-V file, its bindings and push constants are read from
+  /// the shader.
+  pub fn load_compute_shader<T: Into<String>>(
+    &mut self,
+    shader_ref: T,
+    spirv_file: T,
+  ) -> Result<(), String> {
+    let spirv_file = spirv_file.into();
+    let spirv = std::fs::read(&spirv_file).map_err(|e| format!("{}: {}", spirv_file, e))?;
 
-      //self.model_handler.draw_instanced_models(&mut self.vulkan);
+    self.load_compute_shader_from_bytes(shader_ref, &spirv)
+  }
+
+  pub fn load_compute_shader_from_bytes<T: Into<String>>(
+    &mut self,
+    shader_ref: T,
+    spirv: &[u8],
+  ) -> Result<(), String> {
+    self.compute_task_handler.load_shader(
+      &mut self.vulkan,
+      &shader_ref.into(),
+      std::io::Cursor::new(spirv),
+    )
+  }
+
+  pub fn remove_compute_shader(&mut self, shader_ref: &str) {
+    self
+      .compute_task_handler
+      .remove_shader(&mut self.vulkan, shader_ref);
+  }
+
+  /// Creates a named storage buffer, calling it again with data of the same size just updates
+  /// it.
+  pub fn create_compute_storage_buffer<T: Copy, S: Into<String>>(
+    &mut self,
+    buffer_ref: S,
+    data: &[T],
+  ) -> Result<(), String> {
+    self
+      .compute_task_handler
+      .create_storage_buffer(&mut self.vulkan, &buffer_ref.into(), data)
+  }
+
+  pub fn create_compute_uniform_buffer<T: Copy, S: Into<String>>(
+    &mut self,
+    buffer_ref: S,
+    data: &[T],
+  ) -> Result<(), String> {
+    self
+      .compute_task_handler
+      .create_uniform_buffer(&mut self.vulkan, &buffer_ref.into(), data)
+  }
+
+  pub fn create_compute_image<T: Into<String>>(
+    &mut self,
+    image_ref: T,
+    width: u32,
+    height: u32,
+    format: ComputeImageFormat,
+  ) -> Result<(), String> {
+    self.compute_task_handler.create_storage_image(
+      &mut self.vulkan,
+      &image_ref.into(),
+      width,
+      height,
+      format,
+    )
+  }
+
+  pub fn remove_compute_resource(&mut self, resource_ref: &str) {
+    self
+      .compute_task_handler
+      .remove_resource(&mut self.vulkan, resource_ref);
+  }
+
+  /// Binds a compute buffer or image to `layout(set = set, binding = binding)` of the shader.
+  pub fn bind_compute_resource(
+    &mut self,
+    shader_ref: &str,
+    set: u32,
+    binding: u32,
+    resource_ref: &str,
+  ) -> Result<(), String> {
+    self
+      .compute_task_handler
+      .bind(&mut self.vulkan, shader_ref, set, binding, resource_ref)
+  }
+
+  /// Submits the shader without waiting for it, poll the returned fence with
+  /// `is_compute_finished`.
+  pub fn dispatch_compute<P: Copy>(
+    &mut self,
+    shader_ref: &str,
+    group_counts: [u32; 3],
+    push_constants: &[P],
+  ) -> Result<ComputeFence, String> {
+    self
+      .compute_task_handler
+      .dispatch(&mut self.vulkan, shader_ref, group_counts, push_constants)
+  }
+
+  /// Dispatches the shader and waits for it to finish.
+  pub fn run_compute<P: Copy>(
+    &mut self,
+    shader_ref: &str,
+    group_counts: [u32; 3],
+    push_constants: &[P],
+  ) -> Result<(), String> {
+    self
+      .compute_task_handler
+      .run(&mut self.vulkan, shader_ref, group_counts, push_constants)
+  }
+
+  pub fn is_compute_finished(&mut self, fence: ComputeFence) -> bool {
+    self
+      .compute_task_handler
+      .is_finished(&mut self.vulkan, fence)
+  }
 
-      self.vulkan.end_renderpass();
-      self.vulkan.begin_renderpass_texture(present_index);
+  pub fn wait_for_compute(&mut self, fence: ComputeFence) {
+    self.compute_task_handler.wait(&mut self.vulkan, fence);
+  }
 
-      //let mut text_count = 0;
+  /// Reads a compute buffer back, waiting for any dispatches still running.
+  pub fn read_compute_buffer<T: Copy>(&mut self, buffer_ref: &str) -> Option<Vec<T>> {
+    self
+      .compute_task_handler
+      .read_buffer(&mut self.vulkan, buffer_ref)
+  }
 
-      for draw in texture_data {
-        if let Some(buffer_name) = draw.get_buffer() {
-          if draw.adding_buffer_data() {
-            self
-              .texture_handler
-              .add_instanced_texture(draw.texture_data(time), &buffer_name);
-          } else {
-            self
-              .texture_handler
-              .draw_instanced_texture(&mut self.vulkan, &buffer_name);
-          }
-        } else if let Some(texture) = draw.get_texture() {
-          self
-            .texture_handler
-            .draw(&mut self.vulkan, draw.texture_data(time), &texture);
-        } else if let Some(camera) = draw.get_camera() {
-          self.texture_handler.set_camera_location(camera);
-        } else {
-          self.texture_handler.add_text_data(draw, &mut self.vulkan);
-        }
+  pub fn read_compute_image(&mut self, image_ref: &str) -> Option<Vec<u8>> {
+    self
+      .compute_task_handler
+      .read_image(&mut self.vulkan, image_ref)
+  }
 
-        //else if let Some(text) = draw.get_text() {
+  /// Writes the pipeline cache now rather than waiting for destroy.
+  pub fn save_pipeline_cache<T: Into<String>>(&self, path: T) -> Result<(), String> {
+    self.vulkan.save_pipeline_cache(&path.into())
+  }
 
-        //self
-        //  .texture_handler
-        //  .add_text_data(&mut text_count, draw.text_data(), &text, "");
-        //}
-      }
+  pub fn memory_stats(&self) -> MemoryStats {
+    self.vulkan.memory_stats()
+  }
 
-      self.texture_handler.draw_new_text(&mut self.vulkan);
-      //self
-      //  .texture_handler
-      //  .draw_instanced_text(&mut self.vulkan, text_count);
+  /// Model primitives drawn and culled in the last frame, render targets included.
+  pub fn culling_stats(&self) -> CullingStats {
+    self.model_handler.culling_stats()
+  }
 
-      self.vulkan.end_renderpass();
-      self.vulkan.end_render(present_index);
-    }
+  /// Lists the available gpus before a `MaatGraphics` is created, indices match
+  /// `GpuPreference::Index`.
+  pub fn enumerate_gpus(window: &VkWindow, event_loop: &EventLoop<()>) -> Vec<GpuInfo> {
+    Vulkan::enumerate_gpus(window, event_loop)
   }
 
-  pub fn update_animations(&mut self, delta_time: f32) {
-    self
-      .model_handler
-      .update_animations(&mut self.vulkan, delta_time);
+  /// The gpu in use, its features and limits.
+  pub fn gpu_info(&self) -> &GpuInfo {
+    self.vulkan.gpu_info()
   }
 
-  pub fn destroy(&mut self) {
-    unsafe {
-      self.vulkan.device().internal().device_wait_idle().unwrap();
-    }
+  pub fn gpus(&self) -> &Vec<GpuInfo> {
+    self.vulkan.gpus()
+  }
 
-    // self.texture_handler.destroy(&mut self.vulkan);
-    //    self.model_handler.destroy(&mut self.vulkan);
+  pub fn supported_present_modes(&self) -> Vec<PresentMode> {
+    self.vulkan.supported_present_modes()
+  }
 
-    //self.compute_descriptor_sets.destroy(self.vulkan.device());
-    //self.compute_shader.destroy(self.vulkan.device());
+  pub fn present_mode(&self) -> PresentMode {
+    self.vulkan.present_mode()
+  }
 
-    //unsafe {
-    //  self
-    //    .vulkan
-    //    .device()
-    //    .destroy_descriptor_pool(self.compute_descriptor_pool, None);
-    //}
+  pub fn destroy(&mut self) {
+    self.vulkan.flush_pending_destruction();
+
+    self.texture_handler.destroy(&mut self.vulkan);
+    self.model_handler.destroy(&mut self.vulkan);
+    self.compute_handler.destroy(&mut self.vulkan);
+    self.compute_task_handler.destroy(&mut self.vulkan);
+    self.particle_handler.destroy(&mut self.vulkan);
+    self.debug_handler.destroy(&mut self.vulkan);
+    self.shape_handler.destroy(&mut self.vulkan);
+    self.post_process_handler.destroy(&mut self.vulkan);
+
+    self.vulkan.destroy();
   }
 
   //pub fn run<T, V>(
@@ -950,6 +1897,15 @@ impl MaatGraphics {
             total_delta_time += _delta_time as f32;
             total_animation_delta_time += _delta_time as f32;
 
+            let (loaded, failed) = vulkan.upload_loaded_assets();
+            for asset_ref in loaded {
+              callback(MaatEvent::AssetLoaded(asset_ref));
+            }
+            for (asset_ref, reason) in failed {
+              callback(MaatEvent::AssetFailed(asset_ref, reason));
+            }
+            vulkan.reload_changed_assets(_delta_time);
+
             let mut should_exit = false;
 
             if should_exit {
@@ -1245,4 +2201,1550 @@ mod tests {
 
   #[test]
   fn quat() {}
+
+  fn reflect(spv: &[u8]) -> vkwrapper::ShaderReflection {
+    let code = ash::util::read_spv(&mut std::io::Cursor::new(spv)).unwrap();
+    vkwrapper::ShaderReflection::new(&code).unwrap()
+  }
+
+  #[test]
+  fn shader_reflection() {
+    let vertex = reflect(include_bytes!("../shaders/combo_vert.spv"));
+    let fragment = reflect(include_bytes!("../shaders/combo_frag.spv"));
+
+    let range = vkwrapper::ShaderReflection::push_constant_range(&[&vertex, &fragment]).unwrap();
+    assert_eq!(range.stage_flags, vk::ShaderStageFlags::VERTEX);
+    assert_eq!(range.size, 128);
+
+    let bindings = vkwrapper::ShaderReflection::merged_bindings(&[&vertex, &fragment]).unwrap();
+    let bindings = bindings
+      .iter()
+      .map(|b| (b.set, b.binding, b.descriptor_type))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      bindings,
+      vec![
+        (0, 0, vk::DescriptorType::UNIFORM_BUFFER),
+        (1, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+      ]
+    );
+
+    let attributes = vertex.vertex_attributes(0, 0, &[0, 16, 32], 40).unwrap();
+    assert!(vertex.check_vertex_inputs(&attributes).is_ok());
+    assert_eq!(
+      attributes.iter().map(|a| a.format).collect::<Vec<_>>(),
+      vec![
+        vk::Format::R32G32B32A32_SFLOAT,
+        vk::Format::R32G32B32A32_SFLOAT,
+        vk::Format::R32G32_SFLOAT
+      ]
+    );
+  }
+
+  #[test]
+  fn malformed_shader_reflection() {
+    // Header, then OpEntryPoint Fragment %3 ""
+    let header = [0x0723_0203, 0x0001_0000, 0, 10, 0, (4 << 16) | 15, 4, 3, 0];
+    let module = |instructions: &[u32]| {
+      vkwrapper::ShaderReflection::new(&[&header[..], instructions].concat())
+    };
+
+    // OpTypeBool %1, then an OpTypeImage %2 %1 2D cut down to a word count of 3
+    assert!(module(&[(2 << 16) | 20, 1]).is_ok());
+    let error = module(&[(2 << 16) | 20, 1, (3 << 16) | 25, 2, 1])
+      .err()
+      .unwrap();
+    assert!(error.starts_with("Malformed SPIR-V"));
+    for instruction in [
+      vec![(1 << 16) | 15],
+      vec![(3 << 16) | 59, 1, 2],
+      vec![(2 << 16) | 71, 1],
+      vec![(3 << 16) | 72, 1, 0],
+    ] {
+      assert!(module(&instruction).is_err());
+    }
+  }
+
+  #[test]
+  fn custom_pipeline_sets() {
+    let pipeline = CustomPipeline::new(
+      include_bytes!("../shaders/combo_vert.spv"),
+      include_bytes!("../shaders/combo_frag.spv"),
+    );
+
+    // Past the sets the handler binds there is nothing of the pipeline's own
+    assert!(pipeline.extra_bindings(2).unwrap().is_empty());
+    let bindings = pipeline.extra_bindings(1).unwrap();
+    assert_eq!(bindings.len(), 1);
+    assert_eq!(
+      (
+        bindings[0].set,
+        bindings[0].binding,
+        bindings[0].descriptor_type
+      ),
+      (1, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+    );
+    assert!(CustomPipeline::new(&[1, 2, 3, 4], &[])
+      .extra_bindings(2)
+      .is_err());
+
+    // Only textures and buffers have anything to bind them with
+    let binding = |descriptor_type| vkwrapper::ReflectedBinding {
+      set: 2,
+      binding: 0,
+      descriptor_type,
+      count: 1,
+      stages: vk::ShaderStageFlags::FRAGMENT,
+    };
+    assert!(CustomPipeline::check_bindable(&[
+      binding(vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+      binding(vk::DescriptorType::UNIFORM_BUFFER),
+      binding(vk::DescriptorType::STORAGE_BUFFER),
+    ])
+    .is_ok());
+    for descriptor_type in [
+      vk::DescriptorType::STORAGE_IMAGE,
+      vk::DescriptorType::SAMPLED_IMAGE,
+      vk::DescriptorType::SAMPLER,
+      vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+      vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+    ] {
+      assert!(CustomPipeline::check_bindable(&[binding(descriptor_type)]).is_err());
+    }
+
+    assert_eq!(pipeline.offsets_or(vec![0, 16, 32]), vec![0, 16, 32]);
+    let pipeline = pipeline.vertex_offsets(vec![0, 32]);
+    assert_eq!(pipeline.offsets_or(vec![0, 16, 32]), vec![0, 32]);
+
+    // Picking fields still has to feed every input of the shader
+    let vertex = reflect(include_bytes!("../shaders/combo_vert.spv"));
+    let attributes = vertex.vertex_attributes(0, 0, &[0, 32], 40).unwrap();
+    assert!(vertex.check_vertex_inputs(&attributes).is_err());
+  }
+
+  #[test]
+  fn shader_descriptor_layouts() {
+    let check = |vert: &[u8], frag: &[u8], layouts: &[&vkwrapper::DescriptorSetBuilder]| {
+      let (vertex, fragment) = (reflect(vert), reflect(frag));
+      let bindings = vkwrapper::ShaderReflection::merged_bindings(&[&vertex, &fragment]).unwrap();
+      vkwrapper::DescriptorSetBuilder::check_layouts(&bindings, layouts)
+    };
+
+    let uniforms = vkwrapper::DescriptorSet::builder().uniform_buffer_vertex();
+    let texture = vkwrapper::DescriptorSet::builder().combined_image_sampler_fragment();
+    let combo_vert = include_bytes!("../shaders/combo_vert.spv");
+    let combo_frag = include_bytes!("../shaders/combo_frag.spv");
+    assert!(check(combo_vert, combo_frag, &[&uniforms, &texture]).is_ok());
+    assert!(check(
+      include_bytes!("../shaders/instanced_combo_vert.spv"),
+      include_bytes!("../shaders/particle_frag.spv"),
+      &[&uniforms, &texture]
+    )
+    .is_ok());
+    assert!(check(
+      include_bytes!("../shaders/new_text_vert.spv"),
+      include_bytes!("../shaders/new_text_frag.spv"),
+      &[&texture]
+    )
+    .is_ok());
+
+    let mesh_layouts = [
+      &vkwrapper::DescriptorSet::builder().uniform_buffer_vertex(),
+      &vkwrapper::DescriptorSet::builder().storage_vertex(),
+      &vkwrapper::DescriptorSet::builder()
+        .uniform_buffer_fragment()
+        .combined_image_sampler_fragment()
+        .combined_image_sampler_fragment()
+        .combined_image_sampler_fragment()
+        .combined_image_sampler_fragment()
+        .combined_image_sampler_fragment(),
+      &vkwrapper::DescriptorSet::builder()
+        .combined_image_sampler_fragment()
+        .combined_image_sampler_fragment()
+        .combined_image_sampler_fragment()
+        .uniform_buffer_fragment(),
+    ];
+    let mesh_frag = include_bytes!("../shaders/mesh_pbr_frag.spv");
+    assert!(check(
+      include_bytes!("../shaders/mesh_animated_vert.spv"),
+      mesh_frag,
+      &mesh_layouts
+    )
+    .is_ok());
+    assert!(check(
+      include_bytes!("../shaders/instanced_mesh_animated_vert.spv"),
+      mesh_frag,
+      &mesh_layouts
+    )
+    .is_ok());
+
+    let fullscreen_vert = include_bytes!("../shaders/post_fullscreen_vert.spv");
+    assert!(check(
+      fullscreen_vert,
+      include_bytes!("../shaders/skybox_frag.spv"),
+      &[&texture]
+    )
+    .is_ok());
+    assert!(check(
+      fullscreen_vert,
+      include_bytes!("../shaders/post_composite_frag.spv"),
+      &[&texture
+        .clone()
+        .combined_image_sampler_fragment()
+        .combined_image_sampler_fragment()]
+    )
+    .is_ok());
+
+    // Wrong type, wrong stage and a missing set are all reported
+    let storage = vkwrapper::DescriptorSet::builder().storage_vertex();
+    let error = check(combo_vert, combo_frag, &[&storage, &texture]).unwrap_err();
+    assert!(error.contains("Set 0 binding 0"));
+    let vertex_texture = vkwrapper::DescriptorSet::builder().uniform_buffer_fragment();
+    let error = check(combo_vert, combo_frag, &[&vertex_texture, &texture]).unwrap_err();
+    assert!(error.contains("VERTEX"));
+    assert!(check(combo_vert, combo_frag, &[&uniforms]).is_err());
+  }
+
+  #[test]
+  fn shader_reflection_vertex_mismatch() {
+    let vertex = reflect(include_bytes!("../shaders/mesh_animated_vert.spv"));
+
+    // Missing the joint weights
+    let attributes = vertex
+      .vertex_attributes(0, 0, &[0, 12, 24, 32, 44], 60)
+      .unwrap();
+    let error = vertex.check_vertex_inputs(&attributes).unwrap_err();
+    assert!(error.contains("location 5"));
+
+    assert!(vertex.vertex_attributes(0, 0, &[0, 12, 64], 60).is_err());
+  }
+
+  #[test]
+  fn memory_block_alignment() {
+    let mut block = vkwrapper::memory::MemoryBlock::new(1024);
+
+    assert_eq!(block.allocate(100, 1), Some(0));
+    assert_eq!(block.allocate(64, 256), Some(256));
+    // The padding left behind by alignment is still usable
+    assert_eq!(block.allocate(50, 4), Some(100));
+    assert_eq!(block.used(), 214);
+    assert_eq!(block.allocate(1024, 1), None);
+  }
+
+  #[test]
+  fn memory_block_free_coalesces() {
+    let mut block = vkwrapper::memory::MemoryBlock::new(300);
+
+    let a = block.allocate(100, 1).unwrap();
+    let b = block.allocate(100, 1).unwrap();
+    let c = block.allocate(100, 1).unwrap();
+    assert_eq!(block.free_space(), 0);
+
+    block.free(a, 100);
+    block.free(c, 100);
+    assert_eq!(block.free_ranges(), 2);
+    assert_eq!(block.allocate(150, 1), None);
+
+    block.free(b, 100);
+    assert_eq!(block.free_ranges(), 1);
+    assert_eq!(block.largest_free_range(), 300);
+    assert!(block.is_empty());
+  }
+
+  #[test]
+  fn memory_allocator_blocks() {
+    let mut allocator = vkwrapper::MemoryAllocator::with_block_sizes(1024, 1024);
+    assert!(allocator.sub_allocate(16, 16, 0, true).is_none());
+
+    allocator.add_block(vk::DeviceMemory::null(), 0, true, 1024, false);
+    let a = allocator.sub_allocate(512, 16, 0, true).unwrap();
+    let b = allocator.sub_allocate(256, 16, 0, true).unwrap();
+    assert_eq!(b.offset(), 512);
+
+    // Different memory type and images don't share buffer blocks
+    assert!(allocator.sub_allocate(16, 16, 1, true).is_none());
+    assert!(allocator.sub_allocate(16, 16, 0, false).is_none());
+
+    assert_eq!(allocator.release(&a), None);
+    let stats = allocator.stats();
+    assert_eq!(stats.blocks, 1);
+    assert_eq!(stats.allocations, 1);
+    assert_eq!(stats.used_bytes, 256);
+    assert_eq!(stats.free_ranges, 2);
+    assert!((stats.fragmentation() - 0.333).abs() < 0.01);
+
+    // Emptied dedicated blocks hand their memory back to be freed
+    allocator.add_block(vk::DeviceMemory::null(), 2, false, 4096, true);
+    let big = allocator.sub_allocate(4096, 1, 2, false).unwrap();
+    assert_eq!(allocator.release(&big), Some(vk::DeviceMemory::null()));
+    assert_eq!(allocator.stats().blocks, 1);
+  }
+
+  #[test]
+  fn mip_levels_for_dimensions() {
+    assert_eq!(vkwrapper::ImageBuilder::mip_levels_for_dimensions(1, 1), 1);
+    assert_eq!(vkwrapper::ImageBuilder::mip_levels_for_dimensions(2, 2), 2);
+    assert_eq!(
+      vkwrapper::ImageBuilder::mip_levels_for_dimensions(256, 256),
+      9
+    );
+    assert_eq!(
+      vkwrapper::ImageBuilder::mip_levels_for_dimensions(300, 20),
+      9
+    );
+    assert_eq!(vkwrapper::ImageBuilder::mip_levels_for_dimensions(0, 0), 1);
+  }
+
+  fn dds_file(width: u32, height: u32, mips: u32, four_cc: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut bytes = b"DDS ".to_vec();
+    bytes.resize(128, 0);
+    let mut write = |offset: usize, value: u32| {
+      bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    };
+    write(4, 124);
+    write(8, 0x1007 | 0x20000);
+    write(12, height);
+    write(16, width);
+    write(28, mips);
+    write(76, 32);
+    write(80, 0x4);
+    bytes[84..88].copy_from_slice(four_cc);
+    bytes.extend_from_slice(data);
+    bytes
+  }
+
+  #[test]
+  fn dds_bc1_levels_and_decode() {
+    // Red and blue endpoints, every texel using the first colour
+    let block = [0x00, 0xF8, 0x1F, 0x00, 0, 0, 0, 0];
+    let mut data = Vec::new();
+    for _ in 0..5 {
+      data.extend_from_slice(&block);
+    }
+
+    let file = dds_file(8, 8, 2, b"DXT1", &data);
+    let texture = extra::CompressedTexture::from_bytes(&file).unwrap();
+    assert_eq!(texture.format(), vk::Format::BC1_RGBA_UNORM_BLOCK);
+    assert_eq!(texture.levels().len(), 2);
+    assert_eq!(texture.levels()[0].len(), 32);
+    assert_eq!(texture.packed_levels().1, vec![0, 32]);
+
+    let decoded = texture.decode_rgba().unwrap();
+    assert_eq!(decoded.format(), vk::Format::R8G8B8A8_UNORM);
+    assert_eq!(decoded.levels()[0].len(), 8 * 8 * 4);
+    assert_eq!(&decoded.levels()[1][..4], &[255, 0, 0, 255]);
+
+    // Missing the second mip level
+    let truncated = dds_file(8, 8, 2, b"DXT1", &data[..32]);
+    assert!(extra::CompressedTexture::from_bytes(&truncated).is_err());
+  }
+
+  // A 4x4 BC7 KTX2 with each level's offset, length and uncompressed length in `level_index`
+  fn ktx2_file(level_count: u32, level_index: &[u64], data: &[u8]) -> Vec<u8> {
+    let mut file = vec![
+      0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+    ];
+    for value in [
+      vk::Format::BC7_SRGB_BLOCK.as_raw() as u32,
+      1,
+      4,
+      4,
+      0,
+      0,
+      1,
+      level_count,
+      0,
+    ] {
+      file.extend_from_slice(&value.to_le_bytes());
+    }
+    file.resize(80, 0);
+    for value in level_index {
+      file.extend_from_slice(&value.to_le_bytes());
+    }
+    file.extend_from_slice(data);
+    file
+  }
+
+  #[test]
+  fn ktx2_levels() {
+    let file = ktx2_file(1, &[104, 16, 16], &[7; 16]);
+
+    let texture = extra::CompressedTexture::from_bytes(&file).unwrap();
+    assert_eq!(texture.format(), vk::Format::BC7_SRGB_BLOCK);
+    assert_eq!(texture.levels(), &vec![vec![7; 16]]);
+    assert!(texture.decode_rgba().is_err());
+  }
+
+  #[test]
+  fn hostile_texture_headers() {
+    let load = extra::CompressedTexture::from_bytes;
+    let block = [0; 8];
+
+    // More levels than halving the largest side down to 1 gives
+    assert!(load(&dds_file(1, 1, 40, b"DXT1", &[0; 8 * 40])).is_err());
+    assert!(load(&dds_file(1, 1, 2, b"DXT1", &[0; 16])).is_err());
+    assert!(load(&dds_file(8, 8, 4, b"DXT1", &[0; 56])).is_ok());
+    assert!(load(&dds_file(8, 8, 5, b"DXT1", &[0; 64])).is_err());
+
+    // Levels far larger than the file
+    assert!(load(&dds_file(u32::MAX, u32::MAX, 1, b"DXT1", &block)).is_err());
+    let rgba8 = [28u32, 3, 0, 1, 0]
+      .iter()
+      .flat_map(|value| value.to_le_bytes())
+      .collect::<Vec<_>>();
+    assert!(load(&dds_file(65536, 65536, 1, b"DX10", &rgba8)).is_err());
+
+    // Truncated headers
+    assert!(load(&dds_file(4, 4, 1, b"DXT1", &block)[..100]).is_err());
+    let ktx2 = ktx2_file(1, &[104, 16, 16], &[7; 16]);
+    assert!(load(&ktx2[..60]).is_err());
+    assert!(load(&ktx2[..110]).is_err());
+
+    // Level index pointing past the end, or wrapping around it
+    assert!(load(&ktx2_file(1, &[u64::MAX - 4, 16, 16], &[7; 16])).is_err());
+    assert!(load(&ktx2_file(1, &[104, u64::MAX, 16], &[7; 16])).is_err());
+    assert!(load(&ktx2_file(40, &[104, 16, 16], &[7; 16])).is_err());
+  }
+
+  #[test]
+  fn dds_and_png_sample_same_texel() {
+    // Red on the left half and blue on the right, as a BC1 DDS and as a PNG
+    let red_block = [0x00, 0xF8, 0x1F, 0x00, 0, 0, 0, 0];
+    let blue_block = [0x1F, 0x00, 0x00, 0xF8, 0, 0, 0, 0];
+    let dds = dds_file(8, 4, 1, b"DXT1", &[red_block, blue_block].concat());
+
+    let image = image::RgbaImage::from_fn(8, 4, |x, _| {
+      if x < 4 {
+        image::Rgba([255, 0, 0, 255])
+      } else {
+        image::Rgba([0, 0, 255, 255])
+      }
+    });
+    let mut png = Vec::new();
+    image::DynamicImage::ImageRgba8(image)
+      .write_to(&mut png, image::ImageOutputFormat::Png)
+      .unwrap();
+    // Uploaded the same way `TextureHandler` loads images
+    let png = image::load_from_memory(&png).unwrap().fliph().to_rgba8();
+
+    let texel = |data: &[u8], u: f32| {
+      let x = (u * 8.0) as usize * 4;
+      data[x..x + 4].to_vec()
+    };
+
+    // Kept compressed when the device samples BC1, decoded on the CPU otherwise
+    for sampled in [true, false] {
+      let (texture, mirror_uv) = extra::CompressedTexture::from_bytes(&dds)
+        .unwrap()
+        .for_upload(sampled)
+        .unwrap();
+      assert_eq!(mirror_uv, sampled);
+      let texels = if mirror_uv {
+        texture.decode_rgba().unwrap()
+      } else {
+        texture
+      };
+
+      for x in 0..8 {
+        let u = (x as f32 + 0.5) / 8.0;
+        let dds_u = if mirror_uv { 1.0 - u } else { u };
+        assert_eq!(texel(&texels.levels()[0], dds_u), texel(&png, u));
+      }
+    }
+  }
+
+  #[test]
+  fn msaa_sample_count() {
+    let supported = vk::SampleCountFlags::TYPE_1
+      | vk::SampleCountFlags::TYPE_2
+      | vk::SampleCountFlags::TYPE_4
+      | vk::SampleCountFlags::TYPE_8;
+
+    assert_eq!(
+      vkwrapper::Vulkan::supported_sample_count(4, supported),
+      vk::SampleCountFlags::TYPE_4
+    );
+    assert_eq!(
+      vkwrapper::Vulkan::supported_sample_count(16, supported),
+      vk::SampleCountFlags::TYPE_8
+    );
+    assert_eq!(
+      vkwrapper::Vulkan::supported_sample_count(6, supported),
+      vk::SampleCountFlags::TYPE_4
+    );
+    assert_eq!(
+      vkwrapper::Vulkan::supported_sample_count(1, supported),
+      vk::SampleCountFlags::TYPE_1
+    );
+    assert_eq!(
+      vkwrapper::Vulkan::supported_sample_count(8, vk::SampleCountFlags::TYPE_1),
+      vk::SampleCountFlags::TYPE_1
+    );
+  }
+
+  #[test]
+  fn present_mode_fallback() {
+    let fifo_only = [PresentMode::Fifo];
+    let all = [
+      PresentMode::Fifo,
+      PresentMode::FifoRelaxed,
+      PresentMode::Mailbox,
+      PresentMode::Immediate,
+    ];
+
+    assert_eq!(
+      PresentMode::choose(PresentMode::Mailbox, &all),
+      PresentMode::Mailbox
+    );
+    assert_eq!(
+      PresentMode::choose(
+        PresentMode::Mailbox,
+        &[PresentMode::Fifo, PresentMode::Immediate]
+      ),
+      PresentMode::Immediate
+    );
+    assert_eq!(
+      PresentMode::choose(PresentMode::FifoRelaxed, &fifo_only),
+      PresentMode::Fifo
+    );
+    assert_eq!(
+      PresentMode::choose(PresentMode::Immediate, &fifo_only),
+      PresentMode::Fifo
+    );
+    assert_eq!(
+      PresentMode::choose(PresentMode::Mailbox, &[]),
+      PresentMode::Fifo
+    );
+
+    assert_eq!(vkwrapper::VkSwapchain::image_count(None, 2, 8), 3);
+    assert_eq!(vkwrapper::VkSwapchain::image_count(Some(1), 2, 8), 2);
+    assert_eq!(vkwrapper::VkSwapchain::image_count(Some(10), 2, 8), 8);
+    assert_eq!(vkwrapper::VkSwapchain::image_count(Some(10), 2, 0), 10);
+  }
+
+  fn test_gpu(index: usize, gpu_type: GpuType, vram: u64, name: &str) -> GpuInfo {
+    GpuInfo {
+      index,
+      name: name.to_string(),
+      gpu_type,
+      vram,
+      api_version: (1, 3, 0),
+      driver_version: 0,
+      limits: GpuLimits::default(),
+      features: GpuFeatures::default(),
+      suitable: true,
+    }
+  }
+
+  #[test]
+  fn gpu_selection() {
+    let mut gpus = vec![
+      test_gpu(0, GpuType::Integrated, 512, "Intel Iris Xe"),
+      test_gpu(1, GpuType::Cpu, 0, "llvmpipe"),
+      test_gpu(2, GpuType::Discrete, 8192, "NVIDIA GeForce RTX 3060"),
+    ];
+
+    let choose = vkwrapper::gpu::choose_gpu;
+    assert_eq!(choose(&gpus, &GpuPreference::Discrete), Some(2));
+    assert_eq!(choose(&gpus, &GpuPreference::Integrated), Some(0));
+    assert_eq!(choose(&gpus, &GpuPreference::Index(1)), Some(1));
+    assert_eq!(
+      choose(&gpus, &GpuPreference::Named("iris".to_string())),
+      Some(0)
+    );
+    assert_eq!(choose(&gpus, &GpuPreference::Index(7)), Some(2));
+
+    gpus[2].suitable = false;
+    assert_eq!(choose(&gpus, &GpuPreference::Index(2)), Some(0));
+    assert_eq!(choose(&gpus, &GpuPreference::Discrete), Some(0));
+
+    assert_eq!(GpuPreference::parse("1"), Some(GpuPreference::Index(1)));
+    assert_eq!(
+      GpuPreference::parse("Discrete"),
+      Some(GpuPreference::Discrete)
+    );
+    assert_eq!(
+      GpuPreference::parse("RTX"),
+      Some(GpuPreference::Named("RTX".to_string()))
+    );
+    assert_eq!(GpuPreference::parse(" "), None);
+  }
+
+  #[test]
+  fn queue_family_selection() {
+    use vkwrapper::gpu::QueueFamilies;
+
+    let graphics = vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE | vk::QueueFlags::TRANSFER;
+    let compute = vk::QueueFlags::COMPUTE | vk::QueueFlags::TRANSFER;
+    let transfer = vk::QueueFlags::TRANSFER;
+
+    let families = QueueFamilies::choose(&[graphics, compute, transfer], 0);
+    assert_eq!((families.compute, families.transfer), (1, 2));
+    assert!(families.has_dedicated_compute() && families.has_dedicated_transfer());
+    assert_eq!(families.unique(), vec![0, 1, 2]);
+
+    let families = QueueFamilies::choose(&[graphics], 0);
+    assert_eq!((families.compute, families.transfer), (0, 0));
+    assert!(!families.has_dedicated_compute() && !families.has_dedicated_transfer());
+    assert_eq!(families.unique(), vec![0]);
+
+    let families = QueueFamilies::choose(&[compute, graphics], 1);
+    assert_eq!((families.compute, families.transfer), (0, 1));
+    assert_eq!(families.unique(), vec![1, 0]);
+  }
+
+  #[test]
+  fn per_frame_resources() {
+    let mut per_frame = vkwrapper::PerFrame::new(3, |frame| vec![frame]);
+    assert_eq!(per_frame.len(), 3);
+    assert_eq!(per_frame.get(1), &vec![1]);
+    assert_eq!(per_frame.get(4), &vec![1]);
+
+    per_frame.get_mut(2).push(7);
+    assert_eq!(per_frame.into_vec(), vec![vec![0], vec![1], vec![2, 7]]);
+
+    let options = MaatOptions::default();
+    assert_eq!(options.frames_in_flight, DEFAULT_FRAMES_IN_FLIGHT);
+  }
+
+  #[test]
+  fn pipeline_cache_header() {
+    use vkwrapper::pipeline_cache::PipelineCacheHeader;
+
+    let device = PipelineCacheHeader {
+      header_size: 32,
+      version: 1,
+      vendor_id: 0x10de,
+      device_id: 0x2503,
+      cache_uuid: [7; 16],
+    };
+
+    let mut data = device.to_bytes();
+    data.extend_from_slice(&[1, 2, 3, 4]);
+    let header = PipelineCacheHeader::parse(&data).unwrap();
+    assert_eq!(header, device);
+    assert!(header.is_compatible(&device));
+
+    let mut other_driver = device;
+    other_driver.cache_uuid[0] = 8;
+    assert!(!other_driver.is_compatible(&device));
+
+    let mut other_device = device;
+    other_device.device_id = 0x2504;
+    assert!(!other_device.is_compatible(&device));
+
+    assert!(PipelineCacheHeader::parse(&data[..16]).is_none());
+  }
+
+  #[test]
+  fn descriptor_pool_growth() {
+    use vkwrapper::DescriptorAllocator;
+
+    let base = vec![
+      vk::DescriptorPoolSize {
+        ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        descriptor_count: 50,
+      },
+      vk::DescriptorPoolSize {
+        ty: vk::DescriptorType::UNIFORM_BUFFER,
+        descriptor_count: 10,
+      },
+    ];
+
+    let (sizes, max_sets) = DescriptorAllocator::next_pool_sizes(&base, 60, 1, &[]);
+    assert_eq!(sizes[0].descriptor_count, 100);
+    assert_eq!(sizes[1].descriptor_count, 20);
+    assert_eq!(max_sets, 120);
+
+    // Growth stops at 16 times the first pool
+    let (sizes, max_sets) = DescriptorAllocator::next_pool_sizes(&base, 60, 40, &[]);
+    assert_eq!(sizes[0].descriptor_count, 800);
+    assert_eq!(max_sets, 960);
+
+    // A set the first pool was never sized for still fits in the next one
+    let required = [
+      vk::DescriptorPoolSize {
+        ty: vk::DescriptorType::UNIFORM_BUFFER,
+        descriptor_count: 64,
+      },
+      vk::DescriptorPoolSize {
+        ty: vk::DescriptorType::STORAGE_BUFFER,
+        descriptor_count: 2,
+      },
+    ];
+    let (sizes, _) = DescriptorAllocator::next_pool_sizes(&base, 60, 1, &required);
+    assert_eq!(sizes.len(), 3);
+    assert_eq!(sizes[1].descriptor_count, 64);
+    assert_eq!(sizes[2].ty, vk::DescriptorType::STORAGE_BUFFER);
+    assert_eq!(sizes[2].descriptor_count, 2);
+  }
+
+  #[test]
+  fn compute_task_helpers() {
+    use shader_handlers::ComputeTaskHandler;
+    use vkwrapper::ReflectedBinding;
+
+    assert_eq!(ComputeTaskHandler::group_count(1000, 64), 16);
+    assert_eq!(ComputeTaskHandler::group_count(1024, 64), 16);
+    assert_eq!(ComputeTaskHandler::group_count(0, 64), 0);
+
+    let push_constants = ComputeTaskHandler::as_bytes(&[1.5f32, 2.0]);
+    assert_eq!(push_constants.len(), 8);
+    let padded = ComputeTaskHandler::push_constant_bytes(&push_constants, 16).unwrap();
+    assert_eq!(padded.len(), 16);
+    assert_eq!(&padded[8..], &[0; 8]);
+    assert!(ComputeTaskHandler::push_constant_bytes(&push_constants, 4).is_err());
+
+    let particles = vec![[1.0f32, 2.0, 3.0], [4.0, 5.0, 6.0]];
+    let bytes = ComputeTaskHandler::as_bytes(&particles);
+    assert_eq!(
+      ComputeTaskHandler::from_bytes::<[f32; 3]>(&bytes),
+      particles
+    );
+
+    let bindings = [ReflectedBinding {
+      set: 0,
+      binding: 1,
+      descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+      count: 1,
+      stages: vk::ShaderStageFlags::COMPUTE,
+    }];
+    assert!(
+      ComputeTaskHandler::check_binding(&bindings, 0, 1, vk::DescriptorType::STORAGE_BUFFER)
+        .is_ok()
+    );
+    assert!(
+      ComputeTaskHandler::check_binding(&bindings, 0, 1, vk::DescriptorType::STORAGE_IMAGE)
+        .is_err()
+    );
+    assert!(
+      ComputeTaskHandler::check_binding(&bindings, 1, 1, vk::DescriptorType::STORAGE_BUFFER)
+        .is_err()
+    );
+  }
+
+  // Runs collatz.comp through `ComputeTaskHandler` on the installed gpu. Needs a display and a
+  // Vulkan device, run with `cargo test -- --ignored`.
+  #[test]
+  #[ignore]
+  #[cfg(target_os = "linux")]
+  fn compute_dispatch_on_device() {
+    use shader_handlers::ComputeTaskHandler;
+    use winit::platform::x11::EventLoopBuilderExtX11;
+
+    let event_loop = winit::event_loop::EventLoopBuilder::new()
+      .with_any_thread(true)
+      .build()
+      .expect("Failed to create event loop");
+    let mut screen_resolution = [64, 64];
+    let mut window = VkWindow::new(
+      "compute_dispatch_on_device",
+      64,
+      64,
+      &event_loop,
+      &mut screen_resolution,
+    );
+    let mut vulkan = Vulkan::new(
+      &mut window,
+      &event_loop,
+      vk::Extent2D {
+        width: screen_resolution[0],
+        height: screen_resolution[1],
+      },
+      &GpuPreference::Discrete,
+      DEFAULT_FRAMES_IN_FLIGHT,
+      None,
+    );
+    let mut compute = ComputeTaskHandler::new(&mut vulkan);
+
+    let input = vec![1u32, 2, 3, 6, 7, 27];
+    compute
+      .load_shader(
+        &mut vulkan,
+        "collatz",
+        std::io::Cursor::new(&include_bytes!("../shaders/collatz_comp.spv")[..]),
+      )
+      .unwrap();
+    compute
+      .create_storage_buffer(&mut vulkan, "indices", &input)
+      .unwrap();
+    compute
+      .bind(&mut vulkan, "collatz", 0, 0, "indices")
+      .unwrap();
+
+    let fence = compute
+      .dispatch::<u32>(
+        &mut vulkan,
+        "collatz",
+        [ComputeTaskHandler::group_count(input.len() as u32, 1), 1, 1],
+        &[],
+      )
+      .unwrap();
+    if !compute.is_finished(&mut vulkan, fence) {
+      compute.wait(&mut vulkan, fence);
+    }
+    assert!(compute.is_finished(&mut vulkan, fence));
+
+    let collatz = |mut n: u32| {
+      let mut steps = 0;
+      while n != 1 {
+        n = if n & 1 == 0 { n / 2 } else { 3 * n + 1 };
+        steps += 1;
+      }
+      steps
+    };
+    assert_eq!(
+      compute.read_buffer::<u32>(&mut vulkan, "indices").unwrap(),
+      input.iter().map(|n| collatz(*n)).collect::<Vec<_>>()
+    );
+
+    compute.destroy(&mut vulkan);
+    vulkan.destroy();
+  }
+
+  #[test]
+  fn particle_shader_reflection() {
+    let compute = reflect(include_bytes!("../shaders/particles_comp.spv"));
+    assert_eq!(compute.stage(), vk::ShaderStageFlags::COMPUTE);
+    assert_eq!(compute.push_constant_size(), 128);
+    assert_eq!(
+      compute
+        .bindings()
+        .iter()
+        .map(|b| (b.set, b.binding, b.descriptor_type))
+        .collect::<Vec<_>>(),
+      vec![
+        (0, 0, vk::DescriptorType::STORAGE_BUFFER),
+        (0, 1, vk::DescriptorType::STORAGE_BUFFER)
+      ]
+    );
+
+    let vertex = reflect(include_bytes!("../shaders/instanced_combo_vert.spv"));
+    let fragment = reflect(include_bytes!("../shaders/particle_frag.spv"));
+    assert_eq!(fragment.stage(), vk::ShaderStageFlags::FRAGMENT);
+    let bindings = vkwrapper::ShaderReflection::merged_bindings(&[&vertex, &fragment]).unwrap();
+    assert!(bindings.iter().any(|b| b.set == 1
+      && b.binding == 0
+      && b.descriptor_type == vk::DescriptorType::COMBINED_IMAGE_SAMPLER));
+  }
+
+  #[test]
+  fn particle_emitter_reference() {
+    use glam::{Vec2, Vec4};
+    use shader_handlers::particle_handler::SpawnState;
+
+    // PCG hash, the same values particles.comp produces
+    assert_eq!(ParticleEmitter::hash(0), 129708002);
+    assert_eq!(ParticleEmitter::hash(1), 2831084092);
+    assert_eq!(ParticleEmitter::hash(12345), 4099845390);
+    assert!((0..1000).all(|i| (0.0..=1.0).contains(&ParticleEmitter::random(i))));
+
+    let emitter = ParticleEmitter::new("smoke")
+      .position(Vec2::new(100.0, 50.0))
+      .rate(10.0)
+      .lifetime(1.0, 2.0)
+      .direction(90.0, 30.0)
+      .speed(10.0, 20.0)
+      .gravity(Vec2::new(0.0, -10.0))
+      .colour(Vec4::ONE, Vec4::new(1.0, 0.0, 0.0, 0.0))
+      .size(10.0, 20.0)
+      .sprite_sheet(4, 8)
+      .capacity(4);
+
+    for id in 0..100 {
+      let particle = emitter.spawn(id);
+      let [x, y, vx, vy] = particle.pos_vel;
+      let speed = (vx * vx + vy * vy).sqrt();
+      let angle = vy.atan2(vx).to_degrees();
+      assert_eq!((x, y), (100.0, 50.0));
+      assert!((10.0 - 1e-3..=20.0 + 1e-3).contains(&speed));
+      assert!((75.0 - 1e-3..=105.0 + 1e-3).contains(&angle));
+      assert!((1.0..=2.0).contains(&particle.age_lifetime[1]));
+    }
+    assert_eq!(emitter.spawn(7), emitter.spawn(7));
+
+    // Semi-implicit Euler
+    let particle = Particle {
+      pos_vel: [0.0, 0.0, 1.0, 0.0],
+      age_lifetime: [0.0, 1.0, 0.0, 0.0],
+    };
+    let advanced = emitter.advance(&particle, 0.5);
+    assert_eq!(advanced.pos_vel, [0.5, -2.5, 1.0, -5.0]);
+    assert_eq!(advanced.age_lifetime, [0.5, 1.0, 0.0, 0.0]);
+    let dead = Particle::new();
+    assert_eq!(emitter.advance(&dead, 0.5), dead);
+
+    // Half way through its life
+    let instance = emitter.instance(&advanced, Vec2::new(3.0, 4.0), 9.0);
+    assert_eq!(instance.pos_scale, [0.5 - 7.5, -2.5 - 7.5, 15.0, 15.0]);
+    assert_eq!(instance.other_colour, [1.0, 0.5, 0.5, 0.5]);
+    assert_eq!(instance.sprite_sheet, [4.0, 4.0, 0.0, 0.0]);
+    assert_eq!(instance.camera_intensity_time, [3.0, 4.0, -1.0, 9.0]);
+    let instance = emitter.instance(&dead, Vec2::ZERO, 0.0);
+    assert_eq!(instance.pos_scale, [0.0; 4]);
+    assert_eq!(instance.sprite_sheet, [1.0, 0.0, 0.0, 0.0]);
+
+    // 10 a second, the fraction carries over and the slots wrap around the capacity
+    let mut spawn_state = SpawnState::new();
+    assert_eq!(spawn_state.spawn(10.0, 0.25, 4), (0, 2, 0));
+    assert_eq!(spawn_state.spawn(10.0, 0.25, 4), (2, 3, 2));
+    assert_eq!(spawn_state.spawn(10.0, 1.0, 4), (1, 4, 5));
+    assert_eq!(spawn_state.next_slot, 1);
+    assert_eq!(spawn_state.next_id, 9);
+
+    let mut particles = vec![Particle::new(); 4];
+    let step = |first_slot, spawn_count, first_id| ParticleStep {
+      first_slot,
+      spawn_count,
+      first_id,
+      delta_time: 0.1,
+      time: 0.0,
+      camera: Vec2::ZERO,
+    };
+    emitter.simulate(&mut particles, &step(3, 2, 10));
+    assert_eq!(particles[3], emitter.spawn(10));
+    assert_eq!(particles[0], emitter.spawn(11));
+    assert!(!particles[1].is_alive() && !particles[2].is_alive());
+
+    emitter.simulate(&mut particles, &step(1, 0, 12));
+    assert_eq!(particles[3], emitter.advance(&emitter.spawn(10), 0.1));
+  }
+
+  #[test]
+  fn post_process_shader_reflection() {
+    let bindings_of = |reflection: &vkwrapper::ShaderReflection| {
+      reflection
+        .bindings()
+        .iter()
+        .map(|b| (b.set, b.binding, b.descriptor_type))
+        .collect::<Vec<_>>()
+    };
+
+    for spv in [
+      &include_bytes!("../shaders/bloom_downsample_comp.spv")[..],
+      &include_bytes!("../shaders/bloom_upsample_comp.spv")[..],
+    ] {
+      let compute = reflect(spv);
+      assert_eq!(compute.stage(), vk::ShaderStageFlags::COMPUTE);
+      assert_eq!(compute.push_constant_size(), 32);
+      assert_eq!(
+        bindings_of(&compute),
+        vec![
+          (0, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+          (0, 1, vk::DescriptorType::STORAGE_IMAGE)
+        ]
+      );
+    }
+
+    let vertex = reflect(include_bytes!("../shaders/post_fullscreen_vert.spv"));
+    assert_eq!(vertex.stage(), vk::ShaderStageFlags::VERTEX);
+    assert!(vertex.inputs().is_empty());
+
+    let fragment = reflect(include_bytes!("../shaders/post_composite_frag.spv"));
+    assert_eq!(fragment.stage(), vk::ShaderStageFlags::FRAGMENT);
+    assert_eq!(fragment.push_constant_size(), 32);
+    assert_eq!(
+      bindings_of(&fragment),
+      (0..3)
+        .map(|binding| (0, binding, vk::DescriptorType::COMBINED_IMAGE_SAMPLER))
+        .collect::<Vec<_>>()
+    );
+  }
+
+  #[test]
+  fn post_process_reference() {
+    use glam::Vec3;
+    use shader_handlers::PostProcessHandler;
+
+    let close = |a: Vec3, b: Vec3| (a - b).abs().max_element() < 1e-3;
+
+    assert_eq!(
+      ToneMapping::None.apply(Vec3::new(-1.0, 0.5, 4.0)),
+      Vec3::new(0.0, 0.5, 1.0)
+    );
+    assert_eq!(
+      ToneMapping::Reinhard.apply(Vec3::new(1.0, 3.0, 0.0)),
+      Vec3::new(0.5, 0.75, 0.0)
+    );
+    assert!(close(ToneMapping::Aces.apply(Vec3::ZERO), Vec3::ZERO));
+    assert!(close(
+      ToneMapping::Aces.apply(Vec3::splat(0.18)),
+      Vec3::splat(0.2669)
+    ));
+    assert_eq!(ToneMapping::Aces.apply(Vec3::splat(100.0)), Vec3::ONE);
+    assert_eq!(
+      PostProcessSettings::default().tone_mapping,
+      ToneMapping::None
+    );
+
+    assert_eq!(
+      PostProcessHandler::bloom_mip_sizes(1280, 720),
+      vec![(640, 360), (320, 180), (160, 90), (80, 45), (40, 22)]
+    );
+    assert_eq!(PostProcessHandler::bloom_mip_sizes(20, 3)[4], (1, 1));
+
+    assert_eq!(
+      PostProcessHandler::bloom_contribution(Vec3::splat(0.5), 1.0),
+      0.0
+    );
+    assert_eq!(
+      PostProcessHandler::bloom_contribution(Vec3::new(4.0, 0.0, 1.0), 1.0),
+      0.75
+    );
+    assert_eq!(PostProcessHandler::bloom_contribution(Vec3::ZERO, 0.0), 0.0);
+
+    // Every texel of the identity lut holds the colour that samples it
+    let size = 16;
+    let lut = PostProcessHandler::identity_lut(size);
+    assert_eq!(lut.dimensions(), (256, 16));
+    let texel = |uv: glam::Vec2| {
+      let pixel = lut.get_pixel(
+        (uv.x * lut.width() as f32) as u32,
+        (uv.y * lut.height() as f32) as u32,
+      );
+      Vec3::new(pixel[0] as f32, pixel[1] as f32, pixel[2] as f32) / 255.0
+    };
+    for value in 0..size {
+      let encoded = value as f32 / (size - 1) as f32;
+      // Red and green on the lut grid, blue between two slices
+      let encoded = Vec3::new(encoded, 1.0 - encoded, encoded * 0.5);
+      let colour = Vec3::select(
+        encoded.cmple(Vec3::splat(0.04045)),
+        encoded / 12.92,
+        ((encoded + 0.055) / 1.055).powf(2.4),
+      );
+      let (uv, next_uv, blend) = PostProcessHandler::lut_coordinates(colour, size);
+      let graded = texel(uv).lerp(texel(next_uv), blend);
+      assert!(
+        close(graded, PostProcessHandler::linear_to_srgb(colour)),
+        "{}",
+        value
+      );
+    }
+  }
+
+  #[test]
+  fn environment_shader_reflection() {
+    let skybox = reflect(include_bytes!("../shaders/skybox_frag.spv"));
+    assert_eq!(skybox.stage(), vk::ShaderStageFlags::FRAGMENT);
+    assert_eq!(skybox.push_constant_size(), 96);
+    assert_eq!(
+      skybox
+        .bindings()
+        .iter()
+        .map(|b| (b.set, b.binding, b.descriptor_type))
+        .collect::<Vec<_>>(),
+      vec![(0, 0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER)]
+    );
+
+    // The mesh shader reads the environment from set 3 after the material in set 2
+    let mesh = reflect(include_bytes!("../shaders/mesh_pbr_frag.spv"));
+    let environment_bindings = mesh
+      .bindings()
+      .iter()
+      .filter(|b| b.set == 3)
+      .map(|b| (b.binding, b.descriptor_type))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      environment_bindings,
+      vec![
+        (0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+        (1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+        (2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+        (3, vk::DescriptorType::UNIFORM_BUFFER),
+      ]
+    );
+  }
+
+  #[test]
+  fn environment_baking() {
+    use extra::environment::{self, Cubemap, EnvironmentMaps};
+    use glam::{Vec2, Vec3};
+
+    let close = |a: Vec3, b: Vec3, tolerance: f32| (a - b).abs().max_element() < tolerance;
+
+    // Directions land back on the texel they came from
+    for face in 0..6 {
+      for uv in [
+        Vec2::new(0.1, 0.2),
+        Vec2::new(0.5, 0.5),
+        Vec2::new(0.9, 0.7),
+      ] {
+        let (hit_face, hit_uv) = Cubemap::face_uv(Cubemap::direction(face, uv));
+        assert_eq!(hit_face, face);
+        assert!((hit_uv - uv).abs().max_element() < 1e-4, "{} {}", face, uv);
+      }
+    }
+    assert_eq!(Cubemap::face_uv(Vec3::X).0, 0);
+    assert_eq!(Cubemap::face_uv(-Vec3::Y).0, 3);
+    assert_eq!(Cubemap::face_uv(-Vec3::Z).0, 5);
+    assert!((environment::equirectangular_uv(-Vec3::Z) - Vec2::new(0.5, 0.5)).length() < 1e-5);
+    assert!(environment::equirectangular_uv(Vec3::Y).y < 1e-5);
+
+    // A panorama with a bright sky and dark ground keeps them the right way up
+    let (width, height) = (64, 32);
+    let pixels = (0..width * height)
+      .map(|i| {
+        if i / width < height / 2 {
+          Vec3::ONE
+        } else {
+          Vec3::ZERO
+        }
+      })
+      .collect::<Vec<_>>();
+    let sky = Cubemap::from_equirectangular(width, height, &pixels);
+    assert_eq!(sky.size(), 16);
+    assert!(close(sky.sample(Vec3::Y), Vec3::ONE, 1e-5));
+    assert!(close(sky.sample(-Vec3::Y), Vec3::ZERO, 1e-5));
+
+    // A constant environment lights every direction the same, as a white lambertian surface
+    // reflects exactly the light around it
+    let grey = Vec3::new(0.5, 0.25, 1.0);
+    let maps = EnvironmentMaps::bake(Cubemap::from_fn(8, |_| grey));
+    for direction in [Vec3::X, -Vec3::Y, Vec3::new(0.3, 0.5, -0.8)] {
+      assert!(close(maps.irradiance.sample(direction), grey, 1e-3));
+      for specular in &maps.specular {
+        assert!(close(specular.sample(direction), grey, 1e-3));
+      }
+    }
+    assert_eq!(maps.specular.len(), environment::SPECULAR_MIPS as usize);
+    assert_eq!(maps.specular[1].size(), environment::SPECULAR_SIZE / 2);
+
+    // Light from above reaches an upward surface fully and a downward one not at all
+    let sh = environment::project_sh(&Cubemap::from_fn(16, |d| Vec3::splat(d.y.max(0.0))));
+    let up = environment::sh_irradiance(&sh, Vec3::Y).x;
+    let down = environment::sh_irradiance(&sh, -Vec3::Y).x;
+    assert!((up - 2.0 / 3.0).abs() < 0.05, "{}", up);
+    assert!(down < 0.05, "{}", down);
+
+    // Smooth surfaces seen head on reflect all of F0, at grazing angles the Fresnel bias takes
+    // over and rough surfaces lose some of both
+    let smooth = environment::integrate_brdf(1.0, 0.0);
+    assert!(
+      (smooth - Vec2::new(1.0, 0.0)).abs().max_element() < 0.01,
+      "{}",
+      smooth
+    );
+    let grazing = environment::integrate_brdf(0.1, 0.0);
+    assert!(grazing.y > grazing.x && grazing.y > 0.5, "{}", grazing);
+    let rough = environment::integrate_brdf(1.0, 1.0);
+    assert!(rough.x + rough.y < smooth.x + smooth.y, "{}", rough);
+
+    assert_eq!(environment::f32_to_f16(0.0), 0);
+    assert_eq!(environment::f32_to_f16(1.0), 0x3c00);
+    assert_eq!(environment::f32_to_f16(-2.0), 0xc000);
+    assert_eq!(environment::f32_to_f16(65504.0), 0x7bff);
+    assert_eq!(environment::f32_to_f16(1e6), 0x7c00);
+    assert_eq!(environment::f32_to_f16(5.960_464_5e-8), 0x0001);
+    let (data, offsets) = environment::pack_rgba16f(
+      &maps
+        .irradiance
+        .mip_chain()
+        .iter()
+        .map(|level| level.faces().clone())
+        .collect::<Vec<_>>(),
+    );
+    assert_eq!(
+      offsets,
+      vec![
+        0,
+        16 * 16 * 6 * 8,
+        (16 * 16 + 8 * 8) * 6 * 8,
+        (16 * 16 + 8 * 8 + 4 * 4) * 6 * 8,
+        (16 * 16 + 8 * 8 + 4 * 4 + 2 * 2) * 6 * 8
+      ]
+    );
+    assert_eq!(data.len(), (16 * 16 + 8 * 8 + 4 * 4 + 2 * 2 + 1) * 6 * 8);
+  }
+
+  #[test]
+  fn instanced_mesh_shader_reflection() {
+    let vertex = reflect(include_bytes!(
+      "../shaders/instanced_mesh_animated_vert.spv"
+    ));
+    assert_eq!(vertex.stage(), vk::ShaderStageFlags::VERTEX);
+    assert_eq!(vertex.push_constant_size(), 0);
+
+    // Mesh vertex attributes come first then the model matrix columns and colour per instance
+    let mesh = vertex
+      .vertex_attributes(0, 0, &[0, 12, 24, 32, 44, 60], 76)
+      .unwrap();
+    assert_eq!(mesh.len(), 6);
+    let instance = vertex
+      .vertex_attributes(1, 6, &[0, 16, 32, 48, 64], 80)
+      .unwrap();
+    assert_eq!(
+      instance
+        .iter()
+        .map(|a| (a.location, a.format))
+        .collect::<Vec<_>>(),
+      (6..11)
+        .map(|location| (location, vk::Format::R32G32B32A32_SFLOAT))
+        .collect::<Vec<_>>()
+    );
+  }
+
+  #[test]
+  fn instanced_model_data() {
+    use extra::gltf_loader::{Mesh, Node, Primitive};
+    use glam::{Quat, Vec3};
+
+    let node = |primitives: Vec<Primitive>, translation: Vec3| Node {
+      idx: 0,
+      mesh: Mesh { primitives },
+      skin: -1,
+      parent: -1,
+      children: Vec::new(),
+      translation,
+      rotation: Quat::IDENTITY,
+      scale: Vec3::ONE,
+      global_translation: translation,
+      global_rotation: Quat::IDENTITY,
+      global_scale: Vec3::ONE,
+    };
+    let primitive = || Primitive {
+      first_index: 0,
+      index_count: 3,
+      material_index: -1,
+      displacement: [0.0; 3],
+      bounding_box_min: [0.0; 3],
+      bounding_box_max: [0.0; 3],
+    };
+
+    // The middle node has no mesh so takes no instances
+    let nodes = vec![
+      node(vec![primitive()], Vec3::new(1.0, 0.0, 0.0)),
+      node(Vec::new(), Vec3::ZERO),
+      node(vec![primitive(), primitive()], Vec3::new(0.0, 2.0, 0.0)),
+    ];
+
+    let mut tinted = vec![5.0, 0.0, -3.0, 0.0, 2.0, 2.0, 2.0, 0.0, 0.0, 90.0, 0.0, 0.0];
+    tinted.extend_from_slice(&[1.0, 0.5, 0.25, 1.0]);
+    let instances = vec![
+      vec![0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+      tinted,
+    ];
+
+    let data = ModelHandler::instance_data(&nodes, &instances);
+    assert_eq!(data.len(), 4);
+
+    for (i, node_idx) in [0, 0, 2, 2].iter().enumerate() {
+      let (translation, rotation, scale) = Node::transform_from_data(&instances[i % 2]);
+      let expected = Node::calculate_global_matrix(&nodes, *node_idx, translation, rotation, scale);
+      assert_eq!(data[i].model, expected.to_cols_array());
+    }
+
+    assert_eq!(data[0].colour, [1.0; 4]);
+    assert_eq!(data[1].colour, [1.0, 0.5, 0.25, 1.0]);
+    assert_eq!(data[3].model[12..15], [5.0, 2.0, -3.0]);
+  }
+
+  #[test]
+  fn frustum_culling() {
+    use glam::{Mat4, Quat, Vec3, Vec4};
+
+    // Looking down -z from the origin, 90 degrees wide and tall with depth 1 to 100
+    let projection = Mat4::perspective_rh(90f32.to_radians(), 1.0, 1.0, 100.0);
+    let frustum = Frustum::from_camera(projection.to_cols_array(), Mat4::IDENTITY.to_cols_array());
+
+    assert!(frustum.contains_point(Vec3::new(0.0, 0.0, -5.0)));
+    assert!(frustum.contains_point(Vec3::new(4.9, -4.9, -5.0)));
+    assert!(!frustum.contains_point(Vec3::new(5.1, 0.0, -5.0)));
+    assert!(!frustum.contains_point(Vec3::new(0.0, 0.0, 5.0)));
+    assert!(!frustum.contains_point(Vec3::new(0.0, 0.0, -0.5)));
+    assert!(!frustum.contains_point(Vec3::new(0.0, 0.0, -101.0)));
+
+    // Planes are normalised so sphere radii are distances
+    assert!(frustum.intersects_sphere(Vec3::new(0.0, 0.0, 1.0), 2.5));
+    assert!(!frustum.intersects_sphere(Vec3::new(0.0, 0.0, 1.0), 1.5));
+    assert!(frustum.intersects_sphere(Vec3::new(-6.0, 0.0, -5.0), 1.0));
+    assert!(!frustum.intersects_sphere(Vec3::new(-7.0, 0.0, -5.0), 1.0));
+
+    let unit = Aabb::new(Vec3::splat(-0.5), Vec3::splat(0.5));
+    let at = |x, y, z| unit.transform(&Mat4::from_translation(Vec3::new(x, y, z)));
+    assert!(frustum.intersects_aabb(&at(0.0, 0.0, -10.0)));
+    assert!(frustum.intersects_aabb(&at(10.4, 0.0, -10.0)));
+    assert!(!frustum.intersects_aabb(&at(11.1, 0.0, -10.0)));
+    assert!(!frustum.intersects_aabb(&at(0.0, 0.0, 10.0)));
+    assert!(!frustum.intersects_aabb(&at(0.0, -20.0, -10.0)));
+
+    // Turning the camera to face +x moves what it sees
+    let view = Mat4::from_quat(Quat::from_rotation_y(90f32.to_radians())).inverse();
+    let turned = Frustum::from_camera(projection.to_cols_array(), view.to_cols_array());
+    assert!(turned.contains_point(Vec3::new(-10.0, 0.0, 0.0)));
+    assert!(!turned.contains_point(Vec3::new(0.0, 0.0, -10.0)));
+
+    // A rotated box grows to hold its corners
+    let rotated = unit.transform(&Mat4::from_rotation_y(45f32.to_radians()));
+    assert!((rotated.max.x - 0.5 * 2f32.sqrt()).abs() < 1e-5);
+    assert!((rotated.max.y - 0.5).abs() < 1e-5);
+    assert_eq!(
+      unit.union(&at(2.0, 0.0, 0.0)),
+      Aabb::new(Vec3::splat(-0.5), Vec3::new(2.5, 0.5, 0.5))
+    );
+
+    // The default camera's frustum agrees with clipping its own matrices
+    let camera = Camera::new();
+    let frustum = camera.frustum();
+    let clip = Mat4::from_cols_array(&camera.perspective_matrix())
+      * Mat4::from_cols_array(&camera.view_matrix());
+    let view = Mat4::from_cols_array(&camera.view_matrix());
+    assert!(frustum.contains_point(view.inverse().transform_point3(Vec3::new(0.0, 0.0, -10.0))));
+    assert!(!frustum.contains_point(view.inverse().transform_point3(Vec3::new(0.0, 0.0, 10.0))));
+
+    for x in -10..=10 {
+      for y in -10..=10 {
+        for z in -10..=10 {
+          let point = Vec3::new(x as f32, y as f32, z as f32) * 1.7;
+          let p = clip * Vec4::new(point.x, point.y, point.z, 1.0);
+          let clipped = p.x.abs() <= p.w && p.y.abs() <= p.w && p.z >= 0.0 && p.z <= p.w;
+          if (p.x.abs() - p.w).abs() > 1e-3 && p.z.abs() > 1e-3 && (p.z - p.w).abs() > 1e-3 {
+            assert_eq!(frustum.contains_point(point), clipped, "{:?}", point);
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn lod_selection() {
+    use extra::lod::LodGroup;
+
+    let thresholds = [10.0, 50.0, 200.0];
+    assert_eq!(LodGroup::select_level(5.0, &thresholds, 0.1, None), 0);
+    assert_eq!(LodGroup::select_level(10.0, &thresholds, 0.1, None), 1);
+    assert_eq!(LodGroup::select_level(199.0, &thresholds, 0.1, None), 2);
+    assert_eq!(LodGroup::select_level(250.0, &thresholds, 0.1, None), 3);
+
+    // Within the band the previous level is kept either side of a threshold
+    assert_eq!(LodGroup::select_level(10.5, &thresholds, 0.1, Some(0)), 0);
+    assert_eq!(LodGroup::select_level(11.5, &thresholds, 0.1, Some(0)), 1);
+    assert_eq!(LodGroup::select_level(9.5, &thresholds, 0.1, Some(1)), 1);
+    assert_eq!(LodGroup::select_level(8.5, &thresholds, 0.1, Some(1)), 0);
+    assert_eq!(LodGroup::select_level(210.0, &thresholds, 0.1, Some(2)), 2);
+    assert_eq!(LodGroup::select_level(190.0, &thresholds, 0.1, Some(3)), 3);
+    // Big jumps skip levels
+    assert_eq!(LodGroup::select_level(100.0, &thresholds, 0.1, Some(0)), 2);
+
+    // Draws are matched to last frame's by order
+    let mut group = LodGroup::new(
+      vec![("near".to_string(), 10.0), ("far".to_string(), 50.0)],
+      LodMetric::Distance,
+      0.1,
+    );
+    assert_eq!(group.select(10.5, 1.0, 1.0), Some("far"));
+    assert_eq!(group.select(5.0, 1.0, 1.0), Some("near"));
+    assert_eq!(group.select(60.0, 1.0, 1.0), None);
+    assert_eq!(group.selected_levels(), vec![Some(1), Some(0), None]);
+    group.start_frame();
+    assert_eq!(group.select(9.5, 1.0, 1.0), Some("far"));
+    assert_eq!(group.select(10.5, 1.0, 1.0), Some("near"));
+    assert_eq!(group.select(48.0, 1.0, 1.0), None);
+    assert_eq!(group.model_at(10.5, 1.0, 1.0), Some("far"));
+
+    // A radius 2 sphere under a projection scale of 1.5 is half the screen at distance 6
+    let group = LodGroup::new(
+      vec![("near".to_string(), 0.5), ("far".to_string(), 0.0)],
+      LodMetric::ScreenSize,
+      0.0,
+    );
+    assert_eq!(
+      group.threshold_distances(2.0, 1.5),
+      vec![6.0, f32::INFINITY]
+    );
+    assert_eq!(group.model_at(5.9, 2.0, 1.5), Some("near"));
+    assert_eq!(group.model_at(1000.0, 2.0, 1.5), Some("far"));
+  }
+
+  #[test]
+  fn debug_lines() {
+    use extra::debug_lines::{DebugLines, CIRCLE_SEGMENTS};
+    use glam::{Mat4, Vec3};
+
+    let vertex = reflect(include_bytes!("../shaders/debug_line_vert.spv"));
+    assert_eq!(vertex.push_constant_size(), 64);
+    assert!(vertex.bindings().is_empty());
+    assert_eq!(
+      vertex.vertex_attributes(0, 0, &[0, 12], 28).unwrap()[1].format,
+      vk::Format::R32G32B32A32_SFLOAT
+    );
+
+    let red = [1.0, 0.0, 0.0, 1.0];
+    let mut lines = DebugLines::new();
+    lines.line(Vec3::ZERO, Vec3::X, red, true);
+    lines.arrow(Vec3::ZERO, Vec3::new(0.0, 0.0, 5.0), red, false);
+    assert_eq!(lines.vertices(true).len(), 2);
+    assert_eq!(lines.vertices(false).len(), 10);
+    // The arrow head points back from the tip
+    for head in lines.vertices(false)[2..].chunks(2) {
+      assert_eq!(head[0].pos, [0.0, 0.0, 5.0]);
+      assert!((head[1].pos[2] - 4.0).abs() < 1e-5);
+      assert!(((Vec3::from(head[1].pos) - Vec3::new(0.0, 0.0, 4.0)).length() - 0.5).abs() < 1e-5);
+    }
+
+    lines.clear();
+    assert!(lines.is_empty());
+
+    // A box has 12 edges each along a single axis
+    let bounds = Aabb::new(Vec3::splat(-1.0), Vec3::new(1.0, 2.0, 3.0));
+    lines.aabb(&bounds, &Mat4::IDENTITY, red, true);
+    let box_vertices = lines.vertices(true);
+    assert_eq!(box_vertices.len(), 24);
+    for edge in box_vertices.chunks(2) {
+      let delta = Vec3::from(edge[1].pos) - Vec3::from(edge[0].pos);
+      assert_eq!(delta.cmpne(Vec3::ZERO).bitmask().count_ones(), 1);
+    }
+    lines.clear();
+
+    let centre = Vec3::new(1.0, 2.0, 3.0);
+    lines.sphere(centre, 2.0, red, true);
+    assert_eq!(lines.vertices(true).len(), CIRCLE_SEGMENTS * 3 * 2);
+    assert!(lines
+      .vertices(true)
+      .iter()
+      .all(|v| ((Vec3::from(v.pos) - centre).length() - 2.0).abs() < 1e-4));
+    lines.clear();
+
+    lines.grid(Vec3::ZERO, 10.0, 4, red, true);
+    assert_eq!(lines.vertices(true).len(), 5 * 2 * 2);
+    assert!(lines
+      .vertices(true)
+      .iter()
+      .all(|v| v.pos[1] == 0.0 && v.pos[0].abs() <= 5.0 && v.pos[2].abs() <= 5.0));
+    lines.clear();
+
+    // Looking down -z with a 90 degree view, the corners are as far out as they are deep
+    let projection = Mat4::perspective_rh(90f32.to_radians(), 1.0, 1.0, 100.0);
+    let corners = DebugLines::frustum_corners(
+      projection.to_cols_array(),
+      Mat4::IDENTITY.to_cols_array(),
+      10.0,
+    );
+    assert!((corners[0] - Vec3::new(-1.0, -1.0, -1.0)).length() < 1e-4);
+    assert!((corners[7] - Vec3::new(10.0, 10.0, -10.0)).length() < 1e-3);
+
+    // The default camera's projection has no far plane, the corners still stop at `far`
+    let camera = Camera::new();
+    let corners =
+      DebugLines::frustum_corners(camera.perspective_matrix(), camera.view_matrix(), 20.0);
+    let frustum = camera.frustum();
+    let inside = corners.iter().fold(Vec3::ZERO, |sum, corner| sum + *corner) / 8.0;
+    assert!(frustum.contains_point(inside));
+    assert!(corners.iter().all(|corner| corner.is_finite()));
+  }
+
+  #[test]
+  fn shapes() {
+    use extra::shapes::{signed_area, triangulate, Shape, FRINGE};
+    use glam::{Vec2, Vec4};
+
+    let vertex = reflect(include_bytes!("../shaders/shape_vert.spv"));
+    assert_eq!(vertex.push_constant_size(), 16);
+    assert!(vertex.bindings().is_empty());
+    assert_eq!(
+      vertex.vertex_attributes(0, 0, &[0, 8], 24).unwrap()[1].format,
+      vk::Format::R32G32B32A32_SFLOAT
+    );
+
+    let white = Vec4::ONE;
+    let area = |vertices: &[extra::shapes::ShapeVertex]| {
+      vertices
+        .chunks(3)
+        .map(|t| signed_area(&[t[0].pos.into(), t[1].pos.into(), t[2].pos.into()]).abs() * 0.5)
+        .sum::<f32>()
+    };
+
+    // A rect is 2 solid triangles and a fringe quad along each edge
+    let rect = Shape::Rect {
+      position: Vec2::new(10.0, 20.0),
+      size: Vec2::new(100.0, 50.0),
+      radius: 0.0,
+    };
+    let vertices = rect.vertices(white, None, 0.0);
+    assert_eq!(vertices.len(), 2 * 3 + 4 * 6);
+    // The fringe is centred on the edge and fades to nothing outside it
+    assert!((area(&vertices[..6]) - (100.0 - FRINGE) * (50.0 - FRINGE)).abs() < 1e-2);
+    assert!((area(&vertices) - (100.0 + FRINGE) * (50.0 + FRINGE)).abs() < 1e-1);
+    for v in &vertices[6..] {
+      let p = Vec2::from(v.pos);
+      let outside = p.x < 10.0 || p.x > 110.0 || p.y < 20.0 || p.y > 70.0;
+      assert_eq!(v.colour[3] == 0.0, outside);
+    }
+
+    // Transparent shapes draw nothing unless outlined
+    assert!(rect.vertices(Vec4::ZERO, None, 0.0).is_empty());
+    let outline = rect.vertices(Vec4::ZERO, Some((4.0, white)), 0.0);
+    assert_eq!(outline.len(), 3 * 4 * 6);
+    assert!(outline.iter().any(|v| v.colour[3] == 1.0));
+
+    // Rounded corners stay inside the rect
+    let rounded = Shape::Rect {
+      position: Vec2::ZERO,
+      size: Vec2::new(40.0, 20.0),
+      radius: 50.0,
+    };
+    let path = rounded.path();
+    assert!(path.len() > 8);
+    assert!(path
+      .iter()
+      .all(|p| p.x > -1e-4 && p.x < 40.0 + 1e-4 && p.y > -1e-4 && p.y < 20.0 + 1e-4));
+
+    let circle = Shape::Circle {
+      centre: Vec2::new(50.0, 50.0),
+      radius: 20.0,
+    };
+    assert!(circle
+      .path()
+      .iter()
+      .all(|p| (p.distance(Vec2::new(50.0, 50.0)) - 20.0).abs() < 1e-4));
+    let circle_area = area(&circle.vertices(white, None, 0.0));
+    let radius = 20.0 + FRINGE * 0.5;
+    assert!((circle_area - std::f32::consts::PI * radius * radius).abs() < 20.0);
+
+    let line = Shape::Line {
+      from: Vec2::ZERO,
+      to: Vec2::new(10.0, 0.0),
+      thickness: 4.0,
+    };
+    assert_eq!(
+      line.path(),
+      vec![
+        Vec2::new(0.0, 2.0),
+        Vec2::new(10.0, 2.0),
+        Vec2::new(10.0, -2.0),
+        Vec2::new(0.0, -2.0)
+      ]
+    );
+
+    // An L shape is concave, its triangles cover exactly its area in either winding
+    let mut l_shape = vec![
+      Vec2::new(0.0, 0.0),
+      Vec2::new(20.0, 0.0),
+      Vec2::new(20.0, 10.0),
+      Vec2::new(10.0, 10.0),
+      Vec2::new(10.0, 30.0),
+      Vec2::new(0.0, 30.0),
+    ];
+    for _ in 0..2 {
+      let triangles = triangulate(&l_shape);
+      assert_eq!(triangles.len(), 4);
+      let covered: f32 = triangles
+        .iter()
+        .map(|t| signed_area(&[l_shape[t[0]], l_shape[t[1]], l_shape[t[2]]]).abs() * 0.5)
+        .sum();
+      assert!((covered - 400.0).abs() < 1e-3);
+      l_shape.reverse();
+    }
+
+    // Draws only give shape vertices when they are shapes
+    assert!(Draw::new().shape_vertices().is_empty());
+    let rotated = Draw::rect(Vec2::ZERO, Vec2::new(10.0, 10.0))
+      .colour(white)
+      .rotation(90.0)
+      .shape_vertices();
+    assert_eq!(rotated.len(), 30);
+    assert!(rotated
+      .iter()
+      .all(|v| v.pos.iter().all(|x| *x > -1.0 && *x < 11.0)));
+  }
 }
@@ -1,6 +1,6 @@
 use crate::{
-  extra::Math,
-  glam::{Vec3, Vec4},
+  extra::{frustum::Frustum, Math},
+  glam::{Mat4, Vec3, Vec4},
 };
 
 const TP_X_ROT_MIN: f32 = 89.0;
@@ -219,6 +219,19 @@ impl Camera {
     self.view
   }
 
+  /// Where the camera is in world space, taken from the view matrix.
+  pub fn eye_position(&self) -> Vec3 {
+    Mat4::from_cols_array(&self.view)
+      .inverse()
+      .w_axis
+      .truncate()
+  }
+
+  /// What the camera sees in world space, for culling models before they are drawn.
+  pub fn frustum(&self) -> Frustum {
+    Frustum::from_camera(self.perspective, self.view)
+  }
+
   pub fn is_updated(&self) -> bool {
     self.updated
   }
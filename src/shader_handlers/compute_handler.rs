@@ -1,12 +1,12 @@
 use std::io::Cursor;
 
-use ash::vk;
 use glam::Mat4;
 
 use crate::{
   shader_handlers::Camera,
   vkwrapper::{
-    Buffer, ComputeShader, DescriptorPoolBuilder, DescriptorSet, DescriptorWriter, Vulkan,
+    Buffer, ComputeShader, DescriptorAllocator, DescriptorPoolBuilder, DescriptorSet,
+    DescriptorWriter, Vulkan,
   },
 };
 
@@ -50,7 +50,7 @@ pub struct ComputeHandler {
   camera_descriptor_set: DescriptorSet,
   intermediate_descriptor_set: DescriptorSet,
 
-  descriptor_pool: vk::DescriptorPool,
+  descriptor_pool: DescriptorAllocator,
 }
 
 impl ComputeHandler {
@@ -171,4 +171,18 @@ impl ComputeHandler {
 
     //println!("Compute Data: {:?}", compute_data);
   }
+
+  pub fn destroy(&mut self, vulkan: &mut Vulkan) {
+    self.compute_shader.destroy(vulkan.device());
+
+    self.light_visibility_buffer.destroy(vulkan.device());
+    self.point_light_buffer.destroy(vulkan.device());
+    self.camera_buffer.destroy(vulkan.device());
+
+    self.light_culling_descriptor_set.destroy(vulkan.device());
+    self.camera_descriptor_set.destroy(vulkan.device());
+    self.intermediate_descriptor_set.destroy(vulkan.device());
+
+    self.descriptor_pool.destroy(vulkan.device());
+  }
 }
@@ -0,0 +1,826 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+
+use ash::util::read_spv;
+use ash::vk;
+
+use crate::vkwrapper::{
+  Buffer, CommandBuffer, ComputeShader, DescriptorAllocator, DescriptorPoolBuilder, DescriptorSet,
+  Image, ImageBuilder, ReflectedBinding, ShaderReflection, VkCommandPool, VkDevice, Vulkan,
+};
+
+/// Handle to a dispatch submitted with `dispatch`, poll it with `is_finished`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ComputeFence(u64);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ComputeImageFormat {
+  Rgba8,
+  Rgba32Float,
+  R32Float,
+}
+
+impl ComputeImageFormat {
+  fn to_vk(self) -> vk::Format {
+    match self {
+      ComputeImageFormat::Rgba8 => vk::Format::R8G8B8A8_UNORM,
+      ComputeImageFormat::Rgba32Float => vk::Format::R32G32B32A32_SFLOAT,
+      ComputeImageFormat::R32Float => vk::Format::R32_SFLOAT,
+    }
+  }
+
+  pub fn bytes_per_pixel(self) -> u32 {
+    match self {
+      ComputeImageFormat::Rgba8 => 4,
+      ComputeImageFormat::Rgba32Float => 16,
+      ComputeImageFormat::R32Float => 4,
+    }
+  }
+}
+
+enum ComputeResource {
+  Buffer(Buffer<u8>, vk::DescriptorType),
+  Image(Image, ComputeImageFormat),
+}
+
+impl ComputeResource {
+  fn descriptor_type(&self) -> vk::DescriptorType {
+    match self {
+      ComputeResource::Buffer(_, descriptor_type) => *descriptor_type,
+      ComputeResource::Image(_, _) => vk::DescriptorType::STORAGE_IMAGE,
+    }
+  }
+
+  fn destroy(&self, device: &VkDevice) {
+    match self {
+      ComputeResource::Buffer(buffer, _) => buffer.destroy(device),
+      ComputeResource::Image(image, _) => image.destroy(device),
+    }
+  }
+}
+
+struct ComputeProgram {
+  shader: ComputeShader,
+  bindings: Vec<ReflectedBinding>,
+  push_constant_size: u32,
+  descriptor_sets: Vec<DescriptorSet>, // indexed by set number
+  bound: HashMap<(u32, u32), String>,  // (set, binding) to resource name
+}
+
+impl ComputeProgram {
+  fn destroy(&self, device: &VkDevice) {
+    self.shader.destroy(device);
+    for descriptor_set in &self.descriptor_sets {
+      descriptor_set.free(device);
+      descriptor_set.destroy(device);
+    }
+  }
+}
+
+struct Dispatch {
+  fence: ComputeFence,
+  command_buffer: CommandBuffer,
+}
+
+/// General purpose compute. Shaders are loaded from SPIR-V and their bindings read from it,
+/// buffers and storage images are created by name and bound to a shader's set and binding.
+/// All work goes to the compute queue, so it runs alongside rendering on devices with a
+/// dedicated compute family.
+pub struct ComputeTaskHandler {
+  command_pool: VkCommandPool,
+  descriptor_pool: DescriptorAllocator,
+
+  programs: HashMap<String, ComputeProgram>,
+  resources: HashMap<String, ComputeResource>,
+
+  in_flight: Vec<Dispatch>,
+  idle_command_buffers: Vec<CommandBuffer>,
+  next_fence: u64,
+}
+
+impl ComputeTaskHandler {
+  pub fn new(vulkan: &mut Vulkan) -> ComputeTaskHandler {
+    let device = vulkan.device();
+
+    let command_pool = VkCommandPool::new_for_queue_family(device, device.queue_families().compute);
+    let descriptor_pool = DescriptorPoolBuilder::new()
+      .num_storage(16)
+      .num_uniform_buffers(8)
+      .num_storage_images(8)
+      .free_individual_sets()
+      .build(device);
+
+    ComputeTaskHandler {
+      command_pool,
+      descriptor_pool,
+
+      programs: HashMap::new(),
+      resources: HashMap::new(),
+
+      in_flight: Vec::new(),
+      idle_command_buffers: Vec::new(),
+      next_fence: 0,
+    }
+  }
+
+  /// Loading over an existing shader keeps whatever was bound to it that still fits.
+  pub fn load_shader<W: Read + Seek>(
+    &mut self,
+    vulkan: &mut Vulkan,
+    shader_ref: &str,
+    mut spirv: W,
+  ) -> Result<(), String> {
+    let device = vulkan.device();
+
+    let code = read_spv(&mut spirv).map_err(|e| format!("Failed to read compute shader: {}", e))?;
+    let reflection = ShaderReflection::new(&code)
+      .map_err(|e| format!("Failed to reflect compute shader: {}", e))?;
+    if reflection.stage() != vk::ShaderStageFlags::COMPUTE {
+      return Err(format!("{} is not a compute shader", shader_ref));
+    }
+
+    let descriptor_sets = ShaderReflection::descriptor_set_builders(&[&reflection])?
+      .iter()
+      .map(|builder| builder.build(device, &self.descriptor_pool))
+      .collect::<Vec<_>>();
+    let layouts = descriptor_sets
+      .iter()
+      .map(|descriptor_set| descriptor_set.layouts()[0])
+      .collect::<Vec<_>>();
+
+    let shader = match ComputeShader::try_new_with_layouts(
+      device,
+      &code,
+      &layouts,
+      ShaderReflection::push_constant_range(&[&reflection]),
+    ) {
+      Ok(shader) => shader,
+      Err(e) => {
+        for descriptor_set in &descriptor_sets {
+          descriptor_set.free(device);
+          descriptor_set.destroy(device);
+        }
+        return Err(e);
+      }
+    };
+
+    let program = ComputeProgram {
+      shader,
+      bindings: reflection.bindings().clone(),
+      push_constant_size: reflection.push_constant_size(),
+      descriptor_sets,
+      bound: HashMap::new(),
+    };
+
+    if let Some(old_program) = self.programs.insert(shader_ref.to_string(), program) {
+      self.wait_all(device);
+      old_program.destroy(device);
+
+      for ((set, binding), resource_ref) in old_program.bound {
+        if let Err(e) = self.bind(vulkan, shader_ref, set, binding, &resource_ref) {
+          println!("Dropped binding of reloaded compute shader: {}", e);
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  pub fn remove_shader(&mut self, vulkan: &mut Vulkan, shader_ref: &str) {
+    if let Some(program) = self.programs.remove(shader_ref) {
+      self.wait_all(vulkan.device());
+      program.destroy(vulkan.device());
+    }
+  }
+
+  /// Creates a host visible storage buffer, or overwrites it when one of the same size exists.
+  pub fn create_storage_buffer<T: Copy>(
+    &mut self,
+    vulkan: &mut Vulkan,
+    resource_ref: &str,
+    data: &[T],
+  ) -> Result<(), String> {
+    self.create_buffer(
+      vulkan,
+      resource_ref,
+      ComputeTaskHandler::as_bytes(data),
+      vk::DescriptorType::STORAGE_BUFFER,
+    )
+  }
+
+  pub fn create_uniform_buffer<T: Copy>(
+    &mut self,
+    vulkan: &mut Vulkan,
+    resource_ref: &str,
+    data: &[T],
+  ) -> Result<(), String> {
+    self.create_buffer(
+      vulkan,
+      resource_ref,
+      ComputeTaskHandler::as_bytes(data),
+      vk::DescriptorType::UNIFORM_BUFFER,
+    )
+  }
+
+  fn create_buffer(
+    &mut self,
+    vulkan: &mut Vulkan,
+    resource_ref: &str,
+    bytes: Vec<u8>,
+    descriptor_type: vk::DescriptorType,
+  ) -> Result<(), String> {
+    if bytes.is_empty() {
+      return Err(format!("Compute buffer {} has no data", resource_ref));
+    }
+
+    let device = vulkan.device();
+
+    // Buffers the shaders may still be reading are only written once they are done
+    self.wait_all(device);
+
+    if let Some(ComputeResource::Buffer(buffer, existing_type)) =
+      self.resources.get_mut(resource_ref)
+    {
+      if *existing_type == descriptor_type && buffer.data().len() == bytes.len() {
+        buffer.update_data(device, bytes);
+        return Ok(());
+      }
+    }
+
+    let usage = if descriptor_type == vk::DescriptorType::UNIFORM_BUFFER {
+      vk::BufferUsageFlags::UNIFORM_BUFFER
+    } else {
+      vk::BufferUsageFlags::STORAGE_BUFFER
+        | vk::BufferUsageFlags::TRANSFER_SRC
+        | vk::BufferUsageFlags::TRANSFER_DST
+    };
+
+    let buffer = Buffer::new_with_sharing(
+      device,
+      bytes,
+      vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+      usage,
+      true,
+    );
+
+    self.replace_resource(
+      vulkan,
+      resource_ref,
+      ComputeResource::Buffer(buffer, descriptor_type),
+    );
+
+    Ok(())
+  }
+
+  /// Storage image in the general layout, usable as an `image2D` in the shaders.
+  pub fn create_storage_image(
+    &mut self,
+    vulkan: &mut Vulkan,
+    resource_ref: &str,
+    width: u32,
+    height: u32,
+    format: ComputeImageFormat,
+  ) -> Result<(), String> {
+    if width == 0 || height == 0 {
+      return Err(format!(
+        "Compute image {} is {}x{}",
+        resource_ref, width, height
+      ));
+    }
+
+    let device = vulkan.device();
+
+    let image = ImageBuilder::new(format.to_vk(), 1, 1)
+      .set_dimensions(width, height)
+      .usage(
+        vk::ImageUsageFlags::STORAGE
+          | vk::ImageUsageFlags::TRANSFER_SRC
+          | vk::ImageUsageFlags::TRANSFER_DST
+          | vk::ImageUsageFlags::SAMPLED,
+      )
+      .build_device_local(device);
+
+    self.submit_and_wait(device, |device, command_buffer| {
+      let image_barrier = vk::ImageMemoryBarrier::builder()
+        .old_layout(vk::ImageLayout::UNDEFINED)
+        .new_layout(vk::ImageLayout::GENERAL)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)
+        .image(image.internal())
+        .subresource_range(vk::ImageSubresourceRange {
+          aspect_mask: vk::ImageAspectFlags::COLOR,
+          base_mip_level: 0,
+          level_count: 1,
+          base_array_layer: 0,
+          layer_count: 1,
+        });
+
+      unsafe {
+        device.internal().cmd_pipeline_barrier(
+          command_buffer.internal(),
+          vk::PipelineStageFlags::TOP_OF_PIPE,
+          vk::PipelineStageFlags::COMPUTE_SHADER,
+          vk::DependencyFlags::empty(),
+          &[],
+          &[],
+          &[image_barrier.build()],
+        );
+      }
+    });
+
+    self.replace_resource(vulkan, resource_ref, ComputeResource::Image(image, format));
+
+    Ok(())
+  }
+
+  /// Swaps in a new resource, pointing every shader that had the old one bound at it.
+  fn replace_resource(
+    &mut self,
+    vulkan: &mut Vulkan,
+    resource_ref: &str,
+    resource: ComputeResource,
+  ) {
+    let device = vulkan.device();
+
+    self.wait_all(device);
+    if let Some(old_resource) = self.resources.insert(resource_ref.to_string(), resource) {
+      old_resource.destroy(device);
+    }
+
+    let resource = &self.resources[resource_ref];
+    for program in self.programs.values_mut() {
+      for ((set, binding), bound_ref) in program.bound.iter() {
+        if bound_ref == resource_ref {
+          let descriptor_set = &program.descriptor_sets[*set as usize];
+          ComputeTaskHandler::write_descriptor(device, descriptor_set, *binding, resource);
+        }
+      }
+    }
+  }
+
+  pub fn remove_resource(&mut self, vulkan: &mut Vulkan, resource_ref: &str) {
+    if let Some(resource) = self.resources.remove(resource_ref) {
+      self.wait_all(vulkan.device());
+      resource.destroy(vulkan.device());
+
+      for program in self.programs.values_mut() {
+        program
+          .bound
+          .retain(|_, bound_ref| bound_ref != resource_ref);
+      }
+    }
+  }
+
+  /// Binds a buffer or image to the shader's `layout(set, binding)`, the type has to match the
+  /// one declared in the shader.
+  pub fn bind(
+    &mut self,
+    vulkan: &mut Vulkan,
+    shader_ref: &str,
+    set: u32,
+    binding: u32,
+    resource_ref: &str,
+  ) -> Result<(), String> {
+    let device = vulkan.device();
+
+    let program = self
+      .programs
+      .get_mut(shader_ref)
+      .ok_or(format!("No compute shader named {}", shader_ref))?;
+    let resource = self
+      .resources
+      .get(resource_ref)
+      .ok_or(format!("No compute resource named {}", resource_ref))?;
+
+    ComputeTaskHandler::check_binding(&program.bindings, set, binding, resource.descriptor_type())?;
+
+    // A set can't be written while a submitted dispatch uses it
+    for dispatch in self.in_flight.drain(..) {
+      dispatch.command_buffer.reuse_fence().wait(device);
+      self.idle_command_buffers.push(dispatch.command_buffer);
+    }
+
+    ComputeTaskHandler::write_descriptor(
+      device,
+      &program.descriptor_sets[set as usize],
+      binding,
+      resource,
+    );
+    program
+      .bound
+      .insert((set, binding), resource_ref.to_string());
+
+    Ok(())
+  }
+
+  pub fn check_binding(
+    bindings: &[ReflectedBinding],
+    set: u32,
+    binding: u32,
+    descriptor_type: vk::DescriptorType,
+  ) -> Result<(), String> {
+    match bindings
+      .iter()
+      .find(|b| b.set == set && b.binding == binding)
+    {
+      Some(reflected) if reflected.descriptor_type != descriptor_type => Err(format!(
+        "set {} binding {} is a {:?}, not a {:?}",
+        set, binding, reflected.descriptor_type, descriptor_type
+      )),
+      Some(_) => Ok(()),
+      None => Err(format!(
+        "The shader has no binding {} in set {}",
+        binding, set
+      )),
+    }
+  }
+
+  fn write_descriptor(
+    device: &VkDevice,
+    descriptor_set: &DescriptorSet,
+    binding: u32,
+    resource: &ComputeResource,
+  ) {
+    let write = vk::WriteDescriptorSet::builder()
+      .dst_set(descriptor_set.internal()[0])
+      .dst_binding(binding)
+      .descriptor_type(resource.descriptor_type());
+
+    match resource {
+      ComputeResource::Buffer(buffer, _) => {
+        let buffer_info = [vk::DescriptorBufferInfo {
+          buffer: *buffer.internal(),
+          offset: 0,
+          range: vk::WHOLE_SIZE,
+        }];
+        unsafe {
+          device
+            .internal()
+            .update_descriptor_sets(&[write.buffer_info(&buffer_info).build()], &[]);
+        }
+      }
+      ComputeResource::Image(image, _) => {
+        let image_info = [vk::DescriptorImageInfo {
+          sampler: vk::Sampler::null(),
+          image_view: image.view(),
+          image_layout: vk::ImageLayout::GENERAL,
+        }];
+        unsafe {
+          device
+            .internal()
+            .update_descriptor_sets(&[write.image_info(&image_info).build()], &[]);
+        }
+      }
+    }
+  }
+
+  /// Submits the shader to the compute queue and returns straight away. Push constants shorter
+  /// than the shader's block are padded with zeros.
+  pub fn dispatch<P: Copy>(
+    &mut self,
+    vulkan: &mut Vulkan,
+    shader_ref: &str,
+    group_counts: [u32; 3],
+    push_constants: &[P],
+  ) -> Result<ComputeFence, String> {
+    let device = vulkan.device();
+
+    let program = self
+      .programs
+      .get(shader_ref)
+      .ok_or(format!("No compute shader named {}", shader_ref))?;
+
+    if let Some(unbound) = program
+      .bindings
+      .iter()
+      .find(|b| !program.bound.contains_key(&(b.set, b.binding)))
+    {
+      return Err(format!(
+        "Nothing is bound to set {} binding {} of {}",
+        unbound.set, unbound.binding, shader_ref
+      ));
+    }
+
+    let push_constant_data = ComputeTaskHandler::push_constant_bytes(
+      &ComputeTaskHandler::as_bytes(push_constants),
+      program.push_constant_size,
+    )?;
+
+    let mut command_buffer = self
+      .idle_command_buffers
+      .pop()
+      .unwrap_or_else(|| CommandBuffer::new_one_time_submit(device, &self.command_pool));
+
+    command_buffer.reset(device);
+    command_buffer.begin(device);
+
+    let descriptor_sets = program
+      .descriptor_sets
+      .iter()
+      .map(|descriptor_set| descriptor_set.internal()[0])
+      .collect::<Vec<_>>();
+
+    unsafe {
+      // Host writes and earlier dispatches are visible to this one
+      let memory_barrier_before = vk::MemoryBarrier::builder()
+        .src_access_mask(vk::AccessFlags::HOST_WRITE | vk::AccessFlags::SHADER_WRITE)
+        .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+
+      device.internal().cmd_pipeline_barrier(
+        command_buffer.internal(),
+        vk::PipelineStageFlags::HOST | vk::PipelineStageFlags::COMPUTE_SHADER,
+        vk::PipelineStageFlags::COMPUTE_SHADER,
+        vk::DependencyFlags::empty(),
+        &[memory_barrier_before.build()],
+        &[],
+        &[],
+      );
+
+      device.internal().cmd_bind_pipeline(
+        command_buffer.internal(),
+        vk::PipelineBindPoint::COMPUTE,
+        *program.shader.pipeline().internal(),
+      );
+
+      if !descriptor_sets.is_empty() {
+        device.internal().cmd_bind_descriptor_sets(
+          command_buffer.internal(),
+          vk::PipelineBindPoint::COMPUTE,
+          program.shader.pipeline_layout(),
+          0,
+          &descriptor_sets,
+          &[],
+        );
+      }
+
+      if !push_constant_data.is_empty() {
+        device.internal().cmd_push_constants(
+          command_buffer.internal(),
+          program.shader.pipeline_layout(),
+          vk::ShaderStageFlags::COMPUTE,
+          0,
+          &push_constant_data,
+        );
+      }
+
+      device.internal().cmd_dispatch(
+        command_buffer.internal(),
+        group_counts[0],
+        group_counts[1],
+        group_counts[2],
+      );
+
+      let memory_barrier_after = vk::MemoryBarrier::builder()
+        .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+        .dst_access_mask(
+          vk::AccessFlags::HOST_READ
+            | vk::AccessFlags::TRANSFER_READ
+            | vk::AccessFlags::SHADER_READ,
+        );
+
+      device.internal().cmd_pipeline_barrier(
+        command_buffer.internal(),
+        vk::PipelineStageFlags::COMPUTE_SHADER,
+        vk::PipelineStageFlags::HOST
+          | vk::PipelineStageFlags::TRANSFER
+          | vk::PipelineStageFlags::COMPUTE_SHADER,
+        vk::DependencyFlags::empty(),
+        &[memory_barrier_after.build()],
+        &[],
+        &[],
+      );
+    }
+
+    command_buffer.end(device);
+    command_buffer.submit_to_queue(
+      device,
+      device.compute_queue(),
+      Vec::new(),
+      Vec::new(),
+      Vec::new(),
+    );
+
+    let fence = ComputeFence(self.next_fence);
+    self.next_fence += 1;
+    self.in_flight.push(Dispatch {
+      fence,
+      command_buffer,
+    });
+
+    Ok(fence)
+  }
+
+  /// Dispatches and blocks until the shader has finished.
+  pub fn run<P: Copy>(
+    &mut self,
+    vulkan: &mut Vulkan,
+    shader_ref: &str,
+    group_counts: [u32; 3],
+    push_constants: &[P],
+  ) -> Result<(), String> {
+    let fence = self.dispatch(vulkan, shader_ref, group_counts, push_constants)?;
+    self.wait(vulkan, fence);
+
+    Ok(())
+  }
+
+  pub fn is_finished(&mut self, vulkan: &mut Vulkan, fence: ComputeFence) -> bool {
+    let device = vulkan.device();
+
+    let (finished, in_flight): (Vec<Dispatch>, Vec<Dispatch>) = self
+      .in_flight
+      .drain(..)
+      .partition(|dispatch| dispatch.command_buffer.reuse_fence().is_signaled(device));
+
+    self.in_flight = in_flight;
+    self
+      .idle_command_buffers
+      .extend(finished.into_iter().map(|dispatch| dispatch.command_buffer));
+
+    !self
+      .in_flight
+      .iter()
+      .any(|dispatch| dispatch.fence == fence)
+  }
+
+  pub fn wait(&mut self, vulkan: &mut Vulkan, fence: ComputeFence) {
+    if let Some(dispatch) = self
+      .in_flight
+      .iter()
+      .find(|dispatch| dispatch.fence == fence)
+    {
+      dispatch.command_buffer.reuse_fence().wait(vulkan.device());
+    }
+
+    self.is_finished(vulkan, fence);
+  }
+
+  fn wait_all(&mut self, device: &VkDevice) {
+    for dispatch in self.in_flight.drain(..) {
+      dispatch.command_buffer.reuse_fence().wait(device);
+      self.idle_command_buffers.push(dispatch.command_buffer);
+    }
+  }
+
+  /// Waits for submitted dispatches, then copies the buffer back.
+  pub fn read_buffer<T: Copy>(
+    &mut self,
+    vulkan: &mut Vulkan,
+    resource_ref: &str,
+  ) -> Option<Vec<T>> {
+    let device = vulkan.device();
+    self.wait_all(device);
+
+    match self.resources.get(resource_ref) {
+      Some(ComputeResource::Buffer(buffer, _)) => Some(ComputeTaskHandler::from_bytes(
+        &buffer.retrieve_buffer_data(device),
+      )),
+      _ => None,
+    }
+  }
+
+  /// Waits for submitted dispatches, then copies the image back as tightly packed rows.
+  pub fn read_image(&mut self, vulkan: &mut Vulkan, resource_ref: &str) -> Option<Vec<u8>> {
+    let device = vulkan.device();
+    self.wait_all(device);
+
+    let (image, format) = match self.resources.get(resource_ref) {
+      Some(ComputeResource::Image(image, format)) => (image.clone(), *format),
+      _ => return None,
+    };
+
+    let size = image.width() * image.height() * format.bytes_per_pixel();
+    let staging_buffer = Buffer::<u8>::new_generic(
+      device,
+      vec![0; size as usize],
+      vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+      vk::BufferUsageFlags::TRANSFER_DST,
+    );
+
+    self.submit_and_wait(device, |device, command_buffer| {
+      let region = vk::BufferImageCopy::builder()
+        .image_subresource(vk::ImageSubresourceLayers {
+          aspect_mask: vk::ImageAspectFlags::COLOR,
+          mip_level: 0,
+          base_array_layer: 0,
+          layer_count: 1,
+        })
+        .image_extent(vk::Extent3D {
+          width: image.width(),
+          height: image.height(),
+          depth: 1,
+        });
+
+      let memory_barrier = vk::MemoryBarrier::builder()
+        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+        .dst_access_mask(vk::AccessFlags::HOST_READ);
+
+      unsafe {
+        device.internal().cmd_copy_image_to_buffer(
+          command_buffer.internal(),
+          image.internal(),
+          vk::ImageLayout::GENERAL,
+          *staging_buffer.internal(),
+          &[region.build()],
+        );
+
+        device.internal().cmd_pipeline_barrier(
+          command_buffer.internal(),
+          vk::PipelineStageFlags::TRANSFER,
+          vk::PipelineStageFlags::HOST,
+          vk::DependencyFlags::empty(),
+          &[memory_barrier.build()],
+          &[],
+          &[],
+        );
+      }
+    });
+
+    let data = staging_buffer.retrieve_buffer_data(device);
+    staging_buffer.destroy(device);
+
+    Some(data)
+  }
+
+  fn submit_and_wait<F: FnOnce(&VkDevice, &mut CommandBuffer)>(&mut self, device: &VkDevice, f: F) {
+    let mut command_buffer = self
+      .idle_command_buffers
+      .pop()
+      .unwrap_or_else(|| CommandBuffer::new_one_time_submit(device, &self.command_pool));
+
+    command_buffer.reset(device);
+    command_buffer.begin(device);
+    f(device, &mut command_buffer);
+    command_buffer.end(device);
+    command_buffer.submit_to_queue(
+      device,
+      device.compute_queue(),
+      Vec::new(),
+      Vec::new(),
+      Vec::new(),
+    );
+    command_buffer.reuse_fence().wait(device);
+
+    self.idle_command_buffers.push(command_buffer);
+  }
+
+  /// Pads the data with zeros to the size of the shader's push constant block.
+  pub fn push_constant_bytes(data: &[u8], push_constant_size: u32) -> Result<Vec<u8>, String> {
+    if data.len() > push_constant_size as usize {
+      return Err(format!(
+        "{} bytes of push constants given, the shader takes {}",
+        data.len(),
+        push_constant_size
+      ));
+    }
+
+    let mut bytes = data.to_vec();
+    bytes.resize(push_constant_size as usize, 0);
+
+    Ok(bytes)
+  }
+
+  /// Work groups needed to cover `items` with groups of `local_size`.
+  pub fn group_count(items: u32, local_size: u32) -> u32 {
+    items.div_ceil(local_size.max(1))
+  }
+
+  pub fn as_bytes<T: Copy>(data: &[T]) -> Vec<u8> {
+    unsafe {
+      std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data)).to_vec()
+    }
+  }
+
+  pub fn from_bytes<T: Copy>(bytes: &[u8]) -> Vec<T> {
+    let size = std::mem::size_of::<T>();
+    if size == 0 {
+      return Vec::new();
+    }
+
+    bytes
+      .chunks_exact(size)
+      .map(|chunk| unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const T) })
+      .collect()
+  }
+
+  pub fn destroy(&mut self, vulkan: &mut Vulkan) {
+    let device = vulkan.device();
+    self.wait_all(device);
+
+    for (_, program) in self.programs.drain() {
+      program.destroy(device);
+    }
+
+    for (_, resource) in self.resources.drain() {
+      resource.destroy(device);
+    }
+
+    for command_buffer in self.idle_command_buffers.drain(..) {
+      command_buffer.destroy(device);
+    }
+
+    self.command_pool.destroy(device);
+    self.descriptor_pool.destroy(device);
+  }
+}
@@ -0,0 +1,414 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use ash::util::read_spv;
+use ash::vk;
+
+use crate::shader_handlers::ComputeTaskHandler;
+use crate::vkwrapper::{
+  Buffer, DescriptorAllocator, DescriptorSet, DescriptorSetBuilder, GraphicsPipelineBuilder,
+  PerFrame, ReflectedBinding, ShaderReflection, VkDevice, Vulkan,
+};
+
+/// A sprite or model pipeline built from user SPIR-V. The vertex shader reads the handler's
+/// vertices, either every field in order or the fields picked with `vertex_offsets`. Sets the
+/// handler binds come first, any sets the shaders declare after them belong to the pipeline and
+/// are filled with `bind_pipeline_texture` and `bind_pipeline_data`.
+#[derive(Clone)]
+pub struct CustomPipeline {
+  vertex_shader: Vec<u8>,
+  fragment_shader: Vec<u8>,
+  graphics_pipeline_builder: GraphicsPipelineBuilder,
+  vertex_offsets: Option<Vec<u32>>,
+}
+
+impl CustomPipeline {
+  pub fn new(vertex_shader: &[u8], fragment_shader: &[u8]) -> CustomPipeline {
+    CustomPipeline {
+      vertex_shader: vertex_shader.to_vec(),
+      fragment_shader: fragment_shader.to_vec(),
+      graphics_pipeline_builder: GraphicsPipelineBuilder::new()
+        .topology_triangle_list()
+        .front_face_counter_clockwise()
+        .polygon_mode_fill(),
+      vertex_offsets: None,
+    }
+  }
+
+  /// Blending, culling and depth state, the sample count always follows the MSAA setting.
+  pub fn graphics_pipeline_builder(
+    mut self,
+    graphics_pipeline_builder: GraphicsPipelineBuilder,
+  ) -> CustomPipeline {
+    self.graphics_pipeline_builder = graphics_pipeline_builder;
+    self
+  }
+
+  /// Byte offsets of the vertex fields read by locations 0, 1, 2 and so on. Sprite vertices are
+  /// `pos: [f32; 4]`, `colour: [f32; 4]`, `uv: [f32; 2]` and model vertices `pos: [f32; 3]`,
+  /// `normal: [f32; 3]`, `uv: [f32; 2]`, `colour: [f32; 3]`, `joint_indices: [f32; 4]`,
+  /// `joint_weights: [f32; 4]`, both tightly packed.
+  pub fn vertex_offsets(mut self, offsets: Vec<u32>) -> CustomPipeline {
+    self.vertex_offsets = Some(offsets);
+    self
+  }
+
+  pub fn vertex_shader(&self) -> &[u8] {
+    &self.vertex_shader
+  }
+
+  pub fn fragment_shader(&self) -> &[u8] {
+    &self.fragment_shader
+  }
+
+  pub fn get_graphics_pipeline_builder(&self) -> &GraphicsPipelineBuilder {
+    &self.graphics_pipeline_builder
+  }
+
+  /// The offsets given, or every field of the handler's vertex.
+  pub fn offsets_or(&self, all_fields: Vec<u32>) -> Vec<u32> {
+    self.vertex_offsets.clone().unwrap_or(all_fields)
+  }
+
+  /// The bindings of the sets from `first_set` on, the pipeline's own sets.
+  pub fn extra_bindings(&self, first_set: u32) -> Result<Vec<ReflectedBinding>, String> {
+    let reflect = |spirv: &[u8], stage: &str| {
+      read_spv(&mut Cursor::new(spirv))
+        .map_err(|e| format!("Failed to read {} shader: {}", stage, e))
+        .and_then(|code| {
+          ShaderReflection::new(&code)
+            .map_err(|e| format!("Failed to reflect {} shader: {}", stage, e))
+        })
+    };
+
+    let vertex_reflection = reflect(&self.vertex_shader, "vertex")?;
+    let fragment_reflection = reflect(&self.fragment_shader, "fragment")?;
+
+    let bindings = ShaderReflection::merged_bindings(&[&vertex_reflection, &fragment_reflection])?
+      .into_iter()
+      .filter(|binding| binding.set >= first_set)
+      .collect::<Vec<_>>();
+    CustomPipeline::check_bindable(&bindings)?;
+
+    Ok(bindings)
+  }
+
+  /// Only textures, `bind_texture`, and uniform or storage buffers, `bind_data`, can be bound to
+  /// a custom pipeline's own sets.
+  pub fn check_bindable(bindings: &[ReflectedBinding]) -> Result<(), String> {
+    for binding in bindings {
+      match binding.descriptor_type {
+        vk::DescriptorType::COMBINED_IMAGE_SAMPLER
+        | vk::DescriptorType::UNIFORM_BUFFER
+        | vk::DescriptorType::STORAGE_BUFFER => {}
+        descriptor_type => {
+          return Err(format!(
+            "set {} binding {} is a {:?}, custom pipelines can only bind textures and uniform or storage buffers",
+            binding.set, binding.binding, descriptor_type
+          ));
+        }
+      }
+    }
+
+    Ok(())
+  }
+}
+
+enum PipelineResource {
+  Texture(String),
+  Data(Vec<u8>),
+}
+
+/// The descriptor sets a custom pipeline declares after the handler's. Each frame in flight has
+/// its own sets and buffers, brought up to date when the frame next draws with the pipeline.
+pub struct PipelineResources {
+  first_set: u32,
+  bindings: Vec<ReflectedBinding>,
+  descriptor_sets: PerFrame<Vec<DescriptorSet>>,
+  bound: HashMap<(u32, u32), (PipelineResource, u64)>, // resource and the version it was bound at
+  // The version, or image view for textures, each frame's sets were last written with
+  written: PerFrame<HashMap<(u32, u32), u64>>,
+  buffers: PerFrame<HashMap<(u32, u32), Buffer<u8>>>,
+  next_version: u64,
+  fallback_logged: Cell<bool>,
+}
+
+impl PipelineResources {
+  pub fn new(
+    vulkan: &Vulkan,
+    descriptor_pool: &DescriptorAllocator,
+    first_set: u32,
+    bindings: Vec<ReflectedBinding>,
+  ) -> PipelineResources {
+    let set_count = bindings
+      .iter()
+      .map(|binding| binding.set + 1)
+      .max()
+      .unwrap_or(first_set)
+      .max(first_set);
+
+    let descriptor_sets = PerFrame::new(vulkan.frames_in_flight(), |_| {
+      (first_set..set_count)
+        .map(|set| {
+          DescriptorSetBuilder::from_reflection(
+            &bindings
+              .iter()
+              .filter(|binding| binding.set == set)
+              .copied()
+              .collect::<Vec<_>>(),
+          )
+          .build(vulkan.device(), descriptor_pool)
+        })
+        .collect::<Vec<_>>()
+    });
+
+    PipelineResources {
+      first_set,
+      bindings,
+      descriptor_sets,
+      bound: HashMap::new(),
+      written: PerFrame::new(vulkan.frames_in_flight(), |_| HashMap::new()),
+      buffers: PerFrame::new(vulkan.frames_in_flight(), |_| HashMap::new()),
+      next_version: 1,
+      fallback_logged: Cell::new(false),
+    }
+  }
+
+  pub fn first_set(&self) -> u32 {
+    self.first_set
+  }
+
+  /// Layouts of the pipeline's own sets, in set order from `first_set`.
+  pub fn layouts(&self) -> Vec<&DescriptorSet> {
+    self.descriptor_sets.get(0).iter().collect()
+  }
+
+  pub fn descriptor_sets(&self, frame: usize) -> Vec<&DescriptorSet> {
+    self.descriptor_sets.get(frame).iter().collect()
+  }
+
+  pub fn bind_texture(&mut self, set: u32, binding: u32, texture_ref: &str) -> Result<(), String> {
+    ComputeTaskHandler::check_binding(
+      &self.bindings,
+      set,
+      binding,
+      vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+    )?;
+
+    self.bind(
+      set,
+      binding,
+      PipelineResource::Texture(texture_ref.to_string()),
+    );
+    Ok(())
+  }
+
+  /// Data for a uniform or storage buffer, copied into each frame's buffer as it draws.
+  pub fn bind_data(&mut self, set: u32, binding: u32, data: Vec<u8>) -> Result<(), String> {
+    match self
+      .bindings
+      .iter()
+      .find(|b| b.set == set && b.binding == binding)
+    {
+      Some(b)
+        if b.descriptor_type == vk::DescriptorType::UNIFORM_BUFFER
+          || b.descriptor_type == vk::DescriptorType::STORAGE_BUFFER => {}
+      Some(b) => {
+        return Err(format!(
+          "set {} binding {} is a {:?}, not a buffer",
+          set, binding, b.descriptor_type
+        ))
+      }
+      None => {
+        return Err(format!(
+          "The pipeline has no binding {} in set {}",
+          binding, set
+        ))
+      }
+    }
+    if data.is_empty() {
+      return Err(format!("No data given for set {} binding {}", set, binding));
+    }
+
+    self.bind(set, binding, PipelineResource::Data(data));
+    Ok(())
+  }
+
+  fn bind(&mut self, set: u32, binding: u32, resource: PipelineResource) {
+    self
+      .bound
+      .insert((set, binding), (resource, self.next_version));
+    self.next_version += 1;
+  }
+
+  /// Every buffer binding has data, textures that were never bound show the dummy texture.
+  pub fn is_ready(&self) -> bool {
+    self.bindings.iter().all(|b| {
+      b.descriptor_type == vk::DescriptorType::COMBINED_IMAGE_SAMPLER
+        || self.bound.contains_key(&(b.set, b.binding))
+    })
+  }
+
+  /// Same as `is_ready`, logging the first time a draw falls back to the default pipeline
+  /// because a buffer binding has no data yet.
+  pub fn ready_for_draw(&self, pipeline_ref: &str) -> bool {
+    let ready = self.is_ready();
+    if !ready && !self.fallback_logged.replace(true) {
+      let unbound = self
+        .bindings
+        .iter()
+        .filter(|b| {
+          b.descriptor_type != vk::DescriptorType::COMBINED_IMAGE_SAMPLER
+            && !self.bound.contains_key(&(b.set, b.binding))
+        })
+        .map(|b| (b.set, b.binding))
+        .collect::<Vec<_>>();
+      println!(
+        "Pipeline {} has no data bound to (set, binding) {:?}, drawing with the default pipeline until it does",
+        pipeline_ref, unbound
+      );
+    }
+
+    ready
+  }
+
+  /// Writes whatever changed since the frame last drew into its sets. `texture` gives the view
+  /// and sampler of a texture, or of the dummy texture when there is no such texture.
+  pub fn update<F: Fn(Option<&str>) -> (vk::ImageView, vk::Sampler)>(
+    &mut self,
+    vulkan: &mut Vulkan,
+    texture: F,
+  ) {
+    let frame = vulkan.current_frame();
+
+    for binding in &self.bindings {
+      let key = (binding.set, binding.binding);
+      let descriptor_set =
+        &self.descriptor_sets.get(frame)[(binding.set - self.first_set) as usize];
+      let written = self.written.get_mut(frame);
+
+      match self.bound.get(&key) {
+        Some((PipelineResource::Data(data), version)) => {
+          if written.get(&key) == Some(version) {
+            continue;
+          }
+
+          let buffers = self.buffers.get_mut(frame);
+          match buffers.get_mut(&key) {
+            Some(buffer) if buffer.data().len() == data.len() => {
+              buffer.update_data(vulkan.device(), data.clone());
+            }
+            _ => {
+              let usage = if binding.descriptor_type == vk::DescriptorType::STORAGE_BUFFER {
+                vk::BufferUsageFlags::STORAGE_BUFFER
+              } else {
+                vk::BufferUsageFlags::UNIFORM_BUFFER
+              };
+              let buffer = Buffer::new_generic(
+                vulkan.device(),
+                data.clone(),
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                usage,
+              );
+              PipelineResources::write_buffer(vulkan.device(), descriptor_set, binding, &buffer);
+              if let Some(old_buffer) = buffers.insert(key, buffer) {
+                vulkan.destroy_after_frames_in_flight(move |device| {
+                  old_buffer.destroy(device);
+                });
+              }
+            }
+          }
+
+          written.insert(key, *version);
+        }
+        bound => {
+          if binding.descriptor_type != vk::DescriptorType::COMBINED_IMAGE_SAMPLER {
+            continue;
+          }
+
+          let texture_ref = match bound {
+            Some((PipelineResource::Texture(texture_ref), _)) => Some(texture_ref.as_str()),
+            _ => None,
+          };
+          let (image_view, sampler) = texture(texture_ref);
+          // Reloaded textures get a new view, so the view tells when to rewrite the set
+          let view = vk::Handle::as_raw(image_view);
+          if written.get(&key) == Some(&view) {
+            continue;
+          }
+
+          let image_info = vec![
+            vk::DescriptorImageInfo {
+              sampler,
+              image_view,
+              image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            };
+            binding.count as usize
+          ];
+          let write = vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set.internal()[0])
+            .dst_binding(binding.binding)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&image_info);
+          unsafe {
+            vulkan
+              .device()
+              .internal()
+              .update_descriptor_sets(&[write.build()], &[]);
+          }
+
+          written.insert(key, view);
+        }
+      }
+    }
+  }
+
+  fn write_buffer(
+    device: &VkDevice,
+    descriptor_set: &DescriptorSet,
+    binding: &ReflectedBinding,
+    buffer: &Buffer<u8>,
+  ) {
+    let buffer_info = [vk::DescriptorBufferInfo {
+      buffer: *buffer.internal(),
+      offset: 0,
+      range: vk::WHOLE_SIZE,
+    }];
+    let write = vk::WriteDescriptorSet::builder()
+      .dst_set(descriptor_set.internal()[0])
+      .dst_binding(binding.binding)
+      .descriptor_type(binding.descriptor_type)
+      .buffer_info(&buffer_info);
+
+    unsafe {
+      device
+        .internal()
+        .update_descriptor_sets(&[write.build()], &[]);
+    }
+  }
+
+  /// Moves what was bound over to resources rebuilt for the same pipeline, dropping bindings
+  /// the new shaders don't have.
+  pub fn take_bindings(&mut self, old: &mut PipelineResources) {
+    for ((set, binding), (resource, _)) in old.bound.drain() {
+      let kept = match resource {
+        PipelineResource::Texture(texture_ref) => self.bind_texture(set, binding, &texture_ref),
+        PipelineResource::Data(data) => self.bind_data(set, binding, data),
+      };
+      if let Err(e) = kept {
+        println!("Dropped binding of rebuilt pipeline: {}", e);
+      }
+    }
+  }
+
+  pub fn destroy(&self, device: &VkDevice) {
+    for descriptor_set in self.descriptor_sets.iter().flatten() {
+      descriptor_set.free(device);
+      descriptor_set.destroy(device);
+    }
+    for buffer in self.buffers.iter().flat_map(|buffers| buffers.values()) {
+      buffer.destroy(device);
+    }
+  }
+}
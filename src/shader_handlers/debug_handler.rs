@@ -0,0 +1,183 @@
+use std::mem;
+
+use glam::Mat4;
+
+use crate::extra::debug_lines::{DebugLines, DebugVertex};
+use crate::extra::shader_source;
+use crate::offset_of;
+use crate::shader_handlers::Camera;
+use crate::vkwrapper::{Buffer, GraphicsPipelineBuilder, PerFrame, Shader, Vulkan};
+
+// Depth tested and overlay line pipelines
+type DebugShaders = (Shader<DebugVertex>, Shader<DebugVertex>);
+
+/// Lines added during a frame are drawn over the models at the end of the model pass, then
+/// cleared.
+pub struct DebugHandler {
+  lines: DebugLines,
+  depth_tested_shader: Shader<DebugVertex>,
+  overlay_shader: Shader<DebugVertex>,
+  // A vertex buffer per frame in flight and how many vertices it holds, grown as needed
+  vertex_buffers: PerFrame<Option<(Buffer<DebugVertex>, usize)>>,
+}
+
+impl DebugHandler {
+  pub fn new(vulkan: &mut Vulkan) -> DebugHandler {
+    let (depth_tested_shader, overlay_shader) =
+      DebugHandler::create_shaders(vulkan, None).unwrap_or_else(|e| panic!("{}", e));
+
+    DebugHandler {
+      lines: DebugLines::new(),
+      depth_tested_shader,
+      overlay_shader,
+      vertex_buffers: PerFrame::new(vulkan.frames_in_flight(), |_| None),
+    }
+  }
+
+  fn create_shaders(
+    vulkan: &Vulkan,
+    shader_directory: Option<&str>,
+  ) -> Result<DebugShaders, String> {
+    let graphics_pipeline_builder = GraphicsPipelineBuilder::new()
+      .topology_line_list()
+      .front_face_counter_clockwise()
+      .polygon_mode_fill()
+      .cull_none()
+      .blend_alpha()
+      .depth_write_disabled()
+      .samples(vulkan.msaa_samples());
+
+    let create_shader = |graphics_pipeline_builder: &GraphicsPipelineBuilder| {
+      Shader::try_new(
+        vulkan.device(),
+        shader_source(
+          shader_directory,
+          "debug_line_vert.spv",
+          include_bytes!("../../shaders/debug_line_vert.spv"),
+        ),
+        shader_source(
+          shader_directory,
+          "debug_line_frag.spv",
+          include_bytes!("../../shaders/debug_line_frag.spv"),
+        ),
+        DebugVertex {
+          pos: [0.0; 3],
+          colour: [0.0; 4],
+        },
+        vec![
+          offset_of!(DebugVertex, pos) as u32,
+          offset_of!(DebugVertex, colour) as u32,
+        ],
+        graphics_pipeline_builder,
+        vulkan.model_renderpass(),
+        vulkan.viewports(),
+        vulkan.scissors(),
+        &[],
+        None as Option<(u32, Vec<u32>)>,
+      )
+    };
+
+    let depth_tested_shader = create_shader(&graphics_pipeline_builder)?;
+    match create_shader(&graphics_pipeline_builder.depth_test_disabled()) {
+      Ok(overlay_shader) => Ok((depth_tested_shader, overlay_shader)),
+      Err(e) => {
+        depth_tested_shader.destroy(vulkan.device());
+        Err(e)
+      }
+    }
+  }
+
+  /// The lines are drawn in the model pass, so they follow its sample count.
+  pub fn rebuild_pipelines(&mut self, vulkan: &mut Vulkan, shader_directory: Option<&str>) {
+    match DebugHandler::create_shaders(vulkan, shader_directory) {
+      Ok((depth_tested_shader, overlay_shader)) => {
+        let old_depth_tested_shader =
+          mem::replace(&mut self.depth_tested_shader, depth_tested_shader);
+        let old_overlay_shader = mem::replace(&mut self.overlay_shader, overlay_shader);
+        vulkan.destroy_after_frames_in_flight(move |device| {
+          old_depth_tested_shader.destroy(device);
+          old_overlay_shader.destroy(device);
+        });
+      }
+      Err(e) => println!("Failed to rebuild debug line pipelines: {}", e),
+    }
+  }
+
+  pub fn lines(&mut self) -> &mut DebugLines {
+    &mut self.lines
+  }
+
+  pub fn clear(&mut self) {
+    self.lines.clear();
+  }
+
+  /// Draws the lines added since the last draw from `camera`, call in the model pass.
+  pub fn draw(&mut self, vulkan: &mut Vulkan, camera: &Camera) {
+    if self.lines.is_empty() {
+      return;
+    }
+
+    let depth_tested = self.lines.vertices(true).len();
+    let mut vertices = self.lines.vertices(true).clone();
+    vertices.extend_from_slice(self.lines.vertices(false));
+    let vertex_count = vertices.len();
+    self.lines.clear();
+
+    let buffer = self.vertex_buffers.get_mut(vulkan.current_frame());
+    if buffer
+      .as_ref()
+      .is_none_or(|(_, capacity)| *capacity < vertex_count)
+    {
+      let capacity = vertex_count.next_power_of_two();
+      let new_buffer = Buffer::<DebugVertex>::new_vertex(
+        vulkan.device(),
+        vec![
+          DebugVertex {
+            pos: [0.0; 3],
+            colour: [0.0; 4],
+          };
+          capacity
+        ],
+      );
+      if let Some((old_buffer, _)) = buffer.replace((new_buffer, capacity)) {
+        vulkan.destroy_after_frames_in_flight(move |device| {
+          old_buffer.destroy(device);
+        });
+      }
+    }
+
+    let (buffer, _) = buffer.as_mut().unwrap();
+    buffer.update_data(vulkan.device(), vertices);
+
+    let view_projection = Mat4::from_cols_array(&camera.perspective_matrix())
+      * Mat4::from_cols_array(&camera.view_matrix());
+    let data = view_projection.to_cols_array().to_vec();
+
+    if depth_tested > 0 {
+      vulkan.draw_vertex_range(
+        &self.depth_tested_shader,
+        buffer,
+        0,
+        depth_tested as u32,
+        data.clone(),
+      );
+    }
+    if vertex_count > depth_tested {
+      vulkan.draw_vertex_range(
+        &self.overlay_shader,
+        buffer,
+        depth_tested as u32,
+        (vertex_count - depth_tested) as u32,
+        data,
+      );
+    }
+  }
+
+  pub fn destroy(&mut self, vulkan: &mut Vulkan) {
+    self.depth_tested_shader.destroy(vulkan.device());
+    self.overlay_shader.destroy(vulkan.device());
+    for (buffer, _) in self.vertex_buffers.iter().flatten() {
+      buffer.destroy(vulkan.device());
+    }
+  }
+}
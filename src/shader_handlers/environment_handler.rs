@@ -0,0 +1,393 @@
+use std::mem;
+
+use ash::vk;
+use glam::Vec3;
+
+use crate::extra::environment::{self, Cubemap, EnvironmentMaps};
+use crate::extra::shader_source;
+use crate::shader_handlers::Camera;
+use crate::vkwrapper::{
+  Buffer, DescriptorAllocator, DescriptorPoolBuilder, DescriptorSet, DescriptorWriter,
+  GraphicsPipelineBuilder, Image, ImageBuilder, Sampler, Shader, VkDevice, Vulkan,
+};
+
+const ENVIRONMENT_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+#[derive(Clone, Copy)]
+pub struct EnvironmentUbo {
+  enabled: f32,
+  intensity: f32,
+  specular_mips: f32,
+  pad: f32,
+}
+
+// Skybox, irradiance and prefiltered specular cube maps
+struct EnvironmentImages {
+  skybox: Image,
+  irradiance: Image,
+  specular: Image,
+}
+
+impl EnvironmentImages {
+  fn destroy(&self, device: &VkDevice) {
+    self.skybox.destroy(device);
+    self.irradiance.destroy(device);
+    self.specular.destroy(device);
+  }
+}
+
+// Everything that points at the current images, replaced as a whole as frames in flight may
+// still be reading the old one
+struct EnvironmentSets {
+  ubo: Buffer<EnvironmentUbo>,
+  lighting: DescriptorSet,
+  skybox: DescriptorSet,
+}
+
+impl EnvironmentSets {
+  fn destroy(&self, device: &VkDevice) {
+    self.ubo.destroy(device);
+    self.lighting.free(device);
+    self.lighting.destroy(device);
+    self.skybox.free(device);
+    self.skybox.destroy(device);
+  }
+}
+
+/// Image based lighting for the mesh shaders and the skybox drawn behind models. Without an
+/// environment loaded the mesh shaders fall back to their flat ambient term and no sky is drawn.
+pub struct EnvironmentHandler {
+  descriptor_pool: DescriptorAllocator,
+  sampler: Sampler,
+  brdf_lut: Image,
+  // Black 1x1 cube bound until an environment is loaded
+  dummy_cube: Image,
+  images: Option<EnvironmentImages>,
+  sets: EnvironmentSets,
+  skybox_shader: Shader<u32>,
+  intensity: f32,
+}
+
+impl EnvironmentHandler {
+  pub fn new(vulkan: &mut Vulkan) -> EnvironmentHandler {
+    let descriptor_pool = DescriptorPoolBuilder::new()
+      .num_uniform_buffers(8)
+      .num_combined_image_samplers(32)
+      .free_individual_sets()
+      .build(vulkan.device());
+
+    let sampler = Sampler::builder()
+      .min_filter_linear()
+      .mag_filter_linear()
+      .address_mode_clamp_to_edge()
+      .mipmap_mode_linear()
+      .border_colour_float_opaque_black()
+      .compare_op_never()
+      .build(vulkan.device());
+
+    let brdf_lut = EnvironmentHandler::upload_brdf_lut(vulkan);
+    let dummy_cube =
+      EnvironmentHandler::upload_cube_map(vulkan, &[Cubemap::from_fn(1, |_| Vec3::ZERO)]);
+
+    let sets = EnvironmentHandler::create_sets(
+      vulkan,
+      &descriptor_pool,
+      &sampler,
+      &brdf_lut,
+      &dummy_cube,
+      None,
+      1.0,
+    );
+    let skybox_shader = EnvironmentHandler::create_skybox_shader(vulkan, &sets.skybox, None)
+      .unwrap_or_else(|e| panic!("{}", e));
+
+    EnvironmentHandler {
+      descriptor_pool,
+      sampler,
+      brdf_lut,
+      dummy_cube,
+      images: None,
+      sets,
+      skybox_shader,
+      intensity: 1.0,
+    }
+  }
+
+  fn upload_brdf_lut(vulkan: &mut Vulkan) -> Image {
+    let size = environment::BRDF_LUT_SIZE;
+    let lut = environment::brdf_lut(size)
+      .into_iter()
+      .map(|scale_bias| scale_bias.extend(0.0))
+      .collect();
+    let (data, level_offsets) = environment::pack_rgba16f(&[vec![lut]]);
+
+    let src_buffer = Buffer::<u8>::new_image(vulkan.device(), data);
+    let image = ImageBuilder::new(ENVIRONMENT_FORMAT, 1, 1)
+      .usage(vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED)
+      .set_dimensions(size, size)
+      .build_device_local(vulkan.device());
+
+    vulkan.transfer_buffer_levels_to_device_local_images(vec![(
+      src_buffer,
+      image.clone(),
+      level_offsets,
+    )]);
+
+    image
+  }
+
+  /// Each cube map is a mip level, half the size of the one before.
+  fn upload_cube_map(vulkan: &mut Vulkan, levels: &[Cubemap]) -> Image {
+    let faces = levels
+      .iter()
+      .map(|level| level.faces().clone())
+      .collect::<Vec<_>>();
+    let (data, level_offsets) = environment::pack_rgba16f(&faces);
+
+    let src_buffer = Buffer::<u8>::new_image(vulkan.device(), data);
+    let image = ImageBuilder::new(ENVIRONMENT_FORMAT, levels.len() as u32, 6)
+      .cube_map()
+      .usage(vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED)
+      .set_dimensions(levels[0].size(), levels[0].size())
+      .build_device_local(vulkan.device());
+
+    vulkan.transfer_buffer_levels_to_device_local_images(vec![(
+      src_buffer,
+      image.clone(),
+      level_offsets,
+    )]);
+
+    image
+  }
+
+  fn create_sets(
+    vulkan: &Vulkan,
+    descriptor_pool: &DescriptorAllocator,
+    sampler: &Sampler,
+    brdf_lut: &Image,
+    dummy_cube: &Image,
+    images: Option<&EnvironmentImages>,
+    intensity: f32,
+  ) -> EnvironmentSets {
+    let ubo = Buffer::new_uniform_buffer(
+      vulkan.device(),
+      &vec![EnvironmentUbo {
+        enabled: if images.is_some() { 1.0 } else { 0.0 },
+        intensity,
+        specular_mips: environment::SPECULAR_MIPS as f32,
+        pad: 0.0,
+      }],
+    );
+
+    let lighting = EnvironmentHandler::lighting_set(vulkan.device(), descriptor_pool);
+    let skybox = DescriptorSet::builder()
+      .combined_image_sampler_fragment()
+      .build(vulkan.device(), descriptor_pool);
+
+    let (skybox_image, irradiance, specular) = match images {
+      Some(images) => (&images.skybox, &images.irradiance, &images.specular),
+      None => (dummy_cube, dummy_cube, dummy_cube),
+    };
+
+    DescriptorWriter::builder()
+      .update_image(brdf_lut, sampler, &lighting)
+      .update_image(irradiance, sampler, &lighting)
+      .update_image(specular, sampler, &lighting)
+      .update_buffer(&ubo, &lighting)
+      .build(vulkan.device());
+    DescriptorWriter::builder()
+      .update_image(skybox_image, sampler, &skybox)
+      .build(vulkan.device());
+
+    EnvironmentSets {
+      ubo,
+      lighting,
+      skybox,
+    }
+  }
+
+  fn lighting_set(device: &VkDevice, descriptor_pool: &DescriptorAllocator) -> DescriptorSet {
+    DescriptorSet::builder()
+      .combined_image_sampler_fragment() // brdf lut
+      .combined_image_sampler_fragment() // irradiance
+      .combined_image_sampler_fragment() // prefiltered specular
+      .uniform_buffer_fragment()
+      .build(device, descriptor_pool)
+  }
+
+  fn create_skybox_shader(
+    vulkan: &Vulkan,
+    skybox_set: &DescriptorSet,
+    shader_directory: Option<&str>,
+  ) -> Result<Shader<u32>, String> {
+    let graphics_pipeline_builder = GraphicsPipelineBuilder::new()
+      .topology_triangle_list()
+      .front_face_counter_clockwise()
+      .polygon_mode_fill()
+      .cull_none()
+      .blend_none()
+      .depth_write_disabled()
+      .samples(vulkan.msaa_samples());
+
+    // No vertex buffer is bound, the vertex struct only fills the unused binding
+    Shader::try_new(
+      vulkan.device(),
+      shader_source(
+        shader_directory,
+        "post_fullscreen_vert.spv",
+        include_bytes!("../../shaders/post_fullscreen_vert.spv"),
+      ),
+      shader_source(
+        shader_directory,
+        "skybox_frag.spv",
+        include_bytes!("../../shaders/skybox_frag.spv"),
+      ),
+      0u32,
+      Vec::new(),
+      &graphics_pipeline_builder,
+      vulkan.model_renderpass(),
+      vulkan.viewports(),
+      vulkan.scissors(),
+      &[skybox_set],
+      None as Option<(u32, Vec<u32>)>,
+    )
+  }
+
+  /// The skybox is drawn in the model pass, so it follows its sample count.
+  pub fn rebuild_pipelines(&mut self, vulkan: &mut Vulkan, shader_directory: Option<&str>) {
+    match EnvironmentHandler::create_skybox_shader(vulkan, &self.sets.skybox, shader_directory) {
+      Ok(skybox_shader) => {
+        let old_shader = mem::replace(&mut self.skybox_shader, skybox_shader);
+        vulkan.destroy_after_frames_in_flight(move |device| {
+          old_shader.destroy(device);
+        });
+      }
+      Err(e) => println!("Failed to rebuild skybox pipeline: {}", e),
+    }
+  }
+
+  /// Set 3 of the mesh shaders.
+  pub fn lighting_descriptor(&self) -> &DescriptorSet {
+    &self.sets.lighting
+  }
+
+  pub fn is_loaded(&self) -> bool {
+    self.images.is_some()
+  }
+
+  pub fn intensity(&self) -> f32 {
+    self.intensity
+  }
+
+  /// Scales both the sky and the light it gives off.
+  pub fn set_intensity(&mut self, vulkan: &mut Vulkan, intensity: f32) {
+    self.intensity = intensity.max(0.0);
+    self.rewrite_sets(vulkan);
+  }
+
+  /// Loads a longitude, latitude panorama, either a Radiance .hdr or an sRGB image.
+  pub fn load_equirectangular(
+    &mut self,
+    vulkan: &mut Vulkan,
+    location: &str,
+  ) -> Result<(), String> {
+    let (width, height, pixels) = environment::load_linear_image(location)?;
+    self.set_environment(
+      vulkan,
+      Cubemap::from_equirectangular(width, height, &pixels),
+    );
+
+    Ok(())
+  }
+
+  /// Loads six square faces in the +x, -x, +y, -y, +z, -z order.
+  pub fn load_cubemap(&mut self, vulkan: &mut Vulkan, locations: [&str; 6]) -> Result<(), String> {
+    let faces = locations
+      .iter()
+      .map(|location| environment::load_linear_image(location))
+      .collect::<Result<Vec<_>, String>>()?;
+    self.set_environment(vulkan, Cubemap::from_faces(faces)?);
+
+    Ok(())
+  }
+
+  /// Bakes the lighting maps from `skybox` on the CPU and uploads them.
+  pub fn set_environment(&mut self, vulkan: &mut Vulkan, skybox: Cubemap) {
+    let maps = EnvironmentMaps::bake(skybox);
+
+    let images = EnvironmentImages {
+      skybox: EnvironmentHandler::upload_cube_map(vulkan, &maps.skybox.mip_chain()),
+      irradiance: EnvironmentHandler::upload_cube_map(vulkan, &[maps.irradiance]),
+      specular: EnvironmentHandler::upload_cube_map(vulkan, &maps.specular),
+    };
+
+    self.replace_images(vulkan, Some(images));
+  }
+
+  pub fn clear_environment(&mut self, vulkan: &mut Vulkan) {
+    self.replace_images(vulkan, None);
+  }
+
+  fn replace_images(&mut self, vulkan: &mut Vulkan, images: Option<EnvironmentImages>) {
+    let old_images = mem::replace(&mut self.images, images);
+    self.rewrite_sets(vulkan);
+
+    if let Some(old_images) = old_images {
+      vulkan.destroy_after_frames_in_flight(move |device| {
+        old_images.destroy(device);
+      });
+    }
+  }
+
+  fn rewrite_sets(&mut self, vulkan: &mut Vulkan) {
+    let sets = EnvironmentHandler::create_sets(
+      vulkan,
+      &self.descriptor_pool,
+      &self.sampler,
+      &self.brdf_lut,
+      &self.dummy_cube,
+      self.images.as_ref(),
+      self.intensity,
+    );
+
+    let old_sets = mem::replace(&mut self.sets, sets);
+    vulkan.destroy_after_frames_in_flight(move |device| {
+      old_sets.destroy(device);
+    });
+  }
+
+  /// Draws the sky where no model has been drawn, call after the models in a model render pass.
+  pub fn draw_skybox(&self, vulkan: &mut Vulkan, camera: &Camera) {
+    if self.images.is_none() {
+      return;
+    }
+
+    let projection = camera.perspective_matrix();
+    let mut data = camera.view_matrix().to_vec();
+    data.extend_from_slice(&[
+      1.0 / vulkan.viewports().width(),
+      1.0 / vulkan.viewports().height(),
+      0.0,
+      0.0,
+      projection[0],
+      projection[5],
+      self.intensity,
+      0.0,
+    ]);
+
+    vulkan.draw_fullscreen(&self.skybox_shader, &self.sets.skybox, data);
+  }
+
+  pub fn destroy(&mut self, vulkan: &mut Vulkan) {
+    self.skybox_shader.destroy(vulkan.device());
+    self.sets.destroy(vulkan.device());
+    if let Some(images) = self.images.take() {
+      images.destroy(vulkan.device());
+    }
+    self.dummy_cube.destroy(vulkan.device());
+    self.brdf_lut.destroy(vulkan.device());
+
+    self.descriptor_pool.destroy(vulkan.device());
+    self.sampler.destroy(vulkan.device());
+  }
+}
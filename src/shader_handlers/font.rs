@@ -2,18 +2,17 @@ use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::mem;
 
-use ash::vk;
 use image;
 
+use crate::extra::shader_source;
 use crate::offset_of;
-use std::io::Cursor;
 
 use crate::glam::{Vec2, Vec4};
 use crate::shader_handlers::TextureHandler;
 use crate::vkwrapper::Image as vkImage;
 use crate::vkwrapper::{
-  Buffer, DescriptorPoolBuilder, DescriptorSet, DescriptorWriter, GraphicsPipelineBuilder, Sampler,
-  Shader, VkDevice, Vulkan,
+  Buffer, DescriptorAllocator, DescriptorPoolBuilder, DescriptorSet, DescriptorWriter,
+  GraphicsPipelineBuilder, Sampler, Shader, VkDevice, Vulkan,
 };
 
 use std::collections::HashMap;
@@ -89,7 +88,7 @@ pub struct Line {
 
 pub struct FontType {
   texture: vkImage,
-  pool: vk::DescriptorPool,
+  pool: DescriptorAllocator,
   loader: TextMeshCreator,
   descriptor: DescriptorSet,
   shader: Shader<TextVertex>,
@@ -129,7 +128,7 @@ impl Clone for GuiText {
 }
 
 pub struct TextMaster {
-  descriptor_pool: vk::DescriptorPool,
+  descriptor_pool: DescriptorAllocator,
   //texts: HashMap<FontType, Vec<GuiText>>,
   font_type: FontType,
   //text: Vec<(GuiText, Buffer<TextVertex>)>,
@@ -242,6 +241,38 @@ impl TextMaster {
   pub fn font(&self) -> &FontType {
     &self.font_type
   }
+
+  /// Swaps in a reloaded font. Cached text was laid out with the old font's metrics so it is
+  /// rebuilt on the next draw.
+  pub fn replace_font(&mut self, vulkan: &mut Vulkan, font: FontType) {
+    let old_font = mem::replace(&mut self.font_type, font);
+    let old_text = self
+      .text_atlas
+      .drain()
+      .map(|(_, buffer)| buffer)
+      .collect::<Vec<_>>();
+
+    vulkan.destroy_after_frames_in_flight(move |device| {
+      for buffer in old_text {
+        buffer.destroy(device);
+      }
+      old_font.destroy(device);
+    });
+  }
+
+  pub fn reload_shader(&mut self, vulkan: &mut Vulkan, shader_directory: Option<&str>) {
+    self.font_type.reload_shader(vulkan, shader_directory);
+  }
+
+  pub fn destroy(&mut self, device: &VkDevice) {
+    for (_, buffer) in self.text_atlas.drain() {
+      buffer.destroy(device);
+    }
+
+    self.font_type.destroy(device);
+
+    self.descriptor_pool.destroy(device);
+  }
 }
 
 impl TextMeshCreator {
@@ -529,25 +560,84 @@ impl GuiText {
 }
 
 impl FontType {
-  pub fn new(file: String, sampler: &Sampler, vulkan: &mut Vulkan) -> FontType {
-    let descriptor_pool = DescriptorPoolBuilder::new()
-      .num_combined_image_samplers(1)
-      .build(vulkan.device());
+  pub fn new(
+    file: String,
+    sampler: &Sampler,
+    vulkan: &mut Vulkan,
+    shader_directory: Option<&str>,
+  ) -> FontType {
+    match FontType::try_new(file, sampler, vulkan, shader_directory) {
+      Ok(font) => font,
+      Err(e) => panic!("Failed to load font: {}", e),
+    }
+  }
 
+  pub fn try_new(
+    file: String,
+    sampler: &Sampler,
+    vulkan: &mut Vulkan,
+    shader_directory: Option<&str>,
+  ) -> Result<FontType, String> {
     let image = image::open(file.to_owned() + ".png")
-      .expect(&("Failed to load font: ".to_string() + &file))
+      .map_err(|e| format!("{}: {}", file, e))?
       .fliph()
       .to_rgba8();
+    File::open(file.to_owned() + ".fnt").map_err(|e| format!("{}: {}", file, e))?;
+
+    let descriptor_pool = DescriptorPoolBuilder::new()
+      .num_combined_image_samplers(1)
+      .build(vulkan.device());
 
-    let font_texture = TextureHandler::create_device_local_texture_from_image(vulkan, image);
     let font_descriptor_set = DescriptorSet::builder()
       .combined_image_sampler_fragment()
       .build(vulkan.device(), &descriptor_pool);
+
+    let shader = match FontType::create_shader(vulkan, &[&font_descriptor_set], shader_directory) {
+      Ok(shader) => shader,
+      Err(e) => {
+        font_descriptor_set.destroy(vulkan.device());
+        descriptor_pool.destroy(vulkan.device());
+        return Err(e);
+      }
+    };
+
+    let font_texture = TextureHandler::create_device_local_texture_from_image(vulkan, image);
     let font_descriptor_set_writer =
       DescriptorWriter::builder().update_image(&font_texture, &sampler, &font_descriptor_set);
 
     font_descriptor_set_writer.build(vulkan.device());
 
+    let loader = TextMeshCreator::new(file);
+
+    Ok(FontType {
+      texture: font_texture,
+      pool: descriptor_pool,
+      descriptor: font_descriptor_set,
+      shader,
+      loader,
+    })
+  }
+
+  /// Rebuilds the text pipeline from the shader directory, keeping the current one on failure.
+  pub fn reload_shader(&mut self, vulkan: &mut Vulkan, shader_directory: Option<&str>) {
+    match FontType::create_shader(vulkan, &[&self.descriptor], shader_directory) {
+      Ok(shader) => {
+        let old_shader = mem::replace(&mut self.shader, shader);
+        vulkan.destroy_after_frames_in_flight(move |device| {
+          old_shader.destroy(device);
+        });
+      }
+      Err(e) => {
+        println!("Failed to reload text shader: {}", e);
+      }
+    }
+  }
+
+  fn create_shader(
+    vulkan: &Vulkan,
+    descriptor_sets: &[&DescriptorSet],
+    shader_directory: Option<&str>,
+  ) -> Result<Shader<TextVertex>, String> {
     let text_vertex = TextVertex {
       pos: [0.0, 0.0, 0.0, 0.0],
       uv: [0.0, 0.0, 0.0, 0.0],
@@ -558,14 +648,20 @@ impl FontType {
       .topology_triangle_list()
       .front_face_counter_clockwise()
       .polygon_mode_fill()
-      .samples_1();
-
-    let layouts = vec![font_descriptor_set.layouts()[0]];
+      .samples(vulkan.msaa_samples());
 
-    let shader = Shader::new(
+    Shader::try_new(
       vulkan.device(),
-      Cursor::new(&include_bytes!("../../shaders/new_text_vert.spv")[..]),
-      Cursor::new(&include_bytes!("../../shaders/new_text_frag.spv")[..]),
+      shader_source(
+        shader_directory,
+        "new_text_vert.spv",
+        include_bytes!("../../shaders/new_text_vert.spv"),
+      ),
+      shader_source(
+        shader_directory,
+        "new_text_frag.spv",
+        include_bytes!("../../shaders/new_text_frag.spv"),
+      ),
       text_vertex,
       vec![
         offset_of!(TextVertex, pos) as u32,
@@ -576,19 +672,9 @@ impl FontType {
       vulkan.texture_renderpass(),
       vulkan.viewports(),
       vulkan.scissors(),
-      &layouts,
+      descriptor_sets,
       None as Option<(i32, Vec<u32>)>,
-    );
-
-    let loader = TextMeshCreator::new(file);
-
-    FontType {
-      texture: font_texture,
-      pool: descriptor_pool,
-      descriptor: font_descriptor_set,
-      shader,
-      loader,
-    }
+    )
   }
 
   pub fn shader(&self) -> &Shader<TextVertex> {
@@ -606,6 +692,14 @@ impl FontType {
   pub fn load_text(&mut self, text: &mut GuiText) -> TextMeshData {
     self.loader.create_text_data(text)
   }
+
+  pub fn destroy(&self, device: &VkDevice) {
+    self.shader.destroy(device);
+    self.descriptor.destroy(device);
+    self.texture.destroy(device);
+
+    self.pool.destroy(device);
+  }
 }
 
 impl Line {
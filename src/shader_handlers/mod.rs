@@ -1,11 +1,25 @@
 pub use self::camera::{Camera, CameraType};
 pub use self::compute_handler::ComputeHandler;
+pub use self::compute_task_handler::{ComputeFence, ComputeImageFormat, ComputeTaskHandler};
+pub use self::custom_pipeline::{CustomPipeline, PipelineResources};
+pub use self::debug_handler::DebugHandler;
+pub use self::environment_handler::EnvironmentHandler;
 //pub use self::font::Font;
 pub use self::model_handler::ModelHandler;
+pub use self::particle_handler::{Particle, ParticleEmitter, ParticleHandler, ParticleStep};
+pub use self::post_process_handler::{PostProcessHandler, PostProcessSettings, ToneMapping};
+pub use self::shape_handler::ShapeHandler;
 pub use self::texture_handler::{ComboVertex, TextureHandler};
 
 mod camera;
 mod compute_handler;
+mod compute_task_handler;
+mod custom_pipeline;
+mod debug_handler;
+mod environment_handler;
 pub mod font;
 mod model_handler;
+pub mod particle_handler;
+pub mod post_process_handler;
+mod shape_handler;
 mod texture_handler;
@@ -4,18 +4,37 @@ use std::mem;
 
 use ash::vk;
 
-use crate::extra::gltf_loader::{CollisionInformation, GltfModel, MaterialUbo, MeshVertex};
-use crate::extra::{gltf_loader, Math};
+use crate::extra::frustum::{Aabb, CullingStats, Frustum};
+use crate::extra::gltf_loader::{
+  CollisionInformation, GltfModel, MaterialUbo, MeshVertex, Node, ParsedGltf,
+};
+use crate::extra::lod::{LodGroup, LodMetric};
+use crate::extra::{gltf_loader, shader_source, Math};
 use crate::offset_of;
-use crate::shader_handlers::{Camera, TextureHandler};
+use crate::shader_handlers::{
+  Camera, CustomPipeline, EnvironmentHandler, PipelineResources, TextureHandler,
+};
 use crate::vkwrapper::{
-  Buffer, DescriptorPoolBuilder, DescriptorSet, DescriptorWriter, GraphicsPipelineBuilder, Image,
-  Sampler, Shader, VkDevice, Vulkan,
+  Buffer, DescriptorAllocator, DescriptorPoolBuilder, DescriptorSet, DescriptorWriter,
+  GraphicsPipelineBuilder, Image, PerFrame, Sampler, Shader, Vulkan,
 };
 use crate::DrawMode;
 
+use glam::{Quat, Vec3};
+
 const MAX_INSTANCES: usize = 4096;
 
+type CameraUniforms = (PerFrame<Buffer<MeshUniformBuffer>>, PerFrame<DescriptorSet>);
+// Draw data of the instances added this frame and the vertex buffers per frame in flight, one for
+// each MAX_INSTANCES instances, created on first use and replaced when they run out of room, along
+// with how many instances each holds
+type InstancedModel = (
+  Vec<Vec<f32>>,
+  PerFrame<Vec<(Buffer<InstancedMeshData>, usize)>>,
+);
+// Mesh and instanced mesh shaders
+type MeshShaders = (Shader<MeshVertex>, Shader<MeshVertex>);
+
 #[derive(Clone, Copy)]
 pub struct MeshUniformBuffer {
   projection: [f32; 16],
@@ -24,19 +43,17 @@ pub struct MeshUniformBuffer {
   window_size: [f32; 2],
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct InstancedMeshData {
-  model: [f32; 16],
-  offset: [f32; 3],
-  scale: [f32; 3],
+  pub model: [f32; 16],
+  pub colour: [f32; 4],
 }
 
 impl InstancedMeshData {
   pub fn new() -> InstancedMeshData {
     InstancedMeshData {
       model: [0.0; 16],
-      offset: [0.0; 3],
-      scale: [0.0; 3],
+      colour: [0.0; 4],
     }
   }
 }
@@ -47,23 +64,37 @@ pub struct ModelHandler {
 
   models: HashMap<String, GltfModel>,
   mesh_shader: Shader<MeshVertex>,
-
-  //instanced_mesh_shader: Shader<MeshVertex>,
-  //instanced_mesh_buffer: HashMap<String, (Buffer<InstancedMeshData>, usize, Vec<(u32, u32)>)>,
-  uniform_buffer: Buffer<MeshUniformBuffer>,
-  uniform_descriptor_set: DescriptorSet,
+  draw_mode: DrawMode,
+  custom_pipelines: HashMap<String, (Shader<MeshVertex>, PipelineResources)>,
+  pipeline_sources: HashMap<String, CustomPipeline>,
+  model_pipelines: HashMap<String, String>,
+
+  instanced_mesh_shader: Shader<MeshVertex>,
+  // Models drawn with one instanced draw per primitive, by model reference
+  instanced_models: HashMap<String, InstancedModel>,
+  frustum_culling: bool,
+  culling_stats: CullingStats,
+  // Groups drawn as one of their models picked by distance, by group reference
+  lod_groups: HashMap<String, LodGroup>,
+  // Camera uniforms, one per frame in flight
+  uniform_buffers: PerFrame<Buffer<MeshUniformBuffer>>,
+  uniform_descriptor_sets: PerFrame<DescriptorSet>,
+  // Cameras for drawing into render targets, by target name
+  render_target_uniforms: HashMap<String, CameraUniforms>,
 
   //dummy_texture: DescriptorSet,
   mesh_descriptor: DescriptorSet,
+  dummy_material: (Buffer<MaterialUbo>, Vec<Image>),
   dummy_texture: Image,
   dummy_skin_buffer: Buffer<f32>,
   dummy_skin: DescriptorSet,
 
   storage_descriptor_set: DescriptorSet,
+  environment: EnvironmentHandler,
 
   window_size: [f32; 2],
 
-  descriptor_pool: vk::DescriptorPool,
+  descriptor_pool: DescriptorAllocator,
 
   loaded_models: Vec<(String, String)>,
 }
@@ -74,6 +105,7 @@ impl ModelHandler {
       .num_uniform_buffers(30)
       .num_storage(30)
       .num_combined_image_samplers(30)
+      .free_individual_sets()
       .build(vulkan.device());
 
     let sampler = Sampler::builder()
@@ -81,13 +113,16 @@ impl ModelHandler {
       .mag_filter_linear()
       .address_mode_clamp_to_edge()
       .mipmap_mode_linear()
+      .anisotropy(16.0)
       .border_colour_float_opaque_white()
       .compare_op_never()
       .build(vulkan.device());
 
-    let descriptor_set0 = DescriptorSet::builder()
-      .uniform_buffer_vertex()
-      .build(vulkan.device(), &descriptor_pool);
+    let descriptor_sets0 = PerFrame::new(vulkan.frames_in_flight(), |_| {
+      DescriptorSet::builder()
+        .uniform_buffer_vertex()
+        .build(vulkan.device(), &descriptor_pool)
+    });
     let descriptor_set1 = DescriptorSet::builder()
       .storage_vertex()
       .build(vulkan.device(), &descriptor_pool);
@@ -103,16 +138,20 @@ impl ModelHandler {
       .combined_image_sampler_fragment()
       .combined_image_sampler_fragment()
       .build(vulkan.device(), &descriptor_pool);
+    let environment = EnvironmentHandler::new(vulkan);
 
-    let mesh_shader = ModelHandler::create_mesh_shaders(
+    let (mesh_shader, instanced_mesh_shader) = ModelHandler::create_mesh_shaders(
       vulkan,
       DrawMode::Polygon,
       vec![
-        descriptor_set0.clone(),
+        descriptor_sets0.get(0).clone(),
         descriptor_set1.clone(),
         mesh_descriptor.clone(),
+        environment.lighting_descriptor().clone(),
       ],
-    );
+      None,
+    )
+    .unwrap_or_else(|e| panic!("{}", e));
 
     let mut camera = Camera::new();
     camera.update_aspect_ratio(screen_resolution.width as f32 / screen_resolution.height as f32);
@@ -129,13 +168,17 @@ impl ModelHandler {
       window_size,
     }];
 
-    let uniform_buffer =
-      Buffer::<MeshUniformBuffer>::new_uniform_buffer(vulkan.device(), &uniform_data);
+    let uniform_buffers = PerFrame::new(vulkan.frames_in_flight(), |frame| {
+      let uniform_buffer =
+        Buffer::<MeshUniformBuffer>::new_uniform_buffer(vulkan.device(), &uniform_data);
 
-    let descriptor_set_writer =
-      DescriptorWriter::builder().update_buffer(&uniform_buffer, &descriptor_set0);
+      let descriptor_set_writer =
+        DescriptorWriter::builder().update_buffer(&uniform_buffer, descriptor_sets0.get(frame));
 
-    descriptor_set_writer.build(vulkan.device());
+      descriptor_set_writer.build(vulkan.device());
+
+      uniform_buffer
+    });
 
     println!("Before");
     let image = ModelHandler::create_blank_image();
@@ -167,11 +210,10 @@ impl ModelHandler {
       sampler.clone(),
     ];
 
+    let dummy_material_buffer =
+      Buffer::new_uniform_buffer(vulkan.device(), &vec![MaterialUbo::default()]);
     let descriptor_set_writer = DescriptorWriter::builder()
-      .update_buffer(
-        &Buffer::new_uniform_buffer(vulkan.device(), &vec![MaterialUbo::default()]),
-        &mesh_descriptor,
-      )
+      .update_buffer(&dummy_material_buffer, &mesh_descriptor)
       .update_images(&textures, &samplers, &mesh_descriptor);
 
     descriptor_set_writer.build(vulkan.device());
@@ -192,16 +234,28 @@ impl ModelHandler {
 
       models: HashMap::new(),
       mesh_shader,
-
-      uniform_buffer,
-      uniform_descriptor_set: descriptor_set0,
+      instanced_mesh_shader,
+      instanced_models: HashMap::new(),
+      frustum_culling: true,
+      culling_stats: CullingStats::default(),
+      lod_groups: HashMap::new(),
+      draw_mode: DrawMode::Polygon,
+      custom_pipelines: HashMap::new(),
+      pipeline_sources: HashMap::new(),
+      model_pipelines: HashMap::new(),
+
+      uniform_buffers,
+      uniform_descriptor_sets: descriptor_sets0,
+      render_target_uniforms: HashMap::new(),
 
       mesh_descriptor,
+      dummy_material: (dummy_material_buffer, textures),
       dummy_texture,
       dummy_skin_buffer: dummy_buffer,
       dummy_skin,
 
       storage_descriptor_set: descriptor_set1,
+      environment,
 
       window_size,
 
@@ -215,20 +269,193 @@ impl ModelHandler {
     self.loaded_models.clone()
   }
 
-  pub fn set_draw_mode(&mut self, vulkan: &Vulkan, mode: DrawMode) {
-    self.mesh_shader.destroy(vulkan.device());
+  pub fn set_draw_mode(
+    &mut self,
+    vulkan: &mut Vulkan,
+    mode: DrawMode,
+    shader_directory: Option<&str>,
+  ) {
+    self.draw_mode = mode;
+    self.reload_shaders(vulkan, shader_directory);
+  }
 
-    let mesh_shader = ModelHandler::create_mesh_shaders(
+  /// Rebuilds the mesh pipeline from the shader directory, keeping the current one if it fails.
+  pub fn reload_shaders(&mut self, vulkan: &mut Vulkan, shader_directory: Option<&str>) {
+    match ModelHandler::create_mesh_shaders(
       vulkan,
-      mode,
+      self.draw_mode,
       vec![
-        self.uniform_descriptor_set.clone(),
+        self.uniform_descriptor_sets.get(0).clone(),
         self.storage_descriptor_set.clone(),
         self.mesh_descriptor.clone(),
+        self.environment.lighting_descriptor().clone(),
       ],
+      shader_directory,
+    ) {
+      Ok((mesh_shader, instanced_mesh_shader)) => {
+        let old_shader = mem::replace(&mut self.mesh_shader, mesh_shader);
+        let old_instanced_shader =
+          mem::replace(&mut self.instanced_mesh_shader, instanced_mesh_shader);
+        vulkan.destroy_after_frames_in_flight(move |device| {
+          old_shader.destroy(device);
+          old_instanced_shader.destroy(device);
+        });
+      }
+      Err(e) => {
+        println!("Failed to reload mesh shader: {}", e);
+      }
+    }
+
+    self.environment.rebuild_pipelines(vulkan, shader_directory);
+  }
+
+  /// Builds a model pipeline from user SPIR-V. Sets 0 to 3 are the same uniforms, skins,
+  /// material and lighting as `mesh_animated_vert`/`mesh_pbr_frag` get, sets from 4 on are the
+  /// pipeline's own.
+  pub fn create_pipeline<T: Into<String>>(
+    &mut self,
+    vulkan: &mut Vulkan,
+    pipeline_ref: T,
+    pipeline: &CustomPipeline,
+  ) -> Result<(), String> {
+    let resources = PipelineResources::new(
+      vulkan,
+      &self.descriptor_pool,
+      4,
+      pipeline.extra_bindings(4)?,
     );
 
-    self.mesh_shader = mesh_shader;
+    let mut descriptor_sets = vec![
+      self.uniform_descriptor_sets.get(0),
+      &self.storage_descriptor_set,
+      &self.mesh_descriptor,
+      self.environment.lighting_descriptor(),
+    ];
+    descriptor_sets.extend(resources.layouts());
+
+    let shader = match Shader::try_new(
+      vulkan.device(),
+      Cursor::new(pipeline.vertex_shader()),
+      Cursor::new(pipeline.fragment_shader()),
+      ModelHandler::template_mesh_vertex(),
+      pipeline.offsets_or(ModelHandler::mesh_vertex_offsets()),
+      &pipeline
+        .get_graphics_pipeline_builder()
+        .clone()
+        .samples(vulkan.msaa_samples()),
+      vulkan.model_renderpass(),
+      vulkan.viewports(),
+      vulkan.scissors(),
+      &descriptor_sets,
+      None as Option<(u32, Vec<u32>)>,
+    ) {
+      Ok(shader) => shader,
+      Err(e) => {
+        resources.destroy(vulkan.device());
+        return Err(e);
+      }
+    };
+
+    let pipeline_ref = pipeline_ref.into();
+    let mut resources = resources;
+    self
+      .pipeline_sources
+      .insert(pipeline_ref.to_string(), pipeline.clone());
+    if let Some((old_shader, mut old_resources)) = self.custom_pipelines.remove(&pipeline_ref) {
+      resources.take_bindings(&mut old_resources);
+      vulkan.destroy_after_frames_in_flight(move |device| {
+        old_shader.destroy(device);
+        old_resources.destroy(device);
+      });
+    }
+    self
+      .custom_pipelines
+      .insert(pipeline_ref, (shader, resources));
+
+    Ok(())
+  }
+
+  /// Rebuilds the built in and custom pipelines against the current render passes, models keep
+  /// the pipelines they were assigned.
+  pub fn rebuild_pipelines(&mut self, vulkan: &mut Vulkan, shader_directory: Option<&str>) {
+    self.reload_shaders(vulkan, shader_directory);
+
+    for (pipeline_ref, pipeline) in self.pipeline_sources.clone() {
+      if let Err(e) = self.create_pipeline(vulkan, pipeline_ref.to_string(), &pipeline) {
+        println!("Failed to rebuild model pipeline {}: {}", pipeline_ref, e);
+      }
+    }
+  }
+
+  /// Models using the pipeline go back to the default one.
+  pub fn remove_pipeline(&mut self, vulkan: &mut Vulkan, pipeline_ref: &str) {
+    self.pipeline_sources.remove(pipeline_ref);
+    if let Some((shader, resources)) = self.custom_pipelines.remove(pipeline_ref) {
+      self
+        .model_pipelines
+        .retain(|_, pipeline| pipeline != pipeline_ref);
+      vulkan.destroy_after_frames_in_flight(move |device| {
+        shader.destroy(device);
+        resources.destroy(device);
+      });
+    }
+  }
+
+  pub fn has_pipeline(&self, pipeline_ref: &str) -> bool {
+    self.custom_pipelines.contains_key(pipeline_ref)
+  }
+
+  pub fn bind_pipeline_texture(
+    &mut self,
+    pipeline_ref: &str,
+    set: u32,
+    binding: u32,
+    texture_ref: &str,
+  ) -> Result<(), String> {
+    self
+      .custom_pipelines
+      .get_mut(pipeline_ref)
+      .ok_or(format!("No model pipeline named {}", pipeline_ref))?
+      .1
+      .bind_texture(set, binding, texture_ref)
+  }
+
+  pub fn bind_pipeline_data(
+    &mut self,
+    pipeline_ref: &str,
+    set: u32,
+    binding: u32,
+    data: Vec<u8>,
+  ) -> Result<(), String> {
+    self
+      .custom_pipelines
+      .get_mut(pipeline_ref)
+      .ok_or(format!("No model pipeline named {}", pipeline_ref))?
+      .1
+      .bind_data(set, binding, data)
+  }
+
+  /// Writes changes to the pipelines' own sets for this frame, call before the frame's first
+  /// model draw. Textures are sampled as sprites would sample them.
+  pub fn update_pipeline_resources(&mut self, vulkan: &mut Vulkan, textures: &TextureHandler) {
+    for (_, resources) in self.custom_pipelines.values_mut() {
+      if resources.is_ready() {
+        resources.update(vulkan, |texture_ref| textures.sampled_texture(texture_ref));
+      }
+    }
+  }
+
+  pub fn set_model_pipeline(&mut self, model_ref: &str, pipeline_ref: Option<&str>) {
+    match pipeline_ref {
+      Some(pipeline_ref) => {
+        self
+          .model_pipelines
+          .insert(model_ref.to_string(), pipeline_ref.to_string());
+      }
+      None => {
+        self.model_pipelines.remove(model_ref);
+      }
+    }
   }
 
   pub fn all_collision_models(&self) -> HashMap<String, CollisionInformation> {
@@ -269,17 +496,110 @@ impl ModelHandler {
       .update_aspect_ratio(width as f32 / height as f32);
   }
 
-  pub fn update_uniform_buffer(&mut self, device: &VkDevice) {
-    let mut data = self.uniform_buffer.data()[0];
-    data.projection = self.camera.perspective_matrix();
-    data.model = self.camera.view_matrix();
-    data.window_size = self.window_size;
+  /// Writes the camera into the current frame's uniform buffer, call after `start_render`.
+  pub fn update_uniform_buffer(&mut self, vulkan: &Vulkan) {
+    let uniform_buffer = self.uniform_buffers.get_mut(vulkan.current_frame());
+    ModelHandler::write_camera(vulkan, uniform_buffer, &self.camera, self.window_size);
+  }
 
-    self.uniform_buffer.update_data(device, vec![data]);
+  fn write_camera(
+    vulkan: &Vulkan,
+    uniform_buffer: &mut Buffer<MeshUniformBuffer>,
+    camera: &Camera,
+    window_size: [f32; 2],
+  ) {
+    let mut data = uniform_buffer.data()[0];
+    data.projection = camera.perspective_matrix();
+    data.model = camera.view_matrix();
+    data.window_size = window_size;
+
+    uniform_buffer.update_data(vulkan.device(), vec![data]);
+  }
+
+  /// Gives a render target its own camera uniforms, so it can be drawn from a different camera
+  /// than the screen in the same frame.
+  pub fn create_render_target_camera(&mut self, vulkan: &mut Vulkan, target: &str) {
+    self.remove_render_target_camera(vulkan, target);
+
+    let uniform_data = self.uniform_buffers.get(0).data().clone();
+    let descriptor_sets = PerFrame::new(vulkan.frames_in_flight(), |_| {
+      DescriptorSet::builder()
+        .uniform_buffer_vertex()
+        .build(vulkan.device(), &self.descriptor_pool)
+    });
+    let uniform_buffers = PerFrame::new(vulkan.frames_in_flight(), |frame| {
+      let uniform_buffer =
+        Buffer::<MeshUniformBuffer>::new_uniform_buffer(vulkan.device(), &uniform_data);
+      DescriptorWriter::builder()
+        .update_buffer(&uniform_buffer, descriptor_sets.get(frame))
+        .build(vulkan.device());
+
+      uniform_buffer
+    });
+
+    self
+      .render_target_uniforms
+      .insert(target.to_string(), (uniform_buffers, descriptor_sets));
+  }
+
+  pub fn remove_render_target_camera(&mut self, vulkan: &mut Vulkan, target: &str) {
+    if let Some((uniform_buffers, descriptor_sets)) = self.render_target_uniforms.remove(target) {
+      vulkan.destroy_after_frames_in_flight(move |device| {
+        for uniform_buffer in uniform_buffers.iter() {
+          uniform_buffer.destroy(device);
+        }
+        for descriptor_set in descriptor_sets.iter() {
+          descriptor_set.free(device);
+          descriptor_set.destroy(device);
+        }
+      });
+    }
+  }
+
+  /// Draws models seen from `camera` into a render target, call between
+  /// `begin_renderpass_render_target` and `end_renderpass_render_target`.
+  pub fn draw_to_render_target(
+    &mut self,
+    vulkan: &mut Vulkan,
+    target: &str,
+    camera: &Camera,
+    model_data: Vec<(Vec<f32>, String)>,
+  ) {
+    let extent = match vulkan.render_target(target) {
+      Some(render_target) => render_target.extent(),
+      None => return,
+    };
+
+    let frame = vulkan.current_frame();
+    let uniform_descriptor = match self.render_target_uniforms.get_mut(target) {
+      Some((uniform_buffers, descriptor_sets)) => {
+        ModelHandler::write_camera(
+          vulkan,
+          uniform_buffers.get_mut(frame),
+          camera,
+          [extent.width as f32, extent.height as f32],
+        );
+        descriptor_sets.get(frame).clone()
+      }
+      None => return,
+    };
+
+    let frustum = camera.frustum();
+    for (data, model_ref) in model_data {
+      // Render targets see groups from their own camera so don't disturb the screen's levels
+      let model_ref = match self.lod_model(&model_ref, &data, camera, false) {
+        Some(model_ref) => model_ref,
+        None => continue,
+      };
+      let stats = self.draw_with_uniforms(vulkan, data, &model_ref, &uniform_descriptor, &frustum);
+      self.culling_stats.add(stats);
+    }
+    self.environment.draw_skybox(vulkan, camera);
   }
 
   pub fn load_model<T: Into<String>>(&mut self, vulkan: &mut Vulkan, model_ref: T, model: &[u8]) {
     let model_ref = model_ref.into();
+    self.unload_model(vulkan, &model_ref);
 
     let gltf_model = gltf_loader::load_gltf(
       vulkan,
@@ -291,6 +611,69 @@ impl ModelHandler {
     self.models.insert(model_ref, gltf_model);
   }
 
+  pub fn load_parsed_model(&mut self, vulkan: &mut Vulkan, parsed: ParsedGltf) {
+    let model_ref = parsed.reference().to_string();
+    self.unload_model(vulkan, &model_ref);
+
+    let gltf_model = gltf_loader::upload_gltf(vulkan, &self.sampler, &self.dummy_texture, parsed);
+    self.models.insert(model_ref, gltf_model);
+  }
+
+  pub fn unload_model(&mut self, vulkan: &mut Vulkan, model_ref: &str) {
+    if let Some(mut model) = self.models.remove(model_ref) {
+      vulkan.destroy_after_frames_in_flight(move |device| {
+        model.destroy(device);
+      });
+    }
+  }
+
+  pub fn destroy(&mut self, vulkan: &mut Vulkan) {
+    for (_, mut model) in self.models.drain() {
+      model.destroy(vulkan.device());
+    }
+
+    self.mesh_shader.destroy(vulkan.device());
+    self.instanced_mesh_shader.destroy(vulkan.device());
+    for (_, (_, buffers)) in self.instanced_models.drain() {
+      for (buffer, _) in buffers.iter().flatten() {
+        buffer.destroy(vulkan.device());
+      }
+    }
+    for (_, (shader, resources)) in self.custom_pipelines.drain() {
+      shader.destroy(vulkan.device());
+      resources.destroy(vulkan.device());
+    }
+    for uniform_buffer in self.uniform_buffers.iter() {
+      uniform_buffer.destroy(vulkan.device());
+    }
+    for uniform_descriptor_set in self.uniform_descriptor_sets.iter() {
+      uniform_descriptor_set.destroy(vulkan.device());
+    }
+    for (_, (uniform_buffers, descriptor_sets)) in self.render_target_uniforms.drain() {
+      for uniform_buffer in uniform_buffers.iter() {
+        uniform_buffer.destroy(vulkan.device());
+      }
+      for descriptor_set in descriptor_sets.iter() {
+        descriptor_set.destroy(vulkan.device());
+      }
+    }
+    self.storage_descriptor_set.destroy(vulkan.device());
+    self.environment.destroy(vulkan);
+
+    self.mesh_descriptor.destroy(vulkan.device());
+    self.dummy_material.0.destroy(vulkan.device());
+    for image in &self.dummy_material.1 {
+      image.destroy(vulkan.device());
+    }
+    self.dummy_texture.destroy(vulkan.device());
+    self.dummy_skin_buffer.destroy(vulkan.device());
+    self.dummy_skin.destroy(vulkan.device());
+
+    self.descriptor_pool.destroy(vulkan.device());
+
+    self.sampler.destroy(vulkan.device());
+  }
+
   pub fn camera(&self) -> &Camera {
     &self.camera
   }
@@ -306,25 +689,411 @@ impl ModelHandler {
   }
 
   pub fn draw(&mut self, vulkan: &mut Vulkan, data: Vec<f32>, model_ref: &str) {
+    let camera = self.camera.clone();
+    let model_ref = match self.lod_model(model_ref, &data, &camera, true) {
+      Some(model_ref) => model_ref,
+      None => return,
+    };
+    let model_ref = model_ref.as_str();
+
+    if let Some((instances, _)) = self.instanced_models.get_mut(model_ref) {
+      instances.push(data);
+      return;
+    }
+
+    let uniform_descriptor = self.uniform_descriptor_sets.get(vulkan.current_frame());
+    let stats = self.draw_with_uniforms(
+      vulkan,
+      data,
+      model_ref,
+      uniform_descriptor,
+      &self.camera.frustum(),
+    );
+    self.culling_stats.add(stats);
+  }
+
+  pub fn set_frustum_culling(&mut self, enabled: bool) {
+    self.frustum_culling = enabled;
+  }
+
+  /// Primitives drawn and culled since `start_frame`.
+  pub fn culling_stats(&self) -> CullingStats {
+    self.culling_stats
+  }
+
+  /// Resets the culling stats and moves the LOD groups on to a new frame, call before any draws.
+  pub fn start_frame(&mut self) {
+    self.culling_stats = CullingStats::default();
+    for group in self.lod_groups.values_mut() {
+      group.start_frame();
+    }
+  }
+
+  /// Drawing `group_ref` draws one of `levels`, model references with a threshold each, most
+  /// detailed first. Replaces any model or group already drawn with that reference.
+  pub fn create_lod_group(
+    &mut self,
+    group_ref: &str,
+    levels: Vec<(String, f32)>,
+    metric: LodMetric,
+    hysteresis: f32,
+  ) {
+    self.lod_groups.insert(
+      group_ref.to_string(),
+      LodGroup::new(levels, metric, hysteresis),
+    );
+  }
+
+  pub fn remove_lod_group(&mut self, group_ref: &str) {
+    self.lod_groups.remove(group_ref);
+  }
+
+  /// The level drawn for each draw of the group this frame, in draw order.
+  pub fn selected_lods(&self, group_ref: &str) -> Vec<Option<usize>> {
+    self
+      .lod_groups
+      .get(group_ref)
+      .map(|group| group.selected_levels())
+      .unwrap_or_default()
+  }
+
+  /// A line from each node to its parent, where the model is drawn with `data`.
+  pub fn skeleton_lines(nodes: &[Node], data: &[f32]) -> Vec<(Vec3, Vec3)> {
+    let (translation, rotation, scale) = Node::transform_from_data(data);
+    let position = |idx: usize| {
+      Node::calculate_global_matrix(nodes, idx, translation, rotation, scale)
+        .transform_point3(Vec3::ZERO)
+    };
+
+    nodes
+      .iter()
+      .enumerate()
+      .filter(|(_, node)| node.parent >= 0 && (node.parent as usize) < nodes.len())
+      .map(|(idx, node)| (position(node.parent as usize), position(idx)))
+      .collect()
+  }
+
+  pub fn model_nodes(&self, model_ref: &str) -> Option<&Vec<Node>> {
+    self.models.get(model_ref).map(|model| model.nodes())
+  }
+
+  /// Bounds of each of the model's collision objects.
+  pub fn collision_bounds(&self, model_ref: &str) -> Vec<Aabb> {
+    self
+      .models
+      .get(model_ref)
+      .map(|model| {
+        model
+          .collision_info()
+          .objects()
+          .iter()
+          .map(|object| {
+            Aabb::new(
+              Vec3::from(*object.min_bounds()),
+              Vec3::from(*object.max_bounds()),
+            )
+          })
+          .collect()
+      })
+      .unwrap_or_default()
+  }
+
+  /// Radius of the sphere around the model drawn with `scale`, for screen size LOD thresholds.
+  pub fn model_radius(nodes: &[Node], scale: Vec3) -> f32 {
+    nodes
+      .iter()
+      .enumerate()
+      .filter(|(_, node)| !node.mesh.primitives.is_empty())
+      .map(|(idx, node)| {
+        let matrix = Node::calculate_global_matrix(nodes, idx, Vec3::ZERO, Quat::IDENTITY, scale);
+        node
+          .mesh
+          .primitives
+          .iter()
+          .map(|primitive| primitive.bounds().transform(&matrix))
+          .reduce(|a, b| a.union(&b))
+          .unwrap()
+      })
+      .reduce(|a, b| a.union(&b))
+      .map(|bounds| bounds.bounding_sphere().1)
+      .unwrap_or(0.0)
+  }
+
+  // The model a LOD group draws from `camera`, other references are drawn as they are
+  fn lod_model(
+    &mut self,
+    model_ref: &str,
+    data: &[f32],
+    camera: &Camera,
+    track: bool,
+  ) -> Option<String> {
+    let group = match self.lod_groups.get_mut(model_ref) {
+      Some(group) => group,
+      None => return Some(model_ref.to_string()),
+    };
+
+    let (translation, _, scale) = Node::transform_from_data(data);
+    let distance = camera.eye_position().distance(translation);
+    let models = &self.models;
+    let radius = group
+      .levels()
+      .first()
+      .and_then(|(model_ref, _)| models.get(model_ref))
+      .map_or(0.0, |model| {
+        ModelHandler::model_radius(model.nodes(), scale)
+      });
+    let projection_scale = camera.perspective_matrix()[5].abs();
+
+    if track {
+      group.select(distance, radius, projection_scale)
+    } else {
+      group.model_at(distance, radius, projection_scale)
+    }
+    .map(|model_ref| model_ref.to_string())
+  }
+
+  /// Draws of the model are collected and drawn together by `draw_instanced_models`, with the
+  /// default mesh pipeline.
+  pub fn instance_render_model(&mut self, vulkan: &Vulkan, model_ref: &str) {
+    if !self.instanced_models.contains_key(model_ref) {
+      self.instanced_models.insert(
+        model_ref.to_string(),
+        (
+          Vec::new(),
+          PerFrame::new(vulkan.frames_in_flight(), |_| Vec::new()),
+        ),
+      );
+    }
+  }
+
+  pub fn stop_instance_render_model(&mut self, vulkan: &mut Vulkan, model_ref: &str) {
+    if let Some((_, buffers)) = self.instanced_models.remove(model_ref) {
+      vulkan.destroy_after_frames_in_flight(move |device| {
+        for (buffer, _) in buffers.iter().flatten() {
+          buffer.destroy(device);
+        }
+      });
+    }
+  }
+
+  /// The transform of each node with a mesh for every instance, one node after another so each
+  /// primitive's instances are together. Draw data past the transform starting at 12 is the
+  /// instance colour, white if it isn't given.
+  pub fn instance_data(nodes: &[Node], instances: &[Vec<f32>]) -> Vec<InstancedMeshData> {
+    let mut instance_data = Vec::new();
+    for idx in 0..nodes.len() {
+      if nodes[idx].mesh.primitives.is_empty() {
+        continue;
+      }
+
+      for data in instances {
+        let (translation, rotation, scale) = Node::transform_from_data(data);
+        let mut colour = [1.0; 4];
+        for (i, value) in data.iter().skip(12).take(4).enumerate() {
+          colour[i] = *value;
+        }
+
+        instance_data.push(InstancedMeshData {
+          model: Node::calculate_global_matrix(nodes, idx, translation, rotation, scale)
+            .to_cols_array(),
+          colour,
+        });
+      }
+    }
+
+    instance_data
+  }
+
+  /// Draws the instances added since the last call, one draw per primitive for each model.
+  pub fn draw_instanced_models(&mut self, vulkan: &mut Vulkan) {
+    let frame = vulkan.current_frame();
+    let uniform_descriptor = self.uniform_descriptor_sets.get(frame);
+
+    let frustum = self.camera.frustum();
+    for (model_ref, (instances, buffers)) in &mut self.instanced_models {
+      let mut instances = mem::take(instances);
+      let model = match self.models.get(model_ref) {
+        Some(model) => model,
+        None => continue,
+      };
+
+      // Instances are culled whole, counted as all their primitives
+      let primitives = model
+        .nodes()
+        .iter()
+        .map(|node| {
+          node
+            .mesh
+            .primitives
+            .iter()
+            .filter(|primitive| primitive.index_count > 0)
+            .count()
+        })
+        .sum::<usize>();
+
+      let count = instances.len();
+      if self.frustum_culling {
+        instances.retain(|data| ModelHandler::model_visible(&frustum, model.nodes(), data));
+      }
+      self.culling_stats.add(CullingStats {
+        drawn: instances.len() * primitives,
+        culled: (count - instances.len()) * primitives,
+      });
+
+      if instances.is_empty() {
+        continue;
+      }
+
+      // Split into draws of at most MAX_INSTANCES, each with its own buffer
+      let buffers = buffers.get_mut(frame);
+      for (i, chunk) in instances.chunks(MAX_INSTANCES).enumerate() {
+        let instance_data = ModelHandler::instance_data(model.nodes(), chunk);
+        if buffers
+          .get(i)
+          .is_none_or(|(_, capacity)| *capacity < instance_data.len())
+        {
+          let capacity = instance_data.len().next_power_of_two();
+          let new_buffer = Buffer::<InstancedMeshData>::new_vertex(
+            vulkan.device(),
+            vec![InstancedMeshData::new(); capacity],
+          );
+          if i < buffers.len() {
+            let (old_buffer, _) = mem::replace(&mut buffers[i], (new_buffer, capacity));
+            vulkan.destroy_after_frames_in_flight(move |device| {
+              old_buffer.destroy(device);
+            });
+          } else {
+            buffers.push((new_buffer, capacity));
+          }
+        }
+
+        let (buffer, _) = &mut buffers[i];
+        buffer.update_data(vulkan.device(), instance_data);
+
+        vulkan.draw_mesh_instanced(
+          &self.instanced_mesh_shader,
+          &self.mesh_descriptor,
+          uniform_descriptor,
+          &self.dummy_skin,
+          self.environment.lighting_descriptor(),
+          buffer,
+          chunk.len(),
+          model,
+        );
+      }
+    }
+  }
+
+  /// Draws the sky behind the screen's models, call after the last model.
+  pub fn draw_skybox(&self, vulkan: &mut Vulkan) {
+    self.environment.draw_skybox(vulkan, &self.camera);
+  }
+
+  pub fn environment(&self) -> &EnvironmentHandler {
+    &self.environment
+  }
+
+  pub fn mut_environment(&mut self) -> &mut EnvironmentHandler {
+    &mut self.environment
+  }
+
+  fn draw_with_uniforms(
+    &self,
+    vulkan: &mut Vulkan,
+    data: Vec<f32>,
+    model_ref: &str,
+    uniform_descriptor: &DescriptorSet,
+    frustum: &Frustum,
+  ) -> CullingStats {
     if let Some(model) = &self.models.get(model_ref) {
+      let mut shader = &self.mesh_shader;
+      if let Some((custom_shader, resources)) =
+        self.model_pipelines.get(model_ref).and_then(|pipeline| {
+          self
+            .custom_pipelines
+            .get(pipeline)
+            .filter(|(_, resources)| resources.ready_for_draw(pipeline))
+        })
+      {
+        vulkan.bind_descriptor_sets(
+          custom_shader,
+          resources.first_set(),
+          resources.descriptor_sets(vulkan.current_frame()),
+        );
+        shader = custom_shader;
+      }
+
       vulkan.draw_mesh(
-        &self.mesh_shader,
+        shader,
         &self.mesh_descriptor,
-        &self.uniform_descriptor_set,
+        uniform_descriptor,
         &self.dummy_skin,
+        self.environment.lighting_descriptor(),
         data,
         model,
-      );
+        Some(frustum).filter(|_| self.frustum_culling),
+      )
+    } else {
+      CullingStats::default()
     }
   }
 
-  fn create_mesh_pipeline_builder(mode: DrawMode) -> GraphicsPipelineBuilder {
+  /// Whether any of a model's meshes drawn with `data` are in view, skinned meshes always are.
+  pub fn model_visible(frustum: &Frustum, nodes: &[Node], data: &[f32]) -> bool {
+    let (translation, rotation, scale) = Node::transform_from_data(data);
+
+    nodes.iter().enumerate().any(|(idx, node)| {
+      if node.mesh.primitives.is_empty() {
+        return false;
+      }
+      if node.skin != -1 {
+        return true;
+      }
+
+      let matrix = Node::calculate_global_matrix(nodes, idx, translation, rotation, scale);
+      let bounds = node
+        .mesh
+        .primitives
+        .iter()
+        .map(|primitive| primitive.bounds())
+        .reduce(|a, b| a.union(&b))
+        .unwrap();
+      frustum.intersects_aabb(&bounds.transform(&matrix))
+    })
+  }
+
+  fn template_mesh_vertex() -> MeshVertex {
+    MeshVertex {
+      pos: [0.0, 0.0, 0.0],
+      normal: [0.0, 0.0, 0.0],
+      uv: [0.0, 0.0],
+      colour: [0.0, 0.0, 0.0],
+      joint_indices: [0.0, 0.0, 0.0, 0.0],
+      joint_weights: [1.0, 1.0, 1.0, 1.0],
+    }
+  }
+
+  fn mesh_vertex_offsets() -> Vec<u32> {
+    vec![
+      offset_of!(MeshVertex, pos) as u32,
+      offset_of!(MeshVertex, normal) as u32,
+      offset_of!(MeshVertex, uv) as u32,
+      offset_of!(MeshVertex, colour) as u32,
+      offset_of!(MeshVertex, joint_indices) as u32,
+      offset_of!(MeshVertex, joint_weights) as u32,
+    ]
+  }
+
+  fn create_mesh_pipeline_builder(
+    mode: DrawMode,
+    samples: vk::SampleCountFlags,
+  ) -> GraphicsPipelineBuilder {
     let mut gpb = GraphicsPipelineBuilder::new()
       .topology_triangle_list()
       .polygon_mode_fill()
       .front_face_counter_clockwise()
       .cull_front()
-      .samples_1();
+      .samples(samples);
     gpb = {
       match mode {
         DrawMode::Polygon => gpb.polygon_mode_fill(),
@@ -340,48 +1109,73 @@ impl ModelHandler {
     vulkan: &Vulkan,
     draw_mode: DrawMode,
     descriptor_sets: Vec<DescriptorSet>,
-  ) -> Shader<MeshVertex> {
-    let template_mesh_vertex = MeshVertex {
-      pos: [0.0, 0.0, 0.0],
-      normal: [0.0, 0.0, 0.0],
-      uv: [0.0, 0.0],
-      colour: [0.0, 0.0, 0.0],
-      joint_indices: [0.0, 0.0, 0.0, 0.0],
-      joint_weights: [1.0, 1.0, 1.0, 1.0],
-    };
-
-    let graphics_pipeline_builder = ModelHandler::create_mesh_pipeline_builder(draw_mode);
-
-    let layouts = {
-      let mut sets = Vec::new();
-      for i in 0..descriptor_sets.len() {
-        sets.push(descriptor_sets[i].layouts()[0]);
-      }
+    shader_directory: Option<&str>,
+  ) -> Result<MeshShaders, String> {
+    let graphics_pipeline_builder =
+      ModelHandler::create_mesh_pipeline_builder(draw_mode, vulkan.msaa_samples());
 
-      sets
-    };
+    let descriptor_sets = descriptor_sets.iter().collect::<Vec<_>>();
 
-    let mesh_shader = Shader::new(
+    let mesh_shader = Shader::try_new(
       vulkan.device(),
-      Cursor::new(&include_bytes!("../../shaders/mesh_animated_vert.spv")[..]),
-      Cursor::new(&include_bytes!("../../shaders/mesh_pbr_frag.spv")[..]),
-      template_mesh_vertex,
-      vec![
-        offset_of!(MeshVertex, pos) as u32,
-        offset_of!(MeshVertex, normal) as u32,
-        offset_of!(MeshVertex, uv) as u32,
-        offset_of!(MeshVertex, colour) as u32,
-        offset_of!(MeshVertex, joint_indices) as u32,
-        offset_of!(MeshVertex, joint_weights) as u32,
-      ],
+      shader_source(
+        shader_directory,
+        "mesh_animated_vert.spv",
+        include_bytes!("../../shaders/mesh_animated_vert.spv"),
+      ),
+      shader_source(
+        shader_directory,
+        "mesh_pbr_frag.spv",
+        include_bytes!("../../shaders/mesh_pbr_frag.spv"),
+      ),
+      ModelHandler::template_mesh_vertex(),
+      ModelHandler::mesh_vertex_offsets(),
       &graphics_pipeline_builder,
       vulkan.model_renderpass(),
       vulkan.viewports(),
       vulkan.scissors(),
-      &layouts,
+      &descriptor_sets,
       None as Option<(u32, Vec<u32>)>,
+    )?;
+
+    // The model matrix takes a location for each column
+    let model_offset = offset_of!(InstancedMeshData, model) as u32;
+    let instanced_offsets = vec![
+      model_offset,
+      model_offset + 16,
+      model_offset + 32,
+      model_offset + 48,
+      offset_of!(InstancedMeshData, colour) as u32,
+    ];
+
+    let instanced_mesh_shader = Shader::try_new(
+      vulkan.device(),
+      shader_source(
+        shader_directory,
+        "instanced_mesh_animated_vert.spv",
+        include_bytes!("../../shaders/instanced_mesh_animated_vert.spv"),
+      ),
+      shader_source(
+        shader_directory,
+        "mesh_pbr_frag.spv",
+        include_bytes!("../../shaders/mesh_pbr_frag.spv"),
+      ),
+      ModelHandler::template_mesh_vertex(),
+      ModelHandler::mesh_vertex_offsets(),
+      &graphics_pipeline_builder,
+      vulkan.model_renderpass(),
+      vulkan.viewports(),
+      vulkan.scissors(),
+      &descriptor_sets,
+      Some((InstancedMeshData::new(), instanced_offsets)),
     );
 
-    mesh_shader
+    match instanced_mesh_shader {
+      Ok(instanced_mesh_shader) => Ok((mesh_shader, instanced_mesh_shader)),
+      Err(e) => {
+        mesh_shader.destroy(vulkan.device());
+        Err(e)
+      }
+    }
   }
 }
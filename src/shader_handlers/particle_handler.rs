@@ -0,0 +1,621 @@
+use std::collections::HashMap;
+use std::mem;
+
+use ash::util::read_spv;
+use ash::vk;
+
+use glam::{Vec2, Vec4};
+
+use crate::extra::shader_source;
+use crate::shader_handlers::texture_handler::InstancedComboData;
+use crate::vkwrapper::{
+  Buffer, ComputeShader, DescriptorAllocator, DescriptorPoolBuilder, DescriptorSet,
+  DescriptorWriter, PerFrame, VkDevice, Vulkan,
+};
+
+// Matches local_size_x in particles.comp
+const PARTICLE_LOCAL_SIZE: u32 = 64;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Particle {
+  pub pos_vel: [f32; 4],      // x, y, velocity x, velocity y
+  pub age_lifetime: [f32; 4], // age, lifetime, empty
+}
+
+impl Particle {
+  pub fn new() -> Particle {
+    Particle {
+      pos_vel: [0.0; 4],
+      age_lifetime: [0.0; 4],
+    }
+  }
+
+  pub fn is_alive(&self) -> bool {
+    self.age_lifetime[0] < self.age_lifetime[1]
+  }
+}
+
+/// The inputs of one simulation step, enough to repeat it on the CPU with
+/// `ParticleEmitter::simulate`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParticleStep {
+  pub first_slot: u32,
+  pub spawn_count: u32,
+  pub first_id: u32,
+  pub delta_time: f32,
+  pub time: f32,
+  pub camera: Vec2,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct EmitterPushConstants {
+  position_direction: [f32; 4], // x, y, direction, spread
+  speed_lifetime: [f32; 4],     // min speed, max speed, min lifetime, max lifetime
+  gravity_size: [f32; 4],       // gravity x y, start size, end size
+  start_colour: [f32; 4],
+  end_colour: [f32; 4],
+  sprite_camera: [f32; 4], // sprite sheet rows, frames, camera x y
+  delta_time: [f32; 4],    // delta time, time, empty
+  spawn: [u32; 4],         // first slot, count, capacity, first particle id
+}
+
+/// Emitter settings, directions and spreads are in degrees like `Draw::rotation`.
+#[derive(Clone, Debug)]
+pub struct ParticleEmitter {
+  texture: String,
+  position: Vec2,
+  rate: f32,
+  lifetime: Vec2,
+  direction: f32,
+  spread: f32,
+  speed: Vec2,
+  gravity: Vec2,
+  start_colour: Vec4,
+  end_colour: Vec4,
+  start_size: f32,
+  end_size: f32,
+  sprite_rows: u32,
+  sprite_frames: u32,
+  capacity: u32,
+}
+
+impl ParticleEmitter {
+  pub fn new(texture: &str) -> ParticleEmitter {
+    ParticleEmitter {
+      texture: texture.to_string(),
+      position: Vec2::ZERO,
+      rate: 10.0,
+      lifetime: Vec2::ONE,
+      direction: 90.0,
+      spread: 0.0,
+      speed: Vec2::splat(100.0),
+      gravity: Vec2::ZERO,
+      start_colour: Vec4::ONE,
+      end_colour: Vec4::new(1.0, 1.0, 1.0, 0.0),
+      start_size: 16.0,
+      end_size: 16.0,
+      sprite_rows: 1,
+      sprite_frames: 1,
+      capacity: 1024,
+    }
+  }
+
+  pub fn position(mut self, position: Vec2) -> ParticleEmitter {
+    self.position = position;
+    self
+  }
+
+  /// Particles spawned per second.
+  pub fn rate(mut self, rate: f32) -> ParticleEmitter {
+    self.rate = rate.max(0.0);
+    self
+  }
+
+  pub fn lifetime(mut self, min: f32, max: f32) -> ParticleEmitter {
+    self.lifetime = Vec2::new(min, max);
+    self
+  }
+
+  /// Particles leave within `spread` degrees centred on `direction`.
+  pub fn direction(mut self, direction: f32, spread: f32) -> ParticleEmitter {
+    self.direction = direction;
+    self.spread = spread;
+    self
+  }
+
+  pub fn speed(mut self, min: f32, max: f32) -> ParticleEmitter {
+    self.speed = Vec2::new(min, max);
+    self
+  }
+
+  pub fn gravity(mut self, gravity: Vec2) -> ParticleEmitter {
+    self.gravity = gravity;
+    self
+  }
+
+  pub fn colour(mut self, start: Vec4, end: Vec4) -> ParticleEmitter {
+    self.start_colour = start;
+    self.end_colour = end;
+    self
+  }
+
+  pub fn size(mut self, start: f32, end: f32) -> ParticleEmitter {
+    self.start_size = start;
+    self.end_size = end;
+    self
+  }
+
+  /// Plays the first `frames` images of a `rows` by `rows` sprite sheet over each lifetime.
+  pub fn sprite_sheet(mut self, rows: usize, frames: usize) -> ParticleEmitter {
+    self.sprite_rows = rows.max(1) as u32;
+    self.sprite_frames = frames.max(1) as u32;
+    self
+  }
+
+  /// The most particles alive at once, the oldest are replaced when it is reached.
+  pub fn capacity(mut self, capacity: usize) -> ParticleEmitter {
+    self.capacity = capacity.max(1) as u32;
+    self
+  }
+
+  pub fn get_texture(&self) -> &str {
+    &self.texture
+  }
+
+  pub fn get_position(&self) -> Vec2 {
+    self.position
+  }
+
+  pub fn get_capacity(&self) -> u32 {
+    self.capacity
+  }
+
+  pub fn hash(value: u32) -> u32 {
+    let state = value.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+  }
+
+  /// The same random number in [0, 1] the compute shader draws for `value`.
+  pub fn random(value: u32) -> f32 {
+    ParticleEmitter::hash(value) as f32 * (1.0 / 4294967296.0)
+  }
+
+  // GLSL mix
+  fn mix(x: f32, y: f32, a: f32) -> f32 {
+    x * (1.0 - a) + y * a
+  }
+
+  /// A new particle, its direction, speed and lifetime are picked from the particle id so the
+  /// GPU and the CPU agree.
+  pub fn spawn(&self, id: u32) -> Particle {
+    let seed = id.wrapping_mul(3);
+    let angle = self.direction.to_radians()
+      + (ParticleEmitter::random(seed) - 0.5) * self.spread.to_radians();
+    let speed = ParticleEmitter::mix(
+      self.speed.x,
+      self.speed.y,
+      ParticleEmitter::random(seed.wrapping_add(1)),
+    );
+    let lifetime = ParticleEmitter::mix(
+      self.lifetime.x,
+      self.lifetime.y,
+      ParticleEmitter::random(seed.wrapping_add(2)),
+    );
+
+    Particle {
+      pos_vel: [
+        self.position.x,
+        self.position.y,
+        angle.cos() * speed,
+        angle.sin() * speed,
+      ],
+      age_lifetime: [0.0, lifetime, 0.0, 0.0],
+    }
+  }
+
+  /// Ages the particle and moves it with semi-implicit Euler, dead particles are left alone.
+  pub fn advance(&self, particle: &Particle, delta_time: f32) -> Particle {
+    if !particle.is_alive() {
+      return *particle;
+    }
+
+    let [x, y, vx, vy] = particle.pos_vel;
+    let vx = vx + self.gravity.x * delta_time;
+    let vy = vy + self.gravity.y * delta_time;
+
+    Particle {
+      pos_vel: [x + vx * delta_time, y + vy * delta_time, vx, vy],
+      age_lifetime: [
+        particle.age_lifetime[0] + delta_time,
+        particle.age_lifetime[1],
+        0.0,
+        0.0,
+      ],
+    }
+  }
+
+  /// The sprite drawn for a particle, dead particles get a zero sized one.
+  pub(crate) fn instance(
+    &self,
+    particle: &Particle,
+    camera: Vec2,
+    time: f32,
+  ) -> InstancedComboData {
+    let mut instance = InstancedComboData::new();
+
+    if !particle.is_alive() {
+      instance.sprite_sheet = [1.0, 0.0, 0.0, 0.0];
+      return instance;
+    }
+
+    let t = (particle.age_lifetime[0] / particle.age_lifetime[1]).clamp(0.0, 1.0);
+    let size = ParticleEmitter::mix(self.start_size, self.end_size, t);
+    let frames = self.sprite_frames as f32;
+    let colour = self.start_colour * (1.0 - t) + self.end_colour * t;
+
+    instance.pos_scale = [
+      particle.pos_vel[0] - size * 0.5,
+      particle.pos_vel[1] - size * 0.5,
+      size,
+      size,
+    ];
+    instance.other_colour = colour.to_array();
+    instance.is_textured_rotation_overlay_mix = [1.0, 0.0, 0.0, 0.0];
+    instance.sprite_sheet = [
+      self.sprite_rows as f32,
+      (t * frames).floor().min(frames - 1.0),
+      0.0,
+      0.0,
+    ];
+    instance.camera_intensity_time = [camera.x, camera.y, -1.0, time];
+
+    instance
+  }
+
+  /// Runs one step of particles.comp on the CPU, the slot count is the emitter capacity.
+  pub fn simulate(&self, particles: &mut [Particle], step: &ParticleStep) {
+    let capacity = particles.len() as u32;
+
+    for (i, particle) in particles.iter_mut().enumerate() {
+      let spawn_offset = (i as u32 + capacity - step.first_slot) % capacity;
+
+      *particle = if spawn_offset < step.spawn_count {
+        self.spawn(step.first_id.wrapping_add(spawn_offset))
+      } else {
+        self.advance(particle, step.delta_time)
+      };
+    }
+  }
+
+  fn push_constants(&self, step: &ParticleStep) -> EmitterPushConstants {
+    EmitterPushConstants {
+      position_direction: [
+        self.position.x,
+        self.position.y,
+        self.direction.to_radians(),
+        self.spread.to_radians(),
+      ],
+      speed_lifetime: [self.speed.x, self.speed.y, self.lifetime.x, self.lifetime.y],
+      gravity_size: [
+        self.gravity.x,
+        self.gravity.y,
+        self.start_size,
+        self.end_size,
+      ],
+      start_colour: self.start_colour.to_array(),
+      end_colour: self.end_colour.to_array(),
+      sprite_camera: [
+        self.sprite_rows as f32,
+        self.sprite_frames as f32,
+        step.camera.x,
+        step.camera.y,
+      ],
+      delta_time: [step.delta_time, step.time, 0.0, 0.0],
+      spawn: [
+        step.first_slot,
+        step.spawn_count,
+        self.capacity,
+        step.first_id,
+      ],
+    }
+  }
+}
+
+/// Where the next particles go in an emitter's ring buffer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpawnState {
+  pub accumulator: f32,
+  pub next_slot: u32,
+  pub next_id: u32,
+}
+
+impl SpawnState {
+  pub fn new() -> SpawnState {
+    SpawnState {
+      accumulator: 0.0,
+      next_slot: 0,
+      next_id: 0,
+    }
+  }
+
+  /// Spawns the whole particles due after `delta_time`, carrying the fraction over to the next
+  /// step. Returns the first slot, count and first particle id.
+  pub fn spawn(&mut self, rate: f32, delta_time: f32, capacity: u32) -> (u32, u32, u32) {
+    self.accumulator += rate * delta_time.max(0.0);
+    let due = self.accumulator.floor();
+    self.accumulator -= due;
+
+    let count = (due as u32).min(capacity);
+    let spawned = (self.next_slot, count, self.next_id);
+
+    self.next_slot = (self.next_slot + count) % capacity;
+    self.next_id = self.next_id.wrapping_add(count);
+
+    spawned
+  }
+}
+
+struct EmitterResources {
+  emitter: ParticleEmitter,
+  spawn_state: SpawnState,
+  pending_delta_time: f32,
+  last_step: Option<ParticleStep>,
+  particles: Buffer<Particle>,
+  instances: PerFrame<Buffer<InstancedComboData>>,
+  descriptor_sets: PerFrame<DescriptorSet>,
+}
+
+impl EmitterResources {
+  fn destroy(&self, device: &VkDevice) {
+    self.particles.destroy(device);
+    for (instances, descriptor_set) in self.instances.iter().zip(self.descriptor_sets.iter()) {
+      instances.destroy(device);
+      descriptor_set.free(device);
+      descriptor_set.destroy(device);
+    }
+  }
+}
+
+pub struct ParticleHandler {
+  descriptor_pool: DescriptorAllocator,
+  layout_descriptor: DescriptorSet,
+  compute_shader: ComputeShader,
+  emitters: HashMap<String, EmitterResources>,
+}
+
+impl ParticleHandler {
+  pub fn new(vulkan: &mut Vulkan) -> ParticleHandler {
+    let descriptor_pool = DescriptorPoolBuilder::new()
+      .num_storage(16)
+      .free_individual_sets()
+      .build(vulkan.device());
+
+    let layout_descriptor = ParticleHandler::descriptor_set(vulkan.device(), &descriptor_pool);
+
+    let compute_shader = ParticleHandler::create_shader(vulkan, &layout_descriptor, None)
+      .unwrap_or_else(|e| panic!("{}", e));
+
+    ParticleHandler {
+      descriptor_pool,
+      layout_descriptor,
+      compute_shader,
+      emitters: HashMap::new(),
+    }
+  }
+
+  fn descriptor_set(device: &VkDevice, descriptor_pool: &DescriptorAllocator) -> DescriptorSet {
+    DescriptorSet::builder()
+      .storage_compute() // particles
+      .storage_compute() // instances
+      .build(device, descriptor_pool)
+  }
+
+  fn create_shader(
+    vulkan: &Vulkan,
+    layout_descriptor: &DescriptorSet,
+    shader_directory: Option<&str>,
+  ) -> Result<ComputeShader, String> {
+    let code = read_spv(&mut shader_source(
+      shader_directory,
+      "particles_comp.spv",
+      include_bytes!("../../shaders/particles_comp.spv"),
+    ))
+    .map_err(|e| format!("Failed to read particle shader: {}", e))?;
+
+    let push_constant_range = vk::PushConstantRange::builder()
+      .stage_flags(vk::ShaderStageFlags::COMPUTE)
+      .offset(0)
+      .size(mem::size_of::<EmitterPushConstants>() as u32)
+      .build();
+
+    ComputeShader::try_new_with_layouts(
+      vulkan.device(),
+      &code,
+      &[layout_descriptor.layouts()[0]],
+      Some(push_constant_range),
+    )
+  }
+
+  pub fn reload_shaders(&mut self, vulkan: &mut Vulkan, shader_directory: Option<&str>) {
+    match ParticleHandler::create_shader(vulkan, &self.layout_descriptor, shader_directory) {
+      Ok(compute_shader) => {
+        let old_shader = mem::replace(&mut self.compute_shader, compute_shader);
+        vulkan.destroy_after_frames_in_flight(move |device| {
+          old_shader.destroy(device);
+        });
+      }
+      Err(e) => println!("Failed to reload particle shader: {}", e),
+    }
+  }
+
+  /// Replaces an emitter with the same name. The particles alive are kept when the capacity
+  /// doesn't change.
+  pub fn create_emitter(
+    &mut self,
+    vulkan: &mut Vulkan,
+    emitter_ref: &str,
+    emitter: ParticleEmitter,
+  ) {
+    if let Some(resources) = self.emitters.get_mut(emitter_ref) {
+      if resources.emitter.capacity == emitter.capacity {
+        resources.emitter = emitter;
+        return;
+      }
+    }
+
+    self.remove_emitter(vulkan, emitter_ref);
+
+    let device = vulkan.device();
+    let capacity = emitter.capacity as usize;
+
+    let particles = Buffer::new_generic(
+      device,
+      vec![Particle::new(); capacity],
+      vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+      vk::BufferUsageFlags::STORAGE_BUFFER,
+    );
+
+    let instances = PerFrame::new(vulkan.frames_in_flight(), |_| {
+      Buffer::new_generic(
+        device,
+        vec![InstancedComboData::new(); capacity],
+        vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::VERTEX_BUFFER,
+      )
+    });
+
+    let descriptor_sets = PerFrame::new(vulkan.frames_in_flight(), |frame| {
+      let descriptor_set = ParticleHandler::descriptor_set(device, &self.descriptor_pool);
+      DescriptorWriter::builder()
+        .update_buffer(&particles, &descriptor_set)
+        .update_buffer(instances.get(frame), &descriptor_set)
+        .build(device);
+
+      descriptor_set
+    });
+
+    self.emitters.insert(
+      emitter_ref.to_string(),
+      EmitterResources {
+        emitter,
+        spawn_state: SpawnState::new(),
+        pending_delta_time: 0.0,
+        last_step: None,
+        particles,
+        instances,
+        descriptor_sets,
+      },
+    );
+  }
+
+  pub fn remove_emitter(&mut self, vulkan: &mut Vulkan, emitter_ref: &str) {
+    if let Some(resources) = self.emitters.remove(emitter_ref) {
+      vulkan.destroy_after_frames_in_flight(move |device| {
+        resources.destroy(device);
+      });
+    }
+  }
+
+  pub fn set_emitter_position(&mut self, emitter_ref: &str, position: Vec2) {
+    if let Some(resources) = self.emitters.get_mut(emitter_ref) {
+      resources.emitter.position = position;
+    }
+  }
+
+  pub fn emitter(&self, emitter_ref: &str) -> Option<&ParticleEmitter> {
+    self
+      .emitters
+      .get(emitter_ref)
+      .map(|resources| &resources.emitter)
+  }
+
+  /// Time to simulate on the next frame.
+  pub fn update(&mut self, delta_time: f32) {
+    for resources in self.emitters.values_mut() {
+      resources.pending_delta_time += delta_time;
+    }
+  }
+
+  /// Records every emitter's step into the frame, must be called before the render passes begin.
+  pub fn simulate(&mut self, vulkan: &mut Vulkan, camera: Vec2, time: f32) {
+    let frame = vulkan.current_frame();
+
+    for resources in self.emitters.values_mut() {
+      let delta_time = mem::take(&mut resources.pending_delta_time);
+      let capacity = resources.emitter.capacity;
+      let (first_slot, spawn_count, first_id) =
+        resources
+          .spawn_state
+          .spawn(resources.emitter.rate, delta_time, capacity);
+
+      let step = ParticleStep {
+        first_slot,
+        spawn_count,
+        first_id,
+        delta_time,
+        time,
+        camera,
+      };
+
+      let push_constants = resources.emitter.push_constants(&step);
+      vulkan.dispatch_before_draw(
+        &self.compute_shader,
+        resources.descriptor_sets.get(frame),
+        &[push_constants],
+        capacity.div_ceil(PARTICLE_LOCAL_SIZE),
+      );
+
+      resources.last_step = Some(step);
+    }
+  }
+
+  /// The texture, instance buffer and instance count to draw an emitter with this frame.
+  pub fn draw_data(
+    &self,
+    emitter_ref: &str,
+    frame: usize,
+  ) -> Option<(&str, &Buffer<InstancedComboData>, usize)> {
+    self.emitters.get(emitter_ref).map(|resources| {
+      (
+        resources.emitter.get_texture(),
+        resources.instances.get(frame),
+        resources.emitter.capacity as usize,
+      )
+    })
+  }
+
+  /// Waits for the GPU and reads the particles back along with the step that produced them.
+  pub fn read_particles(
+    &self,
+    vulkan: &Vulkan,
+    emitter_ref: &str,
+  ) -> Option<(Vec<Particle>, Option<ParticleStep>)> {
+    self.emitters.get(emitter_ref).map(|resources| {
+      unsafe {
+        vulkan
+          .device()
+          .internal()
+          .device_wait_idle()
+          .expect("Failed to wait for device");
+      }
+
+      (
+        resources.particles.retrieve_buffer_data(vulkan.device()),
+        resources.last_step,
+      )
+    })
+  }
+
+  pub fn destroy(&mut self, vulkan: &mut Vulkan) {
+    for (_, resources) in self.emitters.drain() {
+      resources.destroy(vulkan.device());
+    }
+
+    self.compute_shader.destroy(vulkan.device());
+    self.layout_descriptor.destroy(vulkan.device());
+    self.descriptor_pool.destroy(vulkan.device());
+  }
+}
@@ -0,0 +1,636 @@
+use std::mem;
+
+use ash::util::read_spv;
+use ash::vk;
+
+use glam::{Vec2, Vec3};
+
+use crate::extra::shader_source;
+use crate::shader_handlers::TextureHandler;
+use crate::vkwrapper::{
+  ComputeShader, DescriptorAllocator, DescriptorPoolBuilder, DescriptorSet, DescriptorWriter,
+  GraphicsPipelineBuilder, Image, ImageBuilder, Sampler, Shader, VkDevice, Vulkan,
+};
+
+// Matches local_size_x and local_size_y in the bloom shaders
+const BLOOM_LOCAL_SIZE: u32 = 8;
+pub const BLOOM_MIPS: usize = 5;
+const IDENTITY_LUT_SIZE: u32 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneMapping {
+  None, // only clamps to the displayable range
+  Reinhard,
+  Aces,
+}
+
+impl ToneMapping {
+  fn shader_value(self) -> f32 {
+    match self {
+      ToneMapping::None => 0.0,
+      ToneMapping::Reinhard => 1.0,
+      ToneMapping::Aces => 2.0,
+    }
+  }
+
+  /// Same curves as post_composite.frag.
+  pub fn apply(self, colour: Vec3) -> Vec3 {
+    let colour = colour.max(Vec3::ZERO);
+    match self {
+      ToneMapping::None => colour.min(Vec3::ONE),
+      ToneMapping::Reinhard => colour / (Vec3::ONE + colour),
+      ToneMapping::Aces => ((colour * (2.51 * colour + 0.03))
+        / (colour * (2.43 * colour + 0.59) + 0.14))
+        .clamp(Vec3::ZERO, Vec3::ONE),
+    }
+  }
+}
+
+/// Everything off matches rendering straight to the swapchain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PostProcessSettings {
+  pub bloom: bool,
+  pub bloom_threshold: f32,
+  pub bloom_intensity: f32,
+  pub exposure: bool,
+  pub exposure_multiplier: f32,
+  pub tone_mapping: ToneMapping,
+  pub colour_grading: bool,
+}
+
+impl Default for PostProcessSettings {
+  fn default() -> PostProcessSettings {
+    PostProcessSettings {
+      bloom: false,
+      bloom_threshold: 1.0,
+      bloom_intensity: 0.05,
+      exposure: false,
+      exposure_multiplier: 1.0,
+      tone_mapping: ToneMapping::None,
+      colour_grading: false,
+    }
+  }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct BloomPushConstants {
+  size: [f32; 4],      // destination width, height, source texel width, height
+  threshold: [f32; 4], // brightness threshold, 1 when the threshold is applied, empty
+}
+
+// Screen sized resources, rebuilt when the HDR image is
+struct PostTargets {
+  render_target_version: u64,
+  bloom_mips: Vec<Image>,
+  downsample_sets: Vec<DescriptorSet>,
+  upsample_sets: Vec<DescriptorSet>,
+  composite_set: DescriptorSet,
+}
+
+impl PostTargets {
+  fn destroy(&self, device: &VkDevice) {
+    for image in &self.bloom_mips {
+      image.destroy(device);
+    }
+
+    for descriptor_set in self
+      .downsample_sets
+      .iter()
+      .chain(self.upsample_sets.iter())
+      .chain(std::iter::once(&self.composite_set))
+    {
+      descriptor_set.free(device);
+      descriptor_set.destroy(device);
+    }
+  }
+}
+
+/// Bloom, exposure, tone mapping and colour grading applied to the HDR image the model pass
+/// renders into, before the texture pass draws over the result.
+pub struct PostProcessHandler {
+  settings: PostProcessSettings,
+  descriptor_pool: DescriptorAllocator,
+  sampler: Sampler,
+  bloom_layout: DescriptorSet,
+  composite_layout: DescriptorSet,
+  downsample_shader: ComputeShader,
+  upsample_shader: ComputeShader,
+  composite_shader: Shader<u32>,
+  lut: Image,
+  lut_size: u32,
+  targets: Option<PostTargets>,
+}
+
+impl PostProcessHandler {
+  pub fn new(vulkan: &mut Vulkan) -> PostProcessHandler {
+    let descriptor_pool = DescriptorPoolBuilder::new()
+      .num_combined_image_samplers(2 * BLOOM_MIPS as u32 + 2)
+      .num_storage_images(2 * BLOOM_MIPS as u32)
+      .free_individual_sets()
+      .build(vulkan.device());
+
+    let sampler = Sampler::builder()
+      .min_filter_linear()
+      .mag_filter_linear()
+      .address_mode_clamp_to_edge()
+      .mipmap_mode_nearest()
+      .border_colour_float_opaque_black()
+      .compare_op_never()
+      .build(vulkan.device());
+
+    let bloom_layout = PostProcessHandler::bloom_set(vulkan.device(), &descriptor_pool);
+    let composite_layout = PostProcessHandler::composite_set(vulkan.device(), &descriptor_pool);
+
+    let (downsample_shader, upsample_shader) =
+      PostProcessHandler::create_bloom_shaders(vulkan, &bloom_layout, None)
+        .unwrap_or_else(|e| panic!("{}", e));
+    let composite_shader =
+      PostProcessHandler::create_composite_shader(vulkan, &composite_layout, None)
+        .unwrap_or_else(|e| panic!("{}", e));
+
+    let lut = TextureHandler::create_device_local_texture_from_image(
+      vulkan,
+      PostProcessHandler::identity_lut(IDENTITY_LUT_SIZE),
+    );
+
+    PostProcessHandler {
+      settings: PostProcessSettings::default(),
+      descriptor_pool,
+      sampler,
+      bloom_layout,
+      composite_layout,
+      downsample_shader,
+      upsample_shader,
+      composite_shader,
+      lut,
+      lut_size: IDENTITY_LUT_SIZE,
+      targets: None,
+    }
+  }
+
+  fn bloom_set(device: &VkDevice, descriptor_pool: &DescriptorAllocator) -> DescriptorSet {
+    DescriptorSet::builder()
+      .combined_image_sampler_compute() // source
+      .storage_image_compute() // destination
+      .build(device, descriptor_pool)
+  }
+
+  fn composite_set(device: &VkDevice, descriptor_pool: &DescriptorAllocator) -> DescriptorSet {
+    DescriptorSet::builder()
+      .combined_image_sampler_fragment() // hdr
+      .combined_image_sampler_fragment() // bloom
+      .combined_image_sampler_fragment() // lut
+      .build(device, descriptor_pool)
+  }
+
+  fn create_bloom_shaders(
+    vulkan: &Vulkan,
+    bloom_layout: &DescriptorSet,
+    shader_directory: Option<&str>,
+  ) -> Result<(ComputeShader, ComputeShader), String> {
+    let push_constant_range = vk::PushConstantRange::builder()
+      .stage_flags(vk::ShaderStageFlags::COMPUTE)
+      .offset(0)
+      .size(mem::size_of::<BloomPushConstants>() as u32)
+      .build();
+
+    let downsample_code = read_spv(&mut shader_source(
+      shader_directory,
+      "bloom_downsample_comp.spv",
+      include_bytes!("../../shaders/bloom_downsample_comp.spv"),
+    ))
+    .map_err(|e| format!("Failed to read bloom downsample shader: {}", e))?;
+    let upsample_code = read_spv(&mut shader_source(
+      shader_directory,
+      "bloom_upsample_comp.spv",
+      include_bytes!("../../shaders/bloom_upsample_comp.spv"),
+    ))
+    .map_err(|e| format!("Failed to read bloom upsample shader: {}", e))?;
+
+    let downsample_shader = ComputeShader::try_new_with_layouts(
+      vulkan.device(),
+      &downsample_code,
+      &[bloom_layout.layouts()[0]],
+      Some(push_constant_range),
+    )?;
+    let upsample_shader = match ComputeShader::try_new_with_layouts(
+      vulkan.device(),
+      &upsample_code,
+      &[bloom_layout.layouts()[0]],
+      Some(push_constant_range),
+    ) {
+      Ok(shader) => shader,
+      Err(e) => {
+        downsample_shader.destroy(vulkan.device());
+        return Err(e);
+      }
+    };
+
+    Ok((downsample_shader, upsample_shader))
+  }
+
+  fn create_composite_shader(
+    vulkan: &Vulkan,
+    composite_layout: &DescriptorSet,
+    shader_directory: Option<&str>,
+  ) -> Result<Shader<u32>, String> {
+    let graphics_pipeline_builder = GraphicsPipelineBuilder::new()
+      .topology_triangle_list()
+      .front_face_counter_clockwise()
+      .polygon_mode_fill()
+      .cull_none()
+      .blend_none()
+      .depth_write_disabled()
+      .samples(vulkan.msaa_samples());
+
+    // No vertex buffer is bound, the vertex struct only fills the unused binding
+    Shader::try_new(
+      vulkan.device(),
+      shader_source(
+        shader_directory,
+        "post_fullscreen_vert.spv",
+        include_bytes!("../../shaders/post_fullscreen_vert.spv"),
+      ),
+      shader_source(
+        shader_directory,
+        "post_composite_frag.spv",
+        include_bytes!("../../shaders/post_composite_frag.spv"),
+      ),
+      0u32,
+      Vec::new(),
+      &graphics_pipeline_builder,
+      vulkan.texture_renderpass(),
+      vulkan.viewports(),
+      vulkan.scissors(),
+      &[composite_layout],
+      None as Option<(u32, Vec<u32>)>,
+    )
+  }
+
+  pub fn reload_shaders(&mut self, vulkan: &mut Vulkan, shader_directory: Option<&str>) {
+    match PostProcessHandler::create_bloom_shaders(vulkan, &self.bloom_layout, shader_directory) {
+      Ok((downsample_shader, upsample_shader)) => {
+        let old_downsample = mem::replace(&mut self.downsample_shader, downsample_shader);
+        let old_upsample = mem::replace(&mut self.upsample_shader, upsample_shader);
+        vulkan.destroy_after_frames_in_flight(move |device| {
+          old_downsample.destroy(device);
+          old_upsample.destroy(device);
+        });
+      }
+      Err(e) => println!("Failed to reload bloom shaders: {}", e),
+    }
+
+    self.rebuild_pipelines(vulkan, shader_directory);
+  }
+
+  /// The composite is drawn in the texture pass, so it follows its sample count.
+  pub fn rebuild_pipelines(&mut self, vulkan: &mut Vulkan, shader_directory: Option<&str>) {
+    match PostProcessHandler::create_composite_shader(
+      vulkan,
+      &self.composite_layout,
+      shader_directory,
+    ) {
+      Ok(composite_shader) => {
+        let old_shader = mem::replace(&mut self.composite_shader, composite_shader);
+        vulkan.destroy_after_frames_in_flight(move |device| {
+          old_shader.destroy(device);
+        });
+      }
+      Err(e) => println!("Failed to rebuild post processing composite: {}", e),
+    }
+  }
+
+  pub fn settings(&self) -> &PostProcessSettings {
+    &self.settings
+  }
+
+  pub fn set_bloom(&mut self, enabled: bool, threshold: f32, intensity: f32) {
+    self.settings.bloom = enabled;
+    self.settings.bloom_threshold = threshold;
+    self.settings.bloom_intensity = intensity;
+  }
+
+  pub fn set_exposure(&mut self, enabled: bool, exposure_multiplier: f32) {
+    self.settings.exposure = enabled;
+    self.settings.exposure_multiplier = exposure_multiplier;
+  }
+
+  pub fn set_tone_mapping(&mut self, tone_mapping: ToneMapping) {
+    self.settings.tone_mapping = tone_mapping;
+  }
+
+  /// Loads a lut strip, `size * size` wide and `size` tall, red across each slice, green down
+  /// and one slice per step of blue. `None` turns grading off and keeps the loaded lut.
+  pub fn set_colour_grading(&mut self, vulkan: &mut Vulkan, lut_location: Option<&str>) {
+    let lut_location = match lut_location {
+      Some(location) => location,
+      None => {
+        self.settings.colour_grading = false;
+        return;
+      }
+    };
+
+    let image = match image::open(lut_location) {
+      Ok(image) => image.to_rgba8(),
+      Err(e) => {
+        println!("Failed to load colour grading lut {}: {}", lut_location, e);
+        return;
+      }
+    };
+
+    let (width, height) = image.dimensions();
+    if height < 2 || width != height * height {
+      println!(
+        "Colour grading lut {} is {}x{}, it should be size * size wide and size tall",
+        lut_location, width, height
+      );
+      return;
+    }
+
+    let lut = TextureHandler::create_device_local_texture_from_image(vulkan, image);
+    let old_lut = mem::replace(&mut self.lut, lut);
+    vulkan.destroy_after_frames_in_flight(move |device| {
+      old_lut.destroy(device);
+    });
+    self.lut_size = height;
+    self.settings.colour_grading = true;
+
+    // The composite set still points at the old lut
+    self.invalidate_targets(vulkan);
+  }
+
+  fn invalidate_targets(&mut self, vulkan: &mut Vulkan) {
+    if let Some(targets) = self.targets.take() {
+      vulkan.destroy_after_frames_in_flight(move |device| {
+        targets.destroy(device);
+      });
+    }
+  }
+
+  /// Sizes of the bloom images, each half of the one before starting from half the screen.
+  pub fn bloom_mip_sizes(width: u32, height: u32) -> Vec<(u32, u32)> {
+    let mut sizes = Vec::with_capacity(BLOOM_MIPS);
+    let (mut width, mut height) = (width, height);
+    for _ in 0..BLOOM_MIPS {
+      width = (width / 2).max(1);
+      height = (height / 2).max(1);
+      sizes.push((width, height));
+    }
+
+    sizes
+  }
+
+  /// How much of a colour the first downsample keeps, the part of its brightest channel over
+  /// the threshold.
+  pub fn bloom_contribution(colour: Vec3, threshold: f32) -> f32 {
+    let brightness = colour.max_element();
+    (brightness - threshold).max(0.0) / brightness.max(0.0001)
+  }
+
+  pub fn linear_to_srgb(colour: Vec3) -> Vec3 {
+    let encode = |c: f32| {
+      if c <= 0.0031308 {
+        c * 12.92
+      } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+      }
+    };
+
+    Vec3::new(encode(colour.x), encode(colour.y), encode(colour.z))
+  }
+
+  /// Where post_composite.frag samples a `size` lut strip for a tone mapped colour, the uvs of
+  /// the two nearest blue slices and how far to blend between them.
+  pub fn lut_coordinates(colour: Vec3, size: u32) -> (Vec2, Vec2, f32) {
+    let size = size as f32;
+    let cell = PostProcessHandler::linear_to_srgb(colour) * (size - 1.0);
+    let slice = cell.z.floor();
+    let next_slice = (slice + 1.0).min(size - 1.0);
+
+    let uv = Vec2::new((cell.x + 0.5) / (size * size), (cell.y + 0.5) / size);
+
+    (
+      uv + Vec2::new(slice / size, 0.0),
+      uv + Vec2::new(next_slice / size, 0.0),
+      cell.z - slice,
+    )
+  }
+
+  /// A lut strip that leaves colours as they are.
+  pub fn identity_lut(size: u32) -> image::RgbaImage {
+    let step = |value: u32| (value * 255 / (size - 1)) as u8;
+    image::RgbaImage::from_fn(size * size, size, |x, y| {
+      image::Rgba([step(x % size), step(y), step(x / size), 255])
+    })
+  }
+
+  fn create_targets(&mut self, vulkan: &mut Vulkan) {
+    let device = vulkan.device();
+    let extent = vulkan.screen_extent();
+
+    let bloom_mips = PostProcessHandler::bloom_mip_sizes(extent.width, extent.height)
+      .into_iter()
+      .map(|(width, height)| {
+        ImageBuilder::new(crate::vkwrapper::vulkan::HDR_FORMAT, 1, 1)
+          .usage(
+            vk::ImageUsageFlags::STORAGE
+              | vk::ImageUsageFlags::SAMPLED
+              | vk::ImageUsageFlags::TRANSFER_DST,
+          )
+          .set_dimensions(width, height)
+          .build_device_local(device)
+      })
+      .collect::<Vec<_>>();
+
+    let mut downsample_sets = Vec::with_capacity(BLOOM_MIPS);
+    for i in 0..BLOOM_MIPS {
+      let descriptor_set = PostProcessHandler::bloom_set(device, &self.descriptor_pool);
+      let (source, source_layout) = if i == 0 {
+        (
+          vulkan.hdr_image(),
+          vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        )
+      } else {
+        (&bloom_mips[i - 1], vk::ImageLayout::GENERAL)
+      };
+
+      DescriptorWriter::builder()
+        .update_image_in_layout(source, &self.sampler, source_layout, &descriptor_set)
+        .update_storage_image(&bloom_mips[i], &descriptor_set)
+        .build(device);
+      downsample_sets.push(descriptor_set);
+    }
+
+    // Indexed by the mip written to, each adds in the one below it
+    let mut upsample_sets = Vec::with_capacity(BLOOM_MIPS - 1);
+    for i in 0..BLOOM_MIPS - 1 {
+      let descriptor_set = PostProcessHandler::bloom_set(device, &self.descriptor_pool);
+      DescriptorWriter::builder()
+        .update_image_in_layout(
+          &bloom_mips[i + 1],
+          &self.sampler,
+          vk::ImageLayout::GENERAL,
+          &descriptor_set,
+        )
+        .update_storage_image(&bloom_mips[i], &descriptor_set)
+        .build(device);
+      upsample_sets.push(descriptor_set);
+    }
+
+    let composite_set = PostProcessHandler::composite_set(device, &self.descriptor_pool);
+    DescriptorWriter::builder()
+      .update_image(vulkan.hdr_image(), &self.sampler, &composite_set)
+      .update_image_in_layout(
+        &bloom_mips[0],
+        &self.sampler,
+        vk::ImageLayout::GENERAL,
+        &composite_set,
+      )
+      .update_image(&self.lut, &self.sampler, &composite_set)
+      .build(device);
+
+    vulkan.clear_storage_images(&bloom_mips.iter().collect::<Vec<_>>());
+
+    self.targets = Some(PostTargets {
+      render_target_version: vulkan.render_target_version(),
+      bloom_mips,
+      downsample_sets,
+      upsample_sets,
+      composite_set,
+    });
+  }
+
+  fn bloom_pass(
+    vulkan: &mut Vulkan,
+    shader: &ComputeShader,
+    descriptor_set: &DescriptorSet,
+    source: &Image,
+    destination: &Image,
+    threshold: Option<f32>,
+  ) {
+    let push_constants = BloomPushConstants {
+      size: [
+        destination.width() as f32,
+        destination.height() as f32,
+        1.0 / source.width() as f32,
+        1.0 / source.height() as f32,
+      ],
+      threshold: [
+        threshold.unwrap_or(0.0),
+        if threshold.is_some() { 1.0 } else { 0.0 },
+        0.0,
+        0.0,
+      ],
+    };
+
+    vulkan.dispatch_post_pass(
+      shader,
+      descriptor_set,
+      &[push_constants],
+      [
+        destination.width().div_ceil(BLOOM_LOCAL_SIZE),
+        destination.height().div_ceil(BLOOM_LOCAL_SIZE),
+      ],
+    );
+  }
+
+  /// Records the bloom passes, call between the model pass and the texture pass.
+  pub fn run(&mut self, vulkan: &mut Vulkan) {
+    vulkan.begin_post_processing();
+
+    let version = vulkan.render_target_version();
+    if self
+      .targets
+      .as_ref()
+      .is_none_or(|targets| targets.render_target_version != version)
+    {
+      self.invalidate_targets(vulkan);
+      self.create_targets(vulkan);
+    }
+
+    if !self.settings.bloom {
+      return;
+    }
+
+    let targets = self.targets.as_ref().unwrap();
+    let hdr_image = vulkan.hdr_image().clone();
+
+    for i in 0..BLOOM_MIPS {
+      let (source, threshold) = if i == 0 {
+        (&hdr_image, Some(self.settings.bloom_threshold))
+      } else {
+        (&targets.bloom_mips[i - 1], None)
+      };
+
+      PostProcessHandler::bloom_pass(
+        vulkan,
+        &self.downsample_shader,
+        &targets.downsample_sets[i],
+        source,
+        &targets.bloom_mips[i],
+        threshold,
+      );
+    }
+
+    for i in (0..BLOOM_MIPS - 1).rev() {
+      PostProcessHandler::bloom_pass(
+        vulkan,
+        &self.upsample_shader,
+        &targets.upsample_sets[i],
+        &targets.bloom_mips[i + 1],
+        &targets.bloom_mips[i],
+        None,
+      );
+    }
+  }
+
+  fn composite_data(&self, extent: vk::Extent2D) -> Vec<f32> {
+    let settings = &self.settings;
+    vec![
+      1.0 / extent.width as f32,
+      1.0 / extent.height as f32,
+      if settings.bloom {
+        settings.bloom_intensity
+      } else {
+        0.0
+      },
+      if settings.exposure {
+        settings.exposure_multiplier
+      } else {
+        1.0
+      },
+      settings.tone_mapping.shader_value(),
+      if settings.colour_grading { 1.0 } else { 0.0 },
+      self.lut_size as f32,
+      0.0,
+    ]
+  }
+
+  /// Draws the processed HDR image over the whole screen, the first thing in the texture pass.
+  pub fn composite(&mut self, vulkan: &mut Vulkan) {
+    let targets = match &self.targets {
+      Some(targets) => targets,
+      None => return,
+    };
+
+    let data = self.composite_data(vulkan.screen_extent());
+    vulkan.draw_fullscreen(&self.composite_shader, &targets.composite_set, data);
+  }
+
+  pub fn destroy(&mut self, vulkan: &mut Vulkan) {
+    let device = vulkan.device();
+    if let Some(targets) = self.targets.take() {
+      targets.destroy(device);
+    }
+
+    self.lut.destroy(device);
+    self.downsample_shader.destroy(device);
+    self.upsample_shader.destroy(device);
+    self.composite_shader.destroy(device);
+    self.bloom_layout.destroy(device);
+    self.composite_layout.destroy(device);
+    self.sampler.destroy(device);
+    self.descriptor_pool.destroy(device);
+  }
+}
@@ -0,0 +1,154 @@
+use std::collections::VecDeque;
+use std::mem;
+
+use glam::Vec2;
+
+use crate::draw::Draw;
+use crate::extra::shader_source;
+use crate::extra::shapes::ShapeVertex;
+use crate::offset_of;
+use crate::vkwrapper::{Buffer, GraphicsPipelineBuilder, PerFrame, Shader, Vulkan};
+
+/// Untextured shapes drawn in the sprite pass. Every shape of a frame is uploaded together
+/// before the sprites, then drawn in turn so they layer with the sprites in draw order.
+pub struct ShapeHandler {
+  shader: Shader<ShapeVertex>,
+  // A vertex buffer per frame in flight and how many vertices it holds, grown as needed
+  vertex_buffers: PerFrame<Option<(Buffer<ShapeVertex>, usize)>>,
+  // First vertex and vertex count of each shape still to draw this frame
+  ranges: VecDeque<(u32, u32)>,
+}
+
+impl ShapeHandler {
+  pub fn new(vulkan: &mut Vulkan) -> ShapeHandler {
+    let shader = ShapeHandler::create_shader(vulkan, None).unwrap_or_else(|e| panic!("{}", e));
+
+    ShapeHandler {
+      shader,
+      vertex_buffers: PerFrame::new(vulkan.frames_in_flight(), |_| None),
+      ranges: VecDeque::new(),
+    }
+  }
+
+  fn create_shader(
+    vulkan: &Vulkan,
+    shader_directory: Option<&str>,
+  ) -> Result<Shader<ShapeVertex>, String> {
+    let graphics_pipeline_builder = GraphicsPipelineBuilder::new()
+      .topology_triangle_list()
+      .front_face_counter_clockwise()
+      .polygon_mode_fill()
+      .samples(vulkan.msaa_samples());
+
+    Shader::try_new(
+      vulkan.device(),
+      shader_source(
+        shader_directory,
+        "shape_vert.spv",
+        include_bytes!("../../shaders/shape_vert.spv"),
+      ),
+      shader_source(
+        shader_directory,
+        "shape_frag.spv",
+        include_bytes!("../../shaders/shape_frag.spv"),
+      ),
+      ShapeVertex {
+        pos: [0.0; 2],
+        colour: [0.0; 4],
+      },
+      vec![
+        offset_of!(ShapeVertex, pos) as u32,
+        offset_of!(ShapeVertex, colour) as u32,
+      ],
+      &graphics_pipeline_builder,
+      vulkan.texture_renderpass(),
+      vulkan.viewports(),
+      vulkan.scissors(),
+      &[],
+      None as Option<(u32, Vec<u32>)>,
+    )
+  }
+
+  pub fn rebuild_pipelines(&mut self, vulkan: &mut Vulkan, shader_directory: Option<&str>) {
+    match ShapeHandler::create_shader(vulkan, shader_directory) {
+      Ok(shader) => {
+        let old_shader = mem::replace(&mut self.shader, shader);
+        vulkan.destroy_after_frames_in_flight(move |device| {
+          old_shader.destroy(device);
+        });
+      }
+      Err(e) => println!("Failed to rebuild shape pipeline: {}", e),
+    }
+  }
+
+  /// Uploads the shapes of this frame's draws, call before drawing any of them.
+  pub fn prepare(&mut self, vulkan: &mut Vulkan, draws: &[Draw]) {
+    self.ranges.clear();
+
+    let mut vertices = Vec::new();
+    for draw in draws.iter().filter(|draw| draw.get_shape().is_some()) {
+      let mut shape_vertices = draw.shape_vertices();
+      self
+        .ranges
+        .push_back((vertices.len() as u32, shape_vertices.len() as u32));
+      vertices.append(&mut shape_vertices);
+    }
+
+    if vertices.is_empty() {
+      return;
+    }
+
+    let vertex_count = vertices.len();
+    let buffer = self.vertex_buffers.get_mut(vulkan.current_frame());
+    if buffer
+      .as_ref()
+      .is_none_or(|(_, capacity)| *capacity < vertex_count)
+    {
+      let capacity = vertex_count.next_power_of_two();
+      let new_buffer = Buffer::<ShapeVertex>::new_vertex(
+        vulkan.device(),
+        vec![
+          ShapeVertex {
+            pos: [0.0; 2],
+            colour: [0.0; 4],
+          };
+          capacity
+        ],
+      );
+      if let Some((old_buffer, _)) = buffer.replace((new_buffer, capacity)) {
+        vulkan.destroy_after_frames_in_flight(move |device| {
+          old_buffer.destroy(device);
+        });
+      }
+    }
+
+    if let Some((buffer, _)) = buffer {
+      buffer.update_data(vulkan.device(), vertices);
+    }
+  }
+
+  /// Draws the next shape uploaded by `prepare`, offset by the 2D camera.
+  pub fn draw_next(&mut self, vulkan: &mut Vulkan, window_size: [f32; 2], camera: Vec2) {
+    let (first_vertex, vertex_count) = match self.ranges.pop_front() {
+      Some(range) if range.1 > 0 => range,
+      _ => return,
+    };
+
+    if let Some((buffer, _)) = self.vertex_buffers.get(vulkan.current_frame()) {
+      vulkan.draw_vertex_range(
+        &self.shader,
+        buffer,
+        first_vertex,
+        vertex_count,
+        vec![window_size[0], window_size[1], camera.x, camera.y],
+      );
+    }
+  }
+
+  pub fn destroy(&mut self, vulkan: &mut Vulkan) {
+    self.shader.destroy(vulkan.device());
+    for (buffer, _) in self.vertex_buffers.iter().flatten() {
+      buffer.destroy(vulkan.device());
+    }
+  }
+}
@@ -1,20 +1,25 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Cursor;
 use std::mem;
 
 use ash::vk;
 
+use crate::extra::{shader_source, CompressedTexture};
 use crate::offset_of;
 use crate::shader_handlers::font::{FontType, GuiText, TextMaster};
+use crate::shader_handlers::{CustomPipeline, PipelineResources};
 use crate::vkwrapper::{
-  Buffer, DescriptorPoolBuilder, DescriptorSet, DescriptorWriter, GraphicsPipelineBuilder, Image,
-  ImageBuilder, Sampler, Shader, VkDevice, Vulkan,
+  Buffer, DescriptorAllocator, DescriptorPoolBuilder, DescriptorSet, DescriptorWriter,
+  GraphicsPipelineBuilder, Image, ImageBuilder, PerFrame, Sampler, Shader, VkDevice, Vulkan,
 };
 use crate::Draw;
 
 use glam::{Vec2, Vec3Swizzles, Vec4};
 
 const MAX_INSTANCES: usize = 8196;
+// flip_xy.z of the sprite push constants
+const MIRROR_UV_IDX: usize = 18;
+const SPRITE_FORMAT: vk::Format = vk::Format::A8B8G8R8_SRGB_PACK32;
 
 #[derive(Clone, Debug, Copy)]
 pub struct ComboVertex {
@@ -23,6 +28,8 @@ pub struct ComboVertex {
   pub uv: [f32; 2],
 }
 
+// Also written by particles.comp, so the field order is fixed
+#[repr(C)]
 #[derive(Clone, Debug, Copy)]
 pub struct InstancedComboData {
   //pub pos: [f32; 4],
@@ -102,12 +109,27 @@ struct TextureUniformBuffer {
   window_size: [f32; 2],
 }
 
+// Texture, the instances added this frame and a vertex buffer per frame in flight
+type InstancedBuffer = (
+  String,
+  Vec<InstancedComboData>,
+  PerFrame<Buffer<InstancedComboData>>,
+);
+
+// Combo, instanced combo and particle shaders
+type ComboShaders = (
+  Shader<ComboVertex>,
+  Shader<ComboVertex>,
+  Shader<ComboVertex>,
+);
+
 pub struct TextureHandler {
-  descriptor_pool: vk::DescriptorPool,
+  descriptor_pool: DescriptorAllocator,
   sampler: Sampler,
 
   uniform_buffer: Buffer<TextureUniformBuffer>,
   uniform_descriptor: DescriptorSet,
+  texture_descriptor: DescriptorSet,
 
   text_master: TextMaster,
   text_this_draw: Vec<GuiText>,
@@ -121,9 +143,16 @@ pub struct TextureHandler {
   combo_index_buffer: Buffer<u32>,
   combo_vertex_buffer: Buffer<ComboVertex>,
   instanced_combo_shader: Shader<ComboVertex>,
-  instanced_combo_buffer: HashMap<String, (String, Buffer<InstancedComboData>)>,
+  particle_shader: Shader<ComboVertex>,
+  instanced_combo_buffer: HashMap<String, InstancedBuffer>,
+  custom_pipelines: HashMap<String, (Shader<ComboVertex>, PipelineResources)>,
+  pipeline_sources: HashMap<String, CustomPipeline>,
 
   textures: HashMap<String, (Image, DescriptorSet)>,
+  // Textures whose image belongs to a render target, only their descriptor sets are freed here
+  render_target_textures: HashSet<String>,
+  // Block compressed textures stored unmirrored, their uvs are mirrored when drawn instead
+  unflipped_textures: HashSet<String>,
   dummy_texture: (Image, DescriptorSet),
 
   window_size: [f32; 2],
@@ -140,6 +169,7 @@ impl TextureHandler {
     let descriptor_pool = DescriptorPoolBuilder::new()
       .num_combined_image_samplers(50)
       .num_uniform_buffers(50)
+      .free_individual_sets()
       .build(vulkan.device());
 
     let sampler = Sampler::builder()
@@ -171,18 +201,10 @@ impl TextureHandler {
 
     uniform_descriptor_set_writer.build(vulkan.device());
 
-    let (
-      //_letter_shader,
-      //_instanced_letter_shader,
-      combo_shader,
-      combo_index_buffer,
-      combo_vertex_buffer,
-      instanced_combo_shader,
-      // instanced_combo_buffer,
-    ) = TextureHandler::create_combo_shader(
-      &vulkan,
-      &vec![descriptor_set0.clone(), descriptor_set1.clone()],
-    );
+    let (combo_index_buffer, combo_vertex_buffer) = TextureHandler::create_combo_buffers(&vulkan);
+    let (combo_shader, instanced_combo_shader, particle_shader) =
+      TextureHandler::create_combo_shaders(vulkan, &[&descriptor_set0, &descriptor_set1], None)
+        .unwrap_or_else(|e| panic!("{}", e));
 
     let checked_image = TextureHandler::create_checked_image();
     let dummy_texture =
@@ -199,7 +221,7 @@ impl TextureHandler {
     //let _instanced_letter_buffer =
     //  Buffer::<InstancedTextData>::new_vertex(vulkan.device(), dummy_instanced_data);
 
-    let font = FontType::new(font_location.into(), &sampler, vulkan);
+    let font = FontType::new(font_location.into(), &sampler, vulkan, None);
 
     let mut text_master = TextMaster::new(vulkan, font);
 
@@ -223,6 +245,7 @@ impl TextureHandler {
 
       uniform_buffer,
       uniform_descriptor: descriptor_set0,
+      texture_descriptor: descriptor_set1,
 
       text_master,
       text_this_draw: vec![gui_text_clone],
@@ -236,10 +259,15 @@ impl TextureHandler {
       combo_index_buffer,
       combo_vertex_buffer,
       instanced_combo_shader,
+      particle_shader,
       instanced_combo_buffer: HashMap::new(),
+      custom_pipelines: HashMap::new(),
+      pipeline_sources: HashMap::new(),
 
       //strings,
       textures: HashMap::new(),
+      render_target_textures: HashSet::new(),
+      unflipped_textures: HashSet::new(),
       dummy_texture: (dummy_texture, dummy_descriptor_set),
 
       window_size: [screen_size.width as f32, screen_size.height as f32],
@@ -256,9 +284,261 @@ impl TextureHandler {
     self.camera_position = pos;
   }
 
+  pub fn camera_location(&self) -> Vec2 {
+    self.camera_position
+  }
+
+  pub fn window_size(&self) -> [f32; 2] {
+    self.window_size
+  }
+
+  pub fn unload_texture(&mut self, vulkan: &mut Vulkan, texture_ref: &str) {
+    if let Some((image, descriptor_set)) = self.textures.remove(texture_ref) {
+      let owns_image = !self.render_target_textures.remove(texture_ref);
+      self.unflipped_textures.remove(texture_ref);
+      vulkan.destroy_after_frames_in_flight(move |device| {
+        descriptor_set.free(device);
+        descriptor_set.destroy(device);
+        if owns_image {
+          image.destroy(device);
+        }
+      });
+    }
+  }
+
+  /// Lets sprites draw the colour image of a render target, which stays owned by the target.
+  pub fn insert_render_target<T: Into<String>>(
+    &mut self,
+    vulkan: &mut Vulkan,
+    texture_ref: T,
+    colour_image: &Image,
+  ) {
+    let texture_ref = texture_ref.into();
+    self.insert_texture(vulkan, texture_ref.to_string(), colour_image.clone());
+    self.render_target_textures.insert(texture_ref);
+  }
+
+  /// Reloads a texture from disk under the same reference, keeping the current one if the file
+  /// can't be decoded.
+  pub fn reload_texture(&mut self, vulkan: &mut Vulkan, texture_ref: &str, texture: &str) {
+    if CompressedTexture::is_container(texture) {
+      match CompressedTexture::load(texture) {
+        Ok(compressed) => {
+          self.load_compressed_textures(vulkan, vec![(texture_ref.to_string(), compressed)])
+        }
+        Err(e) => println!("Failed to reload texture {}: {}", texture, e),
+      }
+      return;
+    }
+
+    match image::open(texture) {
+      Ok(image) => {
+        let dl_texture =
+          TextureHandler::create_device_local_texture_from_image(vulkan, image.fliph().to_rgba8());
+        self.insert_texture(vulkan, texture_ref, dl_texture);
+      }
+      Err(e) => {
+        println!("Failed to reload texture {}: {}", texture, e);
+      }
+    }
+  }
+
+  pub fn reload_font(
+    &mut self,
+    vulkan: &mut Vulkan,
+    font_location: &str,
+    shader_directory: Option<&str>,
+  ) {
+    match FontType::try_new(
+      font_location.to_string(),
+      &self.sampler,
+      vulkan,
+      shader_directory,
+    ) {
+      Ok(font) => self.text_master.replace_font(vulkan, font),
+      Err(e) => println!("Failed to reload font {}: {}", font_location, e),
+    }
+  }
+
+  /// Rebuilds the texture and text pipelines from the shader directory. The old pipelines are
+  /// kept when a shader fails to build.
+  pub fn reload_shaders(&mut self, vulkan: &mut Vulkan, shader_directory: Option<&str>) {
+    let descriptor_sets = [&self.uniform_descriptor, &self.texture_descriptor];
+
+    match TextureHandler::create_combo_shaders(vulkan, &descriptor_sets, shader_directory) {
+      Ok((combo_shader, instanced_combo_shader, particle_shader)) => {
+        let old_combo_shader = mem::replace(&mut self.combo_shader, combo_shader);
+        let old_instanced_combo_shader =
+          mem::replace(&mut self.instanced_combo_shader, instanced_combo_shader);
+        let old_particle_shader = mem::replace(&mut self.particle_shader, particle_shader);
+        vulkan.destroy_after_frames_in_flight(move |device| {
+          old_combo_shader.destroy(device);
+          old_instanced_combo_shader.destroy(device);
+          old_particle_shader.destroy(device);
+        });
+      }
+      Err(e) => {
+        println!("Failed to reload texture shaders: {}", e);
+      }
+    }
+
+    self.text_master.reload_shader(vulkan, shader_directory);
+  }
+
+  /// Builds a sprite pipeline from user SPIR-V. Sets 0 and 1 are the same uniforms and texture
+  /// as `combo_vert`/`combo_frag` get, sets from 2 on are the pipeline's own.
+  pub fn create_pipeline<T: Into<String>>(
+    &mut self,
+    vulkan: &mut Vulkan,
+    pipeline_ref: T,
+    pipeline: &CustomPipeline,
+  ) -> Result<(), String> {
+    let combo_vertex = ComboVertex {
+      pos: [0.0, 0.0, 0.0, 0.0],
+      colour: [0.0, 0.0, 0.0, 0.0],
+      uv: [0.0, 0.0],
+    };
+
+    let resources = PipelineResources::new(
+      vulkan,
+      &self.descriptor_pool,
+      2,
+      pipeline.extra_bindings(2)?,
+    );
+
+    let mut descriptor_sets = vec![&self.uniform_descriptor, &self.texture_descriptor];
+    descriptor_sets.extend(resources.layouts());
+
+    let shader = match Shader::try_new(
+      vulkan.device(),
+      Cursor::new(pipeline.vertex_shader()),
+      Cursor::new(pipeline.fragment_shader()),
+      combo_vertex,
+      pipeline.offsets_or(vec![
+        offset_of!(ComboVertex, pos) as u32,
+        offset_of!(ComboVertex, colour) as u32,
+        offset_of!(ComboVertex, uv) as u32,
+      ]),
+      &pipeline
+        .get_graphics_pipeline_builder()
+        .clone()
+        .samples(vulkan.msaa_samples()),
+      vulkan.texture_renderpass(),
+      vulkan.viewports(),
+      vulkan.scissors(),
+      &descriptor_sets,
+      None as Option<(InstancedTextData, Vec<u32>)>,
+    ) {
+      Ok(shader) => shader,
+      Err(e) => {
+        resources.destroy(vulkan.device());
+        return Err(e);
+      }
+    };
+
+    let pipeline_ref = pipeline_ref.into();
+    let mut resources = resources;
+    if let Some((_, old_resources)) = self.custom_pipelines.get_mut(&pipeline_ref) {
+      resources.take_bindings(old_resources);
+    }
+    self.remove_pipeline(vulkan, &pipeline_ref);
+    self
+      .pipeline_sources
+      .insert(pipeline_ref.to_string(), pipeline.clone());
+    self
+      .custom_pipelines
+      .insert(pipeline_ref, (shader, resources));
+
+    Ok(())
+  }
+
+  /// Rebuilds the built in and custom pipelines against the current render passes.
+  pub fn rebuild_pipelines(&mut self, vulkan: &mut Vulkan, shader_directory: Option<&str>) {
+    self.reload_shaders(vulkan, shader_directory);
+
+    for (pipeline_ref, pipeline) in self.pipeline_sources.clone() {
+      if let Err(e) = self.create_pipeline(vulkan, pipeline_ref.to_string(), &pipeline) {
+        println!("Failed to rebuild sprite pipeline {}: {}", pipeline_ref, e);
+      }
+    }
+  }
+
+  pub fn remove_pipeline(&mut self, vulkan: &mut Vulkan, pipeline_ref: &str) {
+    self.pipeline_sources.remove(pipeline_ref);
+    if let Some((shader, resources)) = self.custom_pipelines.remove(pipeline_ref) {
+      vulkan.destroy_after_frames_in_flight(move |device| {
+        shader.destroy(device);
+        resources.destroy(device);
+      });
+    }
+  }
+
+  pub fn has_pipeline(&self, pipeline_ref: &str) -> bool {
+    self.custom_pipelines.contains_key(pipeline_ref)
+  }
+
+  /// Points a texture binding of one of the pipeline's own sets at a texture, which may be
+  /// loaded later.
+  pub fn bind_pipeline_texture(
+    &mut self,
+    pipeline_ref: &str,
+    set: u32,
+    binding: u32,
+    texture_ref: &str,
+  ) -> Result<(), String> {
+    self
+      .custom_pipelines
+      .get_mut(pipeline_ref)
+      .ok_or(format!("No sprite pipeline named {}", pipeline_ref))?
+      .1
+      .bind_texture(set, binding, texture_ref)
+  }
+
+  pub fn bind_pipeline_data(
+    &mut self,
+    pipeline_ref: &str,
+    set: u32,
+    binding: u32,
+    data: Vec<u8>,
+  ) -> Result<(), String> {
+    self
+      .custom_pipelines
+      .get_mut(pipeline_ref)
+      .ok_or(format!("No sprite pipeline named {}", pipeline_ref))?
+      .1
+      .bind_data(set, binding, data)
+  }
+
+  /// The image view and sampler sprites sample for a texture, the dummy texture's when there
+  /// is no such texture.
+  pub fn sampled_texture(&self, texture_ref: Option<&str>) -> (vk::ImageView, vk::Sampler) {
+    TextureHandler::sampled_image(
+      &self.textures,
+      &self.dummy_texture.0,
+      &self.sampler,
+      texture_ref,
+    )
+  }
+
+  fn sampled_image(
+    textures: &HashMap<String, (Image, DescriptorSet)>,
+    dummy_texture: &Image,
+    sampler: &Sampler,
+    texture_ref: Option<&str>,
+  ) -> (vk::ImageView, vk::Sampler) {
+    let image = texture_ref
+      .and_then(|texture_ref| textures.get(texture_ref))
+      .map(|(image, _)| image)
+      .unwrap_or(dummy_texture);
+
+    (image.view(), sampler.internal())
+  }
+
   pub fn destroy(&mut self, vulkan: &mut Vulkan) {
-    for (_, (image, descriptor)) in self.textures.drain().take(1) {
-      image.destroy(vulkan.device());
+    for (texture_ref, (image, descriptor)) in self.textures.drain() {
+      if !self.render_target_textures.contains(&texture_ref) {
+        image.destroy(vulkan.device());
+      }
       descriptor.destroy(vulkan.device());
     }
 
@@ -269,15 +549,26 @@ impl TextureHandler {
     self.combo_index_buffer.destroy(vulkan.device());
     self.combo_vertex_buffer.destroy(vulkan.device());
     self.instanced_combo_shader.destroy(vulkan.device());
-    // self.instanced_combo_buffer.destroy(vulkan.device());
-
-    unsafe {
-      vulkan
-        .device()
-        .internal()
-        .destroy_descriptor_pool(self.descriptor_pool, None);
+    self.particle_shader.destroy(vulkan.device());
+    for buffer in self
+      .instanced_combo_buffer
+      .drain()
+      .flat_map(|(_, (_, _, buffers))| buffers.into_vec())
+    {
+      buffer.destroy(vulkan.device());
+    }
+    for (_, (shader, resources)) in self.custom_pipelines.drain() {
+      shader.destroy(vulkan.device());
+      resources.destroy(vulkan.device());
     }
 
+    self.uniform_buffer.destroy(vulkan.device());
+    self.uniform_descriptor.destroy(vulkan.device());
+    self.texture_descriptor.destroy(vulkan.device());
+    self.text_master.destroy(vulkan.device());
+
+    self.descriptor_pool.destroy(vulkan.device());
+
     self.sampler.destroy(vulkan.device());
   }
 
@@ -307,18 +598,27 @@ impl TextureHandler {
     buffer_name: T,
     texture: T,
   ) {
-    let instance_data = vec![InstancedComboData::new(); MAX_INSTANCES];
-    let instanced_combo_buffer =
-      Buffer::<InstancedComboData>::new_vertex(vulkan.device(), instance_data);
-
-    self
-      .instanced_combo_buffer
-      .insert(buffer_name.into(), (texture.into(), instanced_combo_buffer));
+    let instanced_combo_buffers = PerFrame::new(vulkan.frames_in_flight(), |_| {
+      let instance_data = vec![InstancedComboData::new(); MAX_INSTANCES];
+      Buffer::<InstancedComboData>::new_vertex(vulkan.device(), instance_data)
+    });
+
+    self.instanced_combo_buffer.insert(
+      buffer_name.into(),
+      (texture.into(), Vec::new(), instanced_combo_buffers),
+    );
   }
 
   pub fn load_texture<T: Into<String>>(&mut self, vulkan: &mut Vulkan, texture_ref: T, texture: T) {
     let texture = texture.into();
 
+    if CompressedTexture::is_container(&texture) {
+      let compressed = CompressedTexture::load(&texture)
+        .unwrap_or_else(|e| panic!("Failed to load texture: {}", e));
+      self.load_compressed_textures(vulkan, vec![(texture_ref.into(), compressed)]);
+      return;
+    }
+
     let image = image::open(&texture)
       .expect(&("Failed to load texture: ".to_string() + &texture))
       .fliph()
@@ -326,6 +626,73 @@ impl TextureHandler {
 
     let dl_texture = TextureHandler::create_device_local_texture_from_image(vulkan, image);
 
+    self.insert_texture(vulkan, texture_ref, dl_texture);
+  }
+
+  /// Uploads textures decoded off the main thread in a single transfer submission.
+  pub fn load_decoded_textures(
+    &mut self,
+    vulkan: &mut Vulkan,
+    decoded: Vec<(String, image::RgbaImage)>,
+  ) {
+    let mut transfers = Vec::new();
+    let mut loaded = Vec::new();
+
+    for (texture_ref, image) in decoded {
+      let (staging_buffer, dl_texture) =
+        TextureHandler::create_staged_texture_from_image(vulkan, image, SPRITE_FORMAT);
+      transfers.push((staging_buffer, dl_texture.clone()));
+      loaded.push((texture_ref, dl_texture));
+    }
+
+    vulkan.transfer_buffers_to_device_local_images(transfers);
+
+    for (texture_ref, dl_texture) in loaded {
+      self.insert_texture(vulkan, texture_ref, dl_texture);
+    }
+  }
+
+  /// Uploads KTX2 or DDS textures in a single transfer submission, keeping them compressed when
+  /// the device can sample the format and decoding them on the CPU when it can't.
+  pub fn load_compressed_textures(
+    &mut self,
+    vulkan: &mut Vulkan,
+    textures: Vec<(String, CompressedTexture)>,
+  ) {
+    let mut transfers = Vec::new();
+    let mut loaded = Vec::new();
+
+    for (texture_ref, texture) in textures {
+      match TextureHandler::create_staged_compressed_texture(vulkan, texture) {
+        Ok((staging_buffer, dl_texture, level_offsets, unflipped)) => {
+          transfers.push((staging_buffer, dl_texture.clone(), level_offsets));
+          loaded.push((texture_ref, dl_texture, unflipped));
+        }
+        Err(e) => {
+          println!("Failed to load texture {}: {}", texture_ref, e);
+        }
+      }
+    }
+
+    vulkan.transfer_buffer_levels_to_device_local_images(transfers);
+
+    for (texture_ref, dl_texture, unflipped) in loaded {
+      self.insert_texture(vulkan, texture_ref.to_string(), dl_texture);
+      if unflipped {
+        self.unflipped_textures.insert(texture_ref);
+      }
+    }
+  }
+
+  fn insert_texture<T: Into<String>>(
+    &mut self,
+    vulkan: &mut Vulkan,
+    texture_ref: T,
+    dl_texture: Image,
+  ) {
+    let texture_ref = texture_ref.into();
+    self.unload_texture(vulkan, &texture_ref);
+
     let descriptor_sets = DescriptorSet::builder()
       .combined_image_sampler_fragment()
       .build(vulkan.device(), &self.descriptor_pool);
@@ -336,10 +703,43 @@ impl TextureHandler {
 
     self
       .textures
-      .insert(texture_ref.into(), (dl_texture, descriptor_sets));
+      .insert(texture_ref, (dl_texture, descriptor_sets));
   }
 
-  pub fn draw(&mut self, vulkan: &mut Vulkan, mut data: Vec<f32>, texture: &str) {
+  pub fn draw(&mut self, vulkan: &mut Vulkan, data: Vec<f32>, texture: &str) {
+    self.draw_with_pipeline(vulkan, data, texture, None);
+  }
+
+  /// Falls back to the default pipeline when the named pipeline doesn't exist, or while a
+  /// buffer of its own sets has nothing bound.
+  pub fn draw_with_pipeline(
+    &mut self,
+    vulkan: &mut Vulkan,
+    mut data: Vec<f32>,
+    texture: &str,
+    pipeline: Option<&str>,
+  ) {
+    data[MIRROR_UV_IDX] = self.mirror_uv(texture);
+
+    let mut shader = &self.combo_shader;
+    let custom_pipelines = &mut self.custom_pipelines;
+    if let Some((custom_shader, resources)) = pipeline.and_then(|pipeline| {
+      custom_pipelines
+        .get_mut(pipeline)
+        .filter(|(_, resources)| resources.ready_for_draw(pipeline))
+    }) {
+      let (textures, dummy_texture, sampler) = (&self.textures, &self.dummy_texture, &self.sampler);
+      resources.update(vulkan, |texture_ref| {
+        TextureHandler::sampled_image(textures, &dummy_texture.0, sampler, texture_ref)
+      });
+      vulkan.bind_descriptor_sets(
+        custom_shader,
+        resources.first_set(),
+        resources.descriptor_sets(vulkan.current_frame()),
+      );
+      shader = &*custom_shader;
+    }
+
     let texture_descriptor = {
       if let Some((_, texture_descriptor)) = self.textures.get(texture) {
         texture_descriptor
@@ -355,7 +755,7 @@ impl TextureHandler {
     vulkan.draw_texture(
       &texture_descriptor,
       &self.uniform_descriptor,
-      &self.combo_shader,
+      shader,
       &self.combo_vertex_buffer,
       &self.combo_index_buffer,
       None as Option<&Buffer<u32>>,
@@ -364,6 +764,15 @@ impl TextureHandler {
     );
   }
 
+  /// Push constant value telling the sprite shaders to mirror the uvs of unflipped textures.
+  fn mirror_uv(&self, texture: &str) -> f32 {
+    if self.unflipped_textures.contains(texture) {
+      1.0
+    } else {
+      0.0
+    }
+  }
+
   pub fn add_draw(&mut self, vulkan: &mut Vulkan, mut data: Vec<f32>, texture: &str) {
     let texture_descriptor = {
       if let Some((_, texture_descriptor)) = self.textures.get(texture) {
@@ -376,6 +785,7 @@ impl TextureHandler {
     let last_idx = data.len() - 4;
     data[last_idx] = self.camera_position.x;
     data[last_idx + 1] = self.camera_position.y;
+    data[MIRROR_UV_IDX] = self.mirror_uv(texture);
 
     vulkan.draw_texture(
       &texture_descriptor,
@@ -457,10 +867,16 @@ impl TextureHandler {
   }
 
   pub fn draw_instanced_texture(&mut self, vulkan: &mut Vulkan, buffer: &str) {
-    if let Some((texture, buffer)) = self.instanced_combo_buffer.get_mut(buffer) {
-      let instance_count = buffer.data.len();
+    if let Some((texture, instances, buffers)) = self.instanced_combo_buffer.get_mut(buffer) {
+      let instance_count = instances.len();
+      let mirror_uv = if self.unflipped_textures.contains(texture.as_str()) {
+        1.0
+      } else {
+        0.0
+      };
 
-      buffer.update_with_internal_data(vulkan.device());
+      let buffer = buffers.get_mut(vulkan.current_frame());
+      buffer.update_data(vulkan.device(), std::mem::take(instances));
 
       let texture_descriptor = {
         if let Some((_, texture_descriptor)) = self.textures.get(texture) {
@@ -478,7 +894,7 @@ impl TextureHandler {
         &self.combo_index_buffer,
         Some(&buffer),
         instance_count,
-        vec![self.window_size[0], self.window_size[1]],
+        vec![self.window_size[0], self.window_size[1], mirror_uv],
       );
     }
     //  let descriptor = self.font.descriptor();
@@ -500,15 +916,47 @@ impl TextureHandler {
     //}
   }
 
+  /// Draws particle instances written by the particle compute shader this frame.
+  pub fn draw_particles(
+    &mut self,
+    vulkan: &mut Vulkan,
+    texture: &str,
+    instances: &Buffer<InstancedComboData>,
+    instance_count: usize,
+  ) {
+    let texture_descriptor = {
+      if let Some((_, texture_descriptor)) = self.textures.get(texture) {
+        texture_descriptor
+      } else {
+        &self.dummy_texture.1
+      }
+    };
+
+    vulkan.draw_texture(
+      texture_descriptor,
+      &self.uniform_descriptor,
+      &self.particle_shader,
+      &self.combo_vertex_buffer,
+      &self.combo_index_buffer,
+      Some(instances),
+      instance_count,
+      vec![
+        self.window_size[0],
+        self.window_size[1],
+        self.mirror_uv(texture),
+      ],
+    );
+  }
+
   pub fn add_instanced_texture(&mut self, data: Vec<f32>, buffer_name: &str) {
     let mut data = data;
     let last_idx = data.len() - 4;
     data[last_idx] = self.camera_position.x;
     data[last_idx + 1] = self.camera_position.y;
 
-    if let Some((_, buffer)) = self.instanced_combo_buffer.get_mut(buffer_name) {
-      if buffer.data.len() < MAX_INSTANCES {
-        buffer.data.push(InstancedComboData::from_data(&data));
+    if let Some((_, instances, _)) = self.instanced_combo_buffer.get_mut(buffer_name) {
+      if instances.len() < MAX_INSTANCES {
+        instances.push(InstancedComboData::from_data(&data));
       }
     }
 
@@ -606,31 +1054,87 @@ impl TextureHandler {
     vulkan: &mut Vulkan,
     image: image::ImageBuffer<image::Rgba<u8>, Vec<u8>>,
   ) -> Image {
+    let (src_buffer, dst_image) =
+      TextureHandler::create_staged_texture_from_image(vulkan, image, SPRITE_FORMAT);
+
+    // Goes through the transfer queue when the device has one, the staging buffer is freed once
+    // the copy is done
+    vulkan.transfer_buffers_to_device_local_images(vec![(src_buffer, dst_image.clone())]);
+
+    dst_image
+  }
+
+  /// Creates the staging buffer and the empty device local image, leaving the copy to the caller.
+  /// `format` is an RGBA8 format, sRGB for colours and UNORM for linear data like normal maps.
+  pub fn create_staged_texture_from_image(
+    vulkan: &Vulkan,
+    image: image::ImageBuffer<image::Rgba<u8>, Vec<u8>>,
+    format: vk::Format,
+  ) -> (Buffer<u8>, Image) {
     let dimensions = image.dimensions();
     let image_data = image.into_raw();
 
     let src_buffer = Buffer::<u8>::new_image(vulkan.device(), image_data);
-    let dst_image = ImageBuilder::new(vk::Format::A8B8G8R8_SRGB_PACK32, 1, 1)
-      .usage(vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED)
-      .set_dimensions(dimensions.0, dimensions.1)
-      .build_device_local(vulkan.device());
+    let mut dst_image = ImageBuilder::new(format, 1, 1)
+      .usage(
+        vk::ImageUsageFlags::TRANSFER_SRC
+          | vk::ImageUsageFlags::TRANSFER_DST
+          | vk::ImageUsageFlags::SAMPLED,
+      )
+      .set_dimensions(dimensions.0, dimensions.1);
+
+    // Mips are generated with linear blits once the copy is done
+    if vulkan.device().supports_linear_blit(format) {
+      dst_image = dst_image.full_mip_chain();
+    }
 
-    vulkan.copy_buffer_to_device_local_image(&src_buffer, &dst_image);
+    let dst_image = dst_image.build_device_local(vulkan.device());
 
-    dst_image
+    (src_buffer, dst_image)
   }
 
-  fn create_combo_shader(
+  /// Creates the staging buffer holding every level and the empty device local image, along with
+  /// the offset of each level in the buffer and whether the uvs need mirroring when drawn.
+  pub fn create_staged_compressed_texture(
     vulkan: &Vulkan,
-    descriptor_sets: &Vec<DescriptorSet>,
-  ) -> (
-    //Shader<ComboVertex>,
-    //Shader<ComboVertex>,
-    Shader<ComboVertex>,
-    Buffer<u32>,
-    Buffer<ComboVertex>,
-    Shader<ComboVertex>,
-  ) {
+    texture: CompressedTexture,
+  ) -> Result<(Buffer<u8>, Image, Vec<u64>, bool), String> {
+    let max_dimension = vulkan.gpu_info().limits.max_image_dimension_2d;
+    if texture.width() > max_dimension || texture.height() > max_dimension {
+      return Err(format!(
+        "{}x{} texture is larger than the gpu's {}x{} limit",
+        texture.width(),
+        texture.height(),
+        max_dimension,
+        max_dimension
+      ));
+    }
+
+    let sampled = vulkan.device().supports_sampled_format(texture.format());
+    let (texture, unflipped) = texture.for_upload(sampled)?;
+
+    let format = texture.format();
+    let (data, level_offsets) = texture.packed_levels();
+
+    let src_buffer = Buffer::<u8>::new_image(vulkan.device(), data);
+    let mut dst_image = ImageBuilder::new(format, level_offsets.len() as u32, 1)
+      .usage(
+        vk::ImageUsageFlags::TRANSFER_SRC
+          | vk::ImageUsageFlags::TRANSFER_DST
+          | vk::ImageUsageFlags::SAMPLED,
+      )
+      .set_dimensions(texture.width(), texture.height());
+
+    if level_offsets.len() == 1 && vulkan.device().supports_linear_blit(format) {
+      dst_image = dst_image.full_mip_chain();
+    }
+
+    let dst_image = dst_image.build_device_local(vulkan.device());
+
+    Ok((src_buffer, dst_image, level_offsets, unflipped))
+  }
+
+  fn create_combo_buffers(vulkan: &Vulkan) -> (Buffer<u32>, Buffer<ComboVertex>) {
     let combo_index_buffer_data = vec![0, 1, 2, 3, 4, 5];
     let z = -1.0;
     let combo_vertices = vec![
@@ -666,6 +1170,20 @@ impl TextureHandler {
       },
     ];
 
+    let combo_index_buffer = Buffer::<u32>::new_index(&vulkan.device(), combo_index_buffer_data);
+    let combo_vertex_buffer = Buffer::<ComboVertex>::new_vertex(vulkan.device(), combo_vertices);
+
+    // let instanced_combo_buffer =
+    //   Buffer::<InstancedComboData>::new_vertex(vulkan.device(), instance_data);
+
+    (combo_index_buffer, combo_vertex_buffer)
+  }
+
+  fn create_combo_shaders(
+    vulkan: &Vulkan,
+    descriptor_sets: &[&DescriptorSet],
+    shader_directory: Option<&str>,
+  ) -> Result<ComboShaders, String> {
     let combo_vertex = ComboVertex {
       pos: [0.0, 0.0, 0.0, 0.0],
       colour: [0.0, 0.0, 0.0, 0.0],
@@ -674,31 +1192,24 @@ impl TextureHandler {
     //let instaced_text = InstancedTextData::new();
     let instanced_combo = InstancedComboData::new();
 
-    let combo_index_buffer = Buffer::<u32>::new_index(&vulkan.device(), combo_index_buffer_data);
-    let combo_vertex_buffer = Buffer::<ComboVertex>::new_vertex(vulkan.device(), combo_vertices);
-
-    // let instanced_combo_buffer =
-    //   Buffer::<InstancedComboData>::new_vertex(vulkan.device(), instance_data);
-
     let graphics_pipeline_builder = GraphicsPipelineBuilder::new()
       .topology_triangle_list()
       .front_face_counter_clockwise()
       .polygon_mode_fill()
-      .samples_1();
-
-    let layouts = {
-      let mut sets = Vec::new();
-      for i in 0..descriptor_sets.len() {
-        sets.push(descriptor_sets[i].layouts()[0]);
-      }
+      .samples(vulkan.msaa_samples());
 
-      sets
-    };
-
-    let combo_shader = Shader::new(
+    let combo_shader = Shader::try_new(
       vulkan.device(),
-      Cursor::new(&include_bytes!("../../shaders/combo_vert.spv")[..]),
-      Cursor::new(&include_bytes!("../../shaders/combo_frag.spv")[..]),
+      shader_source(
+        shader_directory,
+        "combo_vert.spv",
+        include_bytes!("../../shaders/combo_vert.spv"),
+      ),
+      shader_source(
+        shader_directory,
+        "combo_frag.spv",
+        include_bytes!("../../shaders/combo_frag.spv"),
+      ),
       combo_vertex,
       vec![
         offset_of!(ComboVertex, pos) as u32,
@@ -709,14 +1220,22 @@ impl TextureHandler {
       vulkan.texture_renderpass(),
       vulkan.viewports(),
       vulkan.scissors(),
-      &layouts,
+      descriptor_sets,
       None as Option<(InstancedTextData, Vec<u32>)>,
-    );
+    )?;
 
-    let instanced_combo_shader = Shader::new(
+    let instanced_combo_shader = match Shader::try_new(
       vulkan.device(),
-      Cursor::new(&include_bytes!("../../shaders/instanced_combo_vert.spv")[..]),
-      Cursor::new(&include_bytes!("../../shaders/combo_frag.spv")[..]),
+      shader_source(
+        shader_directory,
+        "instanced_combo_vert.spv",
+        include_bytes!("../../shaders/instanced_combo_vert.spv"),
+      ),
+      shader_source(
+        shader_directory,
+        "combo_frag.spv",
+        include_bytes!("../../shaders/combo_frag.spv"),
+      ),
       combo_vertex,
       vec![
         offset_of!(ComboVertex, pos) as u32,
@@ -727,7 +1246,7 @@ impl TextureHandler {
       vulkan.texture_renderpass(),
       vulkan.viewports(),
       vulkan.scissors(),
-      &layouts,
+      descriptor_sets,
       Some((
         instanced_combo,
         vec![
@@ -744,7 +1263,59 @@ impl TextureHandler {
           offset_of!(InstancedComboData, camera_intensity_time) as u32, // camera x y, intensity time
         ],
       )),
-    );
+    ) {
+      Ok(shader) => shader,
+      Err(e) => {
+        combo_shader.destroy(vulkan.device());
+        return Err(e);
+      }
+    };
+
+    // Same vertex inputs as the instanced combo shader, the instances come from particles.comp
+    let particle_shader = match Shader::try_new(
+      vulkan.device(),
+      shader_source(
+        shader_directory,
+        "instanced_combo_vert.spv",
+        include_bytes!("../../shaders/instanced_combo_vert.spv"),
+      ),
+      shader_source(
+        shader_directory,
+        "particle_frag.spv",
+        include_bytes!("../../shaders/particle_frag.spv"),
+      ),
+      combo_vertex,
+      vec![
+        offset_of!(ComboVertex, pos) as u32,
+        offset_of!(ComboVertex, colour) as u32,
+        offset_of!(ComboVertex, uv) as u32,
+      ],
+      &graphics_pipeline_builder,
+      vulkan.texture_renderpass(),
+      vulkan.viewports(),
+      vulkan.scissors(),
+      descriptor_sets,
+      Some((
+        instanced_combo,
+        vec![
+          offset_of!(InstancedComboData, pos_scale) as u32,
+          offset_of!(InstancedComboData, other_colour) as u32,
+          offset_of!(InstancedComboData, is_textured_rotation_overlay_mix) as u32,
+          offset_of!(InstancedComboData, sprite_sheet) as u32,
+          offset_of!(InstancedComboData, flip_xy) as u32,
+          offset_of!(InstancedComboData, overlay_colour) as u32,
+          offset_of!(InstancedComboData, attrib6) as u32,
+          offset_of!(InstancedComboData, camera_intensity_time) as u32,
+        ],
+      )),
+    ) {
+      Ok(shader) => shader,
+      Err(e) => {
+        combo_shader.destroy(vulkan.device());
+        instanced_combo_shader.destroy(vulkan.device());
+        return Err(e);
+      }
+    };
 
     //let letter_shader = Shader::new(
     //  vulkan.device(),
@@ -792,14 +1363,12 @@ impl TextureHandler {
     //  )),
     //);
 
-    (
+    Ok((
       //letter_shader,
       //instanced_letter_shader,
       combo_shader,
-      combo_index_buffer,
-      combo_vertex_buffer,
       instanced_combo_shader,
-      //instanced_combo_buffer,
-    )
+      particle_shader,
+    ))
   }
 }
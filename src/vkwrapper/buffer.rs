@@ -1,6 +1,6 @@
 use ash::vk;
 
-use crate::vkwrapper::{Memory, VkDevice};
+use crate::vkwrapper::{Memory, TrackedResource, VkDevice};
 
 pub struct Buffer<T: Sized + Copy> {
   buffer: vk::Buffer,
@@ -20,7 +20,24 @@ impl<T: Sized + Copy> Buffer<T> {
     memory_properties: vk::MemoryPropertyFlags,
     usage: vk::BufferUsageFlags,
   ) -> Buffer<T> {
-    let buffer = Buffer::create_buffer(device, usage, &data);
+    Buffer::new_with_sharing(device, data, memory_properties, usage, false)
+  }
+
+  /// Shared buffers can be used from the graphics, compute and transfer queues without queue
+  /// ownership transfers.
+  pub fn new_with_sharing(
+    device: &VkDevice,
+    data: Vec<T>,
+    memory_properties: vk::MemoryPropertyFlags,
+    usage: vk::BufferUsageFlags,
+    shared_between_queues: bool,
+  ) -> Buffer<T> {
+    let queue_families = if shared_between_queues {
+      device.queue_families().unique()
+    } else {
+      Vec::new()
+    };
+    let buffer = Buffer::create_buffer(device, usage, &data, &queue_families);
     let memory = Memory::new_buffer_memory(device, &buffer, memory_properties, &data);
 
     Buffer {
@@ -32,20 +49,15 @@ impl<T: Sized + Copy> Buffer<T> {
   }
 
   pub fn update_with_internal_data(&mut self, device: &VkDevice) {
-    let requirements = Memory::<T>::buffer_memory_requirements(device, self.buffer);
-
     Memory::<T>::map_data_to_memory(
       device,
-      self.memory.internal(),
-      requirements,
+      self.memory.allocation(),
       &self.data.drain(..).collect(),
     );
   }
 
   pub fn update_data(&mut self, device: &VkDevice, data: Vec<T>) {
-    let requirements = Memory::<T>::buffer_memory_requirements(device, self.buffer);
-
-    Memory::<T>::map_data_to_memory(device, self.memory.internal(), requirements, &data);
+    Memory::<T>::map_data_to_memory(device, self.memory.allocation(), &data);
 
     self.data = data;
   }
@@ -86,21 +98,36 @@ impl<T: Sized + Copy> Buffer<T> {
     Buffer::new_generic(device, data.to_vec(), memory_properties, usage)
   }
 
+  /// Storage buffers are shared with the compute queue, so they can be written by async compute.
   pub fn new_storage_buffer(device: &VkDevice, data: &Vec<T>) -> Buffer<T> {
     let memory_properties =
       vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
     let usage = vk::BufferUsageFlags::STORAGE_BUFFER;
 
-    Buffer::new_generic(device, data.to_vec(), memory_properties, usage)
+    Buffer::new_with_sharing(device, data.to_vec(), memory_properties, usage, true)
   }
 
-  fn create_buffer(device: &VkDevice, usage: vk::BufferUsageFlags, data: &Vec<T>) -> vk::Buffer {
-    let buffer_info = vk::BufferCreateInfo::builder()
+  fn create_buffer(
+    device: &VkDevice,
+    usage: vk::BufferUsageFlags,
+    data: &Vec<T>,
+    queue_families: &[u32],
+  ) -> vk::Buffer {
+    let mut buffer_info = vk::BufferCreateInfo::builder()
       .size(std::mem::size_of::<T>() as u64 * (data.len() as u64))
       .usage(usage)
       .sharing_mode(vk::SharingMode::EXCLUSIVE);
 
-    unsafe { device.internal().create_buffer(&buffer_info, None).unwrap() }
+    if queue_families.len() > 1 {
+      buffer_info = buffer_info
+        .sharing_mode(vk::SharingMode::CONCURRENT)
+        .queue_family_indices(queue_families);
+    }
+
+    let buffer = unsafe { device.internal().create_buffer(&buffer_info, None).unwrap() };
+    device.resources().created(TrackedResource::Buffer);
+
+    buffer
   }
 
   pub fn destroy(&self, device: &VkDevice) {
@@ -108,6 +135,7 @@ impl<T: Sized + Copy> Buffer<T> {
     unsafe {
       device.internal().destroy_buffer(self.buffer, None);
     }
+    device.resources().destroyed(TrackedResource::Buffer);
   }
 
   pub fn internal(&self) -> &vk::Buffer {
@@ -127,9 +155,10 @@ impl<T: Sized + Copy> Buffer<T> {
   }
 
   pub fn descriptor_usage(&self) -> vk::DescriptorType {
+    // Buffers may have other usages too, such as storage buffers also read as vertex buffers
     match self.usage() {
-      vk::BufferUsageFlags::STORAGE_BUFFER => vk::DescriptorType::STORAGE_BUFFER,
-      vk::BufferUsageFlags::UNIFORM_BUFFER => vk::DescriptorType::UNIFORM_BUFFER,
+      bt if bt.contains(vk::BufferUsageFlags::STORAGE_BUFFER) => vk::DescriptorType::STORAGE_BUFFER,
+      bt if bt.contains(vk::BufferUsageFlags::UNIFORM_BUFFER) => vk::DescriptorType::UNIFORM_BUFFER,
       bt => {
         panic!(
           "You cannot update buffer of type: {:?}, Must be type {:?} or {:?}",
@@ -22,6 +22,16 @@ impl CommandBuffer {
     self.cmd
   }
 
+  /// Signalled when the last submission of this command buffer has finished.
+  pub fn reuse_fence(&self) -> &Fence {
+    &self.reuse_fence
+  }
+
+  /// The command buffer itself is freed with its pool.
+  pub fn destroy(&self, device: &VkDevice) {
+    self.reuse_fence.destroy(device);
+  }
+
   pub fn begin(&mut self, device: &VkDevice) {
     let command_buffer_begin_info =
       vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
@@ -144,16 +154,15 @@ impl CommandBuffer {
     }
   }
 
-  pub fn push_constants<T: Copy>(
-    &mut self,
-    device: &VkDevice,
-    shader: &Shader<T>,
-    stage: vk::ShaderStageFlags,
-    data: Vec<f32>,
-  ) {
-    let mut constant_data: [u8; 128] = [0; 128];
+  pub fn push_constants<T: Copy>(&mut self, device: &VkDevice, shader: &Shader<T>, data: Vec<f32>) {
+    let range = match shader.push_constant_range() {
+      Some(range) => range,
+      None => return,
+    };
 
-    for i in 0..(32).min(data.len()) {
+    let mut constant_data = vec![0u8; range.size as usize];
+
+    for i in 0..(range.size as usize / 4).min(data.len()) {
       let bytes = data[i].to_le_bytes();
       for j in 0..4 {
         constant_data[i * 4 + j] = bytes[j];
@@ -164,7 +173,7 @@ impl CommandBuffer {
       device.internal().cmd_push_constants(
         self.cmd,
         shader.pipeline_layout(),
-        stage,
+        range.stage_flags,
         0,
         &constant_data,
       );
@@ -217,6 +226,20 @@ impl CommandBuffer {
     }
   }
 
+  pub fn draw(&mut self, device: &VkDevice, vertex_count: u32) {
+    unsafe {
+      device.internal().cmd_draw(self.cmd, vertex_count, 1, 0, 0);
+    }
+  }
+
+  pub fn draw_range(&mut self, device: &VkDevice, first_vertex: u32, vertex_count: u32) {
+    unsafe {
+      device
+        .internal()
+        .cmd_draw(self.cmd, vertex_count, 1, first_vertex, 0);
+    }
+  }
+
   pub fn end_renderpass(&mut self, device: &VkDevice) {
     unsafe {
       device.internal().cmd_end_render_pass(self.cmd);
@@ -251,6 +274,32 @@ impl CommandBuffer {
     signal_semaphores: Vec<&Semaphore>,
     wait_stages: Vec<vk::PipelineStageFlags>,
     is_compute: bool,
+  ) {
+    let submit_queue = {
+      if is_compute {
+        device.compute_queue()
+      } else {
+        device.present_queue()
+      }
+    };
+
+    self.submit_to_queue(
+      device,
+      submit_queue,
+      wait_semaphores,
+      signal_semaphores,
+      wait_stages,
+    );
+  }
+
+  /// The queue has to be of the family the command buffer's pool was created for.
+  pub fn submit_to_queue(
+    &mut self,
+    device: &VkDevice,
+    submit_queue: vk::Queue,
+    wait_semaphores: Vec<&Semaphore>,
+    signal_semaphores: Vec<&Semaphore>,
+    wait_stages: Vec<vk::PipelineStageFlags>,
   ) {
     let command_buffers = vec![self.cmd];
 
@@ -263,14 +312,6 @@ impl CommandBuffer {
       .map(|s| s.internal())
       .collect::<Vec<vk::Semaphore>>();
 
-    let submit_queue = {
-      if is_compute {
-        device.compute_queue()
-      } else {
-        device.present_queue()
-      }
-    };
-
     let submit_info = vk::SubmitInfo::builder()
       .wait_semaphores(&wait_semaphore)
       .wait_dst_stage_mask(&wait_stages)
@@ -8,9 +8,14 @@ pub struct VkCommandPool {
 
 impl VkCommandPool {
   pub fn new(device: &VkDevice) -> VkCommandPool {
+    VkCommandPool::new_for_queue_family(device, device.queue_family_index())
+  }
+
+  /// Command buffers from the pool can only be submitted to queues of this family.
+  pub fn new_for_queue_family(device: &VkDevice, queue_family_index: u32) -> VkCommandPool {
     let pool_create_info = vk::CommandPoolCreateInfo::builder()
       .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
-      .queue_family_index(device.queue_family_index());
+      .queue_family_index(queue_family_index);
 
     let pool = unsafe {
       device
@@ -41,4 +46,10 @@ impl VkCommandPool {
 
     command_buffers
   }
+
+  pub fn destroy(&self, device: &VkDevice) {
+    unsafe {
+      device.internal().destroy_command_pool(self.pool, None);
+    }
+  }
 }
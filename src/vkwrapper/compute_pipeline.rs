@@ -20,7 +20,7 @@ impl ComputePipeline {
       device
         .internal()
         .create_compute_pipelines(
-          vk::PipelineCache::null(),
+          device.pipeline_cache(),
           &[compute_pipeline_info.build()],
           None,
         )
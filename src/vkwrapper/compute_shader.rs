@@ -20,14 +20,6 @@ impl ComputeShader {
     descriptor_sets: &Vec<DescriptorSet>,
   ) -> ComputeShader {
     let compute_code = read_spv(&mut compute_shader).expect("Failed to read vertex shader");
-    let compute_info = vk::ShaderModuleCreateInfo::builder().code(&compute_code);
-
-    let compute_shader = unsafe {
-      device
-        .internal()
-        .create_shader_module(&compute_info, None)
-        .expect("Vertex shader module error")
-    };
 
     let layouts = {
       let mut sets = Vec::new();
@@ -44,22 +36,50 @@ impl ComputeShader {
       .size(128)
       .build();
 
-    let layout_info = {
-      if layouts.len() == 0 {
-        vk::PipelineLayoutCreateInfo::default()
-      } else {
-        vk::PipelineLayoutCreateInfo::builder()
-          .set_layouts(&layouts)
-          .push_constant_ranges(&[push_constant_range])
-          .build()
-      }
+    let push_constant_range = if layouts.is_empty() {
+      None
+    } else {
+      Some(push_constant_range)
+    };
+
+    match ComputeShader::try_new_with_layouts(device, &compute_code, &layouts, push_constant_range)
+    {
+      Ok(shader) => shader,
+      Err(e) => panic!("{}", e),
+    }
+  }
+
+  /// Builds the pipeline for already read SPIR-V, reporting failures instead of panicking.
+  pub fn try_new_with_layouts(
+    device: &VkDevice,
+    compute_code: &[u32],
+    layouts: &[vk::DescriptorSetLayout],
+    push_constant_range: Option<vk::PushConstantRange>,
+  ) -> Result<ComputeShader, String> {
+    let compute_info = vk::ShaderModuleCreateInfo::builder().code(compute_code);
+
+    let compute_shader = unsafe {
+      device
+        .internal()
+        .create_shader_module(&compute_info, None)
+        .map_err(|e| format!("Compute shader module error: {}", e))?
     };
 
+    let push_constant_ranges = push_constant_range.into_iter().collect::<Vec<_>>();
+    let layout_info = vk::PipelineLayoutCreateInfo::builder()
+      .set_layouts(layouts)
+      .push_constant_ranges(&push_constant_ranges);
+
     let pipeline_layout = unsafe {
       device
         .internal()
         .create_pipeline_layout(&layout_info, None)
-        .unwrap()
+        .map_err(|e| {
+          device
+            .internal()
+            .destroy_shader_module(compute_shader, None);
+          format!("Compute pipeline layout error: {}", e)
+        })?
     };
 
     let shader_entry = CString::new("main").unwrap();
@@ -73,11 +93,11 @@ impl ComputeShader {
 
     let compute_pipeline = ComputePipeline::new(device, &pipeline_layout, shader_stage_create_info);
 
-    ComputeShader {
+    Ok(ComputeShader {
       compute_shader,
       pipeline_layout,
       compute_pipeline,
-    }
+    })
   }
 
   pub fn pipeline(&self) -> &ComputePipeline {
@@ -1,21 +1,29 @@
 use ash::vk;
 
-use crate::vkwrapper::VkDevice;
+use crate::vkwrapper::{DescriptorAllocator, ReflectedBinding, VkDevice};
 
 #[derive(Clone)]
 pub struct DescriptorSet {
   descriptor_sets: Vec<vk::DescriptorSet>,
   descriptor_layouts: Vec<vk::DescriptorSetLayout>,
+  // Pool the sets were allocated from
+  descriptor_pool: vk::DescriptorPool,
+  // Describes the bindings in the layout, used to check shaders against it
+  layout_builder: DescriptorSetBuilder,
 }
 
 impl DescriptorSet {
   pub fn new(
     descriptor_sets: Vec<vk::DescriptorSet>,
     descriptor_layouts: Vec<vk::DescriptorSetLayout>,
+    descriptor_pool: vk::DescriptorPool,
+    layout_builder: DescriptorSetBuilder,
   ) -> DescriptorSet {
     DescriptorSet {
       descriptor_sets,
       descriptor_layouts,
+      descriptor_pool,
+      layout_builder,
     }
   }
 
@@ -31,6 +39,21 @@ impl DescriptorSet {
     &self.descriptor_layouts
   }
 
+  pub fn layout_builder(&self) -> &DescriptorSetBuilder {
+    &self.layout_builder
+  }
+
+  /// Only valid for sets allocated from a pool built with `free_individual_sets`, frees room in
+  /// the pool for new sets.
+  pub fn free(&self, device: &VkDevice) {
+    unsafe {
+      device
+        .internal()
+        .free_descriptor_sets(self.descriptor_pool, &self.descriptor_sets)
+        .expect("Failed to free descriptor sets");
+    }
+  }
+
   pub fn destroy(&self, device: &VkDevice) {
     unsafe {
       for layout in &self.descriptor_layouts {
@@ -42,9 +65,12 @@ impl DescriptorSet {
   }
 }
 
+#[derive(Clone)]
 pub struct DescriptorSetBuilder {
   types: Vec<vk::DescriptorType>,
   stages: Vec<vk::ShaderStageFlags>,
+  bindings: Vec<u32>,
+  counts: Vec<u32>,
 }
 
 impl DescriptorSetBuilder {
@@ -52,7 +78,23 @@ impl DescriptorSetBuilder {
     let types: Vec<vk::DescriptorType> = Vec::new();
     let stages: Vec<vk::ShaderStageFlags> = Vec::new();
 
-    DescriptorSetBuilder { types, stages }
+    DescriptorSetBuilder {
+      types,
+      stages,
+      bindings: Vec::new(),
+      counts: Vec::new(),
+    }
+  }
+
+  /// Uses the binding numbers, types and stages read from the shaders rather than assuming the
+  /// bindings are numbered in the order they were added.
+  pub fn from_reflection(reflected: &[ReflectedBinding]) -> DescriptorSetBuilder {
+    DescriptorSetBuilder {
+      types: reflected.iter().map(|b| b.descriptor_type).collect(),
+      stages: reflected.iter().map(|b| b.stages).collect(),
+      bindings: reflected.iter().map(|b| b.binding).collect(),
+      counts: reflected.iter().map(|b| b.count).collect(),
+    }
   }
 
   pub fn uniform_buffer_vertex(mut self) -> DescriptorSetBuilder {
@@ -81,6 +123,18 @@ impl DescriptorSetBuilder {
     self
   }
 
+  pub fn combined_image_sampler_compute(mut self) -> DescriptorSetBuilder {
+    self.types.push(vk::DescriptorType::COMBINED_IMAGE_SAMPLER);
+    self.stages.push(vk::ShaderStageFlags::COMPUTE);
+    self
+  }
+
+  pub fn storage_image_compute(mut self) -> DescriptorSetBuilder {
+    self.types.push(vk::DescriptorType::STORAGE_IMAGE);
+    self.stages.push(vk::ShaderStageFlags::COMPUTE);
+    self
+  }
+
   pub fn storage_vertex(mut self) -> DescriptorSetBuilder {
     self.types.push(vk::DescriptorType::STORAGE_BUFFER);
     self.stages.push(vk::ShaderStageFlags::VERTEX);
@@ -115,15 +169,19 @@ impl DescriptorSetBuilder {
     self
   }
 
-  pub fn build(&self, device: &VkDevice, descriptor_pool: &vk::DescriptorPool) -> DescriptorSet {
+  pub fn build(
+    &self,
+    device: &VkDevice,
+    descriptor_allocator: &DescriptorAllocator,
+  ) -> DescriptorSet {
     let mut descriptor_layout_bindings = Vec::new();
 
     for i in 0..self.types.len() {
       descriptor_layout_bindings.push(
         *vk::DescriptorSetLayoutBinding::builder()
-          .binding(i as u32)
+          .binding(self.binding(i))
           .descriptor_type(self.types[i])
-          .descriptor_count(1)
+          .descriptor_count(self.counts.get(i).copied().unwrap_or(1))
           .stage_flags(self.stages[i]),
       );
     }
@@ -138,17 +196,94 @@ impl DescriptorSetBuilder {
         .unwrap()
     }];
 
-    let desc_alloc_info = vk::DescriptorSetAllocateInfo::builder()
-      .descriptor_pool(*descriptor_pool)
-      .set_layouts(&descriptor_set_layouts);
+    let (descriptor_pool, descriptor_sets) =
+      descriptor_allocator.allocate(device, &descriptor_set_layouts, &self.pool_sizes());
 
-    let descriptor_sets = unsafe {
-      device
-        .internal()
-        .allocate_descriptor_sets(&desc_alloc_info)
-        .unwrap()
-    };
+    DescriptorSet::new(
+      descriptor_sets,
+      descriptor_set_layouts.to_vec(),
+      descriptor_pool,
+      self.clone(),
+    )
+  }
+
+  /// Checks that every binding the shaders use in `set` exists in this layout with the same
+  /// type, enough descriptors and all the stages that read it.
+  pub fn check_reflected(&self, set: u32, reflected: &[ReflectedBinding]) -> Result<(), String> {
+    for shader_binding in reflected.iter().filter(|b| b.set == set) {
+      let i = match (0..self.types.len()).find(|i| self.binding(*i) == shader_binding.binding) {
+        Some(i) => i,
+        None => {
+          return Err(format!(
+            "Set {} binding {} ({:?}) is used by the shaders but missing from the descriptor set layout",
+            set, shader_binding.binding, shader_binding.descriptor_type
+          ))
+        }
+      };
+
+      if self.types[i] != shader_binding.descriptor_type {
+        return Err(format!(
+          "Set {} binding {} is {:?} in the shaders but {:?} in the descriptor set layout",
+          set, shader_binding.binding, shader_binding.descriptor_type, self.types[i]
+        ));
+      }
+      if !self.stages[i].contains(shader_binding.stages) {
+        return Err(format!(
+          "Set {} binding {} is used in {:?} but the descriptor set layout only allows {:?}",
+          set, shader_binding.binding, shader_binding.stages, self.stages[i]
+        ));
+      }
+      let count = self.counts.get(i).copied().unwrap_or(1);
+      if count < shader_binding.count {
+        return Err(format!(
+          "Set {} binding {} has {} descriptors in the shaders but {} in the descriptor set layout",
+          set, shader_binding.binding, shader_binding.count, count
+        ));
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Checks the bindings the shaders use against the layouts of the descriptor sets that will be
+  /// bound, layout `i` being bound as set `i`.
+  pub fn check_layouts(
+    bindings: &[ReflectedBinding],
+    layouts: &[&DescriptorSetBuilder],
+  ) -> Result<(), String> {
+    if let Some(binding) = bindings.iter().find(|b| b.set as usize >= layouts.len()) {
+      return Err(format!(
+        "Shaders use descriptor set {} but only {} descriptor sets were given",
+        binding.set,
+        layouts.len()
+      ));
+    }
+
+    for (set, layout) in layouts.iter().enumerate() {
+      layout.check_reflected(set as u32, bindings)?;
+    }
+
+    Ok(())
+  }
+
+  fn binding(&self, i: usize) -> u32 {
+    self.bindings.get(i).copied().unwrap_or(i as u32)
+  }
+
+  /// Descriptors of each type the set needs.
+  fn pool_sizes(&self) -> Vec<vk::DescriptorPoolSize> {
+    let mut pool_sizes: Vec<vk::DescriptorPoolSize> = Vec::new();
+    for (i, ty) in self.types.iter().enumerate() {
+      let count = self.counts.get(i).copied().unwrap_or(1);
+      match pool_sizes.iter_mut().find(|size| size.ty == *ty) {
+        Some(size) => size.descriptor_count += count,
+        None => pool_sizes.push(vk::DescriptorPoolSize {
+          ty: *ty,
+          descriptor_count: count,
+        }),
+      }
+    }
 
-    DescriptorSet::new(descriptor_sets, descriptor_set_layouts.to_vec())
+    pool_sizes
   }
 }
@@ -14,6 +14,8 @@ pub struct DescriptorWriterBuilder {
   descriptor_buffer_infos: Vec<vk::DescriptorBufferInfo>,
   descriptor_image_infos: Vec<vk::DescriptorImageInfo>,
   descriptor_write_sets: Vec<vk::WriteDescriptorSet>,
+  // Index of each write's first buffer or image info
+  info_indices: Vec<usize>,
 }
 
 impl DescriptorWriterBuilder {
@@ -22,6 +24,7 @@ impl DescriptorWriterBuilder {
       descriptor_buffer_infos: Vec::new(),
       descriptor_image_infos: Vec::new(),
       descriptor_write_sets: Vec::new(),
+      info_indices: Vec::new(),
     }
   }
 
@@ -44,6 +47,9 @@ impl DescriptorWriterBuilder {
       p_buffer_info: &self.descriptor_buffer_infos[self.descriptor_buffer_infos.len() - 1],
       ..Default::default()
     });
+    self
+      .info_indices
+      .push(self.descriptor_buffer_infos.len() - 1);
 
     self
   }
@@ -122,6 +128,7 @@ impl DescriptorWriterBuilder {
       p_image_info: self.descriptor_image_infos[start_idx..].as_ptr(), //[self.descriptor_image_infos.len() - 1],
       ..Default::default()
     });
+    self.info_indices.push(start_idx);
 
     self
   }
@@ -146,15 +153,90 @@ impl DescriptorWriterBuilder {
       p_image_info: &self.descriptor_image_infos[self.descriptor_image_infos.len() - 1],
       ..Default::default()
     });
+    self
+      .info_indices
+      .push(self.descriptor_image_infos.len() - 1);
+
+    self
+  }
+
+  /// For images sampled while in a layout other than shader read only, such as storage images
+  /// kept in the general layout.
+  pub fn update_image_in_layout(
+    mut self,
+    image: &Image,
+    sampler: &Sampler,
+    image_layout: vk::ImageLayout,
+    descriptor_sets: &DescriptorSet,
+  ) -> DescriptorWriterBuilder {
+    self.descriptor_image_infos.push(vk::DescriptorImageInfo {
+      image_layout,
+      image_view: image.view(),
+      sampler: sampler.internal(),
+    });
+
+    self.descriptor_write_sets.push(vk::WriteDescriptorSet {
+      dst_set: descriptor_sets.internal()[0],
+      dst_binding: self.descriptor_write_sets.len() as u32,
+      descriptor_count: 1,
+      descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+      p_image_info: &self.descriptor_image_infos[self.descriptor_image_infos.len() - 1],
+      ..Default::default()
+    });
+    self
+      .info_indices
+      .push(self.descriptor_image_infos.len() - 1);
+
+    self
+  }
+
+  pub fn update_storage_image(
+    mut self,
+    image: &Image,
+    descriptor_sets: &DescriptorSet,
+  ) -> DescriptorWriterBuilder {
+    self.descriptor_image_infos.push(vk::DescriptorImageInfo {
+      image_layout: vk::ImageLayout::GENERAL,
+      image_view: image.view(),
+      sampler: vk::Sampler::null(),
+    });
+
+    self.descriptor_write_sets.push(vk::WriteDescriptorSet {
+      dst_set: descriptor_sets.internal()[0],
+      dst_binding: self.descriptor_write_sets.len() as u32,
+      descriptor_count: 1,
+      descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+      p_image_info: &self.descriptor_image_infos[self.descriptor_image_infos.len() - 1],
+      ..Default::default()
+    });
+    self
+      .info_indices
+      .push(self.descriptor_image_infos.len() - 1);
 
     self
   }
 
   pub fn build(&self, device: &VkDevice) {
+    // The info vectors may have reallocated as writes were added, point at where they are now
+    let write_sets = self
+      .descriptor_write_sets
+      .iter()
+      .zip(&self.info_indices)
+      .map(|(write_set, &index)| {
+        let mut write_set = *write_set;
+        if write_set.p_buffer_info.is_null() {
+          write_set.p_image_info = &self.descriptor_image_infos[index];
+        } else {
+          write_set.p_buffer_info = &self.descriptor_buffer_infos[index];
+        }
+        write_set
+      })
+      .collect::<Vec<_>>();
+
     unsafe {
       device
         .internal()
-        .update_descriptor_sets(&self.descriptor_write_sets[..], &[]);
+        .update_descriptor_sets(&write_sets[..], &[]);
     }
   }
 }
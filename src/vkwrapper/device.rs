@@ -1,11 +1,14 @@
+use std::cell::RefCell;
 use std::default::Default;
 
 use ash::extensions::khr::{Maintenance1, Surface, Swapchain};
 //pub use ash::version::{DeviceV1_0, EntryV1_0, InstanceV1_0};
-use ash::{vk, Device};
+use ash::{vk, Device, Instance};
 use raw_window_handle::HasDisplayHandle;
 
-use crate::vkwrapper::{VkInstance, VkWindow};
+use crate::vkwrapper::gpu::{self, GpuInfo, GpuPreference, QueueFamilies};
+use crate::vkwrapper::pipeline_cache::{self, PipelineCacheHeader};
+use crate::vkwrapper::{MemoryAllocator, ResourceTracker, VkInstance, VkWindow};
 use raw_window_handle::*;
 
 use crate::vkwrapper::ash_window;
@@ -25,34 +28,80 @@ use winit::{
 
 pub struct VkDevice {
   device: Device,
+  instance: Instance,
   phys_device: vk::PhysicalDevice,
+  gpu_info: GpuInfo,
+  gpus: Vec<GpuInfo>,
+  max_sampler_anisotropy: Option<f32>,
+  sample_counts: vk::SampleCountFlags,
   device_memory_properties: vk::PhysicalDeviceMemoryProperties,
   surface: vk::SurfaceKHR,
   surface_format: vk::SurfaceFormatKHR,
   surface_loader: Surface,
-  queue_family_index: u32,
+  queue_families: QueueFamilies,
   present_queue: vk::Queue,
   compute_queue: vk::Queue,
+  transfer_queue: vk::Queue,
+  resources: ResourceTracker,
+  memory_allocator: RefCell<MemoryAllocator>,
+  pipeline_cache: vk::PipelineCache,
+  pipeline_cache_path: Option<String>,
 }
 
 impl VkDevice {
-  pub fn new(instance: &VkInstance, event_loop: &EventLoop<()>, window: &VkWindow) -> VkDevice {
-    let surface_loader = Surface::new(instance.entry(), instance.internal());
-    let surface = unsafe {
-      ash_window::create_surface(
-        instance.entry(),
-        instance.internal(),
-        window.internal().raw_display_handle().unwrap(), //.raw_display_handle(),
-        window.internal().raw_window_handle().unwrap(),
-        None,
-      )
-      .unwrap()
+  pub fn new(
+    instance: &VkInstance,
+    event_loop: &EventLoop<()>,
+    window: &VkWindow,
+    preference: &GpuPreference,
+    pipeline_cache_path: Option<&str>,
+  ) -> VkDevice {
+    let (surface, surface_loader) = VkDevice::create_surface(instance, window);
+
+    let (phys_device, queue_family_index, gpu_info, gpus) =
+      pick_physical_device(instance, &surface, &surface_loader, preference);
+    println!(
+      "Using gpu {}: {} ({:?})",
+      gpu_info.index, gpu_info.name, gpu_info.gpu_type
+    );
+    let queue_families = QueueFamilies::choose(
+      &unsafe {
+        instance
+          .internal()
+          .get_physical_device_queue_family_properties(phys_device)
+      }
+      .iter()
+      .map(|family| family.queue_flags)
+      .collect::<Vec<_>>(),
+      queue_family_index,
+    );
+    println!("Using queue families {:?}", queue_families);
+
+    let (device, present_queue, compute_queue, transfer_queue, sampler_anisotropy) =
+      create_logical_device(instance, &phys_device, &queue_families);
+
+    let properties = unsafe {
+      instance
+        .internal()
+        .get_physical_device_properties(phys_device)
     };
 
-    let (phys_device, queue_family_index) =
-      pick_physical_device(instance, &surface, &surface_loader);
-    let (device, present_queue, compute_queue) =
-      create_logical_device(instance, &phys_device, queue_family_index);
+    let initial_cache_data = pipeline_cache_path
+      .and_then(|path| {
+        pipeline_cache::load_cache_data(path, &PipelineCacheHeader::from_properties(&properties))
+      })
+      .unwrap_or_default();
+    let pipeline_cache = pipeline_cache::create_pipeline_cache(&device, &initial_cache_data);
+
+    let max_sampler_anisotropy = if sampler_anisotropy {
+      Some(properties.limits.max_sampler_anisotropy)
+    } else {
+      None
+    };
+
+    // Both the colour and depth targets are multisampled
+    let sample_counts = properties.limits.framebuffer_color_sample_counts
+      & properties.limits.framebuffer_depth_sample_counts;
 
     let surface_format = unsafe {
       *surface_loader
@@ -78,25 +127,115 @@ impl VkDevice {
 
     VkDevice {
       device,
+      instance: instance.internal().clone(),
       phys_device,
+      gpu_info,
+      gpus,
+      max_sampler_anisotropy,
+      sample_counts,
       device_memory_properties,
       surface,
       surface_format,
       surface_loader,
-      queue_family_index,
+      queue_families,
       present_queue,
       compute_queue,
+      transfer_queue,
+      resources: ResourceTracker::new(),
+      memory_allocator: RefCell::new(MemoryAllocator::new()),
+      pipeline_cache,
+      pipeline_cache_path: pipeline_cache_path.map(|path| path.to_string()),
+    }
+  }
+
+  fn create_surface(instance: &VkInstance, window: &VkWindow) -> (vk::SurfaceKHR, Surface) {
+    let surface_loader = Surface::new(instance.entry(), instance.internal());
+    let surface = unsafe {
+      ash_window::create_surface(
+        instance.entry(),
+        instance.internal(),
+        window.internal().raw_display_handle().unwrap(), //.raw_display_handle(),
+        window.internal().raw_window_handle().unwrap(),
+        None,
+      )
+      .unwrap()
+    };
+
+    (surface, surface_loader)
+  }
+
+  /// Lists the gpus without creating a device, so one can be chosen before start up.
+  pub fn enumerate_gpus(instance: &VkInstance, window: &VkWindow) -> Vec<GpuInfo> {
+    let (surface, surface_loader) = VkDevice::create_surface(instance, window);
+    let gpus = gpu::enumerate_gpus(instance, &surface, &surface_loader)
+      .into_iter()
+      .map(|(_, info, _)| info)
+      .collect();
+
+    unsafe {
+      surface_loader.destroy_surface(surface, None);
     }
+
+    gpus
   }
 
   pub fn internal(&self) -> &Device {
     &self.device
   }
 
+  pub fn gpu_info(&self) -> &GpuInfo {
+    &self.gpu_info
+  }
+
+  /// Every gpu found when the device was created, including unsuitable ones.
+  pub fn gpus(&self) -> &Vec<GpuInfo> {
+    &self.gpus
+  }
+
   pub fn device_memory_properties(&self) -> vk::PhysicalDeviceMemoryProperties {
     self.device_memory_properties
   }
 
+  /// None when the device doesn't support anisotropic filtering.
+  pub fn max_sampler_anisotropy(&self) -> Option<f32> {
+    self.max_sampler_anisotropy
+  }
+
+  /// Sample counts usable for multisampled colour and depth targets.
+  pub fn sample_counts(&self) -> vk::SampleCountFlags {
+    self.sample_counts
+  }
+
+  /// Whether images of the format can be used as both ends of a linearly filtered blit, which
+  /// mipmap generation needs.
+  pub fn supports_linear_blit(&self, format: vk::Format) -> bool {
+    let properties = unsafe {
+      self
+        .instance
+        .get_physical_device_format_properties(self.phys_device, format)
+    };
+
+    properties.optimal_tiling_features.contains(
+      vk::FormatFeatureFlags::BLIT_SRC
+        | vk::FormatFeatureFlags::BLIT_DST
+        | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR,
+    )
+  }
+
+  /// Whether optimally tiled images of the format can be sampled, used to check for compressed
+  /// texture support.
+  pub fn supports_sampled_format(&self, format: vk::Format) -> bool {
+    let properties = unsafe {
+      self
+        .instance
+        .get_physical_device_format_properties(self.phys_device, format)
+    };
+
+    properties
+      .optimal_tiling_features
+      .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE)
+  }
+
   pub fn surface_format(&self) -> vk::SurfaceFormatKHR {
     self.surface_format
   }
@@ -114,7 +253,11 @@ impl VkDevice {
   }
 
   pub fn queue_family_index(&self) -> u32 {
-    self.queue_family_index
+    self.queue_families.graphics
+  }
+
+  pub fn queue_families(&self) -> QueueFamilies {
+    self.queue_families
   }
 
   pub fn present_queue(&self) -> vk::Queue {
@@ -124,59 +267,94 @@ impl VkDevice {
   pub fn compute_queue(&self) -> vk::Queue {
     self.compute_queue
   }
+
+  pub fn transfer_queue(&self) -> vk::Queue {
+    self.transfer_queue
+  }
+
+  pub fn resources(&self) -> &ResourceTracker {
+    &self.resources
+  }
+
+  pub fn memory_allocator(&self) -> &RefCell<MemoryAllocator> {
+    &self.memory_allocator
+  }
+
+  /// Every graphics and compute pipeline is created through this cache.
+  pub fn pipeline_cache(&self) -> vk::PipelineCache {
+    self.pipeline_cache
+  }
+
+  /// The file the cache was loaded from, and is saved to on destroy.
+  pub fn pipeline_cache_path(&self) -> Option<&str> {
+    self.pipeline_cache_path.as_deref()
+  }
+
+  pub fn save_pipeline_cache(&self, path: &str) -> Result<(), String> {
+    pipeline_cache::save_cache_data(&self.device, self.pipeline_cache, path)
+  }
+
+  pub fn destroy(&self) {
+    self.memory_allocator.borrow_mut().destroy(self);
+
+    if let Some(path) = &self.pipeline_cache_path {
+      if let Err(e) = self.save_pipeline_cache(path) {
+        println!("{}", e);
+      }
+    }
+
+    unsafe {
+      self
+        .device
+        .destroy_pipeline_cache(self.pipeline_cache, None);
+      self.device.destroy_device(None);
+      self.surface_loader.destroy_surface(self.surface, None);
+    }
+  }
 }
 
 fn pick_physical_device(
   instance: &VkInstance,
   surface: &vk::SurfaceKHR,
   surface_loader: &Surface,
-) -> (vk::PhysicalDevice, u32) {
-  let pdevices = unsafe {
-    instance
-      .internal()
-      .enumerate_physical_devices()
-      .expect("Physical device error")
-  };
-  let (pdevice, queue_family_index) = pdevices
-    .iter()
-    .map(|pdevice| unsafe {
-      instance
-        .internal()
-        .get_physical_device_queue_family_properties(*pdevice)
-        .iter()
-        .enumerate()
-        .find_map(|(index, ref info)| {
-          let supports_graphic_and_surface = info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
-            && info.queue_flags.contains(vk::QueueFlags::COMPUTE)
-            && surface_loader
-              .get_physical_device_surface_support(*pdevice, index as u32, *surface)
-              .unwrap();
-          if supports_graphic_and_surface {
-            Some((*pdevice, index))
-          } else {
-            None
-          }
-        })
-    })
-    .find_map(|v| v)
-    .expect("Couldn't find suitable device.");
+  preference: &GpuPreference,
+) -> (vk::PhysicalDevice, u32, GpuInfo, Vec<GpuInfo>) {
+  let devices = gpu::enumerate_gpus(instance, surface, surface_loader);
+  let gpus: Vec<GpuInfo> = devices.iter().map(|(_, info, _)| info.clone()).collect();
 
-  (pdevice, queue_family_index as u32)
+  let preference = GpuPreference::from_env().unwrap_or_else(|| preference.clone());
+  let chosen = gpu::choose_gpu(&gpus, &preference).expect("Couldn't find suitable device.");
+
+  let (pdevice, info, queue_family_index) = devices.into_iter().nth(chosen).unwrap();
+
+  (pdevice, queue_family_index.unwrap(), info, gpus)
 }
 
 fn create_logical_device(
   instance: &VkInstance,
   pdevice: &vk::PhysicalDevice,
-  queue_family_index: u32,
-) -> (Device, vk::Queue, vk::Queue) {
+  queue_families: &QueueFamilies,
+) -> (Device, vk::Queue, vk::Queue, vk::Queue, bool) {
+  let supported_features = unsafe { instance.internal().get_physical_device_features(*pdevice) };
+  let sampler_anisotropy = supported_features.sampler_anisotropy == vk::TRUE;
+
   let priorities = [1.0];
-  let queue_info = [*vk::DeviceQueueCreateInfo::builder()
-    .queue_family_index(queue_family_index)
-    .queue_priorities(&priorities)];
+  let queue_info = queue_families
+    .unique()
+    .into_iter()
+    .map(|queue_family_index| {
+      *vk::DeviceQueueCreateInfo::builder()
+        .queue_family_index(queue_family_index)
+        .queue_priorities(&priorities)
+    })
+    .collect::<Vec<_>>();
   let device_extension_names_raw = [Swapchain::name().as_ptr(), Maintenance1::name().as_ptr()];
   let features = vk::PhysicalDeviceFeatures {
     shader_clip_distance: 1,
     fill_mode_non_solid: 1,
+    sampler_anisotropy: supported_features.sampler_anisotropy,
+    texture_compression_bc: supported_features.texture_compression_bc,
+    texture_compression_astc_ldr: supported_features.texture_compression_astc_ldr,
     ..Default::default()
   };
   let device_create_info = vk::DeviceCreateInfo::builder()
@@ -191,9 +369,20 @@ fn create_logical_device(
       .unwrap()
   };
 
-  // Single queue for both graphics and compute
-  let present_queue = unsafe { device.get_device_queue(queue_family_index, 0) };
-  let compute_queue = present_queue;
+  // One queue per family, compute and transfer get the graphics queue when they share its family
+  let (present_queue, compute_queue, transfer_queue) = unsafe {
+    (
+      device.get_device_queue(queue_families.graphics, 0),
+      device.get_device_queue(queue_families.compute, 0),
+      device.get_device_queue(queue_families.transfer, 0),
+    )
+  };
 
-  (device, present_queue, compute_queue)
+  (
+    device,
+    present_queue,
+    compute_queue,
+    transfer_queue,
+    sampler_anisotropy,
+  )
 }
@@ -41,6 +41,16 @@ impl Fence {
     }
   }
 
+  /// Polls the fence without blocking.
+  pub fn is_signaled(&self, device: &VkDevice) -> bool {
+    unsafe {
+      device
+        .internal()
+        .get_fence_status(self.fence)
+        .expect("Get fence status failed.")
+    }
+  }
+
   pub fn reset(&self, device: &VkDevice) {
     unsafe {
       device
@@ -53,4 +63,10 @@ impl Fence {
   pub fn internal(&self) -> vk::Fence {
     self.fence
   }
+
+  pub fn destroy(&self, device: &VkDevice) {
+    unsafe {
+      device.internal().destroy_fence(self.fence, None);
+    }
+  }
 }
@@ -10,6 +10,7 @@ impl VkFrameBuffer {
   pub fn new(
     device: &VkDevice,
     swapchain: &mut VkSwapchain,
+    colour_image: Option<&Image>,
     depth_image: &Image,
     renderpass: &Renderpass,
   ) -> VkFrameBuffer {
@@ -19,7 +20,11 @@ impl VkFrameBuffer {
       .iter()
       .map(|present_image| {
         let present_image_view = present_image.view();
-        let framebuffer_attachments = [present_image_view, depth_image.view()];
+        // With MSAA the present image is where the multisampled colour is resolved to
+        let framebuffer_attachments = match colour_image {
+          Some(colour_image) => vec![colour_image.view(), depth_image.view(), present_image_view],
+          None => vec![present_image_view, depth_image.view()],
+        };
         let frame_buffer_create_info = vk::FramebufferCreateInfo::builder()
           .render_pass(renderpass.internal())
           .attachments(&framebuffer_attachments)
@@ -39,6 +44,32 @@ impl VkFrameBuffer {
     VkFrameBuffer { framebuffers }
   }
 
+  /// A single framebuffer for rendering into images that aren't part of the swapchain.
+  pub fn new_offscreen(
+    device: &VkDevice,
+    extent: vk::Extent2D,
+    attachments: Vec<vk::ImageView>,
+    renderpass: &Renderpass,
+  ) -> VkFrameBuffer {
+    let frame_buffer_create_info = vk::FramebufferCreateInfo::builder()
+      .render_pass(renderpass.internal())
+      .attachments(&attachments)
+      .width(extent.width)
+      .height(extent.height)
+      .layers(1);
+
+    let framebuffer = unsafe {
+      device
+        .internal()
+        .create_framebuffer(&frame_buffer_create_info, None)
+        .unwrap()
+    };
+
+    VkFrameBuffer {
+      framebuffers: vec![framebuffer],
+    }
+  }
+
   pub fn framebuffers(&self) -> &Vec<vk::Framebuffer> {
     &self.framebuffers
   }
@@ -1,4 +1,4 @@
-use crate::vkwrapper::{CommandBuffer, Semaphore, VkCommandPool, VkDevice};
+use crate::vkwrapper::{CommandBuffer, Fence, Semaphore, VkCommandPool, VkDevice};
 
 pub struct Frame {
   present_semaphore: Semaphore,
@@ -28,6 +28,17 @@ impl Frame {
     )
   }
 
+  /// Signalled once the GPU has finished the frame's last submission, created signalled.
+  pub fn in_flight_fence(&self) -> &Fence {
+    self.command_buffer.reuse_fence()
+  }
+
+  /// Blocks until the frame's previous submission is done, after which its command buffer and
+  /// per frame resources can be written again.
+  pub fn wait(&self, device: &VkDevice) {
+    self.in_flight_fence().wait(device);
+  }
+
   pub fn present_semaphore(&self) -> &Semaphore {
     &self.present_semaphore
   }
@@ -39,4 +50,54 @@ impl Frame {
   pub fn command_buffer(&mut self) -> &mut CommandBuffer {
     &mut self.command_buffer
   }
+
+  pub fn destroy(&self, device: &VkDevice) {
+    self.command_buffer.destroy(device);
+    self.pool.destroy(device);
+    self.present_semaphore.destroy(device);
+    self.render_semaphore.destroy(device);
+  }
+}
+
+/// One of T for each frame in flight, so a frame can write its copy while the GPU is still
+/// reading the others.
+pub struct PerFrame<T> {
+  items: Vec<T>,
+}
+
+impl<T> PerFrame<T> {
+  pub fn new<F: FnMut(usize) -> T>(frames_in_flight: usize, create: F) -> PerFrame<T> {
+    PerFrame {
+      items: (0..frames_in_flight).map(create).collect(),
+    }
+  }
+
+  pub fn get(&self, frame: usize) -> &T {
+    &self.items[frame % self.items.len()]
+  }
+
+  pub fn get_mut(&mut self, frame: usize) -> &mut T {
+    let len = self.items.len();
+    &mut self.items[frame % len]
+  }
+
+  pub fn iter(&self) -> std::slice::Iter<'_, T> {
+    self.items.iter()
+  }
+
+  pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, T> {
+    self.items.iter_mut()
+  }
+
+  pub fn len(&self) -> usize {
+    self.items.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.items.is_empty()
+  }
+
+  pub fn into_vec(self) -> Vec<T> {
+    self.items
+  }
 }
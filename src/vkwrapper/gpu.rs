@@ -0,0 +1,311 @@
+use ash::extensions::khr::Surface;
+use ash::vk;
+
+use crate::vkwrapper::VkInstance;
+
+use std::ffi::CStr;
+
+pub const GPU_ENV_VAR: &str = "MAAT_GPU";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GpuType {
+  Discrete,
+  Integrated,
+  Virtual,
+  Cpu,
+  Other,
+}
+
+impl GpuType {
+  fn from_vk(device_type: vk::PhysicalDeviceType) -> GpuType {
+    match device_type {
+      vk::PhysicalDeviceType::DISCRETE_GPU => GpuType::Discrete,
+      vk::PhysicalDeviceType::INTEGRATED_GPU => GpuType::Integrated,
+      vk::PhysicalDeviceType::VIRTUAL_GPU => GpuType::Virtual,
+      vk::PhysicalDeviceType::CPU => GpuType::Cpu,
+      _ => GpuType::Other,
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum GpuPreference {
+  Discrete,      // discrete, then integrated, then anything else
+  Integrated,    // integrated first, for battery life
+  Named(String), // first device whose name contains this, case insensitive
+  Index(usize),  // index into the enumerated devices
+}
+
+impl GpuPreference {
+  /// Reads `MAAT_GPU`, a device index or part of a device name.
+  pub fn from_env() -> Option<GpuPreference> {
+    std::env::var(GPU_ENV_VAR)
+      .ok()
+      .and_then(|value| GpuPreference::parse(&value))
+  }
+
+  pub fn parse(value: &str) -> Option<GpuPreference> {
+    let value = value.trim();
+    if value.is_empty() {
+      return None;
+    }
+
+    Some(match value.to_lowercase().as_str() {
+      "discrete" => GpuPreference::Discrete,
+      "integrated" => GpuPreference::Integrated,
+      _ => match value.parse::<usize>() {
+        Ok(index) => GpuPreference::Index(index),
+        Err(_) => GpuPreference::Named(value.to_string()),
+      },
+    })
+  }
+}
+
+/// Optional features the game might want to scale its settings with.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GpuFeatures {
+  pub sampler_anisotropy: bool,
+  pub texture_compression_bc: bool,
+  pub texture_compression_astc: bool,
+  pub fill_mode_non_solid: bool,
+  pub wide_lines: bool,
+  pub geometry_shader: bool,
+  pub tessellation_shader: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GpuLimits {
+  pub max_image_dimension_2d: u32,
+  pub max_sampler_anisotropy: f32,
+  pub max_msaa_samples: u32,
+  pub max_push_constants_size: u32,
+  pub max_compute_work_group_size: [u32; 3],
+  pub max_compute_work_group_invocations: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GpuInfo {
+  pub index: usize,
+  pub name: String,
+  pub gpu_type: GpuType,
+  pub vram: u64, // bytes of device local memory
+  pub api_version: (u32, u32, u32),
+  pub driver_version: u32,
+  pub limits: GpuLimits,
+  pub features: GpuFeatures,
+  pub suitable: bool, // has a graphics + compute queue that can present to the window
+}
+
+impl GpuInfo {
+  fn type_rank(&self, preference: &GpuPreference) -> u32 {
+    match (preference, self.gpu_type) {
+      (GpuPreference::Integrated, GpuType::Integrated) => 0,
+      (GpuPreference::Integrated, GpuType::Discrete) => 1,
+      (_, GpuType::Discrete) => 0,
+      (_, GpuType::Integrated) => 1,
+      (_, GpuType::Virtual) => 2,
+      (_, GpuType::Other) => 3,
+      (_, GpuType::Cpu) => 4,
+    }
+  }
+}
+
+/// Index of the device to use, only suitable devices are considered. Named or indexed devices
+/// that don't exist fall back to the discrete first order.
+pub fn choose_gpu(gpus: &[GpuInfo], preference: &GpuPreference) -> Option<usize> {
+  let suitable = gpus.iter().filter(|gpu| gpu.suitable);
+
+  let chosen = match preference {
+    GpuPreference::Index(index) => suitable.clone().find(|gpu| gpu.index == *index),
+    GpuPreference::Named(name) => {
+      let name = name.to_lowercase();
+      suitable
+        .clone()
+        .find(|gpu| gpu.name.to_lowercase().contains(&name))
+    }
+    GpuPreference::Discrete | GpuPreference::Integrated => None,
+  };
+
+  if chosen.is_none() {
+    match preference {
+      GpuPreference::Index(_) | GpuPreference::Named(_) => {
+        println!(
+          "Gpu {:?} not found or unsuitable, picking by type",
+          preference
+        );
+      }
+      _ => {}
+    }
+  }
+
+  chosen
+    .or_else(|| {
+      // Within a type the most VRAM wins, min_by_key keeps driver order for exact ties
+      suitable.min_by_key(|gpu| (gpu.type_rank(preference), u64::MAX - gpu.vram))
+    })
+    .map(|gpu| gpu.index)
+}
+
+/// Every physical device along with the queue family it would render and present with.
+pub fn enumerate_gpus(
+  instance: &VkInstance,
+  surface: &vk::SurfaceKHR,
+  surface_loader: &Surface,
+) -> Vec<(vk::PhysicalDevice, GpuInfo, Option<u32>)> {
+  let pdevices = unsafe {
+    instance
+      .internal()
+      .enumerate_physical_devices()
+      .expect("Physical device error")
+  };
+
+  pdevices
+    .iter()
+    .enumerate()
+    .map(|(index, pdevice)| {
+      let queue_family_index = unsafe {
+        instance
+          .internal()
+          .get_physical_device_queue_family_properties(*pdevice)
+          .iter()
+          .enumerate()
+          .find_map(|(index, info)| {
+            let supports_graphic_and_surface = info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+              && info.queue_flags.contains(vk::QueueFlags::COMPUTE)
+              && surface_loader
+                .get_physical_device_surface_support(*pdevice, index as u32, *surface)
+                .unwrap_or(false);
+            if supports_graphic_and_surface {
+              Some(index as u32)
+            } else {
+              None
+            }
+          })
+      };
+
+      let info = gpu_info(instance, pdevice, index, queue_family_index.is_some());
+
+      (*pdevice, info, queue_family_index)
+    })
+    .collect()
+}
+
+fn gpu_info(
+  instance: &VkInstance,
+  pdevice: &vk::PhysicalDevice,
+  index: usize,
+  suitable: bool,
+) -> GpuInfo {
+  let (properties, features, memory_properties) = unsafe {
+    (
+      instance.internal().get_physical_device_properties(*pdevice),
+      instance.internal().get_physical_device_features(*pdevice),
+      instance
+        .internal()
+        .get_physical_device_memory_properties(*pdevice),
+    )
+  };
+
+  let name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) }
+    .to_string_lossy()
+    .to_string();
+
+  let vram = memory_properties.memory_heaps[..memory_properties.memory_heap_count as usize]
+    .iter()
+    .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+    .map(|heap| heap.size)
+    .sum();
+
+  let limits = properties.limits;
+  let sample_counts =
+    limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts;
+  let max_msaa_samples = (0..7)
+    .rev()
+    .map(|i| 1 << i)
+    .find(|&samples| sample_counts.contains(vk::SampleCountFlags::from_raw(samples)))
+    .unwrap_or(1);
+
+  GpuInfo {
+    index,
+    name,
+    gpu_type: GpuType::from_vk(properties.device_type),
+    vram,
+    api_version: (
+      vk::api_version_major(properties.api_version),
+      vk::api_version_minor(properties.api_version),
+      vk::api_version_patch(properties.api_version),
+    ),
+    driver_version: properties.driver_version,
+    limits: GpuLimits {
+      max_image_dimension_2d: limits.max_image_dimension2_d,
+      max_sampler_anisotropy: limits.max_sampler_anisotropy,
+      max_msaa_samples,
+      max_push_constants_size: limits.max_push_constants_size,
+      max_compute_work_group_size: limits.max_compute_work_group_size,
+      max_compute_work_group_invocations: limits.max_compute_work_group_invocations,
+    },
+    features: GpuFeatures {
+      sampler_anisotropy: features.sampler_anisotropy == vk::TRUE,
+      texture_compression_bc: features.texture_compression_bc == vk::TRUE,
+      texture_compression_astc: features.texture_compression_astc_ldr == vk::TRUE,
+      fill_mode_non_solid: features.fill_mode_non_solid == vk::TRUE,
+      wide_lines: features.wide_lines == vk::TRUE,
+      geometry_shader: features.geometry_shader == vk::TRUE,
+      tessellation_shader: features.tessellation_shader == vk::TRUE,
+    },
+    suitable,
+  }
+}
+
+/// Queue families for each kind of work. Compute and transfer share the graphics family when the
+/// device has no dedicated family for them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QueueFamilies {
+  pub graphics: u32,
+  pub compute: u32,
+  pub transfer: u32,
+}
+
+impl QueueFamilies {
+  pub fn choose(family_flags: &[vk::QueueFlags], graphics: u32) -> QueueFamilies {
+    let find = |wanted: vk::QueueFlags, unwanted: vk::QueueFlags| {
+      family_flags
+        .iter()
+        .position(|flags| flags.contains(wanted) && !flags.intersects(unwanted))
+        .map(|index| index as u32)
+    };
+
+    let compute = find(vk::QueueFlags::COMPUTE, vk::QueueFlags::GRAPHICS).unwrap_or(graphics);
+    let transfer = find(
+      vk::QueueFlags::TRANSFER,
+      vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE,
+    )
+    .unwrap_or(graphics);
+
+    QueueFamilies {
+      graphics,
+      compute,
+      transfer,
+    }
+  }
+
+  pub fn has_dedicated_compute(&self) -> bool {
+    self.compute != self.graphics
+  }
+
+  pub fn has_dedicated_transfer(&self) -> bool {
+    self.transfer != self.graphics
+  }
+
+  /// Each family once, what resources shared between the queues are created with.
+  pub fn unique(&self) -> Vec<u32> {
+    let mut families = vec![self.graphics];
+    for family in [self.compute, self.transfer] {
+      if !families.contains(&family) {
+        families.push(family);
+      }
+    }
+
+    families
+  }
+}
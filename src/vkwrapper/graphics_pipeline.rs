@@ -22,20 +22,32 @@ impl GraphicsPipeline {
   }
 }
 
+#[derive(Clone)]
 pub struct GraphicsPipelineBuilder {
   topology: vk::PrimitiveTopology,
   front_face: vk::FrontFace,
   polygon_mode: vk::PolygonMode,
   samples: vk::SampleCountFlags,
   cull_mode: vk::CullModeFlags,
+  blend_enable: bool,
+  src_blend_factor: vk::BlendFactor,
+  dst_blend_factor: vk::BlendFactor,
+  depth_write: bool,
+  depth_test: bool,
+}
+
+impl Default for GraphicsPipelineBuilder {
+  fn default() -> GraphicsPipelineBuilder {
+    GraphicsPipelineBuilder::new()
+  }
 }
 
 impl GraphicsPipelineBuilder {
   pub fn new() -> GraphicsPipelineBuilder {
-    let topology: vk::PrimitiveTopology = Default::default();
+    let topology = vk::PrimitiveTopology::TRIANGLE_LIST;
     let front_face: vk::FrontFace = Default::default();
     let polygon_mode: vk::PolygonMode = Default::default();
-    let samples: vk::SampleCountFlags = Default::default();
+    let samples = vk::SampleCountFlags::TYPE_1;
     let cull_mode: vk::CullModeFlags = Default::default();
 
     GraphicsPipelineBuilder {
@@ -44,6 +56,11 @@ impl GraphicsPipelineBuilder {
       polygon_mode,
       samples,
       cull_mode,
+      blend_enable: true,
+      src_blend_factor: vk::BlendFactor::SRC_ALPHA,
+      dst_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+      depth_write: true,
+      depth_test: true,
     }
   }
 
@@ -107,6 +124,35 @@ impl GraphicsPipelineBuilder {
     self
   }
 
+  pub fn blend_alpha(mut self) -> GraphicsPipelineBuilder {
+    self.blend_enable = true;
+    self.src_blend_factor = vk::BlendFactor::SRC_ALPHA;
+    self.dst_blend_factor = vk::BlendFactor::ONE_MINUS_SRC_ALPHA;
+    self
+  }
+
+  pub fn blend_additive(mut self) -> GraphicsPipelineBuilder {
+    self.blend_enable = true;
+    self.src_blend_factor = vk::BlendFactor::SRC_ALPHA;
+    self.dst_blend_factor = vk::BlendFactor::ONE;
+    self
+  }
+
+  pub fn blend_none(mut self) -> GraphicsPipelineBuilder {
+    self.blend_enable = false;
+    self
+  }
+
+  pub fn depth_write_disabled(mut self) -> GraphicsPipelineBuilder {
+    self.depth_write = false;
+    self
+  }
+
+  pub fn depth_test_disabled(mut self) -> GraphicsPipelineBuilder {
+    self.depth_test = false;
+    self
+  }
+
   pub fn polygon_mode_fill(mut self) -> GraphicsPipelineBuilder {
     self.polygon_mode = vk::PolygonMode::FILL;
     self
@@ -122,6 +168,11 @@ impl GraphicsPipelineBuilder {
     self
   }
 
+  pub fn samples(mut self, samples: vk::SampleCountFlags) -> GraphicsPipelineBuilder {
+    self.samples = samples;
+    self
+  }
+
   pub fn samples_1(mut self) -> GraphicsPipelineBuilder {
     self.samples = vk::SampleCountFlags::TYPE_1;
     self
@@ -168,6 +219,30 @@ impl GraphicsPipelineBuilder {
     scissors: &Scissors,
     renderpass: &Renderpass,
   ) -> GraphicsPipeline {
+    self
+      .try_build(
+        device,
+        pipeline_layout,
+        shader_stage_create_info,
+        vertex_input_state,
+        viewport,
+        scissors,
+        renderpass,
+      )
+      .expect("Unable to create graphics pipeline")
+  }
+
+  #[allow(clippy::too_many_arguments)]
+  pub fn try_build(
+    &self,
+    device: &VkDevice,
+    pipeline_layout: &vk::PipelineLayout,
+    shader_stage_create_info: Vec<vk::PipelineShaderStageCreateInfo>,
+    vertex_input_state: vk::PipelineVertexInputStateCreateInfo,
+    viewport: &Viewport,
+    scissors: &Scissors,
+    renderpass: &Renderpass,
+  ) -> Result<GraphicsPipeline, vk::Result> {
     let vertex_input_assembly_state_info = vk::PipelineInputAssemblyStateCreateInfo {
       topology: self.topology,
       ..Default::default()
@@ -199,17 +274,17 @@ impl GraphicsPipelineBuilder {
       .compare_op(vk::CompareOp::ALWAYS);
 
     let depth_state_info = vk::PipelineDepthStencilStateCreateInfo::builder()
-      .depth_test_enable(true)
-      .depth_write_enable(true)
+      .depth_test_enable(self.depth_test)
+      .depth_write_enable(self.depth_write)
       .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL)
       .front(*noop_stencil_state_front)
       .back(*noop_stencil_state_back)
       .max_depth_bounds(1.0);
 
     let color_blend_attachment_states = [vk::PipelineColorBlendAttachmentState {
-      blend_enable: vk::TRUE,
-      src_color_blend_factor: vk::BlendFactor::SRC_ALPHA, //vk::BlendFactor::SRC_COLOR,
-      dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA, //vk::BlendFactor::ONE_MINUS_DST_COLOR,
+      blend_enable: self.blend_enable as vk::Bool32,
+      src_color_blend_factor: self.src_blend_factor, //vk::BlendFactor::SRC_COLOR,
+      dst_color_blend_factor: self.dst_blend_factor, //vk::BlendFactor::ONE_MINUS_DST_COLOR,
       color_blend_op: vk::BlendOp::ADD,
       src_alpha_blend_factor: vk::BlendFactor::SRC_ALPHA, //vk::BlendFactor::ZERO,
       dst_alpha_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
@@ -243,10 +318,10 @@ impl GraphicsPipelineBuilder {
     let graphics_pipelines = unsafe {
       device
         .internal()
-        .create_graphics_pipelines(vk::PipelineCache::null(), &[*graphic_pipeline_info], None)
-        .expect("Unable to create graphics pipeline")
+        .create_graphics_pipelines(device.pipeline_cache(), &[*graphic_pipeline_info], None)
+        .map_err(|(_, e)| e)?
     };
 
-    GraphicsPipeline::new(graphics_pipelines[0])
+    Ok(GraphicsPipeline::new(graphics_pipelines[0]))
   }
 }
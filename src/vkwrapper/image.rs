@@ -1,6 +1,6 @@
 use ash::vk;
 
-use crate::vkwrapper::{Memory, VkDevice};
+use crate::vkwrapper::{Memory, TrackedResource, VkDevice};
 
 #[derive(Clone)]
 pub struct Image {
@@ -9,18 +9,28 @@ pub struct Image {
   image_view: vk::ImageView,
   width: u32,
   height: u32,
+  mip_levels: u32,
+  array_layers: u32,
 }
 
 impl Image {
+  #[allow(clippy::too_many_arguments)]
   pub fn new_device_local(
     device: &VkDevice,
     image: vk::Image,
     image_view_info: vk::ImageViewCreateInfo,
     width: u32,
     height: u32,
+    mip_levels: u32,
+    array_layers: u32,
+    tiling: vk::ImageTiling,
   ) -> Image {
-    let memory: Memory<u8> =
-      Memory::<u8>::new_image_memory(device, &image, vk::MemoryPropertyFlags::DEVICE_LOCAL);
+    let memory: Memory<u8> = Memory::<u8>::new_image_memory(
+      device,
+      &image,
+      vk::MemoryPropertyFlags::DEVICE_LOCAL,
+      tiling == vk::ImageTiling::LINEAR,
+    );
 
     let image_view = unsafe {
       device
@@ -28,6 +38,7 @@ impl Image {
         .create_image_view(&image_view_info, None)
         .unwrap()
     };
+    device.resources().created(TrackedResource::Image);
 
     Image {
       image,
@@ -35,6 +46,8 @@ impl Image {
       image_view,
       width,
       height,
+      mip_levels,
+      array_layers,
     }
   }
 
@@ -56,6 +69,8 @@ impl Image {
       image_view,
       width: 1,
       height: 1,
+      mip_levels: 1,
+      array_layers: 1,
     }
   }
 
@@ -65,6 +80,7 @@ impl Image {
       device.internal().destroy_image_view(self.image_view, None);
       device.internal().destroy_image(self.image, None);
     }
+    device.resources().destroyed(TrackedResource::Image);
   }
 
   pub fn view(&self) -> vk::ImageView {
@@ -90,6 +106,14 @@ impl Image {
   pub fn height(&self) -> u32 {
     self.height
   }
+
+  pub fn mip_levels(&self) -> u32 {
+    self.mip_levels
+  }
+
+  pub fn array_layers(&self) -> u32 {
+    self.array_layers
+  }
 }
 
 pub struct ImageBuilder {
@@ -103,6 +127,7 @@ pub struct ImageBuilder {
   tiling: vk::ImageTiling,
   usage: vk::ImageUsageFlags,
   sharing_mode: vk::SharingMode,
+  flags: vk::ImageCreateFlags,
   is_depth: bool,
 }
 
@@ -133,6 +158,7 @@ impl ImageBuilder {
       tiling,
       usage: Default::default(),
       sharing_mode,
+      flags: vk::ImageCreateFlags::empty(),
       is_depth: false,
     }
   }
@@ -166,6 +192,7 @@ impl ImageBuilder {
       tiling,
       usage,
       sharing_mode,
+      flags: vk::ImageCreateFlags::empty(),
       is_depth: false,
     }
   }*/
@@ -203,10 +230,19 @@ impl ImageBuilder {
       tiling,
       usage,
       sharing_mode,
+      flags: vk::ImageCreateFlags::empty(),
       is_depth: true,
     }
   }
 
+  /// Six square layers viewed as a cube, in the +x, -x, +y, -y, +z, -z face order.
+  pub fn cube_map(mut self) -> ImageBuilder {
+    self.array_layers = 6;
+    self.image_view_type = vk::ImageViewType::CUBE;
+    self.flags |= vk::ImageCreateFlags::CUBE_COMPATIBLE;
+    self
+  }
+
   pub fn set_dimensions(mut self, width: u32, height: u32) -> ImageBuilder {
     self.extent = vk::Extent3D {
       width,
@@ -216,6 +252,18 @@ impl ImageBuilder {
     self
   }
 
+  /// Number of levels needed to halve the largest dimension down to a single pixel.
+  pub fn mip_levels_for_dimensions(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+  }
+
+  /// Uses the complete mip chain for the dimensions already set.
+  pub fn full_mip_chain(mut self) -> ImageBuilder {
+    self.mip_levels =
+      ImageBuilder::mip_levels_for_dimensions(self.extent.width, self.extent.height);
+    self
+  }
+
   pub fn usage(mut self, usage: vk::ImageUsageFlags) -> ImageBuilder {
     self.usage = usage;
     self
@@ -231,6 +279,11 @@ impl ImageBuilder {
     self
   }
 
+  pub fn samples(mut self, samples: vk::SampleCountFlags) -> ImageBuilder {
+    self.samples = samples;
+    self
+  }
+
   pub fn samples_1(mut self) -> ImageBuilder {
     self.samples = vk::SampleCountFlags::TYPE_1;
     self
@@ -302,6 +355,7 @@ impl ImageBuilder {
   pub fn build_device_local(&self, device: &VkDevice) -> Image {
     let image = unsafe {
       let image_create_info = vk::ImageCreateInfo::builder()
+        .flags(self.flags)
         .image_type(self.image_type)
         .format(self.format)
         .extent(self.extent)
@@ -326,6 +380,9 @@ impl ImageBuilder {
       image_view_info,
       self.extent.width,
       self.extent.height,
+      self.mip_levels,
+      self.array_layers,
+      self.tiling,
     )
   }
 }
@@ -76,6 +76,15 @@ impl VkInstance {
   pub fn internal(&self) -> &Instance {
     &self.instance
   }
+
+  pub fn destroy(&self) {
+    unsafe {
+      self
+        .debug_utils_loader
+        .destroy_debug_utils_messenger(self.debug_call_back, None);
+      self.instance.destroy_instance(None);
+    }
+  }
 }
 
 fn create_instance(entry: &Entry, window: &VkWindow, event_loop: &EventLoop<()>) -> Instance {
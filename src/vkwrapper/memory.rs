@@ -4,11 +4,365 @@ use std::slice;
 use ash::util::Align;
 use ash::vk;
 
-use crate::vkwrapper::VkDevice;
+use crate::vkwrapper::{TrackedResource, VkDevice};
+
+const DEVICE_LOCAL_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+const HOST_VISIBLE_BLOCK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// A range of free space within a block, as (offset, size).
+type FreeRange = (u64, u64);
+
+/// Free list for a single `vkAllocateMemory` block. Only does the bookkeeping, the device memory
+/// itself lives in `MemoryAllocator`.
+#[derive(Clone, Debug)]
+pub struct MemoryBlock {
+  size: u64,
+  free: Vec<FreeRange>,
+  allocations: usize,
+}
+
+impl MemoryBlock {
+  pub fn new(size: u64) -> MemoryBlock {
+    MemoryBlock {
+      size,
+      free: vec![(0, size)],
+      allocations: 0,
+    }
+  }
+
+  /// First fit. Any padding needed for alignment stays on the free list.
+  pub fn allocate(&mut self, size: u64, alignment: u64) -> Option<u64> {
+    let alignment = alignment.max(1);
+
+    for i in 0..self.free.len() {
+      let (start, len) = self.free[i];
+      let offset = start.div_ceil(alignment) * alignment;
+      let padding = offset - start;
+
+      if padding + size > len {
+        continue;
+      }
+
+      let remaining = len - padding - size;
+      self.free.remove(i);
+      if remaining > 0 {
+        self.free.insert(i, (offset + size, remaining));
+      }
+      if padding > 0 {
+        self.free.insert(i, (start, padding));
+      }
+
+      self.allocations += 1;
+      return Some(offset);
+    }
+
+    None
+  }
+
+  /// Returns a range to the free list, merging it with its neighbours.
+  pub fn free(&mut self, offset: u64, size: u64) {
+    let i = self
+      .free
+      .iter()
+      .position(|(start, _)| *start > offset)
+      .unwrap_or(self.free.len());
+    self.free.insert(i, (offset, size));
+
+    if i + 1 < self.free.len() && offset + size == self.free[i + 1].0 {
+      self.free[i].1 += self.free[i + 1].1;
+      self.free.remove(i + 1);
+    }
+
+    if i > 0 && self.free[i - 1].0 + self.free[i - 1].1 == offset {
+      self.free[i - 1].1 += self.free[i].1;
+      self.free.remove(i);
+    }
+
+    self.allocations -= 1;
+  }
+
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  pub fn used(&self) -> u64 {
+    self.size - self.free_space()
+  }
+
+  pub fn free_space(&self) -> u64 {
+    self.free.iter().map(|(_, size)| size).sum()
+  }
+
+  pub fn largest_free_range(&self) -> u64 {
+    self.free.iter().map(|(_, size)| *size).max().unwrap_or(0)
+  }
+
+  pub fn free_ranges(&self) -> usize {
+    self.free.len()
+  }
+
+  pub fn allocation_count(&self) -> usize {
+    self.allocations
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.allocations == 0
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryStats {
+  pub blocks: usize,
+  pub allocations: usize,
+  pub allocated_bytes: u64,
+  pub used_bytes: u64,
+  pub free_ranges: usize,
+  pub largest_free_range: u64,
+}
+
+impl MemoryStats {
+  /// 0.0 when all free space is in one range, approaching 1.0 as it gets split into small pieces.
+  pub fn fragmentation(&self) -> f32 {
+    let free = self.allocated_bytes - self.used_bytes;
+    if free == 0 {
+      return 0.0;
+    }
+
+    1.0 - self.largest_free_range as f32 / free as f32
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryAllocation {
+  memory: vk::DeviceMemory,
+  block: usize,
+  offset: u64,
+  size: u64,
+}
+
+impl MemoryAllocation {
+  pub fn empty() -> MemoryAllocation {
+    MemoryAllocation {
+      memory: vk::DeviceMemory::null(),
+      block: 0,
+      offset: 0,
+      size: 0,
+    }
+  }
+
+  pub fn memory(&self) -> vk::DeviceMemory {
+    self.memory
+  }
+
+  pub fn offset(&self) -> u64 {
+    self.offset
+  }
+
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+}
+
+struct AllocatorBlock {
+  memory: vk::DeviceMemory,
+  memory_type: u32,
+  // Buffers and optimally tiled images are kept in separate blocks so that
+  // bufferImageGranularity never has to be considered.
+  linear: bool,
+  dedicated: bool,
+  block: MemoryBlock,
+}
+
+/// Hands out ranges of large device memory blocks instead of making a `vkAllocateMemory` call
+/// per resource. Requests bigger than a block get a dedicated allocation that is freed as soon
+/// as the resource is.
+pub struct MemoryAllocator {
+  blocks: Vec<Option<AllocatorBlock>>,
+  device_local_block_size: u64,
+  host_visible_block_size: u64,
+}
+
+impl MemoryAllocator {
+  pub fn new() -> MemoryAllocator {
+    MemoryAllocator::with_block_sizes(DEVICE_LOCAL_BLOCK_SIZE, HOST_VISIBLE_BLOCK_SIZE)
+  }
+
+  pub fn with_block_sizes(device_local: u64, host_visible: u64) -> MemoryAllocator {
+    MemoryAllocator {
+      blocks: Vec::new(),
+      device_local_block_size: device_local,
+      host_visible_block_size: host_visible,
+    }
+  }
+
+  pub fn allocate(
+    &mut self,
+    device: &VkDevice,
+    requirements: vk::MemoryRequirements,
+    memory_type: u32,
+    memory_property: vk::MemoryPropertyFlags,
+    linear: bool,
+  ) -> MemoryAllocation {
+    if let Some(allocation) = self.sub_allocate(
+      requirements.size,
+      requirements.alignment,
+      memory_type,
+      linear,
+    ) {
+      device.resources().created(TrackedResource::Memory);
+      return allocation;
+    }
+
+    let block_size = if memory_property.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+      self.host_visible_block_size
+    } else {
+      self.device_local_block_size
+    };
+    let dedicated = requirements.size > block_size;
+    let size = block_size.max(requirements.size);
+
+    let memory_allocate_info = vk::MemoryAllocateInfo::builder()
+      .allocation_size(size)
+      .memory_type_index(memory_type);
+
+    let memory = unsafe {
+      device
+        .internal()
+        .allocate_memory(&memory_allocate_info, None)
+        .expect("Unable to allocate device memory block")
+    };
+
+    self.add_block(memory, memory_type, linear, size, dedicated);
+
+    let allocation = self
+      .sub_allocate(
+        requirements.size,
+        requirements.alignment,
+        memory_type,
+        linear,
+      )
+      .expect("New memory block too small for allocation");
+    device.resources().created(TrackedResource::Memory);
+
+    allocation
+  }
+
+  pub fn free(&mut self, device: &VkDevice, allocation: &MemoryAllocation) {
+    if let Some(memory) = self.release(allocation) {
+      unsafe {
+        device.internal().free_memory(memory, None);
+      }
+    }
+    device.resources().destroyed(TrackedResource::Memory);
+  }
+
+  /// Finds space in an existing block of the right memory type.
+  pub fn sub_allocate(
+    &mut self,
+    size: u64,
+    alignment: u64,
+    memory_type: u32,
+    linear: bool,
+  ) -> Option<MemoryAllocation> {
+    self
+      .blocks
+      .iter_mut()
+      .enumerate()
+      .filter_map(|(i, block)| block.as_mut().map(|block| (i, block)))
+      .filter(|(_, block)| block.memory_type == memory_type && block.linear == linear)
+      .find_map(|(i, block)| {
+        block
+          .block
+          .allocate(size, alignment)
+          .map(|offset| MemoryAllocation {
+            memory: block.memory,
+            block: i,
+            offset,
+            size,
+          })
+      })
+  }
+
+  pub fn add_block(
+    &mut self,
+    memory: vk::DeviceMemory,
+    memory_type: u32,
+    linear: bool,
+    size: u64,
+    dedicated: bool,
+  ) -> usize {
+    let block = AllocatorBlock {
+      memory,
+      memory_type,
+      linear,
+      dedicated,
+      block: MemoryBlock::new(size),
+    };
+
+    match self.blocks.iter().position(|block| block.is_none()) {
+      Some(i) => {
+        self.blocks[i] = Some(block);
+        i
+      }
+      None => {
+        self.blocks.push(Some(block));
+        self.blocks.len() - 1
+      }
+    }
+  }
+
+  /// Returns the allocation's range to its block. Gives back the block's memory when it was a
+  /// dedicated allocation and should now be freed.
+  pub fn release(&mut self, allocation: &MemoryAllocation) -> Option<vk::DeviceMemory> {
+    let slot = &mut self.blocks[allocation.block];
+    let block = slot
+      .as_mut()
+      .expect("Memory allocation freed from a block that no longer exists");
+
+    block.block.free(allocation.offset, allocation.size);
+
+    if block.dedicated && block.block.is_empty() {
+      return slot.take().map(|block| block.memory);
+    }
+
+    None
+  }
+
+  pub fn stats(&self) -> MemoryStats {
+    self
+      .blocks
+      .iter()
+      .flatten()
+      .fold(MemoryStats::default(), |stats, block| MemoryStats {
+        blocks: stats.blocks + 1,
+        allocations: stats.allocations + block.block.allocation_count(),
+        allocated_bytes: stats.allocated_bytes + block.block.size(),
+        used_bytes: stats.used_bytes + block.block.used(),
+        free_ranges: stats.free_ranges + block.block.free_ranges(),
+        largest_free_range: stats
+          .largest_free_range
+          .max(block.block.largest_free_range()),
+      })
+  }
+
+  pub fn destroy(&mut self, device: &VkDevice) {
+    for block in self.blocks.drain(..).flatten() {
+      unsafe {
+        device.internal().free_memory(block.memory, None);
+      }
+    }
+  }
+}
+
+impl Default for MemoryAllocator {
+  fn default() -> Self {
+    Self::new()
+  }
+}
 
 #[derive(Clone)]
 pub struct Memory<T: Copy> {
-  memory: vk::DeviceMemory,
+  allocation: MemoryAllocation,
   requirements: vk::MemoryRequirements,
   _type: T,
 }
@@ -16,20 +370,21 @@ pub struct Memory<T: Copy> {
 impl<T: Copy> Memory<T> {
   pub fn new_empty() -> Memory<u8> {
     Memory {
-      memory: vk::DeviceMemory::null(),
+      allocation: MemoryAllocation::empty(),
       requirements: vk::MemoryRequirements::default(),
       _type: 0u8,
     }
   }
 
   pub fn data_from_memory(&self, device: &VkDevice, data_len: usize) -> Vec<T> {
-    Memory::<T>::map_memory_to_data(device, self.memory, self.requirements, data_len)
+    Memory::<T>::map_memory_to_data(device, &self.allocation, data_len)
   }
 
   pub fn new_image_memory(
     device: &VkDevice,
     image: &vk::Image,
     memory_property: vk::MemoryPropertyFlags,
+    linear_tiling: bool,
   ) -> Memory<u8> {
     let requirements = Memory::<u8>::image_memory_requirements(device, *image);
 
@@ -40,17 +395,23 @@ impl<T: Copy> Memory<T> {
     )
     .expect("Unable to find suitable memory index for depth image.");
 
-    let memory = Memory::<u8>::allocate_memory(device, requirements, image_memory_index);
+    let allocation = Memory::<u8>::allocate_memory(
+      device,
+      requirements,
+      image_memory_index,
+      memory_property,
+      linear_tiling,
+    );
 
     unsafe {
       device
         .internal()
-        .bind_image_memory(*image, memory, 0)
+        .bind_image_memory(*image, allocation.memory, allocation.offset)
         .expect("Unable to bind image memory");
     };
 
     Memory {
-      memory,
+      allocation,
       requirements,
       _type: 0u8,
     }
@@ -71,32 +432,51 @@ impl<T: Copy> Memory<T> {
     )
     .expect("Unable to find suitable memory index for buffer.");
 
-    let memory = Memory::<T>::allocate_memory(device, requirements, buffer_memory_index);
+    let allocation = Memory::<T>::allocate_memory(
+      device,
+      requirements,
+      buffer_memory_index,
+      memory_property,
+      true,
+    );
 
-    Memory::<T>::map_data_to_memory(device, memory, requirements, data);
+    let memory_type_flags =
+      device.device_memory_properties().memory_types[buffer_memory_index as usize].property_flags;
+    if memory_type_flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+      Memory::<T>::map_data_to_memory(device, &allocation, data);
+    }
 
     unsafe {
       device
         .internal()
-        .bind_buffer_memory(*buffer, memory, 0)
-        .expect("Unable to bind depth image memory");
+        .bind_buffer_memory(*buffer, allocation.memory, allocation.offset)
+        .expect("Unable to bind buffer memory");
     };
 
     Memory {
-      memory,
+      allocation,
       requirements,
       _type: data[0],
     }
   }
 
   pub fn internal(&self) -> vk::DeviceMemory {
-    self.memory
+    self.allocation.memory
+  }
+
+  pub fn allocation(&self) -> &MemoryAllocation {
+    &self.allocation
   }
 
   pub fn destroy(&self, device: &VkDevice) {
-    unsafe {
-      device.internal().free_memory(self.memory, None);
+    if self.allocation.memory == vk::DeviceMemory::null() {
+      return;
     }
+
+    device
+      .memory_allocator()
+      .borrow_mut()
+      .free(device, &self.allocation);
   }
 
   pub fn image_memory_requirements(device: &VkDevice, image: vk::Image) -> vk::MemoryRequirements {
@@ -110,47 +490,40 @@ impl<T: Copy> Memory<T> {
     unsafe { device.internal().get_buffer_memory_requirements(buffer) }
   }
 
-  pub fn map_data_to_memory(
-    device: &VkDevice,
-    memory: vk::DeviceMemory,
-    memory_requirements: vk::MemoryRequirements,
-    data: &Vec<T>,
-  ) {
+  pub fn map_data_to_memory(device: &VkDevice, allocation: &MemoryAllocation, data: &Vec<T>) {
     let index_ptr = unsafe {
       device
         .internal()
         .map_memory(
-          memory,
-          0,
-          memory_requirements.size,
+          allocation.memory,
+          allocation.offset,
+          allocation.size,
           vk::MemoryMapFlags::empty(),
         )
         .unwrap()
     };
 
-    let mut index_slice =
-      unsafe { Align::new(index_ptr, align_of::<T>() as u64, memory_requirements.size) };
+    let mut index_slice = unsafe { Align::new(index_ptr, align_of::<T>() as u64, allocation.size) };
 
     unsafe {
       index_slice.copy_from_slice(data);
 
-      device.internal().unmap_memory(memory);
+      device.internal().unmap_memory(allocation.memory);
     }
   }
 
   pub fn map_memory_to_data(
     device: &VkDevice,
-    memory: vk::DeviceMemory,
-    memory_requirements: vk::MemoryRequirements,
+    allocation: &MemoryAllocation,
     data_len: usize,
   ) -> Vec<T> {
     let index_ptr = unsafe {
       device
         .internal()
         .map_memory(
-          memory,
-          0,
-          memory_requirements.size,
+          allocation.memory,
+          allocation.offset,
+          allocation.size,
           vk::MemoryMapFlags::empty(),
         )
         .unwrap()
@@ -160,7 +533,7 @@ impl<T: Copy> Memory<T> {
     let data = data_slice.to_vec();
 
     unsafe {
-      device.internal().unmap_memory(memory);
+      device.internal().unmap_memory(allocation.memory);
     }
 
     data
@@ -170,17 +543,16 @@ impl<T: Copy> Memory<T> {
     device: &VkDevice,
     memory_requirements: vk::MemoryRequirements,
     memory_index: u32,
-  ) -> vk::DeviceMemory {
-    let memory_allocate_info = vk::MemoryAllocateInfo::builder()
-      .allocation_size(memory_requirements.size)
-      .memory_type_index(memory_index);
-
-    unsafe {
-      device
-        .internal()
-        .allocate_memory(&memory_allocate_info, None)
-        .unwrap()
-    }
+    memory_property: vk::MemoryPropertyFlags,
+    linear: bool,
+  ) -> MemoryAllocation {
+    device.memory_allocator().borrow_mut().allocate(
+      device,
+      memory_requirements,
+      memory_index,
+      memory_property,
+      linear,
+    )
   }
 
   pub fn find_memorytype_index(
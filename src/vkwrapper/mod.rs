@@ -1,4 +1,4 @@
-pub use swapchain::VkSwapchain;
+pub use swapchain::{PresentMode, VkSwapchain};
 
 pub use self::buffer::Buffer;
 pub use self::clear_values::ClearValues;
@@ -6,18 +6,22 @@ pub use self::command_buffer::CommandBuffer;
 pub use self::command_pool::VkCommandPool;
 pub use self::compute_pipeline::ComputePipeline;
 pub use self::compute_shader::ComputeShader;
-pub use self::descriptorset::DescriptorSet;
+pub use self::descriptorset::{DescriptorSet, DescriptorSetBuilder};
 pub use self::descriptorset_writer::DescriptorWriter;
 pub use self::device::VkDevice;
 pub use self::fence::Fence;
 pub use self::framebuffers::VkFrameBuffer;
-pub use self::frames_in_flight::Frame;
+pub use self::frames_in_flight::{Frame, PerFrame};
+pub use self::gpu::{GpuFeatures, GpuInfo, GpuLimits, GpuPreference, GpuType};
 pub use self::graphics_pipeline::{GraphicsPipeline, GraphicsPipelineBuilder};
 pub use self::image::{Image, ImageBuilder};
 pub use self::instance::VkInstance;
-pub use self::memory::Memory;
-pub use self::pool::DescriptorPoolBuilder;
+pub use self::memory::{Memory, MemoryAllocator, MemoryStats};
+pub use self::pool::{DescriptorAllocator, DescriptorPoolBuilder};
+pub use self::reflection::{ReflectedBinding, ShaderReflection};
+pub use self::render_target::RenderTarget;
 pub use self::renderpass::{PassDescription, Renderpass};
+pub use self::resource_tracker::{ResourceTracker, TrackedResource};
 pub use self::sampler::{Sampler, SamplerBuilder};
 pub use self::scissors::Scissors;
 pub use self::semaphore::Semaphore;
@@ -39,12 +43,17 @@ mod device;
 mod fence;
 mod framebuffers;
 mod frames_in_flight;
+pub mod gpu;
 mod graphics_pipeline;
 mod image;
 mod instance;
-mod memory;
+pub mod memory;
+pub mod pipeline_cache;
 mod pool;
+mod reflection;
+mod render_target;
 mod renderpass;
+mod resource_tracker;
 mod sampler;
 mod scissors;
 mod semaphore;
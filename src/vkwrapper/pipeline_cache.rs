@@ -0,0 +1,107 @@
+use ash::{vk, Device};
+
+use std::fs;
+
+const HEADER_SIZE: usize = 32;
+
+/// The header Vulkan puts in front of pipeline cache data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PipelineCacheHeader {
+  pub header_size: u32,
+  pub version: u32,
+  pub vendor_id: u32,
+  pub device_id: u32,
+  pub cache_uuid: [u8; vk::UUID_SIZE],
+}
+
+impl PipelineCacheHeader {
+  pub fn from_properties(properties: &vk::PhysicalDeviceProperties) -> PipelineCacheHeader {
+    PipelineCacheHeader {
+      header_size: HEADER_SIZE as u32,
+      version: vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32,
+      vendor_id: properties.vendor_id,
+      device_id: properties.device_id,
+      cache_uuid: properties.pipeline_cache_uuid,
+    }
+  }
+
+  pub fn parse(data: &[u8]) -> Option<PipelineCacheHeader> {
+    if data.len() < HEADER_SIZE {
+      return None;
+    }
+
+    let read_u32 = |offset: usize| {
+      let mut bytes = [0; 4];
+      bytes.copy_from_slice(&data[offset..offset + 4]);
+      u32::from_le_bytes(bytes)
+    };
+
+    let mut cache_uuid = [0; vk::UUID_SIZE];
+    cache_uuid.copy_from_slice(&data[16..16 + vk::UUID_SIZE]);
+
+    Some(PipelineCacheHeader {
+      header_size: read_u32(0),
+      version: read_u32(4),
+      vendor_id: read_u32(8),
+      device_id: read_u32(12),
+      cache_uuid,
+    })
+  }
+
+  pub fn to_bytes(self) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_SIZE);
+    bytes.extend_from_slice(&self.header_size.to_le_bytes());
+    bytes.extend_from_slice(&self.version.to_le_bytes());
+    bytes.extend_from_slice(&self.vendor_id.to_le_bytes());
+    bytes.extend_from_slice(&self.device_id.to_le_bytes());
+    bytes.extend_from_slice(&self.cache_uuid);
+    bytes
+  }
+
+  /// Whether cache data with this header was written by the same device and driver.
+  pub fn is_compatible(&self, device: &PipelineCacheHeader) -> bool {
+    self.header_size as usize >= HEADER_SIZE
+      && self.version == device.version
+      && self.vendor_id == device.vendor_id
+      && self.device_id == device.device_id
+      && self.cache_uuid == device.cache_uuid
+  }
+}
+
+/// Returns the file's data if it's a cache for this device, stale or unreadable caches give None.
+pub fn load_cache_data(path: &str, device: &PipelineCacheHeader) -> Option<Vec<u8>> {
+  let data = fs::read(path).ok()?;
+
+  match PipelineCacheHeader::parse(&data) {
+    Some(header) if header.is_compatible(device) => Some(data),
+    _ => {
+      println!("Discarding stale pipeline cache: {}", path);
+      None
+    }
+  }
+}
+
+pub fn create_pipeline_cache(device: &Device, initial_data: &[u8]) -> vk::PipelineCache {
+  let pipeline_cache_info = vk::PipelineCacheCreateInfo::builder().initial_data(initial_data);
+
+  unsafe {
+    device
+      .create_pipeline_cache(&pipeline_cache_info, None)
+      .or_else(|_| {
+        // The driver can still reject data that passed the header check
+        device.create_pipeline_cache(&vk::PipelineCacheCreateInfo::default(), None)
+      })
+      .expect("Failed to create pipeline cache")
+  }
+}
+
+pub fn save_cache_data(
+  device: &Device,
+  pipeline_cache: vk::PipelineCache,
+  path: &str,
+) -> Result<(), String> {
+  let data = unsafe { device.get_pipeline_cache_data(pipeline_cache) }
+    .map_err(|e| format!("Failed to get pipeline cache data: {}", e))?;
+
+  fs::write(path, data).map_err(|e| format!("Failed to write pipeline cache {}: {}", path, e))
+}
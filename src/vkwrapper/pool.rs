@@ -1,11 +1,15 @@
 use ash::vk;
 
+use std::cell::RefCell;
+
 use crate::vkwrapper::VkDevice;
 
 pub struct DescriptorPoolBuilder {
   uniform_buffers: u32,
   combined_image_samplers: u32,
   storages: u32,
+  storage_images: u32,
+  flags: vk::DescriptorPoolCreateFlags,
 }
 
 impl DescriptorPoolBuilder {
@@ -14,6 +18,8 @@ impl DescriptorPoolBuilder {
       uniform_buffers: 0,
       combined_image_samplers: 0,
       storages: 0,
+      storage_images: 0,
+      flags: vk::DescriptorPoolCreateFlags::empty(),
     }
   }
 
@@ -32,7 +38,18 @@ impl DescriptorPoolBuilder {
     self
   }
 
-  pub fn build(&self, device: &VkDevice) -> vk::DescriptorPool {
+  pub fn num_storage_images(mut self, num: u32) -> DescriptorPoolBuilder {
+    self.storage_images = num;
+    self
+  }
+
+  pub fn free_individual_sets(mut self) -> DescriptorPoolBuilder {
+    self.flags |= vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET;
+    self
+  }
+
+  /// The counts are what the first pool holds, more pools are added as they fill up.
+  pub fn build(&self, device: &VkDevice) -> DescriptorAllocator {
     let mut descriptor_sizes = Vec::new();
 
     if self.uniform_buffers != 0 {
@@ -56,9 +73,59 @@ impl DescriptorPoolBuilder {
       });
     }
 
+    if self.storage_images != 0 {
+      descriptor_sizes.push(vk::DescriptorPoolSize {
+        ty: vk::DescriptorType::STORAGE_IMAGE,
+        descriptor_count: self.storage_images,
+      });
+    }
+
+    let max_sets =
+      (self.storages + self.combined_image_samplers + self.uniform_buffers + self.storage_images)
+        .max(1);
+
+    DescriptorAllocator::new(device, descriptor_sizes, max_sets, self.flags)
+  }
+}
+
+// Each new pool is twice the size of the one before, up to this many times the first pool
+const MAX_POOL_GROWTH: u32 = 16;
+
+/// Owns a list of descriptor pools, adding a larger pool whenever the current ones run out.
+pub struct DescriptorAllocator {
+  pool_sizes: Vec<vk::DescriptorPoolSize>,
+  max_sets: u32,
+  flags: vk::DescriptorPoolCreateFlags,
+  pools: RefCell<Vec<vk::DescriptorPool>>,
+}
+
+impl DescriptorAllocator {
+  pub fn new(
+    device: &VkDevice,
+    pool_sizes: Vec<vk::DescriptorPoolSize>,
+    max_sets: u32,
+    flags: vk::DescriptorPoolCreateFlags,
+  ) -> DescriptorAllocator {
+    let first_pool = DescriptorAllocator::create_pool(device, &pool_sizes, max_sets, flags);
+
+    DescriptorAllocator {
+      pool_sizes,
+      max_sets,
+      flags,
+      pools: RefCell::new(vec![first_pool]),
+    }
+  }
+
+  fn create_pool(
+    device: &VkDevice,
+    pool_sizes: &[vk::DescriptorPoolSize],
+    max_sets: u32,
+    flags: vk::DescriptorPoolCreateFlags,
+  ) -> vk::DescriptorPool {
     let descriptor_pool_info = vk::DescriptorPoolCreateInfo::builder()
-      .pool_sizes(&descriptor_sizes)
-      .max_sets(self.storages + self.combined_image_samplers + self.uniform_buffers);
+      .flags(flags)
+      .pool_sizes(pool_sizes)
+      .max_sets(max_sets);
 
     unsafe {
       device
@@ -67,4 +134,107 @@ impl DescriptorPoolBuilder {
         .unwrap()
     }
   }
+
+  /// Sizes for the pool after `pool_count` pools, big enough for at least one set needing
+  /// `required` descriptors.
+  pub fn next_pool_sizes(
+    pool_sizes: &[vk::DescriptorPoolSize],
+    max_sets: u32,
+    pool_count: u32,
+    required: &[vk::DescriptorPoolSize],
+  ) -> (Vec<vk::DescriptorPoolSize>, u32) {
+    let growth = 2u32.pow(pool_count.min(31)).min(MAX_POOL_GROWTH);
+
+    let mut next_sizes: Vec<vk::DescriptorPoolSize> = pool_sizes
+      .iter()
+      .map(|size| vk::DescriptorPoolSize {
+        ty: size.ty,
+        descriptor_count: size.descriptor_count * growth,
+      })
+      .collect();
+
+    for required_size in required {
+      match next_sizes
+        .iter_mut()
+        .find(|size| size.ty == required_size.ty)
+      {
+        Some(size) => {
+          size.descriptor_count = size.descriptor_count.max(required_size.descriptor_count);
+        }
+        None => next_sizes.push(*required_size),
+      }
+    }
+
+    (next_sizes, max_sets * growth)
+  }
+
+  /// Allocates a set for each layout. Tries the pools newest first, and adds a pool if none of
+  /// them have room. Returns the pool the sets came from, needed to free them.
+  pub fn allocate(
+    &self,
+    device: &VkDevice,
+    layouts: &[vk::DescriptorSetLayout],
+    required: &[vk::DescriptorPoolSize],
+  ) -> (vk::DescriptorPool, Vec<vk::DescriptorSet>) {
+    let mut pools = self.pools.borrow_mut();
+
+    for pool in pools.iter().rev() {
+      let desc_alloc_info = vk::DescriptorSetAllocateInfo::builder()
+        .descriptor_pool(*pool)
+        .set_layouts(layouts);
+
+      match unsafe { device.internal().allocate_descriptor_sets(&desc_alloc_info) } {
+        Ok(descriptor_sets) => return (*pool, descriptor_sets),
+        Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY) | Err(vk::Result::ERROR_FRAGMENTED_POOL) => {}
+        Err(e) => panic!("Failed to allocate descriptor sets: {}", e),
+      }
+    }
+
+    let (pool_sizes, max_sets) = DescriptorAllocator::next_pool_sizes(
+      &self.pool_sizes,
+      self.max_sets,
+      pools.len() as u32,
+      required,
+    );
+    let pool = DescriptorAllocator::create_pool(
+      device,
+      &pool_sizes,
+      max_sets.max(layouts.len() as u32),
+      self.flags,
+    );
+    pools.push(pool);
+
+    let desc_alloc_info = vk::DescriptorSetAllocateInfo::builder()
+      .descriptor_pool(pool)
+      .set_layouts(layouts);
+
+    let descriptor_sets = unsafe {
+      device
+        .internal()
+        .allocate_descriptor_sets(&desc_alloc_info)
+        .expect("Failed to allocate descriptor sets from a new pool")
+    };
+
+    (pool, descriptor_sets)
+  }
+
+  /// Sets can only be freed individually when built with `free_individual_sets`.
+  pub fn can_free_sets(&self) -> bool {
+    self
+      .flags
+      .contains(vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET)
+  }
+
+  pub fn pool_count(&self) -> usize {
+    self.pools.borrow().len()
+  }
+
+  /// Destroying the pools frees every set allocated from them.
+  pub fn destroy(&self, device: &VkDevice) {
+    for pool in self.pools.borrow_mut().drain(..) {
+      unsafe {
+        device.internal().destroy_descriptor_pool(pool, None);
+      }
+    }
+  }
 }
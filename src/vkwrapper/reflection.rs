@@ -0,0 +1,596 @@
+use std::collections::HashMap;
+
+use ash::vk;
+
+use crate::vkwrapper::descriptorset::DescriptorSetBuilder;
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+
+const OP_ENTRY_POINT: u32 = 15;
+const OP_TYPE_BOOL: u32 = 20;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BUILT_IN: u32 = 11;
+const DECORATION_LOCATION: u32 = 30;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+const STORAGE_CLASS_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_CLASS_INPUT: u32 = 1;
+const STORAGE_CLASS_UNIFORM: u32 = 2;
+const STORAGE_CLASS_PUSH_CONSTANT: u32 = 9;
+const STORAGE_CLASS_STORAGE_BUFFER: u32 = 12;
+
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReflectedBinding {
+  pub set: u32,
+  pub binding: u32,
+  pub descriptor_type: vk::DescriptorType,
+  pub count: u32,
+  pub stages: vk::ShaderStageFlags,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum InputType {
+  Float,
+  Int,
+  Uint,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct VertexInput {
+  pub location: u32,
+  pub input_type: InputType,
+  pub components: u32,
+}
+
+enum SpirvType {
+  Scalar(InputType, u32), // type, width in bits
+  Bool,
+  Vector(u32, u32), // component type, count
+  Matrix(u32, u32), // column type, count
+  Image(u32, u32),  // dim, sampled
+  Sampler,
+  SampledImage,
+  Array(u32, u32), // element type, length constant
+  RuntimeArray(u32),
+  Struct(Vec<u32>),
+  Pointer(u32, u32), // storage class, type
+}
+
+/// The interface of a single SPIR-V module: vertex inputs, descriptor bindings and the push
+/// constant block size.
+pub struct ShaderReflection {
+  stage: vk::ShaderStageFlags,
+  inputs: Vec<VertexInput>,
+  bindings: Vec<ReflectedBinding>,
+  push_constant_size: u32,
+}
+
+struct Module {
+  types: HashMap<u32, SpirvType>,
+  constants: HashMap<u32, u32>,
+  decorations: HashMap<u32, Vec<(u32, u32)>>,
+  member_decorations: HashMap<(u32, u32), Vec<(u32, u32)>>,
+}
+
+impl Module {
+  fn decoration(&self, id: u32, decoration: u32) -> Option<u32> {
+    self
+      .decorations
+      .get(&id)
+      .and_then(|d| d.iter().find(|(kind, _)| *kind == decoration))
+      .map(|(_, value)| *value)
+  }
+
+  fn member_decoration(&self, id: u32, member: u32, decoration: u32) -> Option<u32> {
+    self
+      .member_decorations
+      .get(&(id, member))
+      .and_then(|d| d.iter().find(|(kind, _)| *kind == decoration))
+      .map(|(_, value)| *value)
+  }
+
+  fn get(&self, id: u32) -> Result<&SpirvType, String> {
+    self
+      .types
+      .get(&id)
+      .ok_or(format!("SPIR-V references unknown type %{}", id))
+  }
+
+  fn size_of(&self, id: u32, matrix_stride: Option<u32>) -> Result<u32, String> {
+    Ok(match self.get(id)? {
+      SpirvType::Scalar(_, width) => width / 8,
+      SpirvType::Bool => 4,
+      SpirvType::Vector(component, count) => self.size_of(*component, None)? * count,
+      SpirvType::Matrix(column, count) => match matrix_stride {
+        Some(stride) => stride * count,
+        None => self.size_of(*column, None)? * count,
+      },
+      SpirvType::Array(element, length) => {
+        let length = *self.constants.get(length).unwrap_or(&1);
+        match self.decoration(id, DECORATION_ARRAY_STRIDE) {
+          Some(stride) => stride * length,
+          None => self.size_of(*element, matrix_stride)? * length,
+        }
+      }
+      SpirvType::RuntimeArray(_) => 0,
+      SpirvType::Struct(members) => {
+        let mut size = 0;
+        for (i, member) in members.iter().enumerate() {
+          let i = i as u32;
+          let offset = self
+            .member_decoration(id, i, DECORATION_OFFSET)
+            .unwrap_or(size);
+          let stride = self.member_decoration(id, i, DECORATION_MATRIX_STRIDE);
+          size = size.max(offset + self.size_of(*member, stride)?);
+        }
+        size
+      }
+      _ => return Err(format!("SPIR-V type %{} has no size", id)),
+    })
+  }
+
+  fn descriptor_type(
+    &self,
+    id: u32,
+    storage_class: u32,
+  ) -> Result<(vk::DescriptorType, u32), String> {
+    Ok(match self.get(id)? {
+      SpirvType::Array(element, length) => {
+        let (descriptor_type, _) = self.descriptor_type(*element, storage_class)?;
+        (descriptor_type, *self.constants.get(length).unwrap_or(&1))
+      }
+      SpirvType::RuntimeArray(element) => (self.descriptor_type(*element, storage_class)?.0, 1),
+      SpirvType::Struct(_) => {
+        if storage_class == STORAGE_CLASS_STORAGE_BUFFER
+          || self.decoration(id, DECORATION_BUFFER_BLOCK).is_some()
+        {
+          (vk::DescriptorType::STORAGE_BUFFER, 1)
+        } else {
+          (vk::DescriptorType::UNIFORM_BUFFER, 1)
+        }
+      }
+      SpirvType::SampledImage => (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1),
+      SpirvType::Sampler => (vk::DescriptorType::SAMPLER, 1),
+      SpirvType::Image(dim, sampled) => {
+        let descriptor_type = match (*dim, *sampled) {
+          (DIM_BUFFER, 1) => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+          (DIM_BUFFER, _) => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+          (DIM_SUBPASS_DATA, _) => vk::DescriptorType::INPUT_ATTACHMENT,
+          (_, 2) => vk::DescriptorType::STORAGE_IMAGE,
+          _ => vk::DescriptorType::SAMPLED_IMAGE,
+        };
+        (descriptor_type, 1)
+      }
+      _ => return Err(format!("SPIR-V type %{} is not a descriptor", id)),
+    })
+  }
+
+  fn vertex_inputs(&self, id: u32, location: u32) -> Result<Vec<VertexInput>, String> {
+    // Matrices take up one location per column
+    if let SpirvType::Matrix(column, count) = self.get(id)? {
+      let mut columns = Vec::new();
+      for i in 0..*count {
+        columns.append(&mut self.vertex_inputs(*column, location + i)?);
+      }
+      return Ok(columns);
+    }
+
+    let (component, components) = match self.get(id)? {
+      SpirvType::Vector(component, count) => (*component, *count),
+      SpirvType::Scalar(_, _) => (id, 1),
+      _ => {
+        return Err(format!(
+          "vertex input at location {} is not a scalar, vector or matrix",
+          location
+        ))
+      }
+    };
+
+    let input_type = match self.get(component)? {
+      SpirvType::Scalar(input_type, 32) => *input_type,
+      _ => {
+        return Err(format!(
+          "vertex input at location {} is not 32 bits wide",
+          location
+        ))
+      }
+    };
+
+    Ok(vec![VertexInput {
+      location,
+      input_type,
+      components,
+    }])
+  }
+}
+
+impl ShaderReflection {
+  pub fn new(code: &[u32]) -> Result<ShaderReflection, String> {
+    if code.len() < 5 || code[0] != SPIRV_MAGIC {
+      return Err("Not a SPIR-V module".to_string());
+    }
+
+    let mut module = Module {
+      types: HashMap::new(),
+      constants: HashMap::new(),
+      decorations: HashMap::new(),
+      member_decorations: HashMap::new(),
+    };
+    let mut stage = vk::ShaderStageFlags::empty();
+    let mut variables = Vec::new();
+
+    let mut i = 5;
+    while i < code.len() {
+      let word_count = (code[i] >> 16) as usize;
+      let opcode = code[i] & 0xffff;
+      if word_count == 0 || i + word_count > code.len() {
+        return Err("Malformed SPIR-V instruction stream".to_string());
+      }
+      let op = &code[i + 1..i + word_count];
+      if op.len() < min_operands(opcode) {
+        return Err(format!(
+          "Malformed SPIR-V instruction, opcode {} has {} operands",
+          opcode,
+          op.len()
+        ));
+      }
+
+      match opcode {
+        OP_ENTRY_POINT => {
+          stage = match op[0] {
+            0 => vk::ShaderStageFlags::VERTEX,
+            4 => vk::ShaderStageFlags::FRAGMENT,
+            5 => vk::ShaderStageFlags::COMPUTE,
+            model => return Err(format!("Unsupported SPIR-V execution model {}", model)),
+          };
+        }
+        OP_TYPE_BOOL => {
+          module.types.insert(op[0], SpirvType::Bool);
+        }
+        OP_TYPE_INT => {
+          let input_type = if op[2] == 1 {
+            InputType::Int
+          } else {
+            InputType::Uint
+          };
+          module
+            .types
+            .insert(op[0], SpirvType::Scalar(input_type, op[1]));
+        }
+        OP_TYPE_FLOAT => {
+          module
+            .types
+            .insert(op[0], SpirvType::Scalar(InputType::Float, op[1]));
+        }
+        OP_TYPE_VECTOR => {
+          module.types.insert(op[0], SpirvType::Vector(op[1], op[2]));
+        }
+        OP_TYPE_MATRIX => {
+          module.types.insert(op[0], SpirvType::Matrix(op[1], op[2]));
+        }
+        OP_TYPE_IMAGE => {
+          module.types.insert(op[0], SpirvType::Image(op[2], op[6]));
+        }
+        OP_TYPE_SAMPLER => {
+          module.types.insert(op[0], SpirvType::Sampler);
+        }
+        OP_TYPE_SAMPLED_IMAGE => {
+          module.types.insert(op[0], SpirvType::SampledImage);
+        }
+        OP_TYPE_ARRAY => {
+          module.types.insert(op[0], SpirvType::Array(op[1], op[2]));
+        }
+        OP_TYPE_RUNTIME_ARRAY => {
+          module.types.insert(op[0], SpirvType::RuntimeArray(op[1]));
+        }
+        OP_TYPE_STRUCT => {
+          module
+            .types
+            .insert(op[0], SpirvType::Struct(op[1..].to_vec()));
+        }
+        OP_TYPE_POINTER => {
+          module.types.insert(op[0], SpirvType::Pointer(op[1], op[2]));
+        }
+        OP_CONSTANT if op.len() > 2 => {
+          module.constants.insert(op[1], op[2]);
+        }
+        OP_VARIABLE => {
+          variables.push((op[0], op[1], op[2]));
+        }
+        OP_DECORATE => {
+          let value = op.get(2).copied().unwrap_or(0);
+          module
+            .decorations
+            .entry(op[0])
+            .or_default()
+            .push((op[1], value));
+        }
+        OP_MEMBER_DECORATE => {
+          let value = op.get(3).copied().unwrap_or(0);
+          module
+            .member_decorations
+            .entry((op[0], op[1]))
+            .or_default()
+            .push((op[2], value));
+        }
+        _ => {}
+      }
+
+      i += word_count;
+    }
+
+    let mut inputs = Vec::new();
+    let mut bindings = Vec::new();
+    let mut push_constant_size = 0;
+
+    for (pointer_type, id, storage_class) in variables {
+      let pointee = match module.get(pointer_type)? {
+        SpirvType::Pointer(_, pointee) => *pointee,
+        _ => return Err(format!("SPIR-V variable %{} is not a pointer", id)),
+      };
+
+      match storage_class {
+        STORAGE_CLASS_INPUT if stage == vk::ShaderStageFlags::VERTEX => {
+          if module.decoration(id, DECORATION_BUILT_IN).is_some() {
+            continue;
+          }
+          if let Some(location) = module.decoration(id, DECORATION_LOCATION) {
+            inputs.append(&mut module.vertex_inputs(pointee, location)?);
+          }
+        }
+        STORAGE_CLASS_UNIFORM_CONSTANT | STORAGE_CLASS_UNIFORM | STORAGE_CLASS_STORAGE_BUFFER => {
+          let (descriptor_type, count) = module.descriptor_type(pointee, storage_class)?;
+          bindings.push(ReflectedBinding {
+            set: module
+              .decoration(id, DECORATION_DESCRIPTOR_SET)
+              .unwrap_or(0),
+            binding: module.decoration(id, DECORATION_BINDING).unwrap_or(0),
+            descriptor_type,
+            count,
+            stages: stage,
+          });
+        }
+        STORAGE_CLASS_PUSH_CONSTANT => {
+          push_constant_size = module.size_of(pointee, None)?;
+        }
+        _ => {}
+      }
+    }
+
+    if stage.is_empty() {
+      return Err("SPIR-V module has no entry point".to_string());
+    }
+
+    inputs.sort_by_key(|input| input.location);
+    bindings.sort_by_key(|binding| (binding.set, binding.binding));
+
+    Ok(ShaderReflection {
+      stage,
+      inputs,
+      bindings,
+      push_constant_size: push_constant_size.div_ceil(4) * 4,
+    })
+  }
+
+  pub fn stage(&self) -> vk::ShaderStageFlags {
+    self.stage
+  }
+
+  pub fn inputs(&self) -> &Vec<VertexInput> {
+    &self.inputs
+  }
+
+  pub fn bindings(&self) -> &Vec<ReflectedBinding> {
+    &self.bindings
+  }
+
+  pub fn push_constant_size(&self) -> u32 {
+    self.push_constant_size
+  }
+
+  /// One range covering every stage that declares a push constant block.
+  pub fn push_constant_range(modules: &[&ShaderReflection]) -> Option<vk::PushConstantRange> {
+    let used = modules
+      .iter()
+      .filter(|module| module.push_constant_size > 0)
+      .collect::<Vec<_>>();
+
+    if used.is_empty() {
+      return None;
+    }
+
+    Some(vk::PushConstantRange {
+      stage_flags: used
+        .iter()
+        .fold(vk::ShaderStageFlags::empty(), |stages, module| {
+          stages | module.stage
+        }),
+      offset: 0,
+      size: used
+        .iter()
+        .map(|module| module.push_constant_size)
+        .max()
+        .unwrap(),
+    })
+  }
+
+  /// The bindings of every module merged by set and binding number, with the stages that use
+  /// them combined.
+  pub fn merged_bindings(modules: &[&ShaderReflection]) -> Result<Vec<ReflectedBinding>, String> {
+    let mut merged: Vec<ReflectedBinding> = Vec::new();
+
+    for binding in modules.iter().flat_map(|module| module.bindings.iter()) {
+      match merged
+        .iter_mut()
+        .find(|b| b.set == binding.set && b.binding == binding.binding)
+      {
+        Some(existing) => {
+          if existing.descriptor_type != binding.descriptor_type {
+            return Err(format!(
+              "set {} binding {} is a {:?} in one stage and a {:?} in another",
+              binding.set, binding.binding, existing.descriptor_type, binding.descriptor_type
+            ));
+          }
+          existing.stages |= binding.stages;
+        }
+        None => merged.push(*binding),
+      }
+    }
+
+    merged.sort_by_key(|binding| (binding.set, binding.binding));
+
+    Ok(merged)
+  }
+
+  /// Builders for every descriptor set the modules use, indexed by set number.
+  pub fn descriptor_set_builders(
+    modules: &[&ShaderReflection],
+  ) -> Result<Vec<DescriptorSetBuilder>, String> {
+    let bindings = ShaderReflection::merged_bindings(modules)?;
+    let set_count = bindings.iter().map(|b| b.set + 1).max().unwrap_or(0);
+
+    Ok(
+      (0..set_count)
+        .map(|set| {
+          DescriptorSetBuilder::from_reflection(
+            &bindings
+              .iter()
+              .filter(|b| b.set == set)
+              .copied()
+              .collect::<Vec<_>>(),
+          )
+        })
+        .collect(),
+    )
+  }
+
+  /// Matches the vertex struct fields, given as offsets in location order, against the inputs of
+  /// a vertex shader. The attribute formats are sized from the struct fields.
+  pub fn vertex_attributes(
+    &self,
+    binding: u32,
+    first_location: u32,
+    offsets: &[u32],
+    stride: u32,
+  ) -> Result<Vec<vk::VertexInputAttributeDescription>, String> {
+    let mut attributes = Vec::new();
+
+    for (i, offset) in offsets.iter().enumerate() {
+      let location = first_location + i as u32;
+
+      let field_end = offsets
+        .iter()
+        .filter(|o| *o > offset)
+        .min()
+        .copied()
+        .unwrap_or(stride);
+      if field_end <= *offset {
+        return Err(format!(
+          "vertex struct field for location {} at offset {} is outside the {} byte struct",
+          location, offset, stride
+        ));
+      }
+
+      let components = ((field_end - offset) / 4).clamp(1, 4);
+      let input = self.inputs.iter().find(|input| input.location == location);
+
+      if let Some(input) = input {
+        if field_end - offset < 4 {
+          return Err(format!(
+            "vertex shader input at location {} is a {} component {:?} but the struct field at \
+             offset {} is only {} bytes",
+            location,
+            input.components,
+            input.input_type,
+            offset,
+            field_end - offset
+          ));
+        }
+      }
+
+      let input_type = input.map_or(InputType::Float, |input| input.input_type);
+
+      attributes.push(vk::VertexInputAttributeDescription {
+        location,
+        binding,
+        format: attribute_format(input_type, components),
+        offset: *offset,
+      });
+    }
+
+    Ok(attributes)
+  }
+
+  /// Every vertex shader input has to be fed by one of the attributes.
+  pub fn check_vertex_inputs(
+    &self,
+    attributes: &[vk::VertexInputAttributeDescription],
+  ) -> Result<(), String> {
+    for input in &self.inputs {
+      if !attributes.iter().any(|a| a.location == input.location) {
+        return Err(format!(
+          "vertex shader input at location {} ({} component {:?}) has no matching field in the \
+           vertex struct, only {} offsets were given",
+          input.location,
+          input.components,
+          input.input_type,
+          attributes.len()
+        ));
+      }
+    }
+
+    Ok(())
+  }
+}
+
+// Fewest operands, after the opcode word, each instruction that is read can have
+fn min_operands(opcode: u32) -> usize {
+  match opcode {
+    OP_TYPE_BOOL | OP_TYPE_SAMPLER | OP_TYPE_STRUCT => 1,
+    OP_TYPE_FLOAT | OP_TYPE_SAMPLED_IMAGE | OP_TYPE_RUNTIME_ARRAY | OP_DECORATE => 2,
+    OP_ENTRY_POINT | OP_TYPE_INT | OP_TYPE_VECTOR | OP_TYPE_MATRIX | OP_TYPE_ARRAY
+    | OP_TYPE_POINTER | OP_VARIABLE | OP_MEMBER_DECORATE => 3,
+    OP_TYPE_IMAGE => 8,
+    _ => 0,
+  }
+}
+
+fn attribute_format(input_type: InputType, components: u32) -> vk::Format {
+  match (input_type, components) {
+    (InputType::Float, 1) => vk::Format::R32_SFLOAT,
+    (InputType::Float, 2) => vk::Format::R32G32_SFLOAT,
+    (InputType::Float, 3) => vk::Format::R32G32B32_SFLOAT,
+    (InputType::Float, _) => vk::Format::R32G32B32A32_SFLOAT,
+    (InputType::Int, 1) => vk::Format::R32_SINT,
+    (InputType::Int, 2) => vk::Format::R32G32_SINT,
+    (InputType::Int, 3) => vk::Format::R32G32B32_SINT,
+    (InputType::Int, _) => vk::Format::R32G32B32A32_SINT,
+    (InputType::Uint, 1) => vk::Format::R32_UINT,
+    (InputType::Uint, 2) => vk::Format::R32G32_UINT,
+    (InputType::Uint, 3) => vk::Format::R32G32B32_UINT,
+    (InputType::Uint, _) => vk::Format::R32G32B32A32_UINT,
+  }
+}
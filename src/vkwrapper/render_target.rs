@@ -0,0 +1,146 @@
+use ash::vk;
+
+use crate::vkwrapper::vulkan::HDR_FORMAT;
+use crate::vkwrapper::{Image, ImageBuilder, Renderpass, VkDevice, VkFrameBuffer};
+
+/// Colour and depth images the model renderpass can draw into, at any size. The colour image is
+/// left in `SHADER_READ_ONLY_OPTIMAL` between uses so sprites can sample it.
+pub struct RenderTarget {
+  extent: vk::Extent2D,
+  colour_image: Image,
+  // Multisampled colour resolved into the colour image, only used with MSAA
+  msaa_image: Option<Image>,
+  depth_image: Image,
+  framebuffer: VkFrameBuffer,
+  // Nothing has been written to the colour image yet, it still needs clearing before sampling
+  cleared: bool,
+}
+
+impl RenderTarget {
+  pub fn new(
+    device: &VkDevice,
+    width: u32,
+    height: u32,
+    renderpass: &Renderpass,
+    samples: vk::SampleCountFlags,
+  ) -> RenderTarget {
+    let extent = vk::Extent2D {
+      width: width.max(1),
+      height: height.max(1),
+    };
+
+    let colour_image = ImageBuilder::new(HDR_FORMAT, 1, 1)
+      .usage(
+        vk::ImageUsageFlags::COLOR_ATTACHMENT
+          | vk::ImageUsageFlags::SAMPLED
+          | vk::ImageUsageFlags::TRANSFER_DST,
+      )
+      .set_dimensions(extent.width, extent.height)
+      .build_device_local(device);
+
+    let (msaa_image, depth_image, framebuffer) =
+      RenderTarget::create_attachments(device, extent, &colour_image, renderpass, samples);
+
+    RenderTarget {
+      extent,
+      colour_image,
+      msaa_image,
+      depth_image,
+      framebuffer,
+      cleared: false,
+    }
+  }
+
+  fn create_attachments(
+    device: &VkDevice,
+    extent: vk::Extent2D,
+    colour_image: &Image,
+    renderpass: &Renderpass,
+    samples: vk::SampleCountFlags,
+  ) -> (Option<Image>, Image, VkFrameBuffer) {
+    let msaa_image = if samples != vk::SampleCountFlags::TYPE_1 {
+      Some(
+        ImageBuilder::new(HDR_FORMAT, 1, 1)
+          .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT)
+          .samples(samples)
+          .set_dimensions(extent.width, extent.height)
+          .build_device_local(device),
+      )
+    } else {
+      None
+    };
+
+    let depth_image = ImageBuilder::new_depth(
+      extent.width,
+      extent.height,
+      1,
+      1,
+      vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+    )
+    .samples(samples)
+    .build_device_local(device);
+
+    let attachments = match &msaa_image {
+      Some(msaa_image) => vec![msaa_image.view(), depth_image.view(), colour_image.view()],
+      None => vec![colour_image.view(), depth_image.view()],
+    };
+    let framebuffer = VkFrameBuffer::new_offscreen(device, extent, attachments, renderpass);
+
+    (msaa_image, depth_image, framebuffer)
+  }
+
+  /// Rebuilds everything but the colour image for a renderpass with a new sample count, the
+  /// device must be idle.
+  pub fn recreate_attachments(
+    &mut self,
+    device: &VkDevice,
+    renderpass: &Renderpass,
+    samples: vk::SampleCountFlags,
+  ) {
+    self.destroy_attachments(device);
+
+    let (msaa_image, depth_image, framebuffer) = RenderTarget::create_attachments(
+      device,
+      self.extent,
+      &self.colour_image,
+      renderpass,
+      samples,
+    );
+    self.msaa_image = msaa_image;
+    self.depth_image = depth_image;
+    self.framebuffer = framebuffer;
+  }
+
+  pub fn extent(&self) -> vk::Extent2D {
+    self.extent
+  }
+
+  pub fn colour_image(&self) -> &Image {
+    &self.colour_image
+  }
+
+  pub fn framebuffer(&self) -> vk::Framebuffer {
+    self.framebuffer.framebuffers()[0]
+  }
+
+  pub fn is_cleared(&self) -> bool {
+    self.cleared
+  }
+
+  pub fn set_cleared(&mut self) {
+    self.cleared = true;
+  }
+
+  fn destroy_attachments(&self, device: &VkDevice) {
+    self.framebuffer.destroy(device.internal());
+    self.depth_image.destroy(device);
+    if let Some(msaa_image) = &self.msaa_image {
+      msaa_image.destroy(device);
+    }
+  }
+
+  pub fn destroy(&self, device: &VkDevice) {
+    self.destroy_attachments(device);
+    self.colour_image.destroy(device);
+  }
+}
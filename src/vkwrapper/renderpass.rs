@@ -13,6 +13,7 @@ pub struct PassDescription {
   final_layout: vk::ImageLayout,
   is_depth: bool,
   is_colour: bool,
+  is_resolve: bool,
 }
 
 impl PassDescription {
@@ -36,6 +37,7 @@ impl PassDescription {
       final_layout,
       is_depth: false,
       is_colour: false,
+      is_resolve: false,
     }
   }
 
@@ -57,6 +59,10 @@ impl PassDescription {
     self.is_colour
   }
 
+  pub fn is_resolve(&self) -> bool {
+    self.is_resolve
+  }
+
   pub fn attachment_load_op_load(mut self) -> PassDescription {
     self.load_op = vk::AttachmentLoadOp::LOAD;
     self
@@ -67,11 +73,21 @@ impl PassDescription {
     self
   }
 
+  pub fn attachment_load_op_dont_care(mut self) -> PassDescription {
+    self.load_op = vk::AttachmentLoadOp::DONT_CARE;
+    self
+  }
+
   pub fn attachment_store_op_store(mut self) -> PassDescription {
     self.store_op = vk::AttachmentStoreOp::STORE;
     self
   }
 
+  pub fn attachment_store_op_dont_care(mut self) -> PassDescription {
+    self.store_op = vk::AttachmentStoreOp::DONT_CARE;
+    self
+  }
+
   pub fn stencil_load_op_load(mut self) -> PassDescription {
     self.stencil_load_op = vk::AttachmentLoadOp::LOAD;
     self
@@ -88,6 +104,13 @@ impl PassDescription {
     self
   }
 
+  /// Single sampled colour attachment the multisampled colour attachments are resolved into.
+  pub fn attachment_layout_resolve(mut self) -> PassDescription {
+    self.attachment_layout = vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL;
+    self.is_resolve = true;
+    self
+  }
+
   pub fn attachment_layout_depth_stencil(mut self) -> PassDescription {
     self.attachment_layout = vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL;
     self.is_depth = true;
@@ -104,6 +127,11 @@ impl PassDescription {
     self
   }
 
+  pub fn initial_layout_colour_attachment(mut self) -> PassDescription {
+    self.initial_layout = vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL;
+    self
+  }
+
   pub fn initial_layout_undefined(mut self) -> PassDescription {
     self.initial_layout = vk::ImageLayout::UNDEFINED;
     self
@@ -114,6 +142,11 @@ impl PassDescription {
     self
   }
 
+  pub fn final_layout_colour_attachment(mut self) -> PassDescription {
+    self.final_layout = vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL;
+    self
+  }
+
   pub fn final_layout_general(mut self) -> PassDescription {
     self.final_layout = vk::ImageLayout::GENERAL;
     self
@@ -149,6 +182,11 @@ impl PassDescription {
     self
   }
 
+  pub fn samples(mut self, samples: vk::SampleCountFlags) -> PassDescription {
+    self.samples = samples;
+    self
+  }
+
   pub fn samples_1(mut self) -> PassDescription {
     self.samples = vk::SampleCountFlags::TYPE_1;
     self
@@ -208,7 +246,7 @@ impl Renderpass {
     let mut dependancies = Vec::new();
     //let mut input_attachments = Vec::new();
     let mut color_attachments = Vec::new();
-    //let mut resolve_attachments = Vec::new();
+    let mut resolve_attachments = Vec::new();
     let mut depth_stencil_attachment = None;
     //let mut preserve_attachments = Vec::new();
 
@@ -243,11 +281,20 @@ impl Renderpass {
           attachment: i as u32,
           layout: passes[i].attachment_layout(),
         });
+      } else if passes[i].is_resolve() {
+        resolve_attachments.push(vk::AttachmentReference {
+          attachment: i as u32,
+          layout: passes[i].attachment_layout(),
+        });
       }
     }
 
     let mut subpasses = vk::SubpassDescription::builder().color_attachments(&color_attachments);
 
+    if !resolve_attachments.is_empty() {
+      subpasses = subpasses.resolve_attachments(&resolve_attachments);
+    }
+
     let depth_stencil;
     if depth_stencil_attachment.is_some() {
       depth_stencil = depth_stencil_attachment.unwrap();
@@ -276,6 +323,12 @@ impl Renderpass {
   pub fn internal(&self) -> vk::RenderPass {
     self.renderpass
   }
+
+  pub fn destroy(&self, device: &VkDevice) {
+    unsafe {
+      device.internal().destroy_render_pass(self.renderpass, None);
+    }
+  }
 }
 
 fn create_renderpass(device: &VkDevice, surface_format: vk::SurfaceFormatKHR) -> vk::RenderPass {
@@ -0,0 +1,68 @@
+use std::cell::Cell;
+
+#[derive(Clone, Copy, Debug)]
+pub enum TrackedResource {
+  Memory,
+  Buffer,
+  Image,
+  Sampler,
+}
+
+/// Counts live device handles so anything not destroyed by shutdown can be reported.
+pub struct ResourceTracker {
+  memory: Cell<i64>,
+  buffers: Cell<i64>,
+  images: Cell<i64>,
+  samplers: Cell<i64>,
+}
+
+impl ResourceTracker {
+  pub fn new() -> ResourceTracker {
+    ResourceTracker {
+      memory: Cell::new(0),
+      buffers: Cell::new(0),
+      images: Cell::new(0),
+      samplers: Cell::new(0),
+    }
+  }
+
+  pub fn created(&self, resource: TrackedResource) {
+    let count = self.counter(resource);
+    count.set(count.get() + 1);
+  }
+
+  pub fn destroyed(&self, resource: TrackedResource) {
+    let count = self.counter(resource);
+    count.set(count.get() - 1);
+  }
+
+  pub fn live(&self, resource: TrackedResource) -> i64 {
+    self.counter(resource).get()
+  }
+
+  pub fn report_leaks(&self) {
+    let leaks = [
+      TrackedResource::Memory,
+      TrackedResource::Buffer,
+      TrackedResource::Image,
+      TrackedResource::Sampler,
+    ]
+    .iter()
+    .filter(|r| self.live(**r) != 0)
+    .map(|r| format!("{:?}: {}", r, self.live(*r)))
+    .collect::<Vec<String>>();
+
+    if !leaks.is_empty() {
+      println!("Leaked device handles at shutdown: {}", leaks.join(", "));
+    }
+  }
+
+  fn counter(&self, resource: TrackedResource) -> &Cell<i64> {
+    match resource {
+      TrackedResource::Memory => &self.memory,
+      TrackedResource::Buffer => &self.buffers,
+      TrackedResource::Image => &self.images,
+      TrackedResource::Sampler => &self.samplers,
+    }
+  }
+}
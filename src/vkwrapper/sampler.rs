@@ -1,6 +1,6 @@
 use ash::vk;
 
-use crate::vkwrapper::VkDevice;
+use crate::vkwrapper::{TrackedResource, VkDevice};
 
 #[derive(Clone)]
 pub struct Sampler {
@@ -19,6 +19,7 @@ impl Sampler {
         .create_sampler(&create_info, None)
         .unwrap()
     };
+    device.resources().created(TrackedResource::Sampler);
 
     Sampler { sampler }
   }
@@ -31,6 +32,7 @@ impl Sampler {
     unsafe {
       device.internal().destroy_sampler(self.sampler, None);
     }
+    device.resources().destroyed(TrackedResource::Sampler);
   }
 }
 
@@ -40,6 +42,7 @@ pub struct SamplerBuilder {
   mipmap_mode: vk::SamplerMipmapMode,
   address_mode: vk::SamplerAddressMode,
   max_anisotropy: f32,
+  max_lod: f32,
   border_colour: vk::BorderColor,
   compare_op: vk::CompareOp,
 }
@@ -60,6 +63,7 @@ impl SamplerBuilder {
       mipmap_mode,
       address_mode,
       max_anisotropy,
+      max_lod: vk::LOD_CLAMP_NONE,
       border_colour,
       compare_op,
     }
@@ -115,6 +119,18 @@ impl SamplerBuilder {
     self
   }
 
+  /// Enabled only when the device supports it, and clamped to the device limit.
+  pub fn anisotropy(mut self, max_anisotropy: f32) -> SamplerBuilder {
+    self.max_anisotropy = max_anisotropy;
+    self
+  }
+
+  /// Defaults to sampling every mip level the image has.
+  pub fn max_lod(mut self, max_lod: f32) -> SamplerBuilder {
+    self.max_lod = max_lod;
+    self
+  }
+
   pub fn border_colour_float_transparent_black(mut self) -> SamplerBuilder {
     self.border_colour = vk::BorderColor::FLOAT_TRANSPARENT_BLACK;
     self
@@ -181,6 +197,11 @@ impl SamplerBuilder {
   }
 
   pub fn build(&self, device: &VkDevice) -> Sampler {
+    let max_anisotropy = match device.max_sampler_anisotropy() {
+      Some(limit) if self.max_anisotropy > 1.0 => self.max_anisotropy.min(limit),
+      _ => 1.0,
+    };
+
     let sampler_info = vk::SamplerCreateInfo {
       mag_filter: self.mag_filter,
       min_filter: self.min_filter,
@@ -188,7 +209,9 @@ impl SamplerBuilder {
       address_mode_u: self.address_mode,
       address_mode_v: self.address_mode,
       address_mode_w: self.address_mode,
-      max_anisotropy: self.max_anisotropy,
+      anisotropy_enable: (max_anisotropy > 1.0) as vk::Bool32,
+      max_anisotropy,
+      max_lod: self.max_lod,
       border_color: self.border_colour,
       compare_op: self.compare_op,
       ..Default::default()
@@ -25,4 +25,10 @@ impl Semaphore {
   pub fn internal(&self) -> vk::Semaphore {
     self.semaphore
   }
+
+  pub fn destroy(&self, device: &VkDevice) {
+    unsafe {
+      device.internal().destroy_semaphore(self.semaphore, None);
+    }
+  }
 }
@@ -7,7 +7,8 @@ use ash::util::read_spv;
 use ash::vk;
 
 use crate::vkwrapper::{
-  GraphicsPipeline, GraphicsPipelineBuilder, Renderpass, Scissors, Viewport, VkDevice,
+  DescriptorSet, DescriptorSetBuilder, GraphicsPipeline, GraphicsPipelineBuilder, Renderpass,
+  Scissors, ShaderReflection, Viewport, VkDevice,
 };
 
 pub struct Shader<T: Sized + Copy> {
@@ -15,11 +16,46 @@ pub struct Shader<T: Sized + Copy> {
   vertex_shader: vk::ShaderModule,
   fragment_shader: vk::ShaderModule,
   pipeline_layout: vk::PipelineLayout,
+  push_constant_range: Option<vk::PushConstantRange>,
   graphics_pipeline: GraphicsPipeline,
 }
 
 impl<T: Sized + Copy> Shader<T> {
   pub fn new<W: Read + Seek, S>(
+    device: &VkDevice,
+    vertex_shader: W,
+    fragment_shader: W,
+    vertex_struct: T,
+    offsets: Vec<u32>,
+    graphics_pipeline_builder: &GraphicsPipelineBuilder,
+    renderpass: &Renderpass,
+    viewport: &Viewport,
+    scissors: &Scissors,
+    descriptor_sets: &[&DescriptorSet],
+    instanced: Option<(S, Vec<u32>)>,
+  ) -> Shader<T> {
+    match Shader::try_new(
+      device,
+      vertex_shader,
+      fragment_shader,
+      vertex_struct,
+      offsets,
+      graphics_pipeline_builder,
+      renderpass,
+      viewport,
+      scissors,
+      descriptor_sets,
+      instanced,
+    ) {
+      Ok(shader) => shader,
+      Err(e) => panic!("{}", e),
+    }
+  }
+
+  /// Same as `new` but reports bad SPIR-V or a failed pipeline instead of panicking, so a
+  /// shader can be rebuilt while the old one stays in use.
+  #[allow(clippy::too_many_arguments)]
+  pub fn try_new<W: Read + Seek, S>(
     device: &VkDevice,
     mut vertex_shader: W,
     mut fragment_shader: W,
@@ -29,11 +65,51 @@ impl<T: Sized + Copy> Shader<T> {
     renderpass: &Renderpass,
     viewport: &Viewport,
     scissors: &Scissors,
-    descriptor_set_layouts: &Vec<vk::DescriptorSetLayout>,
+    descriptor_sets: &[&DescriptorSet],
     instanced: Option<(S, Vec<u32>)>,
-  ) -> Shader<T> {
-    let vertex_code = read_spv(&mut vertex_shader).expect("Failed to read vertex shader");
-    let fragment_code = read_spv(&mut fragment_shader).expect("Failed to read fragment shader");
+  ) -> Result<Shader<T>, String> {
+    let vertex_code =
+      read_spv(&mut vertex_shader).map_err(|e| format!("Failed to read vertex shader: {}", e))?;
+    let fragment_code = read_spv(&mut fragment_shader)
+      .map_err(|e| format!("Failed to read fragment shader: {}", e))?;
+
+    let vertex_reflection = ShaderReflection::new(&vertex_code)
+      .map_err(|e| format!("Failed to reflect vertex shader: {}", e))?;
+    let fragment_reflection = ShaderReflection::new(&fragment_code)
+      .map_err(|e| format!("Failed to reflect fragment shader: {}", e))?;
+    let reflections = [&vertex_reflection, &fragment_reflection];
+
+    let bindings = ShaderReflection::merged_bindings(&reflections)?;
+    let layout_builders = descriptor_sets
+      .iter()
+      .map(|set| set.layout_builder())
+      .collect::<Vec<_>>();
+    DescriptorSetBuilder::check_layouts(&bindings, &layout_builders)?;
+    let descriptor_set_layouts = descriptor_sets
+      .iter()
+      .map(|set| set.layouts()[0])
+      .collect::<Vec<_>>();
+
+    let mut vertex_input_attributes = vertex_reflection
+      .vertex_attributes(0, 0, &offsets, mem::size_of::<T>() as u32)
+      .map_err(|e| format!("Vertex layout mismatch: {}", e))?;
+    if let Some((_, instanced_offsets)) = &instanced {
+      vertex_input_attributes.append(
+        &mut vertex_reflection
+          .vertex_attributes(
+            1,
+            offsets.len() as u32,
+            instanced_offsets,
+            mem::size_of::<S>() as u32,
+          )
+          .map_err(|e| format!("Instanced vertex layout mismatch: {}", e))?,
+      );
+    }
+    vertex_reflection
+      .check_vertex_inputs(&vertex_input_attributes)
+      .map_err(|e| format!("Vertex layout mismatch: {}", e))?;
+
+    let push_constant_range = ShaderReflection::push_constant_range(&reflections);
 
     let vertex_info = vk::ShaderModuleCreateInfo::builder().code(&vertex_code);
     let fragment_info = vk::ShaderModuleCreateInfo::builder().code(&fragment_code);
@@ -42,36 +118,36 @@ impl<T: Sized + Copy> Shader<T> {
       device
         .internal()
         .create_shader_module(&vertex_info, None)
-        .expect("Vertex shader module error")
+        .map_err(|e| format!("Vertex shader module error: {}", e))?
     };
     let fragment_shader = unsafe {
-      device
-        .internal()
-        .create_shader_module(&fragment_info, None)
-        .expect("Fragment shader module error")
-    };
-
-    let push_constant_range = vk::PushConstantRange::builder()
-      .stage_flags(vk::ShaderStageFlags::VERTEX)
-      .offset(0)
-      .size(128);
-
-    let layout_info = {
-      if descriptor_set_layouts.len() == 0 {
-        vk::PipelineLayoutCreateInfo::default()
-      } else {
-        *vk::PipelineLayoutCreateInfo::builder()
-          .set_layouts(descriptor_set_layouts)
-          .push_constant_ranges(&[*push_constant_range])
+      match device.internal().create_shader_module(&fragment_info, None) {
+        Ok(module) => module,
+        Err(e) => {
+          device.internal().destroy_shader_module(vertex_shader, None);
+          return Err(format!("Fragment shader module error: {}", e));
+        }
       }
     };
 
-    let pipeline_layout = unsafe {
-      device
-        .internal()
-        .create_pipeline_layout(&layout_info, None)
-        .unwrap()
-    };
+    let push_constant_ranges = push_constant_range.into_iter().collect::<Vec<_>>();
+    let layout_info = vk::PipelineLayoutCreateInfo::builder()
+      .set_layouts(&descriptor_set_layouts)
+      .push_constant_ranges(&push_constant_ranges);
+
+    let pipeline_layout =
+      match unsafe { device.internal().create_pipeline_layout(&layout_info, None) } {
+        Ok(layout) => layout,
+        Err(e) => {
+          unsafe {
+            device.internal().destroy_shader_module(vertex_shader, None);
+            device
+              .internal()
+              .destroy_shader_module(fragment_shader, None);
+          }
+          return Err(format!("Unable to create pipeline layout: {}", e));
+        }
+      };
 
     let shader_entry = CString::new("main").unwrap();
     let shader_stage_create_info = [
@@ -104,27 +180,6 @@ impl<T: Sized + Copy> Shader<T> {
       });
     }
 
-    let mut vertex_input_attributes = Vec::new();
-    for i in 0..offsets.len() {
-      vertex_input_attributes.push(vk::VertexInputAttributeDescription {
-        location: i as u32,
-        binding: 0,
-        format: vk::Format::R32G32B32A32_SFLOAT,
-        offset: offsets[i] as u32,
-      });
-    }
-
-    if let Some((_, instanced_offsets)) = instanced {
-      for i in 0..instanced_offsets.len() {
-        vertex_input_attributes.push(vk::VertexInputAttributeDescription {
-          location: i as u32 + offsets.len() as u32,
-          binding: 1,
-          format: vk::Format::R32G32B32A32_SFLOAT,
-          offset: instanced_offsets[i] as u32,
-        });
-      }
-    }
-
     let vertex_input_state = vk::PipelineVertexInputStateCreateInfo {
       vertex_attribute_description_count: vertex_input_attributes.len() as u32,
       p_vertex_attribute_descriptions: vertex_input_attributes.as_ptr(),
@@ -133,7 +188,7 @@ impl<T: Sized + Copy> Shader<T> {
       ..Default::default()
     };
 
-    let graphics_pipeline = graphics_pipeline_builder.build(
+    let graphics_pipeline = match graphics_pipeline_builder.try_build(
       device,
       &pipeline_layout,
       shader_stage_create_info.to_vec(),
@@ -141,15 +196,30 @@ impl<T: Sized + Copy> Shader<T> {
       viewport,
       scissors,
       renderpass,
-    );
+    ) {
+      Ok(pipeline) => pipeline,
+      Err(e) => {
+        unsafe {
+          device
+            .internal()
+            .destroy_pipeline_layout(pipeline_layout, None);
+          device.internal().destroy_shader_module(vertex_shader, None);
+          device
+            .internal()
+            .destroy_shader_module(fragment_shader, None);
+        }
+        return Err(format!("Unable to create graphics pipeline: {}", e));
+      }
+    };
 
-    Shader {
+    Ok(Shader {
       vertex_struct,
       vertex_shader,
       fragment_shader,
       pipeline_layout,
+      push_constant_range,
       graphics_pipeline,
-    }
+    })
   }
 
   pub fn graphics_pipeline(&self) -> &GraphicsPipeline {
@@ -160,6 +230,11 @@ impl<T: Sized + Copy> Shader<T> {
     self.pipeline_layout
   }
 
+  /// The push constant block the shaders declare, `None` when neither stage has one.
+  pub fn push_constant_range(&self) -> Option<vk::PushConstantRange> {
+    self.push_constant_range
+  }
+
   pub fn destroy(&self, device: &VkDevice) {
     self.graphics_pipeline.destroy(device);
 
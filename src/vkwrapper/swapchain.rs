@@ -3,6 +3,62 @@ use ash::vk;
 
 use crate::vkwrapper::{Image, ImageBuilder, VkDevice, VkInstance};
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PresentMode {
+  Fifo,        // vsync, always supported
+  FifoRelaxed, // vsync, tears instead of waiting when a frame is late
+  Mailbox,     // no tearing, latest frame replaces queued ones
+  Immediate,   // uncapped, may tear
+}
+
+impl PresentMode {
+  pub fn from_vk(mode: vk::PresentModeKHR) -> Option<PresentMode> {
+    match mode {
+      vk::PresentModeKHR::FIFO => Some(PresentMode::Fifo),
+      vk::PresentModeKHR::FIFO_RELAXED => Some(PresentMode::FifoRelaxed),
+      vk::PresentModeKHR::MAILBOX => Some(PresentMode::Mailbox),
+      vk::PresentModeKHR::IMMEDIATE => Some(PresentMode::Immediate),
+      _ => None,
+    }
+  }
+
+  pub fn to_vk(&self) -> vk::PresentModeKHR {
+    match self {
+      PresentMode::Fifo => vk::PresentModeKHR::FIFO,
+      PresentMode::FifoRelaxed => vk::PresentModeKHR::FIFO_RELAXED,
+      PresentMode::Mailbox => vk::PresentModeKHR::MAILBOX,
+      PresentMode::Immediate => vk::PresentModeKHR::IMMEDIATE,
+    }
+  }
+
+  /// Modes to try in order when this one isn't supported, ending in FIFO which always is.
+  fn fallbacks(&self) -> &'static [PresentMode] {
+    match self {
+      PresentMode::Fifo => &[PresentMode::Fifo],
+      PresentMode::FifoRelaxed => &[PresentMode::FifoRelaxed, PresentMode::Fifo],
+      PresentMode::Mailbox => &[
+        PresentMode::Mailbox,
+        PresentMode::Immediate,
+        PresentMode::Fifo,
+      ],
+      PresentMode::Immediate => &[
+        PresentMode::Immediate,
+        PresentMode::Mailbox,
+        PresentMode::Fifo,
+      ],
+    }
+  }
+
+  pub fn choose(preferred: PresentMode, supported: &[PresentMode]) -> PresentMode {
+    preferred
+      .fallbacks()
+      .iter()
+      .cloned()
+      .find(|mode| supported.contains(mode))
+      .unwrap_or(PresentMode::Fifo)
+  }
+}
+
 pub struct VkSwapchain {
   swapchain: vk::SwapchainKHR,
   swapchain_extent: vk::Extent2D,
@@ -10,6 +66,10 @@ pub struct VkSwapchain {
 
   swapchain_loader: Swapchain,
   screen_resolution: vk::Extent2D,
+
+  preferred_present_mode: PresentMode,
+  present_mode: PresentMode,
+  preferred_image_count: Option<u32>,
 }
 
 impl VkSwapchain {
@@ -17,6 +77,8 @@ impl VkSwapchain {
     instance: &VkInstance,
     device: &VkDevice,
     screen_resolution: vk::Extent2D,
+    preferred_present_mode: PresentMode,
+    preferred_image_count: Option<u32>,
   ) -> VkSwapchain {
     let swapchain_loader = Swapchain::new(instance.internal(), device.internal());
 
@@ -25,32 +87,27 @@ impl VkSwapchain {
         .surface_loader()
         .get_physical_device_surface_capabilities(*device.phys_device(), *device.surface())
         .unwrap();
-      let present_modes = device
-        .surface_loader()
-        .get_physical_device_surface_present_modes(*device.phys_device(), *device.surface())
-        .unwrap();
-      let present_mode = present_modes
-        .iter()
-        .cloned()
-        .find(|&mode| mode == vk::PresentModeKHR::MAILBOX)
-        .unwrap_or(
-          present_modes
-            .iter()
-            .cloned()
-            .find(|&mode| mode == vk::PresentModeKHR::IMMEDIATE)
-            .unwrap_or(vk::PresentModeKHR::IMMEDIATE),
-        );
+      let present_mode = PresentMode::choose(
+        preferred_present_mode,
+        &VkSwapchain::supported_present_modes(device),
+      );
 
       (surface_capabilities, present_mode)
     };
 
-    let mut desired_image_count = surface_capabilities.min_image_count + 1;
-    if surface_capabilities.max_image_count > 0
-      && desired_image_count > surface_capabilities.max_image_count
-    {
-      desired_image_count = surface_capabilities.max_image_count;
+    if present_mode != preferred_present_mode {
+      println!(
+        "Present mode {:?} not supported, using {:?}",
+        preferred_present_mode, present_mode
+      );
     }
 
+    let desired_image_count = VkSwapchain::image_count(
+      preferred_image_count,
+      surface_capabilities.min_image_count,
+      surface_capabilities.max_image_count,
+    );
+
     let surface_resolution = match surface_capabilities.current_extent.width {
       std::u32::MAX => surface_capabilities.max_image_extent,
       std::u32::MIN => surface_capabilities.min_image_extent,
@@ -76,7 +133,7 @@ impl VkSwapchain {
       .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
       .pre_transform(pre_transform)
       .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
-      .present_mode(present_mode)
+      .present_mode(present_mode.to_vk())
       .clipped(true)
       .image_array_layers(1);
     let swapchain_extent = swapchain_create_info.image_extent;
@@ -104,6 +161,33 @@ impl VkSwapchain {
 
       swapchain_loader,
       screen_resolution,
+
+      preferred_present_mode,
+      present_mode,
+      preferred_image_count,
+    }
+  }
+
+  pub fn supported_present_modes(device: &VkDevice) -> Vec<PresentMode> {
+    unsafe {
+      device
+        .surface_loader()
+        .get_physical_device_surface_present_modes(*device.phys_device(), *device.surface())
+        .unwrap()
+    }
+    .into_iter()
+    .filter_map(PresentMode::from_vk)
+    .collect()
+  }
+
+  /// Requested image count clamped to the surface limits, defaults to one more than the minimum.
+  /// A `max_count` of 0 means there is no upper limit.
+  pub fn image_count(requested: Option<u32>, min_count: u32, max_count: u32) -> u32 {
+    let count = requested.unwrap_or(min_count + 1).max(min_count);
+    if max_count > 0 {
+      count.min(max_count)
+    } else {
+      count
     }
   }
 
@@ -119,7 +203,27 @@ impl VkSwapchain {
   }
 
   pub fn recreate(&mut self, instance: &VkInstance, device: &VkDevice) {
-    *self = VkSwapchain::new(instance, device, self.screen_resolution);
+    *self = VkSwapchain::new(
+      instance,
+      device,
+      self.screen_resolution,
+      self.preferred_present_mode,
+      self.preferred_image_count,
+    );
+  }
+
+  /// Takes effect the next time the swapchain is recreated.
+  pub fn set_preferred_present_mode(&mut self, present_mode: PresentMode) {
+    self.preferred_present_mode = present_mode;
+  }
+
+  /// Takes effect the next time the swapchain is recreated, `None` uses the default count.
+  pub fn set_preferred_image_count(&mut self, image_count: Option<u32>) {
+    self.preferred_image_count = image_count;
+  }
+
+  pub fn present_mode(&self) -> PresentMode {
+    self.present_mode
   }
 
   pub fn internal(&self) -> &vk::SwapchainKHR {
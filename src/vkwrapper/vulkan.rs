@@ -1,17 +1,26 @@
+use std::collections::HashMap;
 use std::default::Default;
 
 use ash::vk;
-use glam::{EulerRot, Quat, Vec3};
+use glam::{Quat, Vec3};
 
+use crate::extra::frustum::{CullingStats, Frustum};
 use crate::extra::gltf_loader::{GltfModel, Material, Node, Skin};
 use crate::vkwrapper::{
-  Buffer, ClearValues, CommandBuffer, ComputeShader, DescriptorSet, DescriptorWriter, Frame, Image,
-  ImageBuilder, PassDescription, Renderpass, Scissors, Semaphore, Shader, Viewport, VkCommandPool,
-  VkDevice, VkFrameBuffer, VkInstance, VkSwapchain, VkWindow,
+  Buffer, ClearValues, CommandBuffer, ComputeShader, DescriptorSet, DescriptorWriter, Frame,
+  GpuInfo, GpuPreference, Image, ImageBuilder, MemoryStats, PassDescription, PresentMode,
+  RenderTarget, Renderpass, Scissors, Semaphore, Shader, Viewport, VkCommandPool, VkDevice,
+  VkFrameBuffer, VkInstance, VkSwapchain, VkWindow,
 };
 use winit::event_loop::EventLoop;
 
-const FRAMES_IN_FLIGHT: usize = 2;
+pub const DEFAULT_FRAMES_IN_FLIGHT: usize = 2;
+pub const MAX_FRAMES_IN_FLIGHT: usize = 4;
+// Format the model pass renders into before post processing
+pub const HDR_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+// Frame the resource is safe to destroy on, and how to destroy it
+type PendingDestruction = (u64, Box<dyn FnOnce(&VkDevice)>);
 
 // Simple offset_of macro akin to C++ offsetof
 #[macro_export]
@@ -46,9 +55,13 @@ pub struct Vulkan {
   current_frame: usize,
   frames_in_flight: Vec<Frame>,
   max_frames_in_flight: usize,
+  frame_count: u64,
+
+  pending_destruction: Vec<PendingDestruction>,
 
   texture_renderpass: Renderpass,
   model_renderpass: Renderpass,
+  msaa_samples: vk::SampleCountFlags,
   framebuffer: VkFrameBuffer,
 
   swapchain: VkSwapchain,
@@ -58,8 +71,38 @@ pub struct Vulkan {
   draw_command_buffer: CommandBuffer,
   setup_command_buffer: CommandBuffer,
 
+  // Pools for the compute and transfer queue families, these are the graphics family when the
+  // device has no dedicated queues
+  compute_pool: VkCommandPool,
+  transfer_pool: VkCommandPool,
+
+  compute_command_buffer: CommandBuffer,
+  transfer_command_buffer: CommandBuffer,
+  // Takes ownership of images uploaded on a dedicated transfer queue on the graphics queue
+  transfer_acquire_command_buffer: CommandBuffer,
+  transfer_staging_buffers: Vec<Buffer<u8>>,
+
+  transfer_semaphore: Semaphore,
+  // Each async compute submission signals one of these for the next frame to wait on, and the
+  // frame signals the graphics semaphore back so compute doesn't write buffers it still reads
+  compute_semaphores: Vec<Semaphore>,
+  graphics_semaphore: Semaphore,
+  compute_signalled: usize,
+  graphics_signalled: bool,
+
+  // Multisampled colour target, only used with MSAA
+  colour_image: Option<Image>,
   depth_image: Image,
 
+  // Offscreen target of the model pass, multisampled first when MSAA is on
+  hdr_image: Image,
+  hdr_msaa_image: Option<Image>,
+  hdr_framebuffer: VkFrameBuffer,
+  // Bumped whenever the render targets are recreated
+  render_target_version: u64,
+  // Offscreen targets the model pass can draw into in place of the HDR image, by name
+  named_render_targets: HashMap<String, RenderTarget>,
+
   present_complete_semaphore: Semaphore,
   rendering_complete_semaphore: Semaphore,
 
@@ -73,64 +116,56 @@ impl Vulkan {
     window: &mut VkWindow,
     event_loop: &EventLoop<()>,
     screen_resolution: vk::Extent2D,
+    gpu_preference: &GpuPreference,
+    frames_in_flight: usize,
+    pipeline_cache_path: Option<&str>,
   ) -> Vulkan {
+    let frames_in_flight = frames_in_flight.clamp(1, MAX_FRAMES_IN_FLIGHT);
+
     let instance = VkInstance::new(window, event_loop);
-    let device = VkDevice::new(&instance, event_loop, window);
+    let device = VkDevice::new(
+      &instance,
+      event_loop,
+      window,
+      gpu_preference,
+      pipeline_cache_path,
+    );
 
-    let mut swapchain = VkSwapchain::new(&instance, &device, screen_resolution);
+    let mut swapchain = VkSwapchain::new(
+      &instance,
+      &device,
+      screen_resolution,
+      PresentMode::Mailbox,
+      None,
+    );
 
     let pool = VkCommandPool::new(&device);
     let draw_command_buffer = CommandBuffer::new_one_time_submit(&device, &pool);
     let setup_command_buffer = CommandBuffer::new_one_time_submit(&device, &pool);
 
-    let extent = swapchain.extent();
-    let depth_image = ImageBuilder::new_depth(
-      extent.width,
-      extent.height,
-      1,
-      1,
-      vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
-    )
-    .build_device_local(&device);
-    let passes = vec![
-      PassDescription::new(device.surface_format().format)
-        .samples_1()
-        .attachment_load_op_load()
-        .attachment_store_op_store()
-        .attachment_layout_colour()
-        .initial_layout_present_src()
-        .final_layout_present_src(),
-      PassDescription::new(vk::Format::D16_UNORM)
-        .samples_1()
-        .attachment_load_op_clear()
-        .attachment_layout_depth_stencil()
-        .initial_layout_undefined()
-        .final_layout_depth_stencil(),
-    ];
-
-    let texture_renderpass = Renderpass::new(&device, passes);
-
-    let passes = vec![
-      PassDescription::new(device.surface_format().format)
-        .samples_1()
-        .attachment_load_op_clear()
-        .attachment_store_op_store()
-        .attachment_layout_colour()
-        .initial_layout_undefined()
-        .final_layout_present_src(),
-      PassDescription::new(vk::Format::D16_UNORM)
-        .samples_1()
-        .attachment_load_op_clear()
-        .attachment_layout_depth_stencil()
-        .stencil_load_op_clear()
-        .initial_layout_undefined()
-        .final_layout_depth_stencil(),
-    ];
+    let queue_families = device.queue_families();
+    let compute_pool = VkCommandPool::new_for_queue_family(&device, queue_families.compute);
+    let transfer_pool = VkCommandPool::new_for_queue_family(&device, queue_families.transfer);
+    let compute_command_buffer = CommandBuffer::new_one_time_submit(&device, &compute_pool);
+    let transfer_command_buffer = CommandBuffer::new_one_time_submit(&device, &transfer_pool);
+    let transfer_acquire_command_buffer = CommandBuffer::new_one_time_submit(&device, &pool);
 
-    let model_renderpass = Renderpass::new(&device, passes);
+    let transfer_semaphore = Semaphore::new(&device);
+    let graphics_semaphore = Semaphore::new(&device);
 
-    let framebuffer =
-      VkFrameBuffer::new(&device, &mut swapchain, &depth_image, &texture_renderpass);
+    let extent = swapchain.extent();
+    let msaa_samples = vk::SampleCountFlags::TYPE_1;
+
+    let (texture_renderpass, model_renderpass) = Vulkan::create_renderpasses(&device, msaa_samples);
+    let (colour_image, depth_image, framebuffer) =
+      Vulkan::create_render_targets(&device, &mut swapchain, &texture_renderpass, msaa_samples);
+    let (hdr_image, hdr_msaa_image, hdr_framebuffer) = Vulkan::create_hdr_targets(
+      &device,
+      extent,
+      &depth_image,
+      &model_renderpass,
+      msaa_samples,
+    );
 
     let present_complete_semaphore = Semaphore::new(&device);
     let rendering_complete_semaphore = Semaphore::new(&device);
@@ -138,19 +173,11 @@ impl Vulkan {
     let clear_values = ClearValues::new()
       .add_colour(0.2, 0.2, 0.2, 0.0)
       .add_depth(1.0, 0);
-    let scissors = Scissors::new().add_scissor(0, 0, extent.width, extent.height);
-
-    let viewports = Viewport::new(
-      0.0,
-      extent.height as f32,
-      extent.width as f32,
-      -(extent.height as f32),
-      0.0,
-      1.0,
-    );
+    let (viewports, scissors) = Vulkan::screen_viewport(extent);
 
+    let max_frames_in_flight = frames_in_flight;
     let mut frames_in_flight = Vec::new();
-    for _ in 0..FRAMES_IN_FLIGHT {
+    for _ in 0..max_frames_in_flight {
       frames_in_flight.push(Frame::new(&device));
     }
 
@@ -160,18 +187,41 @@ impl Vulkan {
 
       current_frame: 0,
       frames_in_flight,
-      max_frames_in_flight: FRAMES_IN_FLIGHT,
+      max_frames_in_flight,
+      frame_count: 0,
+
+      pending_destruction: Vec::new(),
 
       texture_renderpass,
       model_renderpass,
+      msaa_samples,
 
       swapchain,
       pool,
 
       draw_command_buffer,
       setup_command_buffer,
+      compute_pool,
+      transfer_pool,
 
+      compute_command_buffer,
+      transfer_command_buffer,
+      transfer_acquire_command_buffer,
+      transfer_staging_buffers: Vec::new(),
+
+      transfer_semaphore,
+      compute_semaphores: Vec::new(),
+      graphics_semaphore,
+      compute_signalled: 0,
+      graphics_signalled: false,
+
+      colour_image,
       depth_image,
+      hdr_image,
+      hdr_msaa_image,
+      hdr_framebuffer,
+      render_target_version: 0,
+      named_render_targets: HashMap::new(),
       present_complete_semaphore,
       rendering_complete_semaphore,
       viewports,
@@ -181,6 +231,15 @@ impl Vulkan {
     }
   }
 
+  /// Index of the frame being recorded, for picking per frame resources.
+  pub fn current_frame(&self) -> usize {
+    self.current_frame
+  }
+
+  pub fn frames_in_flight(&self) -> usize {
+    self.max_frames_in_flight
+  }
+
   pub fn swapchain(&mut self) -> &mut VkSwapchain {
     &mut self.swapchain
   }
@@ -190,7 +249,7 @@ impl Vulkan {
   }
 
   pub fn model_renderpass(&self) -> &Renderpass {
-    &self.texture_renderpass
+    &self.model_renderpass
   }
 
   pub fn scissors(&self) -> &Scissors {
@@ -201,42 +260,105 @@ impl Vulkan {
     &self.viewports
   }
 
-  pub fn recreate_swapchain(&mut self) {
+  /// Queues a resource to be destroyed once every frame that could still be using it has
+  /// finished on the gpu.
+  pub fn destroy_after_frames_in_flight<F: FnOnce(&VkDevice) + 'static>(&mut self, destroy: F) {
+    let safe_frame = self.frame_count + self.max_frames_in_flight as u64;
+    self
+      .pending_destruction
+      .push((safe_frame, Box::new(destroy)));
+  }
+
+  fn destroy_finished_resources(&mut self) {
+    let frame_count = self.frame_count;
+    let (finished, pending): (Vec<_>, Vec<_>) = self
+      .pending_destruction
+      .drain(..)
+      .partition(|(safe_frame, _)| *safe_frame <= frame_count);
+
+    self.pending_destruction = pending;
+    for (_, destroy) in finished {
+      destroy(&self.device);
+    }
+  }
+
+  /// Waits for the device to go idle and destroys everything still queued for destruction.
+  pub fn flush_pending_destruction(&mut self) {
     unsafe {
-      let device = self.device.internal();
+      self.device.internal().device_wait_idle().unwrap();
+    }
+
+    for (_, destroy) in self.pending_destruction.drain(..) {
+      destroy(&self.device);
+    }
+  }
+
+  pub fn destroy(&mut self) {
+    self.flush_pending_destruction();
+
+    for staging_buffer in self.transfer_staging_buffers.drain(..) {
+      staging_buffer.destroy(&self.device);
+    }
+
+    for frame in &self.frames_in_flight {
+      frame.destroy(&self.device);
+    }
+
+    self.draw_command_buffer.destroy(&self.device);
+    self.setup_command_buffer.destroy(&self.device);
+    self.transfer_command_buffer.destroy(&self.device);
+    self.transfer_acquire_command_buffer.destroy(&self.device);
+    self.compute_command_buffer.destroy(&self.device);
+    self.pool.destroy(&self.device);
+    self.compute_pool.destroy(&self.device);
+    self.transfer_pool.destroy(&self.device);
+
+    self.transfer_semaphore.destroy(&self.device);
+    for semaphore in &self.compute_semaphores {
+      semaphore.destroy(&self.device);
+    }
+    self.graphics_semaphore.destroy(&self.device);
+
+    self.present_complete_semaphore.destroy(&self.device);
+    self.rendering_complete_semaphore.destroy(&self.device);
+
+    self.destroy_render_targets();
+    for (_, render_target) in self.named_render_targets.drain() {
+      render_target.destroy(&self.device);
+    }
+    self.texture_renderpass.destroy(&self.device);
+    self.model_renderpass.destroy(&self.device);
+    self.swapchain.destroy(&self.device);
 
-      device.device_wait_idle().unwrap();
+    self.device.resources().report_leaks();
 
-      self.framebuffer.destroy(device);
+    self.device.destroy();
+    self.instance.destroy();
+  }
 
-      device.destroy_image_view(self.depth_image.view(), None);
-      device.destroy_image(self.depth_image.internal(), None);
-      device.free_memory(self.depth_image.memory(), None);
+  pub fn recreate_swapchain(&mut self) {
+    unsafe {
+      self.device.internal().device_wait_idle().unwrap();
     }
+
+    self.destroy_render_targets();
     self.swapchain.destroy(&self.device);
 
     self.swapchain.recreate(&self.instance, &self.device);
     let extent = self.swapchain.extent();
 
-    self.depth_image = ImageBuilder::new_depth(
-      extent.width,
-      extent.height,
-      1,
-      1,
-      vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
-    )
-    .build_device_local(&self.device);
+    self.create_current_render_targets();
 
-    self.framebuffer = VkFrameBuffer::new(
-      &self.device,
-      &mut self.swapchain,
-      &self.depth_image,
-      &self.texture_renderpass,
-    );
+    let (viewports, scissors) = Vulkan::screen_viewport(extent);
+    self.viewports = viewports;
+    self.scissors = scissors;
+  }
 
-    self.scissors = Scissors::new().add_scissor(0, 0, extent.width, extent.height);
+  // Flipped so y points up
+  fn screen_viewport(extent: vk::Extent2D) -> (Viewport, Scissors) {
+    let scissors = Scissors::new().add_scissor(0, 0, extent.width, extent.height);
 
-    self.viewports = Viewport::new(
+    let viewports = Viewport::new(
       0.0,
       extent.height as f32,
       extent.width as f32,
@@ -244,195 +366,863 @@ impl Vulkan {
       0.0,
       1.0,
     );
+
+    (viewports, scissors)
   }
 
-  pub fn copy_buffer_to_device_local_image(&mut self, src_buffer: &Buffer<u8>, dst_image: &Image) {
-    Vulkan::record_submit_commandbuffer(
-      &self.device,
-      &mut self.setup_command_buffer,
-      self.device.present_queue(),
-      Vec::new(),
-      &Semaphore::new(&self.device),
-      &Semaphore::new(&self.device),
-      |device, texture_command_buffer| {
-        let texture_barrier = vk::ImageMemoryBarrier {
-          dst_access_mask: vk::AccessFlags::TRANSFER_WRITE,
-          new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-          image: dst_image.internal(),
-          subresource_range: vk::ImageSubresourceRange {
-            aspect_mask: vk::ImageAspectFlags::COLOR,
-            level_count: 1,
-            layer_count: 1,
-            ..Default::default()
-          },
-          ..Default::default()
-        };
+  pub fn enumerate_gpus(window: &VkWindow, event_loop: &EventLoop<()>) -> Vec<GpuInfo> {
+    let instance = VkInstance::new(window, event_loop);
+    let gpus = VkDevice::enumerate_gpus(&instance, window);
+    instance.destroy();
 
-        unsafe {
-          device.internal().cmd_pipeline_barrier(
-            texture_command_buffer.internal(),
-            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-            vk::PipelineStageFlags::TRANSFER,
-            vk::DependencyFlags::empty(),
-            &[],
-            &[],
-            &[texture_barrier],
-          );
-        }
+    gpus
+  }
 
-        let mut buffer_copy_regions = Vec::new();
+  pub fn gpu_info(&self) -> &GpuInfo {
+    self.device.gpu_info()
+  }
 
-        buffer_copy_regions.push(
-          vk::BufferImageCopy::builder()
-            .image_subresource(
-              vk::ImageSubresourceLayers::builder()
-                .aspect_mask(vk::ImageAspectFlags::COLOR)
-                .layer_count(1)
-                .build(),
-            )
-            .image_extent(vk::Extent3D {
-              width: dst_image.width(),   //image_dimensions.0,
-              height: dst_image.height(), //image_dimensions.1,
-              depth: 1,
-            })
-            .build(),
-        );
+  pub fn gpus(&self) -> &Vec<GpuInfo> {
+    self.device.gpus()
+  }
 
-        unsafe {
-          device.internal().cmd_copy_buffer_to_image(
-            texture_command_buffer.internal(),
-            *src_buffer.internal(),
-            dst_image.internal(),
-            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-            &buffer_copy_regions[..], //&[buffer_copy_regions.build()],
-          );
-        }
+  pub fn supported_present_modes(&self) -> Vec<PresentMode> {
+    VkSwapchain::supported_present_modes(&self.device)
+  }
 
-        let texture_barrier_end = vk::ImageMemoryBarrier {
-          src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
-          dst_access_mask: vk::AccessFlags::SHADER_READ,
-          old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-          new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-          image: dst_image.internal(),
-          subresource_range: vk::ImageSubresourceRange {
-            aspect_mask: vk::ImageAspectFlags::COLOR,
-            level_count: 1,
-            layer_count: 1,
-            ..Default::default()
-          },
-          ..Default::default()
-        };
+  pub fn present_mode(&self) -> PresentMode {
+    self.swapchain.present_mode()
+  }
 
-        unsafe {
-          device.internal().cmd_pipeline_barrier(
-            texture_command_buffer.internal(),
-            vk::PipelineStageFlags::TRANSFER,
-            vk::PipelineStageFlags::FRAGMENT_SHADER,
-            vk::DependencyFlags::empty(),
-            &[],
-            &[],
-            &[texture_barrier_end],
-          );
-        }
-      },
-    );
+  /// Recreates the swapchain with the given present mode, or the closest supported one, which is
+  /// returned.
+  pub fn set_present_mode(&mut self, present_mode: PresentMode) -> PresentMode {
+    self.swapchain.set_preferred_present_mode(present_mode);
+    self.recreate_swapchain();
+    self.swapchain.present_mode()
   }
 
-  pub fn copy_buffer_to_device_local_buffer<T: Copy>(
-    &mut self,
-    src_buffer: &Buffer<T>,
-    dst_buffer: &Buffer<T>,
-  ) {
-    Vulkan::record_submit_commandbuffer(
-      &self.device,
-      &mut self.setup_command_buffer,
-      //&self.setup_commands_reuse_fence,
-      self.device.present_queue(),
-      Vec::new(),
-      &Semaphore::new(&self.device), //[],
-      &Semaphore::new(&self.device), //[],
-      |device, buffer_command_buffer| {
-        /*let buffer_barrier = vk::BufferMemoryBarrier {
-          dst_access_mask: vk::AccessFlags::TRANSFER_WRITE,
-          buffer: dst_buffer.internal(),
-          size: std::mem::size_of::<T>() as u64 * (src_buffer.data().len() as u64)
-          ..Default::default()
-        };*/
-        let buffer_barrier = vk::BufferMemoryBarrier::builder()
-          .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
-          .buffer(*dst_buffer.internal())
-          .size(std::mem::size_of::<T>() as u64 * (src_buffer.data().len() as u64));
+  /// Recreates the swapchain asking for `image_count` images, clamped to the surface limits.
+  pub fn set_swapchain_image_count(&mut self, image_count: Option<u32>) {
+    self.swapchain.set_preferred_image_count(image_count);
+    self.recreate_swapchain();
+  }
 
-        unsafe {
-          device.internal().cmd_pipeline_barrier(
-            buffer_command_buffer.internal(),
-            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-            vk::PipelineStageFlags::TRANSFER,
-            vk::DependencyFlags::empty(),
-            &[],
-            &[buffer_barrier.build()],
-            &[],
-          );
-        }
+  pub fn msaa_samples(&self) -> vk::SampleCountFlags {
+    self.msaa_samples
+  }
 
-        let buffer_copy = vk::BufferCopy::builder()
-          .size(std::mem::size_of::<T>() as u64 * (src_buffer.data().len() as u64));
-        /*
-        let buffer_copy_regions = vk::BufferImageCopy::builder()
-            .image_subresource(
-              vk::ImageSubresourceLayers::builder()
-                .aspect_mask(vk::ImageAspectFlags::COLOR)
-                .layer_count(1)
-                .build(),
-            )
-            .image_extent(vk::Extent3D {
-              width: dst_image.width(),//image_dimensions.0,
-              height: dst_image.height(),//image_dimensions.1,
-              depth: 1,
-            });*/
+  /// Switches the render passes to the highest sample count the device supports that isn't over
+  /// `samples`, 1 turns MSAA off. Pipelines built against the old render passes need rebuilding.
+  pub fn set_msaa_samples(&mut self, samples: u32) -> vk::SampleCountFlags {
+    let msaa_samples = Vulkan::supported_sample_count(samples, self.device.sample_counts());
+    if msaa_samples == self.msaa_samples {
+      return msaa_samples;
+    }
 
-        unsafe {
-          device.internal().cmd_copy_buffer(
-            buffer_command_buffer.internal(),
-            *src_buffer.internal(),
-            *dst_buffer.internal(),
-            &[buffer_copy.build()],
-          );
-          /*device.cmd_copy_buffer_to_image(
-            texture_command_buffer,
-            *src_buffer.internal(),
-            dst_image.internal(),
-            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-            &[buffer_copy_regions.build()],
-          );*/
-        }
+    unsafe {
+      self.device.internal().device_wait_idle().unwrap();
+    }
 
-        let buffer_barrier_end = vk::BufferMemoryBarrier::builder()
-          .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
-          .dst_access_mask(vk::AccessFlags::SHADER_READ)
-          .buffer(*dst_buffer.internal())
-          .size(std::mem::size_of::<T>() as u64 * (src_buffer.data().len() as u64));
-        /*
-        let buffer_barrier_end = vk::BufferMemoryBarrier {
-          src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
-          dst_access_mask: vk::AccessFlags::SHADER_READ,
-          buffer: dst_buffer.internal(),
-          ..Default::default()
-        };*/
+    self.destroy_render_targets();
+    self.texture_renderpass.destroy(&self.device);
+    self.model_renderpass.destroy(&self.device);
 
-        unsafe {
-          device.internal().cmd_pipeline_barrier(
-            buffer_command_buffer.internal(),
-            vk::PipelineStageFlags::TRANSFER,
-            vk::PipelineStageFlags::FRAGMENT_SHADER,
-            vk::DependencyFlags::empty(),
-            &[],
-            &[buffer_barrier_end.build()],
-            &[],
-          );
-        }
-      },
-    );
+    self.msaa_samples = msaa_samples;
+    let (texture_renderpass, model_renderpass) =
+      Vulkan::create_renderpasses(&self.device, msaa_samples);
+    self.texture_renderpass = texture_renderpass;
+    self.model_renderpass = model_renderpass;
+
+    self.create_current_render_targets();
+    for render_target in self.named_render_targets.values_mut() {
+      render_target.recreate_attachments(&self.device, &self.model_renderpass, msaa_samples);
+    }
+
+    msaa_samples
+  }
+
+  /// Highest of the supported sample counts that is no more than requested.
+  pub fn supported_sample_count(
+    requested: u32,
+    supported: vk::SampleCountFlags,
+  ) -> vk::SampleCountFlags {
+    [64, 32, 16, 8, 4, 2]
+      .iter()
+      .map(|count| vk::SampleCountFlags::from_raw(*count))
+      .find(|count| count.as_raw() <= requested && supported.contains(*count))
+      .unwrap_or(vk::SampleCountFlags::TYPE_1)
+  }
+
+  /// The model pass clears and draws into the HDR image, which post processing composites into
+  /// the swapchain image at the start of the texture pass. With MSAA both passes render into
+  /// multisampled targets and resolve into the HDR and swapchain images.
+  fn create_renderpasses(
+    device: &VkDevice,
+    samples: vk::SampleCountFlags,
+  ) -> (Renderpass, Renderpass) {
+    let format = device.surface_format().format;
+    let msaa = samples != vk::SampleCountFlags::TYPE_1;
+
+    // Nothing is kept from before the pass, the composite covers the whole screen
+    let texture_colour = if msaa {
+      PassDescription::new(format)
+        .samples(samples)
+        .attachment_load_op_clear()
+        .attachment_store_op_dont_care()
+        .attachment_layout_colour()
+        .initial_layout_undefined()
+        .final_layout_colour_attachment()
+    } else {
+      PassDescription::new(format)
+        .samples_1()
+        .attachment_load_op_clear()
+        .attachment_store_op_store()
+        .attachment_layout_colour()
+        .initial_layout_undefined()
+        .final_layout_present_src()
+    };
+
+    let mut passes = vec![
+      texture_colour,
+      PassDescription::new(vk::Format::D16_UNORM)
+        .samples(samples)
+        .attachment_load_op_clear()
+        .attachment_layout_depth_stencil()
+        .initial_layout_undefined()
+        .final_layout_depth_stencil(),
+    ];
+
+    if msaa {
+      passes.push(Vulkan::resolve_pass_description(format));
+    }
+
+    let texture_renderpass = Renderpass::new(device, passes);
+
+    // The HDR image stays a colour attachment, begin_post_processing moves it to be sampled
+    let model_colour = if msaa {
+      PassDescription::new(HDR_FORMAT)
+        .samples(samples)
+        .attachment_load_op_clear()
+        .attachment_store_op_dont_care()
+        .attachment_layout_colour()
+        .initial_layout_undefined()
+        .final_layout_colour_attachment()
+    } else {
+      PassDescription::new(HDR_FORMAT)
+        .samples_1()
+        .attachment_load_op_clear()
+        .attachment_store_op_store()
+        .attachment_layout_colour()
+        .initial_layout_undefined()
+        .final_layout_colour_attachment()
+    };
+
+    let mut passes = vec![
+      model_colour,
+      PassDescription::new(vk::Format::D16_UNORM)
+        .samples(samples)
+        .attachment_load_op_clear()
+        .attachment_layout_depth_stencil()
+        .stencil_load_op_clear()
+        .initial_layout_undefined()
+        .final_layout_depth_stencil(),
+    ];
+
+    if msaa {
+      passes.push(
+        PassDescription::new(HDR_FORMAT)
+          .samples_1()
+          .attachment_load_op_dont_care()
+          .attachment_store_op_store()
+          .attachment_layout_resolve()
+          .initial_layout_undefined()
+          .final_layout_colour_attachment(),
+      );
+    }
+
+    let model_renderpass = Renderpass::new(device, passes);
+
+    (texture_renderpass, model_renderpass)
+  }
+
+  fn resolve_pass_description(format: vk::Format) -> PassDescription {
+    PassDescription::new(format)
+      .samples_1()
+      .attachment_load_op_dont_care()
+      .attachment_store_op_store()
+      .attachment_layout_resolve()
+      .initial_layout_undefined()
+      .final_layout_present_src()
+  }
+
+  fn create_render_targets(
+    device: &VkDevice,
+    swapchain: &mut VkSwapchain,
+    renderpass: &Renderpass,
+    samples: vk::SampleCountFlags,
+  ) -> (Option<Image>, Image, VkFrameBuffer) {
+    let extent = swapchain.extent();
+
+    let colour_image = if samples != vk::SampleCountFlags::TYPE_1 {
+      Some(
+        ImageBuilder::new(device.surface_format().format, 1, 1)
+          .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT)
+          .samples(samples)
+          .set_dimensions(extent.width, extent.height)
+          .build_device_local(device),
+      )
+    } else {
+      None
+    };
+
+    let depth_image = ImageBuilder::new_depth(
+      extent.width,
+      extent.height,
+      1,
+      1,
+      vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+    )
+    .samples(samples)
+    .build_device_local(device);
+
+    let framebuffer = VkFrameBuffer::new(
+      device,
+      swapchain,
+      colour_image.as_ref(),
+      &depth_image,
+      renderpass,
+    );
+
+    (colour_image, depth_image, framebuffer)
+  }
+
+  /// The model pass shares the depth image with the texture pass.
+  fn create_hdr_targets(
+    device: &VkDevice,
+    extent: vk::Extent2D,
+    depth_image: &Image,
+    renderpass: &Renderpass,
+    samples: vk::SampleCountFlags,
+  ) -> (Image, Option<Image>, VkFrameBuffer) {
+    let hdr_image = ImageBuilder::new(HDR_FORMAT, 1, 1)
+      .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+      .set_dimensions(extent.width, extent.height)
+      .build_device_local(device);
+
+    let hdr_msaa_image = if samples != vk::SampleCountFlags::TYPE_1 {
+      Some(
+        ImageBuilder::new(HDR_FORMAT, 1, 1)
+          .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT)
+          .samples(samples)
+          .set_dimensions(extent.width, extent.height)
+          .build_device_local(device),
+      )
+    } else {
+      None
+    };
+
+    let attachments = match &hdr_msaa_image {
+      Some(msaa_image) => vec![msaa_image.view(), depth_image.view(), hdr_image.view()],
+      None => vec![hdr_image.view(), depth_image.view()],
+    };
+    let framebuffer = VkFrameBuffer::new_offscreen(device, extent, attachments, renderpass);
+
+    (hdr_image, hdr_msaa_image, framebuffer)
+  }
+
+  fn create_current_render_targets(&mut self) {
+    let (colour_image, depth_image, framebuffer) = Vulkan::create_render_targets(
+      &self.device,
+      &mut self.swapchain,
+      &self.texture_renderpass,
+      self.msaa_samples,
+    );
+
+    let (hdr_image, hdr_msaa_image, hdr_framebuffer) = Vulkan::create_hdr_targets(
+      &self.device,
+      self.swapchain.extent(),
+      &depth_image,
+      &self.model_renderpass,
+      self.msaa_samples,
+    );
+
+    self.colour_image = colour_image;
+    self.depth_image = depth_image;
+    self.framebuffer = framebuffer;
+    self.hdr_image = hdr_image;
+    self.hdr_msaa_image = hdr_msaa_image;
+    self.hdr_framebuffer = hdr_framebuffer;
+    self.render_target_version += 1;
+  }
+
+  fn destroy_render_targets(&mut self) {
+    self.framebuffer.destroy(self.device.internal());
+    self.hdr_framebuffer.destroy(self.device.internal());
+    self.depth_image.destroy(&self.device);
+    self.hdr_image.destroy(&self.device);
+    if let Some(colour_image) = self.colour_image.take() {
+      colour_image.destroy(&self.device);
+    }
+    if let Some(hdr_msaa_image) = self.hdr_msaa_image.take() {
+      hdr_msaa_image.destroy(&self.device);
+    }
+  }
+
+  /// What the model pass rendered this frame, sampled by post processing.
+  pub fn hdr_image(&self) -> &Image {
+    &self.hdr_image
+  }
+
+  /// Changes whenever the swapchain or MSAA change recreates the HDR image, anything holding its
+  /// view or sized to the screen needs rebuilding.
+  pub fn render_target_version(&self) -> u64 {
+    self.render_target_version
+  }
+
+  pub fn screen_extent(&self) -> vk::Extent2D {
+    self.swapchain.extent()
+  }
+
+  pub fn copy_buffer_to_device_local_image(&mut self, src_buffer: &Buffer<u8>, dst_image: &Image) {
+    Vulkan::record_submit_commandbuffer(
+      &self.device,
+      &mut self.setup_command_buffer,
+      self.device.present_queue(),
+      Vec::new(),
+      &Semaphore::new(&self.device),
+      &Semaphore::new(&self.device),
+      |device, texture_command_buffer| {
+        Vulkan::record_buffer_to_image_copy(
+          device,
+          texture_command_buffer,
+          src_buffer,
+          dst_image,
+          &[0],
+        );
+      },
+    );
+  }
+
+  /// Records every staging buffer to image copy into one submission on the transfer command
+  /// buffer. Nothing waits on the copies here, the staging buffers are held on to and freed the
+  /// next time the transfer command buffer is reused.
+  pub fn transfer_buffers_to_device_local_images(&mut self, transfers: Vec<(Buffer<u8>, Image)>) {
+    self.transfer_buffer_levels_to_device_local_images(
+      transfers
+        .into_iter()
+        .map(|(src_buffer, dst_image)| (src_buffer, dst_image, vec![0]))
+        .collect(),
+    );
+  }
+
+  /// Same as `transfer_buffers_to_device_local_images` but for buffers holding more than one mip
+  /// level, given as the offset of each level in the buffer. Levels past the ones given are
+  /// generated.
+  pub fn transfer_buffer_levels_to_device_local_images(
+    &mut self,
+    transfers: Vec<(Buffer<u8>, Image, Vec<u64>)>,
+  ) {
+    if transfers.is_empty() {
+      return;
+    }
+
+    self.transfer_command_buffer.reset(&self.device);
+    for staging_buffer in self.transfer_staging_buffers.drain(..) {
+      staging_buffer.destroy(&self.device);
+    }
+
+    let queue_families = self.device.queue_families();
+    if !queue_families.has_dedicated_transfer() {
+      self.transfer_command_buffer.begin(&self.device);
+      for (src_buffer, dst_image, level_offsets) in &transfers {
+        Vulkan::record_buffer_to_image_copy(
+          &self.device,
+          &mut self.transfer_command_buffer,
+          src_buffer,
+          dst_image,
+          level_offsets,
+        );
+      }
+      self.transfer_command_buffer.end(&self.device);
+
+      self.transfer_command_buffer.submit_queue(
+        &self.device,
+        Vec::new(),
+        Vec::new(),
+        Vec::new(),
+        false,
+      );
+    } else {
+      // The copies run on the transfer queue, then the graphics queue takes ownership of the
+      // images to generate mips and move them to their shader read layout, as transfer queues
+      // can't blit.
+      self.transfer_acquire_command_buffer.reset(&self.device);
+
+      self.transfer_command_buffer.begin(&self.device);
+      for (src_buffer, dst_image, level_offsets) in &transfers {
+        Vulkan::record_buffer_to_image_upload(
+          &self.device,
+          &mut self.transfer_command_buffer,
+          src_buffer,
+          dst_image,
+          level_offsets,
+        );
+        Vulkan::record_image_ownership_transfer(
+          &self.device,
+          &mut self.transfer_command_buffer,
+          dst_image,
+          queue_families.transfer,
+          queue_families.graphics,
+          true,
+        );
+      }
+      self.transfer_command_buffer.end(&self.device);
+
+      self.transfer_command_buffer.submit_to_queue(
+        &self.device,
+        self.device.transfer_queue(),
+        Vec::new(),
+        vec![&self.transfer_semaphore],
+        Vec::new(),
+      );
+
+      self.transfer_acquire_command_buffer.begin(&self.device);
+      for (_, dst_image, level_offsets) in &transfers {
+        Vulkan::record_image_ownership_transfer(
+          &self.device,
+          &mut self.transfer_acquire_command_buffer,
+          dst_image,
+          queue_families.transfer,
+          queue_families.graphics,
+          false,
+        );
+        Vulkan::record_image_levels_finish(
+          &self.device,
+          &mut self.transfer_acquire_command_buffer,
+          dst_image,
+          level_offsets.len() as u32,
+        );
+      }
+      self.transfer_acquire_command_buffer.end(&self.device);
+
+      self.transfer_acquire_command_buffer.submit_to_queue(
+        &self.device,
+        self.device.present_queue(),
+        vec![&self.transfer_semaphore],
+        Vec::new(),
+        vec![vk::PipelineStageFlags::TRANSFER],
+      );
+    }
+
+    for (src_buffer, _, _) in transfers {
+      self.transfer_staging_buffers.push(src_buffer);
+    }
+  }
+
+  fn record_buffer_to_image_copy(
+    device: &VkDevice,
+    texture_command_buffer: &mut CommandBuffer,
+    src_buffer: &Buffer<u8>,
+    dst_image: &Image,
+    level_offsets: &[u64],
+  ) {
+    Vulkan::record_buffer_to_image_upload(
+      device,
+      texture_command_buffer,
+      src_buffer,
+      dst_image,
+      level_offsets,
+    );
+    Vulkan::record_image_levels_finish(
+      device,
+      texture_command_buffer,
+      dst_image,
+      level_offsets.len() as u32,
+    );
+  }
+
+  /// Moves every level to TRANSFER_DST_OPTIMAL and copies in the levels given, only uses
+  /// transfer commands so it can be recorded for a transfer queue.
+  fn record_buffer_to_image_upload(
+    device: &VkDevice,
+    texture_command_buffer: &mut CommandBuffer,
+    src_buffer: &Buffer<u8>,
+    dst_image: &Image,
+    level_offsets: &[u64],
+  ) {
+    let texture_barrier = vk::ImageMemoryBarrier {
+      dst_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+      new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+      image: dst_image.internal(),
+      subresource_range: vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        level_count: dst_image.mip_levels(),
+        layer_count: dst_image.array_layers(),
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+
+    unsafe {
+      device.internal().cmd_pipeline_barrier(
+        texture_command_buffer.internal(),
+        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::DependencyFlags::empty(),
+        &[],
+        &[],
+        &[texture_barrier],
+      );
+    }
+
+    let buffer_copy_regions = level_offsets
+      .iter()
+      .take(dst_image.mip_levels() as usize)
+      .enumerate()
+      .map(|(level, offset)| {
+        vk::BufferImageCopy::builder()
+          .buffer_offset(*offset)
+          .image_subresource(
+            vk::ImageSubresourceLayers::builder()
+              .aspect_mask(vk::ImageAspectFlags::COLOR)
+              .mip_level(level as u32)
+              .layer_count(dst_image.array_layers())
+              .build(),
+          )
+          .image_extent(vk::Extent3D {
+            width: (dst_image.width() >> level).max(1),
+            height: (dst_image.height() >> level).max(1),
+            depth: 1,
+          })
+          .build()
+      })
+      .collect::<Vec<vk::BufferImageCopy>>();
+
+    unsafe {
+      device.internal().cmd_copy_buffer_to_image(
+        texture_command_buffer.internal(),
+        *src_buffer.internal(),
+        dst_image.internal(),
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        &buffer_copy_regions[..],
+      );
+    }
+  }
+
+  /// Release (on the source queue) or acquire (on the destination queue) half of a queue family
+  /// ownership transfer of an image in TRANSFER_DST_OPTIMAL.
+  fn record_image_ownership_transfer(
+    device: &VkDevice,
+    command_buffer: &mut CommandBuffer,
+    image: &Image,
+    src_queue_family: u32,
+    dst_queue_family: u32,
+    is_release: bool,
+  ) {
+    let (src_access_mask, dst_access_mask, src_stage, dst_stage) = if is_release {
+      (
+        vk::AccessFlags::TRANSFER_WRITE,
+        vk::AccessFlags::empty(),
+        vk::PipelineStageFlags::TRANSFER,
+        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+      )
+    } else {
+      (
+        vk::AccessFlags::empty(),
+        vk::AccessFlags::TRANSFER_READ | vk::AccessFlags::TRANSFER_WRITE,
+        vk::PipelineStageFlags::TOP_OF_PIPE,
+        vk::PipelineStageFlags::TRANSFER,
+      )
+    };
+
+    let barrier = vk::ImageMemoryBarrier {
+      src_access_mask,
+      dst_access_mask,
+      old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+      new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+      src_queue_family_index: src_queue_family,
+      dst_queue_family_index: dst_queue_family,
+      image: image.internal(),
+      subresource_range: vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        level_count: image.mip_levels(),
+        layer_count: image.array_layers(),
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+
+    unsafe {
+      device.internal().cmd_pipeline_barrier(
+        command_buffer.internal(),
+        src_stage,
+        dst_stage,
+        vk::DependencyFlags::empty(),
+        &[],
+        &[],
+        &[barrier],
+      );
+    }
+  }
+
+  /// Generates the levels past the `copied_levels` written by an upload and moves the whole
+  /// image over to SHADER_READ_ONLY_OPTIMAL. Needs a graphics queue for the blits.
+  fn record_image_levels_finish(
+    device: &VkDevice,
+    texture_command_buffer: &mut CommandBuffer,
+    dst_image: &Image,
+    copied_levels: u32,
+  ) {
+    let first_generated = copied_levels.min(dst_image.mip_levels());
+    Vulkan::record_mipmap_generation(device, texture_command_buffer, dst_image, first_generated);
+
+    // Generating mips moves each level it blits from over to SHADER_READ_ONLY_OPTIMAL, leaving
+    // the copied levels before those and the final level
+    let mut remaining_levels = vec![(0, first_generated)];
+    if first_generated < dst_image.mip_levels() {
+      remaining_levels = vec![(0, first_generated - 1), (dst_image.mip_levels() - 1, 1)];
+    }
+
+    let texture_barriers_end = remaining_levels
+      .into_iter()
+      .filter(|(_, level_count)| *level_count > 0)
+      .map(|(base_mip_level, level_count)| vk::ImageMemoryBarrier {
+        src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+        dst_access_mask: vk::AccessFlags::SHADER_READ,
+        old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        image: dst_image.internal(),
+        subresource_range: vk::ImageSubresourceRange {
+          aspect_mask: vk::ImageAspectFlags::COLOR,
+          base_mip_level,
+          level_count,
+          layer_count: dst_image.array_layers(),
+          ..Default::default()
+        },
+        ..Default::default()
+      })
+      .collect::<Vec<vk::ImageMemoryBarrier>>();
+
+    unsafe {
+      device.internal().cmd_pipeline_barrier(
+        texture_command_buffer.internal(),
+        vk::PipelineStageFlags::TRANSFER,
+        vk::PipelineStageFlags::FRAGMENT_SHADER,
+        vk::DependencyFlags::empty(),
+        &[],
+        &[],
+        &texture_barriers_end,
+      );
+    }
+  }
+
+  /// Fills in every level from `first_level` on by blitting each level down from the one before
+  /// it. Expects all levels to be in TRANSFER_DST_OPTIMAL with the earlier levels written.
+  fn record_mipmap_generation(
+    device: &VkDevice,
+    command_buffer: &mut CommandBuffer,
+    image: &Image,
+    first_level: u32,
+  ) {
+    let mut width = (image.width() >> (first_level - 1)).max(1) as i32;
+    let mut height = (image.height() >> (first_level - 1)).max(1) as i32;
+
+    for level in first_level..image.mip_levels() {
+      let subresource_range = vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level: level - 1,
+        level_count: 1,
+        layer_count: image.array_layers(),
+        ..Default::default()
+      };
+
+      let to_src_barrier = vk::ImageMemoryBarrier {
+        src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+        dst_access_mask: vk::AccessFlags::TRANSFER_READ,
+        old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        new_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        image: image.internal(),
+        subresource_range,
+        ..Default::default()
+      };
+
+      let next_width = (width / 2).max(1);
+      let next_height = (height / 2).max(1);
+
+      let blit = vk::ImageBlit {
+        src_subresource: vk::ImageSubresourceLayers {
+          aspect_mask: vk::ImageAspectFlags::COLOR,
+          mip_level: level - 1,
+          base_array_layer: 0,
+          layer_count: image.array_layers(),
+        },
+        src_offsets: [
+          vk::Offset3D { x: 0, y: 0, z: 0 },
+          vk::Offset3D {
+            x: width,
+            y: height,
+            z: 1,
+          },
+        ],
+        dst_subresource: vk::ImageSubresourceLayers {
+          aspect_mask: vk::ImageAspectFlags::COLOR,
+          mip_level: level,
+          base_array_layer: 0,
+          layer_count: image.array_layers(),
+        },
+        dst_offsets: [
+          vk::Offset3D { x: 0, y: 0, z: 0 },
+          vk::Offset3D {
+            x: next_width,
+            y: next_height,
+            z: 1,
+          },
+        ],
+      };
+
+      let to_read_barrier = vk::ImageMemoryBarrier {
+        src_access_mask: vk::AccessFlags::TRANSFER_READ,
+        dst_access_mask: vk::AccessFlags::SHADER_READ,
+        old_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        image: image.internal(),
+        subresource_range,
+        ..Default::default()
+      };
+
+      unsafe {
+        device.internal().cmd_pipeline_barrier(
+          command_buffer.internal(),
+          vk::PipelineStageFlags::TRANSFER,
+          vk::PipelineStageFlags::TRANSFER,
+          vk::DependencyFlags::empty(),
+          &[],
+          &[],
+          &[to_src_barrier],
+        );
+
+        device.internal().cmd_blit_image(
+          command_buffer.internal(),
+          image.internal(),
+          vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+          image.internal(),
+          vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+          &[blit],
+          vk::Filter::LINEAR,
+        );
+
+        device.internal().cmd_pipeline_barrier(
+          command_buffer.internal(),
+          vk::PipelineStageFlags::TRANSFER,
+          vk::PipelineStageFlags::FRAGMENT_SHADER,
+          vk::DependencyFlags::empty(),
+          &[],
+          &[],
+          &[to_read_barrier],
+        );
+      }
+
+      width = next_width;
+      height = next_height;
+    }
+  }
+
+  pub fn copy_buffer_to_device_local_buffer<T: Copy>(
+    &mut self,
+    src_buffer: &Buffer<T>,
+    dst_buffer: &Buffer<T>,
+  ) {
+    Vulkan::record_submit_commandbuffer(
+      &self.device,
+      &mut self.setup_command_buffer,
+      //&self.setup_commands_reuse_fence,
+      self.device.present_queue(),
+      Vec::new(),
+      &Semaphore::new(&self.device), //[],
+      &Semaphore::new(&self.device), //[],
+      |device, buffer_command_buffer| {
+        /*let buffer_barrier = vk::BufferMemoryBarrier {
+          dst_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+          buffer: dst_buffer.internal(),
+          size: std::mem::size_of::<T>() as u64 * (src_buffer.data().len() as u64)
+          ..Default::default()
+        };*/
+        let buffer_barrier = vk::BufferMemoryBarrier::builder()
+          .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+          .buffer(*dst_buffer.internal())
+          .size(std::mem::size_of::<T>() as u64 * (src_buffer.data().len() as u64));
+
+        unsafe {
+          device.internal().cmd_pipeline_barrier(
+            buffer_command_buffer.internal(),
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[buffer_barrier.build()],
+            &[],
+          );
+        }
+
+        let buffer_copy = vk::BufferCopy::builder()
+          .size(std::mem::size_of::<T>() as u64 * (src_buffer.data().len() as u64));
+        /*
+        let buffer_copy_regions = vk::BufferImageCopy::builder()
+            .image_subresource(
+              vk::ImageSubresourceLayers::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .layer_count(1)
+                .build(),
+            )
+            .image_extent(vk::Extent3D {
+              width: dst_image.width(),//image_dimensions.0,
+              height: dst_image.height(),//image_dimensions.1,
+              depth: 1,
+            });*/
+
+        unsafe {
+          device.internal().cmd_copy_buffer(
+            buffer_command_buffer.internal(),
+            *src_buffer.internal(),
+            *dst_buffer.internal(),
+            &[buffer_copy.build()],
+          );
+          /*device.cmd_copy_buffer_to_image(
+            texture_command_buffer,
+            *src_buffer.internal(),
+            dst_image.internal(),
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[buffer_copy_regions.build()],
+          );*/
+        }
+
+        let buffer_barrier_end = vk::BufferMemoryBarrier::builder()
+          .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+          .dst_access_mask(vk::AccessFlags::SHADER_READ)
+          .buffer(*dst_buffer.internal())
+          .size(std::mem::size_of::<T>() as u64 * (src_buffer.data().len() as u64));
+        /*
+        let buffer_barrier_end = vk::BufferMemoryBarrier {
+          src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+          dst_access_mask: vk::AccessFlags::SHADER_READ,
+          buffer: dst_buffer.internal(),
+          ..Default::default()
+        };*/
+
+        unsafe {
+          device.internal().cmd_pipeline_barrier(
+            buffer_command_buffer.internal(),
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[buffer_barrier_end.build()],
+            &[],
+          );
+        }
+      },
+    );
   }
 
   pub fn run_compute_simultaneous<T: Copy, L: Copy>(
@@ -446,113 +1236,165 @@ impl Vulkan {
     y: u32,
     z: u32,
   ) {
-    Vulkan::record_submit_commandbuffer(
-      &self.device,
-      &mut self.setup_command_buffer,
-      self.device.compute_queue(),
-      Vec::new(),
-      &Semaphore::new(&self.device),
-      &Semaphore::new(&self.device),
-      |device, compute_command_buffer| {
-        let mut buffer_barriers_before = Vec::new();
-
-        buffer_barriers_before.push(
-          vk::BufferMemoryBarrier::builder()
-            .src_access_mask(vk::AccessFlags::TRANSFER_READ)
-            .dst_access_mask(vk::AccessFlags::SHADER_WRITE)
-            .buffer(*light_visibility_buffer.internal())
-            .size(std::mem::size_of::<T>() as u64 * (light_visibility_buffer.data().len() as u64))
-            .build(),
+    // On a dedicated compute queue the graphics work is synchronised with semaphores instead and
+    // graphics stages can't be named in the barriers, they only order against earlier dispatches
+    let async_compute = self.device.queue_families().has_dedicated_compute();
+    let (stage_before, stage_after, access_before, access_after) = if async_compute {
+      (
+        vk::PipelineStageFlags::COMPUTE_SHADER,
+        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+        vk::AccessFlags::SHADER_WRITE,
+        vk::AccessFlags::empty(),
+      )
+    } else {
+      (
+        vk::PipelineStageFlags::FRAGMENT_SHADER,
+        vk::PipelineStageFlags::FRAGMENT_SHADER,
+        vk::AccessFlags::TRANSFER_READ,
+        vk::AccessFlags::SHADER_READ,
+      )
+    };
+
+    let record = |device: &VkDevice, compute_command_buffer: &mut CommandBuffer| {
+      let mut buffer_barriers_before = Vec::new();
+
+      buffer_barriers_before.push(
+        vk::BufferMemoryBarrier::builder()
+          .src_access_mask(access_before)
+          .dst_access_mask(vk::AccessFlags::SHADER_WRITE)
+          .buffer(*light_visibility_buffer.internal())
+          .size(std::mem::size_of::<T>() as u64 * (light_visibility_buffer.data().len() as u64))
+          .build(),
+      );
+
+      buffer_barriers_before.push(
+        vk::BufferMemoryBarrier::builder()
+          .src_access_mask(access_before)
+          .dst_access_mask(vk::AccessFlags::SHADER_WRITE)
+          .buffer(*point_light_buffer.internal())
+          .size(std::mem::size_of::<L>() as u64 * (point_light_buffer.data().len() as u64))
+          .build(),
+      );
+
+      unsafe {
+        device.internal().cmd_pipeline_barrier(
+          compute_command_buffer.internal(),
+          stage_before,
+          vk::PipelineStageFlags::COMPUTE_SHADER,
+          vk::DependencyFlags::empty(),
+          &[],
+          &buffer_barriers_before,
+          &[],
         );
 
-        buffer_barriers_before.push(
-          vk::BufferMemoryBarrier::builder()
-            .src_access_mask(vk::AccessFlags::TRANSFER_READ)
-            .dst_access_mask(vk::AccessFlags::SHADER_WRITE)
-            .buffer(*point_light_buffer.internal())
-            .size(std::mem::size_of::<L>() as u64 * (point_light_buffer.data().len() as u64))
-            .build(),
+        device.internal().cmd_bind_descriptor_sets(
+          compute_command_buffer.internal(),
+          vk::PipelineBindPoint::COMPUTE,
+          compute_shader.pipeline_layout(),
+          0,
+          descriptor_sets,
+          &[],
         );
 
-        unsafe {
-          device.internal().cmd_pipeline_barrier(
-            compute_command_buffer.internal(),
-            vk::PipelineStageFlags::FRAGMENT_SHADER,
-            vk::PipelineStageFlags::COMPUTE_SHADER,
-            vk::DependencyFlags::empty(),
-            &[],
-            &buffer_barriers_before,
-            &[],
-          );
+        let push_constant_data: Vec<u8> = {
+          push_constants
+            .iter()
+            .map(|x| x.to_le_bytes().to_vec())
+            .flatten()
+            .collect()
+        };
 
-          device.internal().cmd_bind_descriptor_sets(
-            compute_command_buffer.internal(),
-            vk::PipelineBindPoint::COMPUTE,
-            compute_shader.pipeline_layout(),
-            0,
-            descriptor_sets,
-            &[],
-          );
+        device.internal().cmd_push_constants(
+          compute_command_buffer.internal(),
+          compute_shader.pipeline_layout(),
+          vk::ShaderStageFlags::COMPUTE,
+          0,
+          &push_constant_data, //&[0 as u8; 128 * 4],
+        );
 
-          let push_constant_data: Vec<u8> = {
-            push_constants
-              .iter()
-              .map(|x| x.to_le_bytes().to_vec())
-              .flatten()
-              .collect()
-          };
+        device.internal().cmd_bind_pipeline(
+          compute_command_buffer.internal(),
+          vk::PipelineBindPoint::COMPUTE,
+          *compute_shader.pipeline().internal(),
+        );
 
-          device.internal().cmd_push_constants(
-            compute_command_buffer.internal(),
-            compute_shader.pipeline_layout(),
-            vk::ShaderStageFlags::COMPUTE,
-            0,
-            &push_constant_data, //&[0 as u8; 128 * 4],
-          );
+        device
+          .internal()
+          .cmd_dispatch(compute_command_buffer.internal(), x, y, z)
+      }
 
-          device.internal().cmd_bind_pipeline(
-            compute_command_buffer.internal(),
-            vk::PipelineBindPoint::COMPUTE,
-            *compute_shader.pipeline().internal(),
-          );
+      let mut buffer_barriers_after = Vec::new();
 
-          device
-            .internal()
-            .cmd_dispatch(compute_command_buffer.internal(), x, y, z)
-        }
+      buffer_barriers_after.push(
+        vk::BufferMemoryBarrier::builder()
+          .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+          .dst_access_mask(access_after)
+          .buffer(*light_visibility_buffer.internal())
+          .size(std::mem::size_of::<T>() as u64 * (light_visibility_buffer.data().len() as u64))
+          .build(),
+      );
 
-        let mut buffer_barriers_after = Vec::new();
+      buffer_barriers_after.push(
+        vk::BufferMemoryBarrier::builder()
+          .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+          .dst_access_mask(access_after)
+          .buffer(*point_light_buffer.internal())
+          .size(std::mem::size_of::<L>() as u64 * (point_light_buffer.data().len() as u64))
+          .build(),
+      );
 
-        buffer_barriers_after.push(
-          vk::BufferMemoryBarrier::builder()
-            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
-            .dst_access_mask(vk::AccessFlags::SHADER_READ)
-            .buffer(*light_visibility_buffer.internal())
-            .size(std::mem::size_of::<T>() as u64 * (light_visibility_buffer.data().len() as u64))
-            .build(),
+      unsafe {
+        device.internal().cmd_pipeline_barrier(
+          compute_command_buffer.internal(),
+          vk::PipelineStageFlags::COMPUTE_SHADER,
+          stage_after,
+          vk::DependencyFlags::empty(),
+          &[],
+          &buffer_barriers_after,
+          &[],
         );
+      }
+    };
 
-        buffer_barriers_after.push(
-          vk::BufferMemoryBarrier::builder()
-            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
-            .dst_access_mask(vk::AccessFlags::SHADER_READ)
-            .buffer(*point_light_buffer.internal())
-            .size(std::mem::size_of::<L>() as u64 * (point_light_buffer.data().len() as u64))
-            .build(),
-        );
+    self.compute_command_buffer.reset(&self.device);
+    self.compute_command_buffer.begin(&self.device);
+    record(&self.device, &mut self.compute_command_buffer);
+    self.compute_command_buffer.end(&self.device);
 
-        unsafe {
-          device.internal().cmd_pipeline_barrier(
-            compute_command_buffer.internal(),
-            vk::PipelineStageFlags::COMPUTE_SHADER,
-            vk::PipelineStageFlags::FRAGMENT_SHADER,
-            vk::DependencyFlags::empty(),
-            &[],
-            &buffer_barriers_after,
-            &[],
-          );
-        }
-      },
+    if !async_compute {
+      self.compute_command_buffer.submit_to_queue(
+        &self.device,
+        self.device.compute_queue(),
+        Vec::new(),
+        Vec::new(),
+        Vec::new(),
+      );
+      return;
+    }
+
+    // Waits for the last frame that read the buffers, later dispatches are ordered after this one
+    // by the barriers. A binary semaphore can't be signalled twice without a wait in between, so
+    // every dispatch until the next frame signals its own one for the frame to wait on.
+    let mut wait_semaphores = Vec::new();
+    let mut wait_stages = Vec::new();
+    if self.graphics_signalled {
+      wait_semaphores.push(&self.graphics_semaphore);
+      wait_stages.push(vk::PipelineStageFlags::COMPUTE_SHADER);
+      self.graphics_signalled = false;
+    }
+
+    if self.compute_signalled == self.compute_semaphores.len() {
+      self.compute_semaphores.push(Semaphore::new(&self.device));
+    }
+    let signal_semaphores = vec![&self.compute_semaphores[self.compute_signalled]];
+    self.compute_signalled += 1;
+
+    self.compute_command_buffer.submit_to_queue(
+      &self.device,
+      self.device.compute_queue(),
+      wait_semaphores,
+      signal_semaphores,
+      wait_stages,
     );
   }
 
@@ -579,7 +1421,7 @@ impl Vulkan {
 
     Vulkan::record_submit_commandbuffer(
       &self.device,
-      &mut self.setup_command_buffer,
+      &mut self.compute_command_buffer,
       self.device.compute_queue(),
       Vec::new(),
       &Semaphore::new(&self.device),
@@ -673,85 +1515,470 @@ impl Vulkan {
       },
     );
 
-    unsafe { self.device.internal().device_wait_idle().unwrap() };
-    *data = src_buffer.retrieve_buffer_data(&self.device);
-  }
+    unsafe { self.device.internal().device_wait_idle().unwrap() };
+    *data = src_buffer.retrieve_buffer_data(&self.device);
+  }
+
+  pub fn device(&self) -> &VkDevice {
+    &self.device
+  }
+
+  pub fn save_pipeline_cache(&self, path: &str) -> Result<(), String> {
+    self.device.save_pipeline_cache(path)
+  }
+
+  pub fn memory_stats(&self) -> MemoryStats {
+    self.device.memory_allocator().borrow().stats()
+  }
+
+  /// Helper function for submitting command buffers. Immediately waits for the fence before the command buffer
+  /// is executed. That way we can delay the waiting for the fences by 1 frame which is good for performance.
+  /// Make sure to create the fence in a signaled state on the first use.
+  #[allow(clippy::too_many_arguments)]
+  pub fn record_submit_commandbuffer<F: FnOnce(&VkDevice, &mut CommandBuffer)>(
+    device: &VkDevice,
+    command_buffer: &mut CommandBuffer,
+    submit_queue: vk::Queue,
+    wait_mask: Vec<vk::PipelineStageFlags>,
+    wait_semaphores: &Semaphore,
+    signal_semaphores: &Semaphore,
+    f: F,
+  ) {
+    command_buffer.reset(device);
+    command_buffer.begin(device);
+
+    f(device, command_buffer);
+
+    command_buffer.end(device);
+
+    command_buffer.submit_to_queue(
+      device,
+      submit_queue,
+      vec![wait_semaphores],
+      vec![signal_semaphores],
+      wait_mask,
+    );
+  }
+
+  pub fn draw_text<T: Copy>(
+    &mut self,
+    texture_descriptor: &DescriptorSet,
+    shader: &Shader<T>,
+    vertex_buffer: &Buffer<T>,
+    data: Vec<f32>,
+  ) {
+    let draw_command_buffer = self.frames_in_flight[self.current_frame].command_buffer();
+
+    draw_command_buffer.bind_descriptor_sets(
+      &self.device,
+      shader,
+      0,
+      vec![texture_descriptor],
+      false,
+    );
+
+    draw_command_buffer.bind_graphics_pipeline(&self.device, shader);
+
+    draw_command_buffer.set_viewport(&self.device, vec![&self.viewports]);
+
+    draw_command_buffer.set_scissors(&self.device, vec![&self.scissors]);
+
+    draw_command_buffer.bind_vertex(&self.device, 0, vertex_buffer);
+
+    draw_command_buffer.push_constants(&self.device, shader, data);
+
+    draw_command_buffer.draw_buffer(&self.device, vertex_buffer);
+  }
+
+  pub fn draw_texture<T: Copy, L: Copy, S: Copy>(
+    &mut self,
+    texture_descriptor: &DescriptorSet,
+    uniform_descriptor: &DescriptorSet,
+    shader: &Shader<T>,
+    vertex_buffer: &Buffer<T>,
+    index_buffer: &Buffer<L>,
+    instanced_buffer: Option<&Buffer<S>>,
+    instance_count: usize,
+    data: Vec<f32>,
+  ) {
+    let draw_command_buffer = self.frames_in_flight[self.current_frame].command_buffer();
+
+    draw_command_buffer.bind_descriptor_sets(
+      &self.device,
+      shader,
+      0,
+      vec![uniform_descriptor],
+      false,
+    );
+
+    draw_command_buffer.bind_descriptor_sets(
+      &self.device,
+      shader,
+      1,
+      vec![texture_descriptor],
+      false,
+    );
+
+    draw_command_buffer.bind_graphics_pipeline(&self.device, shader);
+
+    draw_command_buffer.set_viewport(&self.device, vec![&self.viewports]);
+
+    draw_command_buffer.set_scissors(&self.device, vec![&self.scissors]);
+
+    draw_command_buffer.bind_vertex(&self.device, 0, vertex_buffer);
+
+    if let Some(buffer) = instanced_buffer {
+      draw_command_buffer.bind_vertex(&self.device, 1, buffer);
+    }
+
+    draw_command_buffer.bind_index(&self.device, index_buffer);
+
+    draw_command_buffer.push_constants(&self.device, shader, data);
+
+    if instanced_buffer.is_some() {
+      draw_command_buffer.draw_indexed_instanced(
+        &self.device,
+        instance_count as u32,
+        &index_buffer,
+      );
+    } else {
+      draw_command_buffer.draw_indexed_buffer(&self.device, &index_buffer);
+    }
+  }
+
+  /// Binds sets from `first_set` on for the next draw with `shader`, they stay bound while the
+  /// draw binds the lower sets.
+  pub fn bind_descriptor_sets<T: Copy>(
+    &mut self,
+    shader: &Shader<T>,
+    first_set: u32,
+    descriptor_sets: Vec<&DescriptorSet>,
+  ) {
+    if descriptor_sets.is_empty() {
+      return;
+    }
+
+    self.frames_in_flight[self.current_frame]
+      .command_buffer()
+      .bind_descriptor_sets(&self.device, shader, first_set, descriptor_sets, false);
+  }
+
+  /// Records a dispatch into this frame's command buffer ahead of the render passes. It waits on
+  /// earlier dispatches and its writes are visible to vertex input later in the frame.
+  pub fn dispatch_before_draw<P: Copy>(
+    &mut self,
+    compute_shader: &ComputeShader,
+    descriptor_set: &DescriptorSet,
+    push_constants: &[P],
+    group_count: u32,
+  ) {
+    let command_buffer = self.frames_in_flight[self.current_frame]
+      .command_buffer()
+      .internal();
+
+    let push_constant_data = unsafe {
+      std::slice::from_raw_parts(
+        push_constants.as_ptr() as *const u8,
+        std::mem::size_of_val(push_constants),
+      )
+    };
+
+    unsafe {
+      let memory_barrier_before = vk::MemoryBarrier::builder()
+        .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+        .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+
+      self.device.internal().cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::COMPUTE_SHADER,
+        vk::PipelineStageFlags::COMPUTE_SHADER,
+        vk::DependencyFlags::empty(),
+        &[memory_barrier_before.build()],
+        &[],
+        &[],
+      );
+
+      self.device.internal().cmd_bind_pipeline(
+        command_buffer,
+        vk::PipelineBindPoint::COMPUTE,
+        *compute_shader.pipeline().internal(),
+      );
+
+      self.device.internal().cmd_bind_descriptor_sets(
+        command_buffer,
+        vk::PipelineBindPoint::COMPUTE,
+        compute_shader.pipeline_layout(),
+        0,
+        &descriptor_set.internal()[..],
+        &[],
+      );
+
+      self.device.internal().cmd_push_constants(
+        command_buffer,
+        compute_shader.pipeline_layout(),
+        vk::ShaderStageFlags::COMPUTE,
+        0,
+        push_constant_data,
+      );
+
+      self
+        .device
+        .internal()
+        .cmd_dispatch(command_buffer, group_count, 1, 1);
+
+      let memory_barrier_after = vk::MemoryBarrier::builder()
+        .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+        .dst_access_mask(vk::AccessFlags::VERTEX_ATTRIBUTE_READ);
+
+      self.device.internal().cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::COMPUTE_SHADER,
+        vk::PipelineStageFlags::VERTEX_INPUT,
+        vk::DependencyFlags::empty(),
+        &[memory_barrier_after.build()],
+        &[],
+        &[],
+      );
+    }
+  }
+
+  /// Moves the HDR image from the model pass to being sampled, call after the model pass ends.
+  pub fn begin_post_processing(&mut self) {
+    self.record_attachment_to_sampled(self.hdr_image.internal());
+  }
+
+  fn record_attachment_to_sampled(&mut self, image: vk::Image) {
+    let command_buffer = self.frames_in_flight[self.current_frame]
+      .command_buffer()
+      .internal();
+
+    let image_barrier = vk::ImageMemoryBarrier::builder()
+      .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+      .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+      .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+      .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+      .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+      .dst_access_mask(vk::AccessFlags::SHADER_READ)
+      .image(image)
+      .subresource_range(vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1,
+      });
+
+    unsafe {
+      self.device.internal().cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER,
+        vk::DependencyFlags::empty(),
+        &[],
+        &[],
+        &[image_barrier.build()],
+      );
+    }
+  }
+
+  /// Moves freshly created storage images to the general layout and clears them, recorded into
+  /// this frame's command buffer.
+  pub fn clear_storage_images(&mut self, images: &[&Image]) {
+    let command_buffer = self.frames_in_flight[self.current_frame]
+      .command_buffer()
+      .internal();
+
+    let subresource_range = vk::ImageSubresourceRange {
+      aspect_mask: vk::ImageAspectFlags::COLOR,
+      base_mip_level: 0,
+      level_count: 1,
+      base_array_layer: 0,
+      layer_count: 1,
+    };
+
+    let to_general = images
+      .iter()
+      .map(|image| {
+        vk::ImageMemoryBarrier::builder()
+          .old_layout(vk::ImageLayout::UNDEFINED)
+          .new_layout(vk::ImageLayout::GENERAL)
+          .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+          .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+          .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+          .image(image.internal())
+          .subresource_range(subresource_range)
+          .build()
+      })
+      .collect::<Vec<_>>();
+
+    unsafe {
+      self.device.internal().cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::TOP_OF_PIPE,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::DependencyFlags::empty(),
+        &[],
+        &[],
+        &to_general,
+      );
+
+      for image in images {
+        self.device.internal().cmd_clear_color_image(
+          command_buffer,
+          image.internal(),
+          vk::ImageLayout::GENERAL,
+          &vk::ClearColorValue {
+            float32: [0.0, 0.0, 0.0, 0.0],
+          },
+          &[subresource_range],
+        );
+      }
+
+      let memory_barrier = vk::MemoryBarrier::builder()
+        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+        .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+
+      self.device.internal().cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER,
+        vk::DependencyFlags::empty(),
+        &[memory_barrier.build()],
+        &[],
+        &[],
+      );
+    }
+  }
+
+  /// Records one full screen compute pass between the model and texture passes. It waits on the
+  /// passes before it, including the last frame's composite, and its writes are visible to
+  /// later passes and fragment shaders.
+  pub fn dispatch_post_pass<P: Copy>(
+    &mut self,
+    compute_shader: &ComputeShader,
+    descriptor_set: &DescriptorSet,
+    push_constants: &[P],
+    group_count: [u32; 2],
+  ) {
+    let command_buffer = self.frames_in_flight[self.current_frame]
+      .command_buffer()
+      .internal();
+
+    let push_constant_data = unsafe {
+      std::slice::from_raw_parts(
+        push_constants.as_ptr() as *const u8,
+        std::mem::size_of_val(push_constants),
+      )
+    };
+
+    unsafe {
+      let memory_barrier_before = vk::MemoryBarrier::builder()
+        .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+        .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+
+      self.device.internal().cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER,
+        vk::PipelineStageFlags::COMPUTE_SHADER,
+        vk::DependencyFlags::empty(),
+        &[memory_barrier_before.build()],
+        &[],
+        &[],
+      );
+
+      self.device.internal().cmd_bind_pipeline(
+        command_buffer,
+        vk::PipelineBindPoint::COMPUTE,
+        *compute_shader.pipeline().internal(),
+      );
 
-  pub fn device(&self) -> &VkDevice {
-    &self.device
-  }
+      self.device.internal().cmd_bind_descriptor_sets(
+        command_buffer,
+        vk::PipelineBindPoint::COMPUTE,
+        compute_shader.pipeline_layout(),
+        0,
+        &descriptor_set.internal()[..],
+        &[],
+      );
 
-  /// Helper function for submitting command buffers. Immediately waits for the fence before the command buffer
-  /// is executed. That way we can delay the waiting for the fences by 1 frame which is good for performance.
-  /// Make sure to create the fence in a signaled state on the first use.
-  #[allow(clippy::too_many_arguments)]
-  pub fn record_submit_commandbuffer<F: FnOnce(&VkDevice, &mut CommandBuffer)>(
-    device: &VkDevice,
-    command_buffer: &mut CommandBuffer,
-    _submit_queue: vk::Queue,
-    wait_mask: Vec<vk::PipelineStageFlags>,
-    wait_semaphores: &Semaphore,
-    signal_semaphores: &Semaphore,
-    f: F,
-  ) {
-    command_buffer.reset(device);
-    command_buffer.begin(device);
+      self.device.internal().cmd_push_constants(
+        command_buffer,
+        compute_shader.pipeline_layout(),
+        vk::ShaderStageFlags::COMPUTE,
+        0,
+        push_constant_data,
+      );
 
-    f(device, command_buffer);
+      self
+        .device
+        .internal()
+        .cmd_dispatch(command_buffer, group_count[0], group_count[1], 1);
+
+      let memory_barrier_after = vk::MemoryBarrier::builder()
+        .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+        .dst_access_mask(vk::AccessFlags::SHADER_READ);
+
+      self.device.internal().cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::COMPUTE_SHADER,
+        vk::PipelineStageFlags::FRAGMENT_SHADER,
+        vk::DependencyFlags::empty(),
+        &[memory_barrier_after.build()],
+        &[],
+        &[],
+      );
+    }
+  }
 
-    command_buffer.end(device);
+  /// Draws a single screen covering triangle with no vertex buffer, set 0 is the only set.
+  pub fn draw_fullscreen<T: Copy>(
+    &mut self,
+    shader: &Shader<T>,
+    descriptor_set: &DescriptorSet,
+    data: Vec<f32>,
+  ) {
+    let draw_command_buffer = self.frames_in_flight[self.current_frame].command_buffer();
 
-    let is_compute = false;
-    command_buffer.submit_queue(
-      device,
-      vec![wait_semaphores],
-      vec![signal_semaphores],
-      wait_mask,
-      is_compute,
-    );
+    draw_command_buffer.bind_descriptor_sets(&self.device, shader, 0, vec![descriptor_set], false);
+    draw_command_buffer.bind_graphics_pipeline(&self.device, shader);
+    draw_command_buffer.set_viewport(&self.device, vec![&self.viewports]);
+    draw_command_buffer.set_scissors(&self.device, vec![&self.scissors]);
+    draw_command_buffer.push_constants(&self.device, shader, data);
+    draw_command_buffer.draw(&self.device, 3);
   }
 
-  pub fn draw_text<T: Copy>(
+  /// Draws `vertex_count` vertices of `vertex_buffer` from `first_vertex`, without descriptor
+  /// sets.
+  pub fn draw_vertex_range<T: Copy>(
     &mut self,
-    texture_descriptor: &DescriptorSet,
     shader: &Shader<T>,
     vertex_buffer: &Buffer<T>,
+    first_vertex: u32,
+    vertex_count: u32,
     data: Vec<f32>,
   ) {
     let draw_command_buffer = self.frames_in_flight[self.current_frame].command_buffer();
 
-    draw_command_buffer.bind_descriptor_sets(
-      &self.device,
-      shader,
-      0,
-      vec![texture_descriptor],
-      false,
-    );
-
     draw_command_buffer.bind_graphics_pipeline(&self.device, shader);
-
     draw_command_buffer.set_viewport(&self.device, vec![&self.viewports]);
-
     draw_command_buffer.set_scissors(&self.device, vec![&self.scissors]);
-
     draw_command_buffer.bind_vertex(&self.device, 0, vertex_buffer);
-
-    draw_command_buffer.push_constants(&self.device, shader, vk::ShaderStageFlags::VERTEX, data);
-
-    draw_command_buffer.draw_buffer(&self.device, vertex_buffer);
+    draw_command_buffer.push_constants(&self.device, shader, data);
+    draw_command_buffer.draw_range(&self.device, first_vertex, vertex_count);
   }
 
-  pub fn draw_texture<T: Copy, L: Copy, S: Copy>(
+  #[allow(clippy::too_many_arguments)]
+  pub fn draw_mesh<T: Copy>(
     &mut self,
-    texture_descriptor: &DescriptorSet,
-    uniform_descriptor: &DescriptorSet,
     shader: &Shader<T>,
-    vertex_buffer: &Buffer<T>,
-    index_buffer: &Buffer<L>,
-    instanced_buffer: Option<&Buffer<S>>,
-    instance_count: usize,
+    mesh_descriptor: &DescriptorSet,
+    uniform_descriptor: &DescriptorSet,
+    dummy_skin: &DescriptorSet,
+    environment_descriptor: &DescriptorSet,
     data: Vec<f32>,
-  ) {
+    model: &GltfModel,
+    frustum: Option<&Frustum>,
+  ) -> CullingStats {
     let draw_command_buffer = self.frames_in_flight[self.current_frame].command_buffer();
 
     draw_command_buffer.bind_descriptor_sets(
@@ -761,51 +1988,62 @@ impl Vulkan {
       vec![uniform_descriptor],
       false,
     );
-
     draw_command_buffer.bind_descriptor_sets(
       &self.device,
       shader,
-      1,
-      vec![texture_descriptor],
+      3,
+      vec![environment_descriptor],
       false,
     );
 
     draw_command_buffer.bind_graphics_pipeline(&self.device, shader);
-
     draw_command_buffer.set_viewport(&self.device, vec![&self.viewports]);
-
     draw_command_buffer.set_scissors(&self.device, vec![&self.scissors]);
 
-    draw_command_buffer.bind_vertex(&self.device, 0, vertex_buffer);
-
-    if let Some(buffer) = instanced_buffer {
-      draw_command_buffer.bind_vertex(&self.device, 1, buffer);
-    }
-
-    draw_command_buffer.bind_index(&self.device, index_buffer);
+    draw_command_buffer.bind_vertex(&self.device, 0, model.vertex_buffer());
+    draw_command_buffer.bind_index(&self.device, model.index_buffer());
 
-    draw_command_buffer.push_constants(&self.device, shader, vk::ShaderStageFlags::VERTEX, data);
+    let (translation, rotation, scale) = Node::transform_from_data(&data);
 
-    if instanced_buffer.is_some() {
-      draw_command_buffer.draw_indexed_instanced(
-        &self.device,
-        instance_count as u32,
-        &index_buffer,
-      );
-    } else {
-      draw_command_buffer.draw_indexed_buffer(&self.device, &index_buffer);
+    let mut stats = CullingStats::default();
+    for i in 0..model.nodes().len() {
+      stats.add(self.draw_node(
+        shader,
+        mesh_descriptor,
+        i,
+        translation,
+        rotation,
+        scale,
+        &data,
+        model.nodes(),
+        &model.skins(),
+        &model.materials(),
+        dummy_skin,
+        frustum,
+      ));
     }
+
+    stats
   }
 
-  pub fn draw_mesh<T: Copy>(
+  /// Draws every instance of each primitive at once. The instance buffer holds the transforms of
+  /// each node with a mesh in turn, `instance_count` for each.
+  #[allow(clippy::too_many_arguments)]
+  pub fn draw_mesh_instanced<T: Copy, S: Copy>(
     &mut self,
     shader: &Shader<T>,
     mesh_descriptor: &DescriptorSet,
     uniform_descriptor: &DescriptorSet,
     dummy_skin: &DescriptorSet,
-    data: Vec<f32>,
+    environment_descriptor: &DescriptorSet,
+    instance_buffer: &Buffer<S>,
+    instance_count: usize,
     model: &GltfModel,
   ) {
+    if instance_count == 0 {
+      return;
+    }
+
     let draw_command_buffer = self.frames_in_flight[self.current_frame].command_buffer();
 
     draw_command_buffer.bind_descriptor_sets(
@@ -815,41 +2053,70 @@ impl Vulkan {
       vec![uniform_descriptor],
       false,
     );
+    draw_command_buffer.bind_descriptor_sets(
+      &self.device,
+      shader,
+      3,
+      vec![environment_descriptor],
+      false,
+    );
 
     draw_command_buffer.bind_graphics_pipeline(&self.device, shader);
     draw_command_buffer.set_viewport(&self.device, vec![&self.viewports]);
     draw_command_buffer.set_scissors(&self.device, vec![&self.scissors]);
 
     draw_command_buffer.bind_vertex(&self.device, 0, model.vertex_buffer());
+    draw_command_buffer.bind_vertex(&self.device, 1, instance_buffer);
     draw_command_buffer.bind_index(&self.device, model.index_buffer());
 
-    let translation = Vec3::new(data[0], data[1], data[2]);
-    //let rotation = Vec3::new(data[8], data[9], data[10]);
-    let rotation = Quat::from_euler(
-      EulerRot::YXZ,
-      data[9].to_radians(),
-      data[8].to_radians(),
-      data[10].to_radians(),
-    );
-    let scale = Vec3::new(data[4], data[5], data[6]);
-
-    for i in 0..model.nodes().len() {
-      self.draw_node(
+    let skins = model.skins();
+    let materials = model.materials();
+    let mesh_nodes = model
+      .nodes()
+      .iter()
+      .filter(|node| !node.mesh.primitives.is_empty());
+    for (slot, node) in mesh_nodes.enumerate() {
+      draw_command_buffer.bind_descriptor_sets(
+        &self.device,
         shader,
-        mesh_descriptor,
-        i,
-        translation,
-        rotation,
-        scale,
-        &data,
-        model.nodes(),
-        &model.skins(),
-        &model.materials(),
-        dummy_skin,
+        1,
+        if !skins.is_empty() && node.skin != -1 {
+          vec![&skins[node.skin as usize].descriptor_set]
+        } else {
+          vec![dummy_skin]
+        },
+        false,
       );
+
+      for primitive in &node.mesh.primitives {
+        if primitive.index_count > 0 {
+          let image_descriptor = if materials.len() > primitive.material_index as usize {
+            materials[primitive.material_index as usize].descriptor()
+          } else {
+            mesh_descriptor
+          };
+
+          draw_command_buffer.bind_descriptor_sets(
+            &self.device,
+            shader,
+            2,
+            vec![image_descriptor],
+            false,
+          );
+
+          draw_command_buffer.draw_indexed(
+            &self.device,
+            primitive.index_count,
+            instance_count as u32,
+            primitive.first_index,
+            (slot * instance_count) as u32,
+          );
+        }
+      }
     }
   }
 
+  #[allow(clippy::too_many_arguments)]
   fn draw_node<T: Copy>(
     &mut self,
     shader: &Shader<T>,
@@ -863,13 +2130,35 @@ impl Vulkan {
     skins: &Vec<Skin>,
     materials: &Vec<Material>,
     dummy_skin: &DescriptorSet,
-  ) {
+    frustum: Option<&Frustum>,
+  ) -> CullingStats {
     let draw_command_buffer = self.frames_in_flight[self.current_frame].command_buffer();
+    let mut stats = CullingStats::default();
+
+    let global_matrix = Node::calculate_global_matrix(nodes, idx, translation, rotation, scale);
+
+    // Skinned vertices move away from their bounds so those are always drawn
+    let visible_primitives = nodes[idx]
+      .mesh
+      .primitives
+      .iter()
+      .filter(|primitive| primitive.index_count > 0)
+      .filter(|primitive| match frustum {
+        Some(frustum) if nodes[idx].skin == -1 => {
+          let visible = frustum.intersects_aabb(&primitive.bounds().transform(&global_matrix));
+          if !visible {
+            stats.culled += 1;
+          }
+          visible
+        }
+        _ => true,
+      })
+      .collect::<Vec<_>>();
+    stats.drawn = visible_primitives.len();
 
-    if nodes[idx].mesh.primitives.len() > 0 {
+    if !visible_primitives.is_empty() {
       let push_constant_data: [f32; 32] = [0.0; 32];
-      let matrix: [f32; 16] =
-        Node::calculate_global_matrix(nodes, idx, translation, rotation, scale).to_cols_array();
+      let matrix: [f32; 16] = global_matrix.to_cols_array();
 
       let push_constant_data = push_constant_data
         .iter()
@@ -888,12 +2177,7 @@ impl Vulkan {
         })
         .collect::<Vec<f32>>();
 
-      draw_command_buffer.push_constants(
-        &self.device,
-        shader,
-        vk::ShaderStageFlags::VERTEX,
-        push_constant_data,
-      );
+      draw_command_buffer.push_constants(&self.device, shader, push_constant_data);
 
       draw_command_buffer.bind_descriptor_sets(
         &self.device,
@@ -907,32 +2191,32 @@ impl Vulkan {
         false,
       );
 
-      for primitive in &nodes[idx].mesh.primitives {
-        if primitive.index_count > 0 {
-          let image_descriptor = if materials.len() > primitive.material_index as usize {
-            materials[primitive.material_index as usize].descriptor()
-          } else {
-            mesh_descriptor
-          };
+      for primitive in visible_primitives {
+        let image_descriptor = if materials.len() > primitive.material_index as usize {
+          materials[primitive.material_index as usize].descriptor()
+        } else {
+          mesh_descriptor
+        };
 
-          draw_command_buffer.bind_descriptor_sets(
-            &self.device,
-            shader,
-            2,
-            vec![image_descriptor],
-            false,
-          );
+        draw_command_buffer.bind_descriptor_sets(
+          &self.device,
+          shader,
+          2,
+          vec![image_descriptor],
+          false,
+        );
 
-          draw_command_buffer.draw_indexed(
-            &self.device,
-            primitive.index_count,
-            1,
-            primitive.first_index,
-            0,
-          );
-        }
+        draw_command_buffer.draw_indexed(
+          &self.device,
+          primitive.index_count,
+          1,
+          primitive.first_index,
+          0,
+        );
       }
     }
+
+    stats
   }
 
   pub fn end_renderpass(&mut self) {
@@ -942,6 +2226,10 @@ impl Vulkan {
   }
 
   pub fn start_render(&mut self) -> Option<u32> {
+    // The frame's semaphores, command buffer and per frame buffers are only free to reuse once
+    // its last submission is done
+    self.frames_in_flight[self.current_frame].wait(&self.device);
+
     let present_index_result = unsafe {
       self.swapchain.swapchain_loader().acquire_next_image(
         *self.swapchain.internal(),
@@ -965,6 +2253,9 @@ impl Vulkan {
     command_buffer.reset(&self.device);
     command_buffer.begin(&self.device);
 
+    self.clear_new_render_targets();
+    self.destroy_finished_resources();
+
     Some(present_index)
   }
 
@@ -992,8 +2283,20 @@ impl Vulkan {
     let wait_mask = vec![vk::PipelineStageFlags::BOTTOM_OF_PIPE];
     let _submit_queue = self.device.present_queue();
 
-    let wait_semaphores = vec![present_semaphore];
-    let signal_semaphores = vec![render_semaphore];
+    let mut wait_semaphores = vec![present_semaphore];
+    let mut wait_mask = wait_mask;
+    let mut signal_semaphores = vec![render_semaphore];
+
+    // Async compute ran since the last frame, wait for it and hand the buffers back after
+    if self.compute_signalled > 0 {
+      for semaphore in &self.compute_semaphores[..self.compute_signalled] {
+        wait_semaphores.push(semaphore);
+        wait_mask.push(vk::PipelineStageFlags::FRAGMENT_SHADER);
+      }
+      signal_semaphores.push(&self.graphics_semaphore);
+      self.compute_signalled = 0;
+      self.graphics_signalled = true;
+    }
 
     command_buffer.end(&self.device);
     command_buffer.submit_queue(
@@ -1038,6 +2341,7 @@ impl Vulkan {
     };
 
     self.current_frame = (self.current_frame + 1) % self.max_frames_in_flight;
+    self.frame_count += 1;
   }
 
   pub fn begin_renderpass_texture(&mut self, present_index: u32) {
@@ -1051,14 +2355,184 @@ impl Vulkan {
     );
   }
 
-  pub fn begin_renderpass_model(&mut self, present_index: u32) {
+  /// The model pass draws into the HDR image, which isn't tied to a swapchain image.
+  pub fn begin_renderpass_model(&mut self, _present_index: u32) {
     let command_buffer = self.frames_in_flight[self.current_frame].command_buffer();
+
+    // The previous frame may still be sampling the HDR image
+    unsafe {
+      self.device.internal().cmd_pipeline_barrier(
+        command_buffer.internal(),
+        vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER,
+        vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        vk::DependencyFlags::empty(),
+        &[],
+        &[],
+        &[],
+      );
+    }
+
     command_buffer.begin_renderpass(
       &self.device,
       &self.clear_values,
       &self.model_renderpass,
-      self.framebuffer.framebuffers()[present_index as usize],
+      self.hdr_framebuffer.framebuffers()[0],
       self.swapchain.extent(),
     );
   }
+
+  /// Creates an offscreen target the model pipelines can draw into, replacing any with the same
+  /// name. Its colour image reads as transparent black until something is drawn into it.
+  pub fn create_render_target(&mut self, name: &str, width: u32, height: u32) -> &RenderTarget {
+    self.destroy_render_target(name);
+
+    let render_target = RenderTarget::new(
+      &self.device,
+      width,
+      height,
+      &self.model_renderpass,
+      self.msaa_samples,
+    );
+
+    self
+      .named_render_targets
+      .entry(name.to_string())
+      .or_insert(render_target)
+  }
+
+  pub fn render_target(&self, name: &str) -> Option<&RenderTarget> {
+    self.named_render_targets.get(name)
+  }
+
+  pub fn destroy_render_target(&mut self, name: &str) {
+    if let Some(render_target) = self.named_render_targets.remove(name) {
+      self.destroy_after_frames_in_flight(move |device| {
+        render_target.destroy(device);
+      });
+    }
+  }
+
+  // Render targets are sampled as soon as they exist, so give them a defined layout and contents
+  // before anything in the frame can read them
+  fn clear_new_render_targets(&mut self) {
+    let command_buffer = self.frames_in_flight[self.current_frame]
+      .command_buffer()
+      .internal();
+
+    let subresource_range = vk::ImageSubresourceRange {
+      aspect_mask: vk::ImageAspectFlags::COLOR,
+      base_mip_level: 0,
+      level_count: 1,
+      base_array_layer: 0,
+      layer_count: 1,
+    };
+
+    for render_target in self.named_render_targets.values_mut() {
+      if render_target.is_cleared() {
+        continue;
+      }
+
+      let image = render_target.colour_image().internal();
+      let to_transfer = vk::ImageMemoryBarrier::builder()
+        .old_layout(vk::ImageLayout::UNDEFINED)
+        .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+        .image(image)
+        .subresource_range(subresource_range);
+      let to_sampled = vk::ImageMemoryBarrier::builder()
+        .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+        .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+        .dst_access_mask(vk::AccessFlags::SHADER_READ)
+        .image(image)
+        .subresource_range(subresource_range);
+
+      unsafe {
+        self.device.internal().cmd_pipeline_barrier(
+          command_buffer,
+          vk::PipelineStageFlags::TOP_OF_PIPE,
+          vk::PipelineStageFlags::TRANSFER,
+          vk::DependencyFlags::empty(),
+          &[],
+          &[],
+          &[to_transfer.build()],
+        );
+        self.device.internal().cmd_clear_color_image(
+          command_buffer,
+          image,
+          vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+          &vk::ClearColorValue {
+            float32: [0.0, 0.0, 0.0, 0.0],
+          },
+          &[subresource_range],
+        );
+        self.device.internal().cmd_pipeline_barrier(
+          command_buffer,
+          vk::PipelineStageFlags::TRANSFER,
+          vk::PipelineStageFlags::FRAGMENT_SHADER,
+          vk::DependencyFlags::empty(),
+          &[],
+          &[],
+          &[to_sampled.build()],
+        );
+      }
+
+      render_target.set_cleared();
+    }
+  }
+
+  /// Starts a model pass into a named render target, drawing with its viewport until
+  /// `end_renderpass_render_target`. Returns false if there is no target with that name.
+  pub fn begin_renderpass_render_target(&mut self, name: &str) -> bool {
+    let render_target = match self.named_render_targets.get(name) {
+      Some(render_target) => render_target,
+      None => return false,
+    };
+
+    let command_buffer = self.frames_in_flight[self.current_frame].command_buffer();
+
+    // Sprites in the previous frame may still be sampling the colour image
+    unsafe {
+      self.device.internal().cmd_pipeline_barrier(
+        command_buffer.internal(),
+        vk::PipelineStageFlags::FRAGMENT_SHADER,
+        vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        vk::DependencyFlags::empty(),
+        &[],
+        &[],
+        &[],
+      );
+    }
+
+    command_buffer.begin_renderpass(
+      &self.device,
+      &self.clear_values,
+      &self.model_renderpass,
+      render_target.framebuffer(),
+      render_target.extent(),
+    );
+
+    let (viewports, scissors) = Vulkan::screen_viewport(render_target.extent());
+    self.viewports = viewports;
+    self.scissors = scissors;
+
+    true
+  }
+
+  /// Ends the pass and leaves the colour image ready for sprites to sample.
+  pub fn end_renderpass_render_target(&mut self, name: &str) {
+    self.end_renderpass();
+
+    if let Some(render_target) = self.named_render_targets.get(name) {
+      self.record_attachment_to_sampled(render_target.colour_image().internal());
+    }
+
+    let (viewports, scissors) = Vulkan::screen_viewport(self.swapchain.extent());
+    self.viewports = viewports;
+    self.scissors = scissors;
+  }
 }